@@ -0,0 +1,562 @@
+//! Append-only log of the changes made through the API and MCP: who made them
+//! (browser session, API token or MCP session), with which parameters, and how
+//! they turned out.
+
+use crate::auth::{self, AuthMethod, Principal};
+use crate::db::DbPool;
+use crate::jobs::{self, now_millis, JobRunner};
+use crate::models::AuditEntry;
+use crate::state::AppState;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{MatchedPath, Query, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+pub const ACTOR_SESSION: &str = "session";
+pub const ACTOR_TOKEN: &str = "token";
+pub const ACTOR_MCP: &str = "mcp";
+
+/// Request bodies up to this size are recorded; larger ones are only described.
+const MAX_RECORDED_BODY: usize = 64 * 1024;
+/// Error responses are kept up to this size.
+const MAX_RECORDED_ERROR: usize = 4 * 1024;
+const REDACTED: &str = "[redacted]";
+
+/// Parameter names whose values are never stored.
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "secret",
+    "token",
+    "api_key",
+    "apikey",
+    "authorization",
+    "private_key",
+];
+
+pub struct NewEntry<'a> {
+    pub principal: &'a Principal,
+    /// Set for tool calls made over MCP, empty when the client sent no session id
+    pub mcp_session: Option<&'a str>,
+    pub action: &'a str,
+    pub path: Option<&'a str>,
+    pub server_id: Option<i64>,
+    pub params: &'a Value,
+    /// HTTP status of the response, for API requests
+    pub status: Option<u16>,
+    pub success: bool,
+    pub error: Option<&'a str>,
+    /// Job the action started
+    pub job_id: Option<&'a str>,
+}
+
+pub async fn record(db: &DbPool, e: NewEntry<'_>) -> Result<i64, sqlx::Error> {
+    let (actor, token_id) = match (e.mcp_session, e.principal.method) {
+        (Some(_), AuthMethod::Token(id)) => (ACTOR_MCP, Some(id)),
+        (Some(_), AuthMethod::Session) => (ACTOR_MCP, None),
+        (None, AuthMethod::Token(id)) => (ACTOR_TOKEN, Some(id)),
+        (None, AuthMethod::Session) => (ACTOR_SESSION, None),
+    };
+    // The token name is copied so the entry stays readable once the token is revoked
+    let res = sqlx::query(
+        "INSERT INTO audit_log (created_at, actor, user_id, username, token_id, token_name, mcp_session, action, path, server_id, params, status, success, error, job_id) VALUES (?, ?, ?, ?, ?, (SELECT name FROM api_tokens WHERE id = ?), ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(now_millis())
+    .bind(actor)
+    .bind(e.principal.user_id)
+    .bind(&e.principal.username)
+    .bind(token_id)
+    .bind(token_id)
+    .bind(e.mcp_session.filter(|session| !session.is_empty()))
+    .bind(e.action)
+    .bind(e.path)
+    .bind(e.server_id)
+    .bind(redact(e.params).to_string())
+    .bind(e.status.map(i64::from))
+    .bind(e.success)
+    .bind(e.error)
+    .bind(e.job_id)
+    .execute(db)
+    .await?;
+
+    Ok(res.last_insert_rowid())
+}
+
+/// Waits for the job started with `entry` to end, then records its outcome as a
+/// copy of the entry without an HTTP status.
+pub async fn record_job_end(
+    db: &DbPool,
+    jobs: &JobRunner,
+    job_id: &str,
+    entry: NewEntry<'_>,
+) -> Result<i64, sqlx::Error> {
+    jobs.wait(job_id).await?;
+    let job = jobs.get(job_id).await?.map(|detail| detail.job);
+    let status = job
+        .as_ref()
+        .map(|job| job.status.as_str())
+        .unwrap_or("unknown");
+    let error = match job.as_ref().and_then(|job| job.error.clone()) {
+        Some(error) => Some(error),
+        None if status != jobs::STATUS_SUCCEEDED => Some(format!("Job {}", status)),
+        None => None,
+    };
+    record(
+        db,
+        NewEntry {
+            status: None,
+            success: status == jobs::STATUS_SUCCEEDED,
+            error: error.as_deref(),
+            job_id: Some(job_id),
+            ..entry
+        },
+    )
+    .await
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_KEYS.iter().any(|secret| key.contains(secret))
+}
+
+/// Copy of `params` without credentials. Compose files are reduced to their size,
+/// as their environment entries commonly hold passwords.
+pub fn redact(params: &Value) -> Value {
+    match params {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let value = if is_secret(key) && !value.is_null() {
+                        Value::from(REDACTED)
+                    } else if key == "content" && value.is_string() {
+                        Value::from(format!(
+                            "[{} bytes]",
+                            value.as_str().unwrap_or_default().len()
+                        ))
+                    } else {
+                        redact(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        _ => params.clone(),
+    }
+}
+
+/// Server a request is about, from its path or a `server_id`/`serverId` parameter.
+fn server_of(path: &str, params: &Value) -> Option<i64> {
+    auth::path_server(path).or_else(|| {
+        ["server_id", "serverId"]
+            .iter()
+            .find_map(|key| params.get(key)?.as_i64())
+    })
+}
+
+/// Records every authenticated request that may change something. Runs after
+/// [`auth::require_auth`], so requests it rejects are not recorded. MCP tool calls
+/// are recorded by the tool handler instead, with their session.
+pub async fn record_requests(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    let Some(principal) = req.extensions().get::<Principal>().cloned() else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path().to_string();
+    if path == "/mcp" || path == "/sse" {
+        return next.run(req).await;
+    }
+    let action = format!(
+        "{} {}",
+        req.method(),
+        req.extensions()
+            .get::<MatchedPath>()
+            .map(|matched| matched.as_str())
+            .unwrap_or(&path)
+    );
+
+    let (req, params) = request_params(req).await;
+    let server_id = server_of(&path, &params);
+
+    let res = next.run(req).await;
+    let status = res.status();
+    let (res, error, job_id) = if status == StatusCode::ACCEPTED {
+        let (res, job_id) = started_job(res).await;
+        (res, None, job_id)
+    } else if status.is_success() {
+        (res, None, None)
+    } else {
+        let (res, error) = error_text(res).await;
+        (res, error, None)
+    };
+
+    let entry = NewEntry {
+        principal: &principal,
+        mcp_session: None,
+        action: &action,
+        path: Some(&path),
+        server_id,
+        params: &params,
+        status: Some(status.as_u16()),
+        success: status.is_success(),
+        error: error.as_deref(),
+        job_id: job_id.as_deref(),
+    };
+    if let Err(e) = record(&state.db, entry).await {
+        tracing::error!("Failed to write audit log entry for {}: {}", action, e);
+    }
+
+    // Accepting a job says nothing about its outcome, which gets an entry of its own
+    if let Some(job_id) = job_id {
+        tokio::spawn(async move {
+            let entry = NewEntry {
+                principal: &principal,
+                mcp_session: None,
+                action: &action,
+                path: Some(&path),
+                server_id,
+                params: &params,
+                status: Some(status.as_u16()),
+                success: true,
+                error: None,
+                job_id: Some(&job_id),
+            };
+            if let Err(e) = record_job_end(&state.db, &state.jobs, &job_id, entry).await {
+                tracing::error!("Failed to write audit log entry for job {}: {}", job_id, e);
+            }
+        });
+    }
+    res
+}
+
+/// Job id of a `202 Accepted` response, e.g. [`crate::models::JobStarted`]. The
+/// body is put back for the client.
+async fn started_job(res: Response) -> (Response, Option<String>) {
+    let (parts, body) = res.into_parts();
+    match to_bytes(body, MAX_RECORDED_BODY).await {
+        Ok(bytes) => {
+            let job_id = serde_json::from_slice::<Value>(&bytes)
+                .ok()
+                .and_then(|body| Some(body.get("job_id")?.as_str()?.to_string()));
+            (Response::from_parts(parts, Body::from(bytes)), job_id)
+        }
+        Err(_) => (Response::from_parts(parts, Body::empty()), None),
+    }
+}
+
+/// Query parameters and JSON body of a request, merged into one object. The body is
+/// put back so the handler can still read it.
+async fn request_params(req: Request) -> (Request, Value) {
+    let mut params: Map<String, Value> = Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        .map(|Query(query)| {
+            query
+                .into_iter()
+                .map(|(key, value)| (key, Value::from(value)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+
+    match length {
+        None | Some(0) => (req, Value::Object(params)),
+        // Uploads and other large bodies are streamed to the handler untouched
+        Some(length) if length > MAX_RECORDED_BODY || !content_type.contains("json") => {
+            params.insert(
+                "body".to_string(),
+                Value::from(format!("[{} bytes of {}]", length, content_type)),
+            );
+            (req, Value::Object(params))
+        }
+        Some(_) => {
+            let (parts, body) = req.into_parts();
+            let bytes = to_bytes(body, MAX_RECORDED_BODY).await.unwrap_or_default();
+            match serde_json::from_slice::<Value>(&bytes) {
+                Ok(Value::Object(body)) => params.extend(body),
+                Ok(body) => {
+                    params.insert("body".to_string(), body);
+                }
+                Err(_) => {
+                    params.insert("body".to_string(), Value::from("[invalid JSON]"));
+                }
+            }
+            (
+                Request::from_parts(parts, Body::from(bytes)),
+                Value::Object(params),
+            )
+        }
+    }
+}
+
+/// Text of an error response, which is put back for the client.
+async fn error_text(res: Response) -> (Response, Option<String>) {
+    let (parts, body) = res.into_parts();
+    match to_bytes(body, MAX_RECORDED_ERROR).await {
+        Ok(bytes) => {
+            let text = String::from_utf8_lossy(&bytes).trim().to_string();
+            (
+                Response::from_parts(parts, Body::from(bytes)),
+                (!text.is_empty()).then_some(text),
+            )
+        }
+        // Too long to keep; the status has to do
+        Err(_) => (Response::from_parts(parts, Body::empty()), None),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuditFilter<'a> {
+    pub username: Option<&'a str>,
+    /// session, token or mcp
+    pub actor: Option<&'a str>,
+    /// Part of the action, e.g. `containers/operate`
+    pub action: Option<&'a str>,
+    pub server_id: Option<i64>,
+    pub success: Option<bool>,
+    /// Only entries at or after this time (ms since epoch)
+    pub from: Option<i64>,
+    /// Only entries before this time (ms since epoch)
+    pub to: Option<i64>,
+}
+
+const WHERE: &str = "WHERE (? IS NULL OR username = ?) AND (? IS NULL OR actor = ?) AND (? IS NULL OR action LIKE '%' || ? || '%') AND (? IS NULL OR server_id = ?) AND (? IS NULL OR success = ?) AND (? IS NULL OR created_at >= ?) AND (? IS NULL OR created_at < ?)";
+
+macro_rules! bind_filter {
+    ($query:expr, $filter:expr) => {
+        $query
+            .bind($filter.username)
+            .bind($filter.username)
+            .bind($filter.actor)
+            .bind($filter.actor)
+            .bind($filter.action)
+            .bind($filter.action)
+            .bind($filter.server_id)
+            .bind($filter.server_id)
+            .bind($filter.success)
+            .bind($filter.success)
+            .bind($filter.from)
+            .bind($filter.from)
+            .bind($filter.to)
+            .bind($filter.to)
+    };
+}
+
+/// Returns up to `limit` matching entries after skipping `offset`, newest first,
+/// and the total number of matches.
+pub async fn list(
+    db: &DbPool,
+    filter: &AuditFilter<'_>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AuditEntry>, i64), sqlx::Error> {
+    let total: (i64,) = bind_filter!(
+        sqlx::query_as(&format!("SELECT COUNT(*) FROM audit_log {}", WHERE)),
+        filter
+    )
+    .fetch_one(db)
+    .await?;
+
+    let query = format!(
+        "SELECT * FROM audit_log {} ORDER BY id DESC LIMIT ? OFFSET ?",
+        WHERE
+    );
+    let items = bind_filter!(sqlx::query_as::<_, AuditEntry>(&query), filter)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(db)
+        .await?;
+
+    Ok((items, total.0))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Entries as CSV, one per line after a header.
+pub fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut out = String::from(
+        "id,created_at,actor,user_id,username,token_id,token_name,mcp_session,action,path,server_id,params,status,success,error,job_id\n",
+    );
+    for e in entries {
+        let fields = [
+            e.id.to_string(),
+            e.created_at.to_string(),
+            e.actor.clone(),
+            e.user_id.map(|id| id.to_string()).unwrap_or_default(),
+            e.username.clone().unwrap_or_default(),
+            e.token_id.map(|id| id.to_string()).unwrap_or_default(),
+            e.token_name.clone().unwrap_or_default(),
+            e.mcp_session.clone().unwrap_or_default(),
+            e.action.clone(),
+            e.path.clone().unwrap_or_default(),
+            e.server_id.map(|id| id.to_string()).unwrap_or_default(),
+            e.params.clone().unwrap_or_default(),
+            e.status.map(|s| s.to_string()).unwrap_or_default(),
+            e.success.to_string(),
+            e.error.clone().unwrap_or_default(),
+            e.job_id.clone().unwrap_or_default(),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&line.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::NewJob;
+    use axum::response::IntoResponse;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn redacts_credentials_and_compose_files() {
+        let params = json!({
+            "name": "prod",
+            "api_key": "k",
+            "Password": "p",
+            "registry": { "username": "me", "client_secret": "s", "token": null },
+            "items": [{ "accessToken": "t" }, "plain"],
+            "content": "services: {}",
+            "server_id": 3,
+        });
+
+        assert_eq!(
+            redact(&params),
+            json!({
+                "name": "prod",
+                "api_key": REDACTED,
+                "Password": REDACTED,
+                "registry": { "username": "me", "client_secret": REDACTED, "token": null },
+                "items": [{ "accessToken": REDACTED }, "plain"],
+                "content": "[12 bytes]",
+                "server_id": 3,
+            })
+        );
+    }
+
+    fn entry(action: &str, params: &str, error: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            id: 1,
+            created_at: 1700000000000,
+            actor: ACTOR_TOKEN.to_string(),
+            user_id: Some(1),
+            username: Some("admin".to_string()),
+            token_id: Some(2),
+            token_name: Some("ci".to_string()),
+            mcp_session: None,
+            action: action.to_string(),
+            path: Some("/api/deploy".to_string()),
+            server_id: None,
+            params: Some(params.to_string()),
+            status: Some(202),
+            success: true,
+            error: error.map(str::to_string),
+            job_id: Some("job-1".to_string()),
+        }
+    }
+
+    #[test]
+    fn escapes_csv_fields() {
+        let csv = to_csv(&[entry(
+            "POST /api/deploy",
+            r#"{"path":"/src/web","tag":"a,b"}"#,
+            Some("line one\nline \"two\""),
+        )]);
+        let lines: Vec<&str> = csv.splitn(2, '\n').collect();
+
+        assert!(lines[0].ends_with(",error,job_id"));
+        assert_eq!(
+            lines[1],
+            "1,1700000000000,token,1,admin,2,ci,,POST /api/deploy,/api/deploy,,\"{\"\"path\"\":\"\"/src/web\"\",\"\"tag\"\":\"\"a,b\"\"}\",202,true,\"line one\nline \"\"two\"\"\",job-1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_job_ids_of_accepted_requests() {
+        let res = (
+            StatusCode::ACCEPTED,
+            axum::Json(json!({ "job_id": "job-1" })),
+        )
+            .into_response();
+        let (res, job_id) = started_job(res).await;
+
+        assert_eq!(job_id.as_deref(), Some("job-1"));
+        let body = to_bytes(res.into_body(), MAX_RECORDED_BODY).await.unwrap();
+        assert_eq!(&body[..], br#"{"job_id":"job-1"}"#);
+    }
+
+    #[tokio::test]
+    async fn records_how_jobs_end() {
+        let db = crate::db::memory().await;
+        let jobs = JobRunner::new(Arc::new(db.clone()));
+        let job_id = jobs
+            .spawn(
+                NewJob {
+                    kind: "deploy",
+                    repository_path: None,
+                    params: &json!({}),
+                    retry_of: None,
+                    triggered_by: "admin",
+                },
+                |_| async { Err(anyhow::anyhow!("compose up failed")) },
+            )
+            .await
+            .unwrap();
+        let principal = Principal {
+            user_id: 1,
+            username: "admin".to_string(),
+            method: AuthMethod::Session,
+            role: auth::Role::Admin,
+            servers: None,
+            repositories: None,
+        };
+        let params = json!({ "path": "/src/web" });
+        let entry = || NewEntry {
+            principal: &principal,
+            mcp_session: None,
+            action: "POST /api/deploy",
+            path: Some("/api/deploy"),
+            server_id: None,
+            params: &params,
+            status: Some(202),
+            success: true,
+            error: None,
+            job_id: Some(&job_id),
+        };
+
+        record(&db, entry()).await.unwrap();
+        record_job_end(&db, &jobs, &job_id, entry()).await.unwrap();
+
+        let (items, total) = list(&db, &AuditFilter::default(), 10, 0).await.unwrap();
+        assert_eq!(total, 2);
+        let (ended, accepted) = (&items[0], &items[1]);
+        assert_eq!((accepted.status, accepted.success), (Some(202), true));
+        assert_eq!((ended.status, ended.success), (None, false));
+        assert_eq!(ended.error.as_deref(), Some("compose up failed"));
+        assert_eq!(ended.job_id, accepted.job_id);
+        assert_eq!(ended.action, accepted.action);
+    }
+}
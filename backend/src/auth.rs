@@ -0,0 +1,901 @@
+use crate::db::DbPool;
+use crate::jobs::now_millis;
+use crate::models::{ApiToken, Job, User};
+use crate::pipeline;
+use crate::state::AppState;
+use crate::targets;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::{Component, Path};
+
+pub const SESSION_COOKIE: &str = "onepanel_session";
+/// Sessions last a week from login.
+const SESSION_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// Marks API tokens so they are recognisable in scripts and secret scanners.
+const TOKEN_PREFIX: &str = "opci_";
+
+/// What a user or token may do. Each role may do everything the ones before it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Lists servers, repositories, deployments and jobs
+    Viewer,
+    /// Also builds images
+    Builder,
+    /// Also deploys, rolls back, promotes and operates containers and compose stacks
+    Deployer,
+    /// Also manages servers, repositories, registries, users and the 1Panel proxy
+    Admin,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "viewer" => Some(Role::Viewer),
+            "builder" => Some(Role::Builder),
+            "deployer" => Some(Role::Deployer),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Builder => "builder",
+            Role::Deployer => "deployer",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Role stored in the database; unknown values grant the least.
+    fn of(s: &str) -> Self {
+        Role::parse(s).unwrap_or(Role::Viewer)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Who a request is made by, set on every authenticated request.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub user_id: i64,
+    pub username: String,
+    pub method: AuthMethod,
+    /// The user's role, lowered to the token's when a token is used
+    pub role: Role,
+    /// Servers the caller may act on; all when `None`
+    pub servers: Option<Vec<i64>>,
+    /// Repository paths the caller may act on; all when `None`
+    pub repositories: Option<Vec<String>>,
+}
+
+/// Why a request may not be carried out.
+#[derive(Debug)]
+pub enum AccessError {
+    Forbidden(String),
+    /// The permissions could not be checked
+    Internal(String),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::Forbidden(msg) => write!(f, "Permission denied: {}", msg),
+            AccessError::Internal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl IntoResponse for AccessError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AccessError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AccessError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+impl From<sqlx::Error> for AccessError {
+    fn from(e: sqlx::Error) -> Self {
+        AccessError::Internal(e.to_string())
+    }
+}
+
+impl Principal {
+    pub fn require(&self, role: Role) -> Result<(), AccessError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            Err(AccessError::Forbidden(format!(
+                "this needs the {} role, {} has {}",
+                role, self.username, self.role
+            )))
+        }
+    }
+
+    pub fn can_access_server(&self, id: i64) -> bool {
+        self.servers.as_ref().is_none_or(|ids| ids.contains(&id))
+    }
+
+    pub fn can_access_repository(&self, path: &str) -> bool {
+        self.repositories
+            .as_ref()
+            .is_none_or(|repos| repos.iter().any(|repo| repo == path))
+    }
+
+    /// Whether `path` lies in a repository the caller may access, for file and git reads.
+    pub fn can_access_path(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        self.repositories.as_ref().is_none_or(|repos| {
+            !path.components().any(|c| c == Component::ParentDir)
+                && repos.iter().any(|repo| path.starts_with(repo))
+        })
+    }
+
+    pub fn require_server(&self, id: i64) -> Result<(), AccessError> {
+        if self.can_access_server(id) {
+            Ok(())
+        } else {
+            Err(AccessError::Forbidden(format!(
+                "server {} is outside the token's scope",
+                id
+            )))
+        }
+    }
+
+    pub fn require_repository(&self, path: &str) -> Result<(), AccessError> {
+        if self.can_access_repository(path) {
+            Ok(())
+        } else {
+            Err(AccessError::Forbidden(format!(
+                "repository {} is outside the token's scope",
+                path
+            )))
+        }
+    }
+
+    pub fn require_path(&self, path: &str) -> Result<(), AccessError> {
+        if self.can_access_path(path) {
+            Ok(())
+        } else {
+            Err(AccessError::Forbidden(format!(
+                "{} is outside the token's repositories",
+                path
+            )))
+        }
+    }
+
+    /// Whether the caller may act on every server and repository.
+    fn is_unscoped(&self) -> bool {
+        self.servers.is_none() && self.repositories.is_none()
+    }
+
+    /// Checks the caller meets `caller`, on top of its role.
+    fn require_caller(&self, caller: Caller) -> Result<(), AccessError> {
+        match caller {
+            Caller::Any => Ok(()),
+            Caller::Unscoped if self.is_unscoped() => Ok(()),
+            Caller::Unscoped => Err(AccessError::Forbidden(
+                "this needs a token without server or repository scope".to_string(),
+            )),
+            Caller::Session if self.method == AuthMethod::Session => Ok(()),
+            Caller::Session => Err(AccessError::Forbidden(
+                "this needs a browser session; API tokens cannot do it".to_string(),
+            )),
+        }
+    }
+
+    /// Checks the caller may see the job, and with `act` also cancel or retry it.
+    pub fn require_job(&self, job: &Job, act: bool) -> Result<(), AccessError> {
+        if act {
+            self.require(job_role(&job.kind))?;
+        }
+        if let Some(path) = &job.repository_path {
+            self.require_repository(path)?;
+        }
+        if let Some(id) = job.server_id {
+            self.require_server(id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Role needed to cancel or retry a job of `kind`.
+fn job_role(kind: &str) -> Role {
+    match kind {
+        pipeline::KIND_BUILD | pipeline::KIND_DOCKER_BUILD => Role::Builder,
+        _ => Role::Deployer,
+    }
+}
+
+/// Checks the caller may deploy the repository `path` (a path or name): the
+/// repository and every server it deploys to must be in scope. Unknown
+/// repositories are left to the deploy to report.
+pub async fn authorize_deploy(
+    db: &DbPool,
+    principal: &Principal,
+    path: &str,
+) -> Result<(), AccessError> {
+    if principal.servers.is_none() && principal.repositories.is_none() {
+        return Ok(());
+    }
+    let Ok(repo) = pipeline::resolve_repository(db, path).await else {
+        return Ok(());
+    };
+    principal.require_repository(&repo.path)?;
+    for target in targets::list(db, &repo).await? {
+        principal.require_server(target.server_id)?;
+    }
+    Ok(())
+}
+
+/// Checks the caller may promote the repository `path` into the environment `to`
+/// (by default the one after `from`).
+pub async fn authorize_promote(
+    db: &DbPool,
+    principal: &Principal,
+    path: &str,
+    from: &str,
+    to: Option<&str>,
+) -> Result<(), AccessError> {
+    if principal.servers.is_none() && principal.repositories.is_none() {
+        return Ok(());
+    }
+    let Ok(repo) = pipeline::resolve_repository(db, path).await else {
+        return Ok(());
+    };
+    principal.require_repository(&repo.path)?;
+    if let Ok((_, target)) = pipeline::resolve_environments(db, &repo, from, to).await {
+        principal.require_server(target.server_id)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// Browser session cookie
+    Session,
+    /// Bearer API token with this id
+    Token(i64),
+}
+
+pub async fn hash_password(password: String) -> anyhow::Result<String> {
+    // Argon2 is deliberately slow; keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("Could not hash password: {}", e))
+    })
+    .await?
+}
+
+pub async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// New random secret, and the hash stored in its place.
+fn new_secret(prefix: &str) -> (String, String) {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let secret = format!(
+        "{}{}",
+        prefix,
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+    let hash = hash_secret(&secret);
+    (secret, hash)
+}
+
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+pub async fn user_count(db: &DbPool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users")
+        .fetch_one(db)
+        .await
+}
+
+/// Creates the admin, unless a user exists already.
+pub async fn create_first_user(
+    db: &DbPool,
+    username: &str,
+    password_hash: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(
+        "INSERT INTO users (username, password_hash, role, created_at)
+         SELECT ?, ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM users) RETURNING id",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(Role::Admin.as_str())
+    .bind(now_millis())
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list_users(db: &DbPool) -> Result<Vec<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY username ASC")
+        .fetch_all(db)
+        .await
+}
+
+pub async fn create_user(
+    db: &DbPool,
+    username: &str,
+    password_hash: &str,
+    role: Role,
+) -> Result<User, sqlx::Error> {
+    sqlx::query_as::<_, User>(
+        "INSERT INTO users (username, password_hash, role, created_at) VALUES (?, ?, ?, ?) RETURNING *",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(role.as_str())
+    .bind(now_millis())
+    .fetch_one(db)
+    .await
+}
+
+pub async fn set_role(db: &DbPool, user_id: i64, role: Role) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+        .bind(role.as_str())
+        .bind(user_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Number of admins other than `user_id`, so the last one is not demoted or deleted.
+pub async fn other_admins(db: &DbPool, user_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users WHERE role = ? AND id != ?")
+        .bind(Role::Admin.as_str())
+        .bind(user_id)
+        .fetch_one(db)
+        .await
+}
+
+/// Deletes the user with their sessions and API tokens.
+pub async fn delete_user(db: &DbPool, user_id: i64) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM api_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    let res = sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn find_user(db: &DbPool, username: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn get_user(db: &DbPool, id: i64) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn set_password(
+    db: &DbPool,
+    user_id: i64,
+    password_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET password_hash = ? WHERE id = ?")
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Starts a session for the user and returns its secret, the cookie value.
+pub async fn create_session(db: &DbPool, user_id: i64) -> Result<String, sqlx::Error> {
+    let now = now_millis();
+    // Logging in is a good time to forget sessions nobody will use again
+    sqlx::query("DELETE FROM sessions WHERE expires_at <= ?")
+        .bind(now)
+        .execute(db)
+        .await?;
+
+    let (secret, hash) = new_secret("");
+    sqlx::query(
+        "INSERT INTO sessions (token_hash, user_id, created_at, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(hash)
+    .bind(user_id)
+    .bind(now)
+    .bind(now + SESSION_TTL_SECS * 1000)
+    .execute(db)
+    .await?;
+    Ok(secret)
+}
+
+pub async fn delete_session(db: &DbPool, secret: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE token_hash = ?")
+        .bind(hash_secret(secret))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Ends every session of the user except `keep`, e.g. after a password change.
+pub async fn delete_other_sessions(
+    db: &DbPool,
+    user_id: i64,
+    keep: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE user_id = ? AND token_hash != ?")
+        .bind(user_id)
+        .bind(keep.map(hash_secret).unwrap_or_default())
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// An `api_tokens` row; the scope lists are stored as JSON.
+#[derive(sqlx::FromRow)]
+struct TokenRow {
+    id: i64,
+    name: String,
+    role: Option<String>,
+    server_ids: Option<String>,
+    repositories: Option<String>,
+    created_at: i64,
+    last_used_at: Option<i64>,
+    expires_at: Option<i64>,
+}
+
+impl From<TokenRow> for ApiToken {
+    fn from(row: TokenRow) -> Self {
+        ApiToken {
+            id: row.id,
+            name: row.name,
+            role: row.role,
+            server_ids: row.server_ids.as_deref().map(parse_scope),
+            repositories: row.repositories.as_deref().map(parse_scope),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+/// A stored scope list. An unreadable one allows nothing rather than everything.
+fn parse_scope<T: serde::de::DeserializeOwned>(json: &str) -> Vec<T> {
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+const TOKEN_COLUMNS: &str =
+    "id, name, role, server_ids, repositories, created_at, last_used_at, expires_at";
+
+pub async fn list_tokens(db: &DbPool, user_id: i64) -> Result<Vec<ApiToken>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, TokenRow>(&format!(
+        "SELECT {TOKEN_COLUMNS} FROM api_tokens WHERE user_id = ? ORDER BY created_at DESC"
+    ))
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(ApiToken::from).collect())
+}
+
+/// What a new token may do: a role no higher than its user's, and optionally
+/// only some servers and repositories.
+pub struct TokenGrant {
+    pub role: Option<Role>,
+    pub server_ids: Option<Vec<i64>>,
+    pub repositories: Option<Vec<String>>,
+}
+
+/// Creates an API token and returns it with its secret, which is not stored.
+pub async fn create_token(
+    db: &DbPool,
+    user_id: i64,
+    name: &str,
+    expires_at: Option<i64>,
+    grant: TokenGrant,
+) -> Result<(ApiToken, String), sqlx::Error> {
+    let (secret, hash) = new_secret(TOKEN_PREFIX);
+    let row = sqlx::query_as::<_, TokenRow>(&format!(
+        "INSERT INTO api_tokens (name, token_hash, user_id, role, server_ids, repositories, created_at, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING {TOKEN_COLUMNS}"
+    ))
+    .bind(name)
+    .bind(hash)
+    .bind(user_id)
+    .bind(grant.role.map(Role::as_str))
+    .bind(grant.server_ids.map(|ids| serde_json::json!(ids).to_string()))
+    .bind(grant.repositories.map(|repos| serde_json::json!(repos).to_string()))
+    .bind(now_millis())
+    .bind(expires_at)
+    .fetch_one(db)
+    .await?;
+    Ok((row.into(), secret))
+}
+
+pub async fn delete_token(db: &DbPool, user_id: i64, id: i64) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM api_tokens WHERE id = ? AND user_id = ?")
+        .bind(id)
+        .bind(user_id)
+        .execute(db)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Value of the session cookie sent with the request.
+pub fn session_secret(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// `Set-Cookie` value starting a session. `SameSite=Strict` keeps other sites
+/// from riding on it.
+pub fn session_cookie(secret: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE, secret, SESSION_TTL_SECS
+    ))
+    .expect("session secrets are hex")
+}
+
+/// `Set-Cookie` value ending the session in the browser.
+pub fn clear_session_cookie() -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0",
+        SESSION_COOKIE
+    ))
+    .expect("static cookie")
+}
+
+/// Identifies the caller from a bearer token, or else the session cookie.
+pub async fn authenticate(
+    db: &DbPool,
+    headers: &HeaderMap,
+) -> Result<Option<Principal>, sqlx::Error> {
+    let now = now_millis();
+
+    if let Some(token) = bearer_token(headers) {
+        let found = sqlx::query_as::<
+            _,
+            (
+                i64,
+                i64,
+                String,
+                String,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+            ),
+        >(
+            "SELECT t.id, u.id, u.username, u.role, t.role, t.server_ids, t.repositories
+             FROM api_tokens t JOIN users u ON u.id = t.user_id
+             WHERE t.token_hash = ? AND (t.expires_at IS NULL OR t.expires_at > ?)",
+        )
+        .bind(hash_secret(token))
+        .bind(now)
+        .fetch_optional(db)
+        .await?;
+        let Some((token_id, user_id, username, user_role, token_role, server_ids, repositories)) =
+            found
+        else {
+            return Ok(None);
+        };
+        sqlx::query("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(token_id)
+            .execute(db)
+            .await?;
+        // A token never grants more than its user currently has
+        let role = match token_role {
+            Some(token_role) => Role::of(&user_role).min(Role::of(&token_role)),
+            None => Role::of(&user_role),
+        };
+        return Ok(Some(Principal {
+            user_id,
+            username,
+            method: AuthMethod::Token(token_id),
+            role,
+            servers: server_ids.as_deref().map(parse_scope),
+            repositories: repositories.as_deref().map(parse_scope),
+        }));
+    }
+
+    if let Some(secret) = session_secret(headers) {
+        let found = sqlx::query_as::<_, (i64, String, String)>(
+            "SELECT u.id, u.username, u.role FROM sessions s JOIN users u ON u.id = s.user_id
+             WHERE s.token_hash = ? AND s.expires_at > ?",
+        )
+        .bind(hash_secret(secret))
+        .bind(now)
+        .fetch_optional(db)
+        .await?;
+        return Ok(found.map(|(user_id, username, role)| Principal {
+            user_id,
+            username,
+            method: AuthMethod::Session,
+            role: Role::of(&role),
+            servers: None,
+            repositories: None,
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Origins allowed to call the API from a browser: the UI as served on `port`, plus
+/// any listed in `ONEPANEL_ALLOWED_ORIGINS` (comma separated). Debug builds also
+/// allow the Vite dev server.
+pub fn allowed_origins(port: u16) -> Vec<HeaderValue> {
+    let mut origins = vec![
+        format!("http://localhost:{}", port),
+        format!("http://127.0.0.1:{}", port),
+    ];
+    if cfg!(debug_assertions) {
+        origins.push("http://localhost:5173".to_string());
+        origins.push("http://127.0.0.1:5173".to_string());
+    }
+    if let Ok(extra) = std::env::var("ONEPANEL_ALLOWED_ORIGINS") {
+        origins.extend(
+            extra
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty()),
+        );
+    }
+    origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect()
+}
+
+/// Paths reachable without logging in: the login flow and the static UI.
+fn is_public(path: &str) -> bool {
+    matches!(
+        path,
+        "/api/auth/status" | "/api/auth/setup" | "/api/auth/login" | "/api/auth/logout"
+    ) || !(path.starts_with("/api/") || path == "/sse" || path == "/mcp")
+}
+
+/// Who may make a request, besides having the role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Caller {
+    /// Any session or token; handlers check the token's scope
+    Any,
+    /// A session or a token limited to no servers or repositories, for changes
+    /// that reach beyond a scope
+    Unscoped,
+    /// A browser session only
+    Session,
+}
+
+/// Least role allowed to make a request, and who may make it. Reads need no more
+/// than a viewer; writes not listed here need an admin. Users, servers and
+/// registries are only managed from a session, and repositories are only added or
+/// removed without a scope. Handlers check scopes and finer permissions, e.g.
+/// cancelling a deploy job needs a deployer.
+fn required_role(method: &Method, path: &str) -> (Role, Caller) {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let read = matches!(*method, Method::GET | Method::HEAD);
+    let role = match segments.as_slice() {
+        ["api", "users", ..] => return (Role::Admin, Caller::Session),
+        ["api", "audit", ..] => return (Role::Admin, Caller::Unscoped),
+        ["api", "servers"] | ["api", "servers", _] | ["api", "registries", ..] if !read => {
+            return (Role::Admin, Caller::Session)
+        }
+        ["api", "directories"] if !read => return (Role::Admin, Caller::Unscoped),
+        ["api", "servers", _, "proxy", ..] => Role::Admin,
+        _ if read => Role::Viewer,
+        ["api", "auth", ..] | ["api", "notifications"] | ["sse"] | ["mcp"] => Role::Viewer,
+        // Queries sent as POST
+        ["api", "fs" | "git", _]
+        | ["api", "directories", "config" | "targets", "get"]
+        | ["api", "servers", _, "composes", "content"] => Role::Viewer,
+        ["api", "docker", ..] | ["api", "jobs", _, "cancel" | "retry"] => Role::Builder,
+        ["api", "deploy", ..]
+        | ["api", "environments", "promote"]
+        | ["api", "servers", _, "composes" | "containers" | "images" | "uploads", ..] => {
+            Role::Deployer
+        }
+        _ => Role::Admin,
+    };
+    (role, Caller::Any)
+}
+
+/// Server a request path is about, e.g. `/api/servers/3/composes`.
+pub(crate) fn path_server(path: &str) -> Option<i64> {
+    path.strip_prefix("/api/servers/")?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Rejects requests from other origins that change state, requests to the API
+/// without a valid session or token, and requests the caller's role or scope does
+/// not allow. The caller is added to the request extensions as a [`Principal`].
+pub async fn require_auth(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    // CORS only stops other pages from reading responses; a cross-origin form post
+    // would still be carried out, so writes are checked against the allowed origins
+    if !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        if let Some(origin) = req.headers().get(header::ORIGIN) {
+            if !state.allowed_origins.contains(origin) {
+                return (StatusCode::FORBIDDEN, "Cross-origin request rejected").into_response();
+            }
+        }
+    }
+
+    if is_public(req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    match authenticate(&state.db, req.headers()).await {
+        Ok(Some(principal)) => {
+            let path = req.uri().path();
+            let (role, caller) = required_role(req.method(), path);
+            let allowed = principal
+                .require(role)
+                .and_then(|()| principal.require_caller(caller))
+                .and_then(|()| match path_server(path) {
+                    Some(id) => principal.require_server(id),
+                    None => Ok(()),
+                });
+            if let Err(e) = allowed {
+                return e.into_response();
+            }
+            req.extensions_mut().insert(principal);
+            next.run(req).await
+        }
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Authentication required",
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_roles_per_route() {
+        use Caller::*;
+        use Role::*;
+
+        let get = Method::GET;
+        let post = Method::POST;
+        let put = Method::PUT;
+        let delete = Method::DELETE;
+        let cases = [
+            (&get, "/api/servers", Viewer, Any),
+            (&get, "/api/servers/3/containers", Viewer, Any),
+            (&get, "/api/deployments", Viewer, Any),
+            (&get, "/api/registries", Viewer, Any),
+            (&get, "/api/directories", Viewer, Any),
+            (&post, "/api/fs/read", Viewer, Any),
+            (&post, "/api/directories/targets/get", Viewer, Any),
+            (&post, "/api/servers/3/composes/content", Viewer, Any),
+            (&post, "/api/auth/tokens", Viewer, Any),
+            (&post, "/mcp", Viewer, Any),
+            (&post, "/api/docker/build", Builder, Any),
+            (&post, "/api/jobs/abc/retry", Builder, Any),
+            (&post, "/api/deploy", Deployer, Any),
+            (&post, "/api/environments/promote", Deployer, Any),
+            (
+                &post,
+                "/api/servers/3/composes/content/update",
+                Deployer,
+                Any,
+            ),
+            (&delete, "/api/servers/3/uploads", Deployer, Any),
+            (&post, "/api/directories/config/update", Admin, Any),
+            (&post, "/api/environments", Admin, Any),
+            (&post, "/api/promotions/4/approve", Admin, Any),
+            (&get, "/api/servers/3/proxy/api/v1/dashboard", Admin, Any),
+            (&get, "/api/audit", Admin, Unscoped),
+            (&post, "/api/directories", Admin, Unscoped),
+            (&delete, "/api/directories", Admin, Unscoped),
+            (&get, "/api/users", Admin, Session),
+            (&post, "/api/users", Admin, Session),
+            (&put, "/api/users/2", Admin, Session),
+            (&post, "/api/servers", Admin, Session),
+            (&put, "/api/servers/3", Admin, Session),
+            (&delete, "/api/servers/3", Admin, Session),
+            (&post, "/api/registries", Admin, Session),
+            (&delete, "/api/registries/1", Admin, Session),
+        ];
+        for (method, path, role, caller) in cases {
+            assert_eq!(
+                required_role(method, path),
+                (role, caller),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    fn principal(method: AuthMethod, servers: Option<Vec<i64>>) -> Principal {
+        Principal {
+            user_id: 1,
+            username: "admin".to_string(),
+            method,
+            role: Role::Admin,
+            servers,
+            repositories: None,
+        }
+    }
+
+    #[test]
+    fn limits_management_to_sessions_and_unscoped_tokens() {
+        let session = principal(AuthMethod::Session, None);
+        let token = principal(AuthMethod::Token(1), None);
+        let scoped = principal(AuthMethod::Token(2), Some(vec![3]));
+
+        for caller in [Caller::Any, Caller::Unscoped, Caller::Session] {
+            assert!(session.require_caller(caller).is_ok());
+        }
+        assert!(token.require_caller(Caller::Unscoped).is_ok());
+        assert!(token.require_caller(Caller::Session).is_err());
+        assert!(scoped.require_caller(Caller::Any).is_ok());
+        assert!(scoped.require_caller(Caller::Unscoped).is_err());
+    }
+}
@@ -0,0 +1,569 @@
+use anyhow::{anyhow, Result};
+use similar::TextDiff;
+use std::fmt;
+use yaml_rust2::parser::{Event, Parser};
+use yaml_rust2::scanner::{Marker, TScalarStyle};
+
+/// A Docker image reference: `[registry/]repository[:tag][@digest]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub name: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Self {
+        let reference = reference.trim();
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest.to_string())),
+            None => (reference, None),
+        };
+
+        // A colon before the last slash belongs to the registry port, not the tag
+        let last_segment = rest.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let (name, tag) = match rest[last_segment..].rfind(':') {
+            Some(i) => (
+                &rest[..last_segment + i],
+                Some(rest[last_segment + i + 1..].to_string()),
+            ),
+            None => (rest, None),
+        };
+
+        ImageRef {
+            name: name.to_string(),
+            tag,
+            digest,
+        }
+    }
+
+    /// Whether this reference points at `image_name`, ignoring the Docker Hub
+    /// prefixes and a registry host written in front of either name. Namespaces
+    /// count: `bitnami/redis` is not `redis`.
+    pub fn matches(&self, image_name: &str) -> bool {
+        let own = normalize_name(&self.name);
+        let wanted = normalize_name(image_name);
+        own == wanted || without_registry(own) == wanted || own == without_registry(wanted)
+    }
+
+    /// The tag, or the digest for references pinned by digest.
+    pub fn version(&self) -> Option<&str> {
+        self.tag.as_deref().or(self.digest.as_deref())
+    }
+
+    /// Same repository as written in the file, pointed at `tag`. A digest is
+    /// dropped since it would keep pinning the old image.
+    pub fn with_tag(&self, tag: &str) -> ImageRef {
+        ImageRef {
+            name: self.name.clone(),
+            tag: Some(tag.to_string()),
+            digest: None,
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+fn normalize_name(name: &str) -> &str {
+    let name = name
+        .trim_start_matches("docker.io/")
+        .trim_start_matches("index.docker.io/");
+    name.strip_prefix("library/").unwrap_or(name)
+}
+
+/// Name without its registry host. Like Docker, the first component is taken for a
+/// host when it contains a `.` or `:` or is `localhost`.
+fn without_registry(name: &str) -> &str {
+    match name.split_once('/') {
+        Some((host, rest)) if host.contains(['.', ':']) || host == "localhost" => rest,
+        _ => name,
+    }
+}
+
+/// The `image` value of one service and where it sits in the source text.
+#[derive(Debug, Clone)]
+pub struct ServiceImage {
+    pub service: String,
+    pub image: ImageRef,
+    /// Byte range of the scalar including any quotes
+    span: (usize, usize),
+    style: TScalarStyle,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageChange {
+    pub service: String,
+    pub from: ImageRef,
+    pub to: ImageRef,
+}
+
+#[derive(Debug, Clone)]
+pub struct ComposeEdit {
+    pub content: String,
+    pub changes: Vec<ImageChange>,
+}
+
+impl ComposeEdit {
+    /// Tag (or digest) the first changed service was running before the edit.
+    pub fn previous_version(&self) -> Option<&str> {
+        self.changes.first().and_then(|c| c.from.version())
+    }
+}
+
+/// A parsed compose file. Edits replace only the image scalars in the original
+/// text, so comments, ordering and formatting are kept as they are.
+pub struct ComposeFile {
+    source: String,
+    services: Vec<ServiceImage>,
+}
+
+impl ComposeFile {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser::new_from_str(source);
+        let mut events = Vec::new();
+        loop {
+            let (event, marker) = parser
+                .next_token()
+                .map_err(|e| anyhow!("Invalid compose YAML: {}", e))?;
+            if event == Event::StreamEnd {
+                break;
+            }
+            events.push((event, marker));
+        }
+
+        let mut walker = Walker {
+            source,
+            byte_offsets: source.char_indices().map(|(i, _)| i).collect(),
+            events,
+            pos: 0,
+            services: Vec::new(),
+        };
+        walker.document()?;
+
+        Ok(ComposeFile {
+            source: source.to_string(),
+            services: walker.services,
+        })
+    }
+
+    /// Services whose image is `image_name`, in file order.
+    pub fn services_using<'a>(
+        &'a self,
+        image_name: &'a str,
+    ) -> impl Iterator<Item = &'a ServiceImage> + 'a {
+        self.services
+            .iter()
+            .filter(move |s| s.image.matches(image_name))
+    }
+
+    /// Points `service` (or, when `None`, every service using `image_name`) at
+    /// `image_name:tag`. A service that already runs the image keeps the
+    /// registry prefix it is written with.
+    pub fn set_image(
+        &self,
+        image_name: &str,
+        tag: &str,
+        service: Option<&str>,
+    ) -> Result<ComposeEdit> {
+        let targets: Vec<&ServiceImage> = match service {
+            Some(name) => vec![self
+                .services
+                .iter()
+                .find(|s| s.service == name)
+                .ok_or_else(|| anyhow!("Service {} has no image in compose file", name))?],
+            None => self.services_using(image_name).collect(),
+        };
+
+        if targets.is_empty() {
+            return Err(anyhow!(
+                "No service in compose file uses image {}",
+                image_name
+            ));
+        }
+
+        let mut changes = Vec::new();
+        let mut content = self.source.clone();
+        // Replace from the end so earlier spans stay valid
+        let mut ordered = targets.clone();
+        ordered.sort_by_key(|s| std::cmp::Reverse(s.span.0));
+        for target in ordered {
+            let to = if target.image.matches(image_name) {
+                target.image.with_tag(tag)
+            } else {
+                ImageRef::parse(image_name).with_tag(tag)
+            };
+            content.replace_range(
+                target.span.0..target.span.1,
+                &render_scalar(&to.to_string(), target.style),
+            );
+            changes.push(ImageChange {
+                service: target.service.clone(),
+                from: target.image.clone(),
+                to,
+            });
+        }
+        changes.reverse();
+
+        Ok(ComposeEdit { content, changes })
+    }
+}
+
+fn render_scalar(value: &str, style: TScalarStyle) -> String {
+    match style {
+        TScalarStyle::SingleQuoted => format!("'{}'", value.replace('\'', "''")),
+        TScalarStyle::DoubleQuoted => {
+            format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Walks the parser events of the first document looking for `services.*.image`.
+struct Walker<'a> {
+    source: &'a str,
+    /// Byte offset of every char; parser markers count chars
+    byte_offsets: Vec<usize>,
+    events: Vec<(Event, Marker)>,
+    pos: usize,
+    services: Vec<ServiceImage>,
+}
+
+impl Walker<'_> {
+    fn next(&mut self) -> Option<(Event, Marker)> {
+        let item = self.events.get(self.pos).cloned();
+        self.pos += 1;
+        item
+    }
+
+    fn peek(&self) -> Option<&Event> {
+        self.events.get(self.pos).map(|(e, _)| e)
+    }
+
+    fn document(&mut self) -> Result<()> {
+        while matches!(
+            self.peek(),
+            Some(Event::StreamStart) | Some(Event::DocumentStart)
+        ) {
+            self.pos += 1;
+        }
+
+        if !matches!(self.peek(), Some(Event::MappingStart(..))) {
+            // Empty file or a top level that is not a mapping: no services
+            return Ok(());
+        }
+        self.pos += 1;
+
+        while let Some(key) = self.mapping_key() {
+            if key == "services" && matches!(self.peek(), Some(Event::MappingStart(..))) {
+                self.pos += 1;
+                self.services_mapping()?;
+            } else {
+                self.skip_node();
+            }
+        }
+        Ok(())
+    }
+
+    fn services_mapping(&mut self) -> Result<()> {
+        while let Some(service) = self.mapping_key() {
+            if matches!(self.peek(), Some(Event::MappingStart(..))) {
+                self.pos += 1;
+                self.service_mapping(&service)?;
+            } else {
+                self.skip_node();
+            }
+        }
+        Ok(())
+    }
+
+    fn service_mapping(&mut self, service: &str) -> Result<()> {
+        while let Some(key) = self.mapping_key() {
+            match self.peek() {
+                Some(Event::Scalar(..)) if key == "image" => {
+                    if let Some((Event::Scalar(value, style, ..), marker)) = self.next() {
+                        let span = self.scalar_span(&value, style, &marker).ok_or_else(|| {
+                            anyhow!(
+                                "Unsupported image value for service {} at line {}",
+                                service,
+                                marker.line()
+                            )
+                        })?;
+                        self.services.push(ServiceImage {
+                            service: service.to_string(),
+                            image: ImageRef::parse(&value),
+                            span,
+                            style,
+                        });
+                    }
+                }
+                _ => self.skip_node(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next key of the current mapping, or consumes `MappingEnd` and returns `None`.
+    /// Non-scalar keys are skipped together with their values.
+    fn mapping_key(&mut self) -> Option<String> {
+        loop {
+            match self.peek()? {
+                Event::MappingEnd => {
+                    self.pos += 1;
+                    return None;
+                }
+                Event::Scalar(..) => {
+                    if let Some((Event::Scalar(key, ..), _)) = self.next() {
+                        return Some(key);
+                    }
+                }
+                _ => {
+                    self.skip_node();
+                    self.skip_node();
+                }
+            }
+        }
+    }
+
+    fn skip_node(&mut self) {
+        let mut depth = 0usize;
+        while let Some((event, _)) = self.next() {
+            match event {
+                Event::MappingStart(..) | Event::SequenceStart(..) => depth += 1,
+                Event::MappingEnd | Event::SequenceEnd => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            if depth == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Finds the byte range of a scalar in the source, including its quotes.
+    fn scalar_span(
+        &self,
+        value: &str,
+        style: TScalarStyle,
+        marker: &Marker,
+    ) -> Option<(usize, usize)> {
+        let start = *self.byte_offsets.get(marker.index())?;
+        let rest = &self.source[start..];
+
+        match style {
+            TScalarStyle::Plain => rest
+                .starts_with(value)
+                .then(|| (start, start + value.len())),
+            TScalarStyle::SingleQuoted => {
+                let body = rest.strip_prefix('\'')?;
+                let mut chars = body.char_indices().peekable();
+                while let Some((i, c)) = chars.next() {
+                    if c == '\'' {
+                        if chars.peek().map(|(_, c)| *c) == Some('\'') {
+                            chars.next();
+                        } else {
+                            return Some((start, start + 1 + i + 1));
+                        }
+                    }
+                }
+                None
+            }
+            TScalarStyle::DoubleQuoted => {
+                let body = rest.strip_prefix('"')?;
+                let mut chars = body.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => return Some((start, start + 1 + i + 1)),
+                        _ => {}
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Unified diff between two versions of the compose file at `path`.
+pub fn unified_diff(path: &str, current: &str, proposed: &str) -> String {
+    TextDiff::from_lines(current, proposed)
+        .unified_diff()
+        .context_radius(3)
+        .header(path, path)
+        .to_string()
+}
+
+/// Token identifying a previewed edit. Applying with it only succeeds while the
+/// remote file still has the content the preview was computed from.
+pub fn edit_token(current: &str, proposed: &str) -> String {
+    let mut input = Vec::with_capacity(current.len() + proposed.len() + 1);
+    input.extend_from_slice(current.as_bytes());
+    input.push(0);
+    input.extend_from_slice(proposed.as_bytes());
+    format!("{:x}", md5::compute(&input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(name: &str, tag: Option<&str>, digest: Option<&str>) -> ImageRef {
+        ImageRef {
+            name: name.to_string(),
+            tag: tag.map(str::to_string),
+            digest: digest.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_image_references() {
+        assert_eq!(ImageRef::parse("nginx"), image("nginx", None, None));
+        assert_eq!(
+            ImageRef::parse(" nginx:1.25 "),
+            image("nginx", Some("1.25"), None)
+        );
+        assert_eq!(
+            ImageRef::parse("registry.local:5000/team/app:2"),
+            image("registry.local:5000/team/app", Some("2"), None)
+        );
+        assert_eq!(
+            ImageRef::parse("registry.local:5000/app"),
+            image("registry.local:5000/app", None, None)
+        );
+        assert_eq!(
+            ImageRef::parse("app@sha256:abc"),
+            image("app", None, Some("sha256:abc"))
+        );
+        assert_eq!(
+            ImageRef::parse("localhost:5000/app:1@sha256:abc"),
+            image("localhost:5000/app", Some("1"), Some("sha256:abc"))
+        );
+        assert_eq!(
+            ImageRef::parse("localhost:5000/app:1@sha256:abc").to_string(),
+            "localhost:5000/app:1@sha256:abc"
+        );
+    }
+
+    #[test]
+    fn matches_names_with_registry_but_not_namespace() {
+        let matches = |reference: &str, name: &str| ImageRef::parse(reference).matches(name);
+
+        assert!(matches("redis:7", "redis"));
+        assert!(matches("docker.io/library/redis:7", "redis"));
+        assert!(matches("redis", "docker.io/redis"));
+        assert!(matches("registry.local:5000/web:1", "web"));
+        assert!(matches("localhost/web", "web"));
+        assert!(matches("web:1", "registry.local:5000/web"));
+        assert!(matches("registry.local/team/web:1", "team/web"));
+        assert!(!matches("bitnami/redis:7", "redis"));
+        assert!(!matches("team/web", "web"));
+        assert!(!matches("registry.local/other/web", "team/web"));
+        assert!(!matches("registry.one/web", "registry.two/web"));
+        assert!(!matches("redis-exporter", "redis"));
+    }
+
+    fn set(source: &str, image_name: &str, tag: &str) -> String {
+        ComposeFile::parse(source)
+            .unwrap()
+            .set_image(image_name, tag, None)
+            .unwrap()
+            .content
+    }
+
+    #[test]
+    fn replaces_only_the_image_scalar() {
+        assert_eq!(
+            set("services:\n  web:\n    image: web:1 # pinned\n", "web", "2"),
+            "services:\n  web:\n    image: web:2 # pinned\n"
+        );
+        assert_eq!(
+            set("services:\n  web:\n    image: \"web:1\"\n", "web", "2"),
+            "services:\n  web:\n    image: \"web:2\"\n"
+        );
+        assert_eq!(
+            set("services:\n  web:\n    image: 'web:1'\n", "web", "2"),
+            "services:\n  web:\n    image: 'web:2'\n"
+        );
+        assert_eq!(
+            set(
+                "services: {web: {image: web:1, ports: ['80:80']}}\n",
+                "web",
+                "2"
+            ),
+            "services: {web: {image: web:2, ports: ['80:80']}}\n"
+        );
+        assert_eq!(
+            set("services:\n  web:\n    image: web\n", "web", "2"),
+            "services:\n  web:\n    image: web:2\n"
+        );
+        assert_eq!(
+            set(
+                "services:\n  web:\n    image: registry.local:5000/web:1\n",
+                "web",
+                "2"
+            ),
+            "services:\n  web:\n    image: registry.local:5000/web:2\n"
+        );
+        // A digest would keep pinning the old image
+        assert_eq!(
+            set("services:\n  web:\n    image: web@sha256:abc\n", "web", "2"),
+            "services:\n  web:\n    image: web:2\n"
+        );
+    }
+
+    #[test]
+    fn finds_scalars_after_multibyte_text() {
+        let source = "# café ☕ 配置\nservices:\n  web:\n    environment:\n      GREETING: \"héllo wörld\"\n    image: 'web:1' # 当前\n";
+        assert_eq!(
+            set(source, "web", "2"),
+            source.replace("'web:1'", "'web:2'")
+        );
+    }
+
+    #[test]
+    fn leaves_other_namespaces_alone() {
+        let source =
+            "services:\n  cache:\n    image: bitnami/redis:7\n  redis:\n    image: redis:7\n";
+        let edit = ComposeFile::parse(source)
+            .unwrap()
+            .set_image("redis", "8", None)
+            .unwrap();
+        assert_eq!(
+            edit.content,
+            "services:\n  cache:\n    image: bitnami/redis:7\n  redis:\n    image: redis:8\n"
+        );
+        assert_eq!(edit.changes.len(), 1);
+        assert_eq!(edit.changes[0].service, "redis");
+        assert_eq!(edit.previous_version(), Some("7"));
+    }
+
+    #[test]
+    fn sets_named_service_and_rejects_unknown_images() {
+        let compose = ComposeFile::parse(
+            "services:\n  web:\n    image: nginx:1\n  db:\n    image: postgres\n",
+        )
+        .unwrap();
+
+        let edit = compose.set_image("team/web", "3", Some("web")).unwrap();
+        assert_eq!(
+            edit.content,
+            "services:\n  web:\n    image: team/web:3\n  db:\n    image: postgres\n"
+        );
+        assert_eq!(edit.changes[0].from, ImageRef::parse("nginx:1"));
+
+        assert!(compose.set_image("redis", "1", None).is_err());
+        assert!(compose.set_image("web", "1", Some("cache")).is_err());
+    }
+}
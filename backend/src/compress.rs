@@ -0,0 +1,100 @@
+use crate::models::Server;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// How an image archive is compressed before it is uploaded. `docker load` detects
+/// the format by itself, so loading a compressed archive needs nothing extra.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip(u32),
+    Zstd(i32),
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip(level) => write!(f, "gzip level {}", level),
+            Compression::Zstd(level) => write!(f, "zstd level {}", level),
+        }
+    }
+}
+
+impl Compression {
+    /// Parses an algorithm and optional level; gzip when no algorithm is given.
+    pub fn parse(algorithm: Option<&str>, level: Option<i64>) -> Result<Self, String> {
+        let algorithm = algorithm
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .unwrap_or("gzip")
+            .to_lowercase();
+        let level = |range: RangeInclusive<i64>, default: i64| match level {
+            None => Ok(default),
+            Some(level) if range.contains(&level) => Ok(level),
+            Some(level) => Err(format!(
+                "Invalid {} level {}; expected {} to {}",
+                algorithm,
+                level,
+                range.start(),
+                range.end()
+            )),
+        };
+
+        match algorithm.as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip(level(1..=9, 6)? as u32)),
+            "zstd" => Ok(Compression::Zstd(level(1..=22, 3)? as i32)),
+            _ => Err(format!(
+                "Invalid compression {}; expected none, gzip or zstd",
+                algorithm
+            )),
+        }
+    }
+
+    /// Compression configured on the server.
+    pub fn of(server: &Server) -> Result<Self, String> {
+        Compression::parse(server.compression.as_deref(), server.compression_level)
+    }
+
+    /// File extension of an archive compressed this way.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "tar",
+            Compression::Gzip(_) => "tar.gz",
+            Compression::Zstd(_) => "tar.zst",
+        }
+    }
+
+    /// Writes a compressed copy of `src` to `dst` on the blocking thread pool.
+    pub async fn compress(self, src: &Path, dst: &Path) -> io::Result<()> {
+        let (src, dst) = (src.to_path_buf(), dst.to_path_buf());
+        tokio::task::spawn_blocking(move || {
+            let mut input = BufReader::new(File::open(&src)?);
+            let output = BufWriter::new(File::create(&dst)?);
+            match self {
+                Compression::None => {
+                    let mut output = output;
+                    io::copy(&mut input, &mut output)?;
+                    output.flush()
+                }
+                Compression::Gzip(level) => {
+                    let mut encoder =
+                        flate2::write::GzEncoder::new(output, flate2::Compression::new(level));
+                    io::copy(&mut input, &mut encoder)?;
+                    encoder.finish()?.flush()
+                }
+                Compression::Zstd(level) => {
+                    let mut encoder = zstd::Encoder::new(output, level)?;
+                    io::copy(&mut input, &mut encoder)?;
+                    encoder.finish()?.flush()
+                }
+            }
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
@@ -4,19 +4,39 @@ use std::fs;
 use std::path::Path;
 
 const DB_URL: &str = "sqlite://onepanel.db?mode=rwc";
+/// The database file `DB_URL` opens
+pub const DB_FILE: &str = "onepanel.db";
 
 pub type DbPool = Pool<Sqlite>;
 
 pub async fn init_db() -> Result<DbPool, sqlx::Error> {
-    if !Path::new("onepanel.db").exists() {
-        fs::File::create("onepanel.db").unwrap();
+    if !Path::new(DB_FILE).exists() {
+        fs::File::create(DB_FILE).unwrap();
     }
 
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
         .connect(DB_URL)
         .await?;
+    migrate(&pool).await?;
+    Ok(pool)
+}
 
+/// In-memory database with the full schema. A single connection, as every
+/// connection to `:memory:` opens a database of its own.
+#[cfg(test)]
+pub async fn memory() -> DbPool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    migrate(&pool).await.unwrap();
+    pool
+}
+
+/// Creates the tables, and adds the columns introduced since to older databases.
+async fn migrate(pool: &DbPool) -> Result<(), sqlx::Error> {
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS servers (
@@ -25,6 +45,17 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
             host TEXT NOT NULL,
             port INTEGER NOT NULL,
             api_key TEXT NOT NULL,
+            request_timeout_secs INTEGER,
+            upload_timeout_secs INTEGER,
+            scheme TEXT,
+            ca_cert TEXT,
+            tls_fingerprint TEXT,
+            accept_invalid_certs INTEGER NOT NULL DEFAULT 0,
+            api_version TEXT,
+            base_path TEXT,
+            compression TEXT,
+            compression_level INTEGER,
+            upload_dir TEXT,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP
         );
         CREATE TABLE IF NOT EXISTS repositories (
@@ -44,23 +75,320 @@ pub async fn init_db() -> Result<DbPool, sqlx::Error> {
             duration INTEGER,
             server_name TEXT
         );
+        CREATE TABLE IF NOT EXISTS jobs (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            status TEXT NOT NULL,
+            repository_path TEXT,
+            image_tag TEXT,
+            server_id INTEGER,
+            error TEXT,
+            params TEXT,
+            retry_of TEXT,
+            triggered_by TEXT,
+            created_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS job_steps (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            job_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            status TEXT NOT NULL,
+            error TEXT,
+            output TEXT,
+            started_at INTEGER,
+            finished_at INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS job_logs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            job_id TEXT NOT NULL,
+            line TEXT NOT NULL,
+            timestamp INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_job_logs_job_id ON job_logs (job_id);
+        CREATE TABLE IF NOT EXISTS deployments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            job_id TEXT,
+            server_id INTEGER NOT NULL,
+            stack_name TEXT NOT NULL,
+            compose_path TEXT NOT NULL,
+            image_name TEXT NOT NULL,
+            previous_tag TEXT,
+            new_tag TEXT NOT NULL,
+            previous_content TEXT NOT NULL,
+            new_content TEXT NOT NULL,
+            status TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            action TEXT NOT NULL DEFAULT 'deploy',
+            repository_path TEXT,
+            commit_hash TEXT,
+            triggered_by TEXT,
+            error TEXT,
+            finished_at INTEGER,
+            duration_ms INTEGER,
+            environment TEXT,
+            promoted_from TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_deployments_target ON deployments (server_id, compose_path);
+        CREATE TABLE IF NOT EXISTS deploy_targets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repository_id INTEGER NOT NULL,
+            server_id INTEGER NOT NULL,
+            compose_path TEXT NOT NULL,
+            service TEXT,
+            position INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS idx_deploy_targets_repository ON deploy_targets (repository_id);
+        CREATE TABLE IF NOT EXISTS environments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repository_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            server_id INTEGER NOT NULL,
+            compose_path TEXT NOT NULL,
+            service TEXT,
+            position INTEGER NOT NULL DEFAULT 0,
+            requires_approval INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (repository_id, name)
+        );
+        CREATE TABLE IF NOT EXISTS promotions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repository_path TEXT NOT NULL,
+            from_env TEXT NOT NULL,
+            to_env TEXT NOT NULL,
+            image TEXT NOT NULL,
+            status TEXT NOT NULL,
+            triggered_by TEXT,
+            job_id TEXT,
+            created_at INTEGER NOT NULL,
+            decided_at INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS registries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            url TEXT NOT NULL,
+            username TEXT,
+            password TEXT,
+            insecure INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL UNIQUE,
+            password_hash TEXT NOT NULL,
+            role TEXT NOT NULL DEFAULT 'admin',
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token_hash TEXT NOT NULL UNIQUE,
+            user_id INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS api_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            user_id INTEGER NOT NULL,
+            role TEXT,
+            server_ids TEXT,
+            repositories TEXT,
+            created_at INTEGER NOT NULL,
+            last_used_at INTEGER,
+            expires_at INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_at INTEGER NOT NULL,
+            actor TEXT NOT NULL,
+            user_id INTEGER,
+            username TEXT,
+            token_id INTEGER,
+            token_name TEXT,
+            mcp_session TEXT,
+            action TEXT NOT NULL,
+            path TEXT,
+            server_id INTEGER,
+            params TEXT,
+            status INTEGER,
+            success INTEGER NOT NULL,
+            error TEXT,
+            job_id TEXT
+        );
+        -- Chunks of an interrupted upload the server already has, by file content
+        CREATE TABLE IF NOT EXISTS upload_progress (
+            server_id INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            remote_path TEXT NOT NULL,
+            chunks_done INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            PRIMARY KEY (server_id, sha256)
+        );
+        -- The audit log is append-only
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     // Migration for existing DB
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN request_timeout_secs INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN upload_timeout_secs INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN scheme TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN ca_cert TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN tls_fingerprint TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query(
+        "ALTER TABLE servers ADD COLUMN accept_invalid_certs INTEGER NOT NULL DEFAULT 0",
+    )
+    .execute(pool)
+    .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN api_version TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN base_path TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN compression TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN compression_level INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE servers ADD COLUMN upload_dir TEXT")
+        .execute(pool)
+        .await;
+
     let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN docker_image_name TEXT")
-        .execute(&pool)
+        .execute(pool)
         .await;
 
     let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN default_server_id INTEGER")
-        .execute(&pool)
+        .execute(pool)
         .await;
 
     let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN default_compose_path TEXT")
-        .execute(&pool)
+        .execute(pool)
         .await;
 
-    Ok(pool)
+    let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN health_timeout_secs INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN health_check_url TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN deploy_strategy TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN deploy_concurrency INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN deploy_pause_secs INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE repositories ADD COLUMN registry_id INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE jobs ADD COLUMN params TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE jobs ADD COLUMN retry_of TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE job_steps ADD COLUMN output TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE jobs ADD COLUMN triggered_by TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN action TEXT NOT NULL DEFAULT 'deploy'")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN repository_path TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN commit_hash TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN triggered_by TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN error TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN finished_at INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN duration_ms INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN environment TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE deployments ADD COLUMN promoted_from TEXT")
+        .execute(pool)
+        .await;
+
+    // Users from before roles existed had full access
+    let _ = sqlx::query("ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'admin'")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE api_tokens ADD COLUMN role TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE api_tokens ADD COLUMN server_ids TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE api_tokens ADD COLUMN repositories TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE audit_log ADD COLUMN job_id TEXT")
+        .execute(pool)
+        .await;
+
+    Ok(())
 }
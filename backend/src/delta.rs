@@ -0,0 +1,395 @@
+use crate::compose::ImageRef;
+use crate::onepanel::{self, OnePanelClient};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Entries this small are kept in memory while reading an archive, which covers
+/// `manifest.json` and the image configs.
+const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// Chain IDs of the layers of the images on the server from the same repository as
+/// `image`. Only those are inspected, which covers repeated deploys of an app.
+pub async fn remote_chain_ids(
+    client: &OnePanelClient,
+    image: &str,
+) -> onepanel::Result<HashSet<String>> {
+    let repository = ImageRef::parse(image).name;
+    let mut chains = HashSet::new();
+    for remote in client.list_images().await? {
+        if !remote
+            .tags
+            .iter()
+            .any(|tag| ImageRef::parse(tag).name == repository)
+        {
+            continue;
+        }
+        let inspect = client.inspect_image(&remote.id).await?;
+        chains.extend(chain_ids(&inspect.root_fs.layers));
+    }
+    Ok(chains)
+}
+
+/// Chain ID of every layer: Docker identifies a layer by its diff ID together with
+/// all layers below it, so a layer can only be reused on top of the same parents.
+fn chain_ids(diff_ids: &[String]) -> Vec<String> {
+    let mut chain: Option<String> = None;
+    diff_ids
+        .iter()
+        .map(|diff_id| {
+            let id = match &chain {
+                None => diff_id.clone(),
+                Some(parent) => format!(
+                    "sha256:{:x}",
+                    Sha256::digest(format!("{} {}", parent, diff_id))
+                ),
+            };
+            chain = Some(id.clone());
+            id
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ManifestEntry {
+    config: String,
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    rootfs: ConfigRootFs,
+}
+
+#[derive(Deserialize)]
+struct ConfigRootFs {
+    diff_ids: Vec<String>,
+}
+
+/// What `trim` left out of an archive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Trimmed {
+    pub layers: usize,
+    pub skipped_layers: usize,
+    pub skipped_bytes: u64,
+}
+
+/// Copies the `docker save` archive `src` to `dst` without the layer files whose
+/// chain ID is in `present`. `docker load` only opens a layer file when it lacks
+/// the layer, so the manifest and configs stay as they are.
+pub async fn trim(src: &Path, dst: &Path, present: HashSet<String>) -> Result<Trimmed> {
+    let (src, dst) = (src.to_path_buf(), dst.to_path_buf());
+    tokio::task::spawn_blocking(move || trim_blocking(&src, &dst, &present)).await?
+}
+
+fn trim_blocking(src: &Path, dst: &Path, present: &HashSet<String>) -> Result<Trimmed> {
+    // First pass: metadata and the targets of links between layer files
+    let mut metadata = HashMap::new();
+    let mut links = HashMap::new();
+    let mut archive = tar::Archive::new(BufReader::new(File::open(src)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize(&entry.path()?);
+        if let Some(target) = entry.link_name()? {
+            let base = match entry.header().entry_type() {
+                // Symlinks are relative to their directory, hard links to the root
+                tar::EntryType::Symlink => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                _ => PathBuf::new(),
+            };
+            links.insert(path, normalize(&base.join(target)));
+        } else if entry.size() <= MAX_METADATA_SIZE {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            metadata.insert(path, content);
+        }
+    }
+
+    let manifest: Vec<ManifestEntry> = serde_json::from_slice(
+        metadata
+            .get(Path::new("manifest.json"))
+            .ok_or_else(|| anyhow!("archive has no manifest.json"))?,
+    )
+    .context("invalid manifest.json")?;
+
+    let mut trimmed = Trimmed::default();
+    let mut skipped = Vec::new();
+    let mut skip = HashSet::new();
+    let mut keep = HashSet::new();
+    for image in &manifest {
+        let config: ImageConfig = serde_json::from_slice(
+            metadata
+                .get(&normalize(Path::new(&image.config)))
+                .ok_or_else(|| anyhow!("archive has no config {}", image.config))?,
+        )
+        .with_context(|| format!("invalid image config {}", image.config))?;
+        if config.rootfs.diff_ids.len() != image.layers.len() {
+            return Err(anyhow!(
+                "layers of {} do not match its config",
+                image.config
+            ));
+        }
+
+        let chains = chain_ids(&config.rootfs.diff_ids);
+        for (layer, chain) in image.layers.iter().zip(&chains) {
+            trimmed.layers += 1;
+            let mut path = normalize(Path::new(layer));
+            if present.contains(chain) {
+                skipped.push(path.clone());
+                skip.insert(path);
+            } else {
+                // A kept link needs its target as well
+                while let Some(target) = links.get(&path) {
+                    keep.insert(path);
+                    path = target.clone();
+                }
+                keep.insert(path);
+            }
+        }
+    }
+    skip.retain(|path| !keep.contains(path));
+    trimmed.skipped_layers = skipped.iter().filter(|path| skip.contains(*path)).count();
+    if skip.is_empty() {
+        return Ok(trimmed);
+    }
+
+    // Second pass: copy everything else
+    let mut archive = tar::Archive::new(BufReader::new(File::open(src)?));
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(dst)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if skip.contains(&normalize(&path)) {
+            trimmed.skipped_bytes += entry.size();
+            continue;
+        }
+        let mut header = entry.header().clone();
+        match entry.link_name()? {
+            Some(target) => {
+                let target = target.into_owned();
+                builder.append_link(&mut header, &path, &target)?;
+            }
+            None => builder.append_data(&mut header, &path, &mut entry)?,
+        }
+    }
+    builder.into_inner()?.into_inner()?;
+    Ok(trimmed)
+}
+
+/// Path without `.` components and with `..` applied, for comparing archive paths.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(part) => normalized.push(part),
+            _ => {}
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image of a `docker save` archive: its layers as diff ID and file, where a
+    /// file already written for an earlier layer is added as a symlink to it.
+    struct SavedImage<'a> {
+        config: &'a str,
+        layers: &'a [(&'a str, &'a str)],
+    }
+
+    fn save(path: &Path, images: &[SavedImage]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        let mut written: HashMap<&str, &str> = HashMap::new();
+        let mut manifest = Vec::new();
+        for image in images {
+            let diff_ids: Vec<&str> = image.layers.iter().map(|(diff_id, _)| *diff_id).collect();
+            let config =
+                serde_json::json!({ "rootfs": { "type": "layers", "diff_ids": diff_ids } });
+            append(&mut builder, image.config, config.to_string().as_bytes());
+
+            for (diff_id, file) in image.layers {
+                match written.get(diff_id) {
+                    Some(target) if target != file => {
+                        let mut header = tar::Header::new_gnu();
+                        header.set_entry_type(tar::EntryType::Symlink);
+                        header.set_size(0);
+                        let target = format!("../{}", target);
+                        builder.append_link(&mut header, file, &target).unwrap();
+                    }
+                    Some(_) => {}
+                    None => {
+                        append(&mut builder, file, format!("layer {}", diff_id).as_bytes());
+                        written.insert(diff_id, file);
+                    }
+                }
+            }
+            let files: Vec<&str> = image.layers.iter().map(|(_, file)| *file).collect();
+            manifest.push(serde_json::json!({ "Config": image.config, "Layers": files }));
+        }
+        append(
+            &mut builder,
+            "manifest.json",
+            serde_json::Value::from(manifest).to_string().as_bytes(),
+        );
+        builder.finish().unwrap();
+    }
+
+    fn append(builder: &mut tar::Builder<File>, path: &str, content: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, content).unwrap();
+    }
+
+    fn entries(path: &Path) -> Vec<String> {
+        let mut archive = tar::Archive::new(File::open(path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect()
+    }
+
+    fn temp_paths(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        (
+            dir.join(format!("delta-{}-{}.tar", name, id)),
+            dir.join(format!("delta-{}-{}-trimmed.tar", name, id)),
+        )
+    }
+
+    #[test]
+    fn chains_layers_to_their_parents() {
+        let diff_ids = ["sha256:a".to_string(), "sha256:b".to_string()];
+        let chains = chain_ids(&diff_ids);
+
+        assert_eq!(chains[0], "sha256:a");
+        assert_eq!(
+            chains[1],
+            format!("sha256:{:x}", Sha256::digest("sha256:a sha256:b"))
+        );
+        assert_eq!(chain_ids(&diff_ids[..1]), chains[..1]);
+        // The same layer on another parent is another layer
+        assert_ne!(
+            chain_ids(&["sha256:c".to_string(), "sha256:b".to_string()])[1],
+            chains[1]
+        );
+    }
+
+    #[test]
+    fn normalizes_archive_paths() {
+        assert_eq!(
+            normalize(Path::new("./abc/layer.tar")),
+            Path::new("abc/layer.tar")
+        );
+        assert_eq!(
+            normalize(Path::new("def/../abc/layer.tar")),
+            Path::new("abc/layer.tar")
+        );
+        assert_eq!(
+            normalize(Path::new("abc/./../../layer.tar")),
+            Path::new("layer.tar")
+        );
+        assert_eq!(
+            normalize(Path::new("/blobs/sha256/abc")),
+            Path::new("blobs/sha256/abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_layers_the_server_has() {
+        let (src, dst) = temp_paths("skip");
+        save(
+            &src,
+            &[SavedImage {
+                config: "cfg.json",
+                layers: &[
+                    ("sha256:a", "a/layer.tar"),
+                    ("sha256:b", "b/layer.tar"),
+                    ("sha256:c", "c/layer.tar"),
+                ],
+            }],
+        );
+        let chains = chain_ids(&["sha256:a".into(), "sha256:b".into()]);
+
+        let trimmed = trim(&src, &dst, chains.into_iter().collect())
+            .await
+            .unwrap();
+        let kept = entries(&dst);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+
+        assert_eq!((trimmed.layers, trimmed.skipped_layers), (3, 2));
+        assert_eq!(trimmed.skipped_bytes, 2 * "layer sha256:a".len() as u64);
+        assert_eq!(kept, ["cfg.json", "c/layer.tar", "manifest.json"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_layers_a_kept_symlink_points_to() {
+        let (src, dst) = temp_paths("symlink");
+        // `other` shares the base layer `a` of `web`, but on top of `x`
+        save(
+            &src,
+            &[
+                SavedImage {
+                    config: "web.json",
+                    layers: &[("sha256:a", "a/layer.tar"), ("sha256:b", "b/layer.tar")],
+                },
+                SavedImage {
+                    config: "other.json",
+                    layers: &[("sha256:x", "x/layer.tar"), ("sha256:a", "xa/layer.tar")],
+                },
+            ],
+        );
+        let present = chain_ids(&["sha256:a".into(), "sha256:b".into()]);
+
+        let trimmed = trim(&src, &dst, present.into_iter().collect())
+            .await
+            .unwrap();
+        let kept = entries(&dst);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+
+        assert_eq!((trimmed.layers, trimmed.skipped_layers), (4, 1));
+        assert_eq!(
+            kept,
+            [
+                "web.json",
+                "a/layer.tar",
+                "other.json",
+                "x/layer.tar",
+                "xa/layer.tar",
+                "manifest.json"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_archives_without_known_layers_alone() {
+        let (src, dst) = temp_paths("none");
+        save(
+            &src,
+            &[SavedImage {
+                config: "cfg.json",
+                layers: &[("sha256:a", "a/layer.tar")],
+            }],
+        );
+
+        let present = HashSet::from(["sha256:other".to_string()]);
+        let trimmed = trim(&src, &dst, present).await.unwrap();
+        std::fs::remove_file(&src).unwrap();
+
+        assert_eq!((trimmed.layers, trimmed.skipped_layers), (1, 0));
+        assert!(!dst.exists());
+    }
+}
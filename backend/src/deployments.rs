@@ -0,0 +1,291 @@
+use crate::db::DbPool;
+use crate::jobs::now_millis;
+use crate::models::Deployment;
+
+/// Compose file was rewritten but the stack has not been brought up yet.
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_DEPLOYED: &str = "deployed";
+pub const STATUS_FAILED: &str = "failed";
+/// The compose content this deployment replaced has been restored.
+pub const STATUS_ROLLED_BACK: &str = "rolled_back";
+
+pub const ACTION_DEPLOY: &str = "deploy";
+/// Deploy of an image already running in another environment, without a rebuild.
+pub const ACTION_PROMOTE: &str = "promote";
+pub const ACTION_ROLLBACK: &str = "rollback";
+
+/// Whether a deployment has an outcome that a retry of its job must leave alone.
+/// Failed deployments do not: the retry may still bring the stack up.
+pub fn is_settled(status: &str) -> bool {
+    status == STATUS_DEPLOYED || status == STATUS_ROLLED_BACK
+}
+
+pub struct NewDeployment<'a> {
+    pub job_id: &'a str,
+    pub action: &'a str,
+    pub server_id: i64,
+    pub stack_name: &'a str,
+    pub compose_path: &'a str,
+    pub image_name: &'a str,
+    pub previous_tag: Option<&'a str>,
+    pub new_tag: &'a str,
+    pub previous_content: &'a str,
+    pub new_content: &'a str,
+    pub repository_path: Option<&'a str>,
+    pub commit_hash: Option<&'a str>,
+    pub triggered_by: &'a str,
+    pub environment: Option<&'a str>,
+    pub promoted_from: Option<&'a str>,
+}
+
+pub async fn record(db: &DbPool, d: NewDeployment<'_>) -> Result<i64, sqlx::Error> {
+    let res = sqlx::query(
+        "INSERT INTO deployments (job_id, action, server_id, stack_name, compose_path, image_name, previous_tag, new_tag, previous_content, new_content, repository_path, commit_hash, triggered_by, environment, promoted_from, status, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(d.job_id)
+    .bind(d.action)
+    .bind(d.server_id)
+    .bind(d.stack_name)
+    .bind(d.compose_path)
+    .bind(d.image_name)
+    .bind(d.previous_tag)
+    .bind(d.new_tag)
+    .bind(d.previous_content)
+    .bind(d.new_content)
+    .bind(d.repository_path)
+    .bind(d.commit_hash)
+    .bind(d.triggered_by)
+    .bind(d.environment)
+    .bind(d.promoted_from)
+    .bind(STATUS_PENDING)
+    .bind(now_millis())
+    .execute(db)
+    .await?;
+
+    Ok(res.last_insert_rowid())
+}
+
+/// Stores the outcome of a deployment. The duration is measured from the start
+/// of the job that performed it, so it includes building and pushing the image.
+pub async fn finish(
+    db: &DbPool,
+    id: i64,
+    status: &str,
+    error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let now = now_millis();
+    sqlx::query(
+        "UPDATE deployments SET status = ?, error = ?, finished_at = ?, duration_ms = ? - COALESCE((SELECT started_at FROM jobs WHERE jobs.id = deployments.job_id), created_at) WHERE id = ?",
+    )
+    .bind(status)
+    .bind(error)
+    .bind(now)
+    .bind(now)
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_status(db: &DbPool, id: i64, status: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE deployments SET status = ? WHERE id = ?")
+        .bind(status)
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn get(db: &DbPool, id: i64) -> Result<Option<Deployment>, sqlx::Error> {
+    sqlx::query_as::<_, Deployment>("SELECT * FROM deployments WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
+
+/// The most recent deployment to `compose_path` on `server_id` that has not been
+/// rolled back yet, i.e. the one whose previous content a rollback restores.
+/// Repeated rollbacks therefore walk further back through the history.
+pub async fn latest_active(
+    db: &DbPool,
+    server_id: i64,
+    compose_path: &str,
+) -> Result<Option<Deployment>, sqlx::Error> {
+    sqlx::query_as::<_, Deployment>(
+        "SELECT * FROM deployments WHERE server_id = ? AND compose_path = ? AND action IN (?, ?) AND status != ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(server_id)
+    .bind(compose_path)
+    .bind(ACTION_DEPLOY)
+    .bind(ACTION_PROMOTE)
+    .bind(STATUS_ROLLED_BACK)
+    .fetch_optional(db)
+    .await
+}
+
+/// Commit an image tag was built from, as recorded when it was first deployed.
+pub async fn commit_of(
+    db: &DbPool,
+    image_name: &str,
+    tag: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let row: Option<(String,)> = sqlx::query_as(
+        "SELECT commit_hash FROM deployments WHERE image_name = ? AND new_tag = ? AND commit_hash IS NOT NULL ORDER BY id DESC LIMIT 1",
+    )
+    .bind(image_name)
+    .bind(tag)
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|(hash,)| hash))
+}
+
+#[derive(Debug, Default)]
+pub struct DeploymentFilter<'a> {
+    pub repository_path: Option<&'a str>,
+    pub server_id: Option<i64>,
+    pub environment: Option<&'a str>,
+    pub status: Option<&'a str>,
+    /// Only deployments started at or after this time (ms since epoch)
+    pub from: Option<i64>,
+    /// Only deployments started before this time (ms since epoch)
+    pub to: Option<i64>,
+    /// Only these servers, for callers with a limited scope
+    pub servers: Option<&'a [i64]>,
+    /// Only these repositories or none, for callers with a limited scope
+    pub repositories: Option<&'a [String]>,
+}
+
+/// Returns one page of the deployment ledger, newest first, and the total number of matches.
+pub async fn list(
+    db: &DbPool,
+    filter: &DeploymentFilter<'_>,
+    page: i64,
+    page_size: i64,
+) -> Result<(Vec<Deployment>, i64), sqlx::Error> {
+    const WHERE: &str = "WHERE (? IS NULL OR repository_path = ?) AND (? IS NULL OR server_id = ?) AND (? IS NULL OR environment = ?) AND (? IS NULL OR status = ?) AND (? IS NULL OR created_at >= ?) AND (? IS NULL OR created_at < ?) AND (? IS NULL OR server_id IN (SELECT value FROM json_each(?))) AND (? IS NULL OR repository_path IS NULL OR repository_path IN (SELECT value FROM json_each(?)))";
+    let servers = filter.servers.map(|ids| serde_json::json!(ids).to_string());
+    let repositories = filter
+        .repositories
+        .map(|paths| serde_json::json!(paths).to_string());
+
+    let total: (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM deployments {}", WHERE))
+        .bind(filter.repository_path)
+        .bind(filter.repository_path)
+        .bind(filter.server_id)
+        .bind(filter.server_id)
+        .bind(filter.environment)
+        .bind(filter.environment)
+        .bind(filter.status)
+        .bind(filter.status)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(&servers)
+        .bind(&servers)
+        .bind(&repositories)
+        .bind(&repositories)
+        .fetch_one(db)
+        .await?;
+
+    let items = sqlx::query_as::<_, Deployment>(&format!(
+        "SELECT * FROM deployments {} ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?",
+        WHERE
+    ))
+    .bind(filter.repository_path)
+    .bind(filter.repository_path)
+    .bind(filter.server_id)
+    .bind(filter.server_id)
+    .bind(filter.environment)
+    .bind(filter.environment)
+    .bind(filter.status)
+    .bind(filter.status)
+    .bind(filter.from)
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(filter.to)
+    .bind(&servers)
+    .bind(&servers)
+    .bind(&repositories)
+    .bind(&repositories)
+    .bind(page_size)
+    .bind((page - 1) * page_size)
+    .fetch_all(db)
+    .await?;
+
+    Ok((items, total.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn deployment(db: &DbPool, server_id: i64, repository_path: Option<&str>) {
+        record(
+            db,
+            NewDeployment {
+                job_id: "job",
+                action: ACTION_DEPLOY,
+                server_id,
+                stack_name: "web",
+                compose_path: "/opt/web/docker-compose.yml",
+                image_name: "web",
+                previous_tag: None,
+                new_tag: "web:2",
+                previous_content: "",
+                new_content: "",
+                repository_path,
+                commit_hash: None,
+                triggered_by: "test",
+                environment: None,
+                promoted_from: None,
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_only_deployments_in_scope() {
+        let db = crate::db::memory().await;
+        deployment(&db, 1, Some("/src/web")).await;
+        deployment(&db, 1, Some("/src/api")).await;
+        deployment(&db, 2, Some("/src/web")).await;
+        deployment(&db, 2, None).await;
+
+        let list = |servers: Option<Vec<i64>>, repositories: Option<Vec<String>>| {
+            let db = db.clone();
+            async move {
+                let filter = DeploymentFilter {
+                    servers: servers.as_deref(),
+                    repositories: repositories.as_deref(),
+                    ..Default::default()
+                };
+                let (items, total) = super::list(&db, &filter, 1, 10).await.unwrap();
+                assert_eq!(items.len() as i64, total);
+                items
+                    .iter()
+                    .map(|d| (d.server_id, d.repository_path.clone()))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(list(None, None).await.len(), 4);
+        assert_eq!(
+            list(Some(vec![1]), None).await,
+            [
+                (1, Some("/src/api".to_string())),
+                (1, Some("/src/web".to_string()))
+            ]
+        );
+        assert_eq!(
+            list(None, Some(vec!["/src/web".to_string()])).await,
+            [
+                (2, None),
+                (2, Some("/src/web".to_string())),
+                (1, Some("/src/web".to_string()))
+            ]
+        );
+        assert!(list(Some(vec![]), None).await.is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::jobs::JobLog;
 use bollard::Docker;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
@@ -114,27 +115,32 @@ pub async fn list_tags(image_name: &str) -> Result<Vec<DockerImage>, String> {
     Ok(result)
 }
 
-// use futures_util::stream::StreamExt; // Removed
+async fn forward_lines<R: tokio::io::AsyncRead + Unpin>(reader: R, log: &JobLog) -> String {
+    use tokio::io::{AsyncBufReadExt, BufReader};
 
-pub async fn build_image(req: DockerBuildRequest) -> Result<String, String> {
-    // let docker = Docker::connect_with_local_defaults().map_err(|e| e.to_string())?; // Unused for Command based build
-
-    // Prepare tarball
-    // Bollard build_image requires a tarball of the context.
-    // For simplicity, we might want to use std::process::Command for "docker build" if we want to avoid creating tarball manually in Rust.
-    // Creating a tarball of a directory in Rust is doable but adds complexity.
-    // Given "OnePanel-CI" likely runs on a machine with `docker` CLI available (since it connects to docker socket).
-    // Using Command is often more robust for "build from folder" than streaming tarball if we don't want to implement tar logic.
-    // However, if we want to stick to pure Rust/API...
-    // Let's use Command for now as it's easier to implement "build from this directory".
-    // Wait, `req.path` is local path.
+    let mut lines = BufReader::new(reader).lines();
+    let mut captured = String::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        log.line(line.clone());
+        captured.push_str(&line);
+        captured.push('\n');
+    }
+    captured
+}
 
+/// Runs `docker build` in `req.path`, forwarding every stdout/stderr line to `log`
+/// as it is produced. Returns the captured stdout on success and stderr on failure.
+pub async fn build_image(req: DockerBuildRequest, log: &JobLog) -> Result<String, String> {
+    // Using the docker CLI rather than the API avoids tarballing the build context ourselves.
+    use std::process::Stdio;
     use tokio::process::Command;
 
     let full_tag = format!("{}:{}", req.image_name, req.version);
     let latest_tag = format!("{}:latest", req.image_name);
 
-    let output = Command::new("docker")
+    log.line(format!("docker build -t {} -t {} .", full_tag, latest_tag));
+
+    let mut child = Command::new("docker")
         .arg("build")
         .arg("-t")
         .arg(&full_tag)
@@ -142,14 +148,32 @@ pub async fn build_image(req: DockerBuildRequest) -> Result<String, String> {
         .arg(&latest_tag)
         .arg(".")
         .current_dir(&req.path)
-        .output()
-        .await
+        .kill_on_drop(true)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
         .map_err(|e| format!("Failed to execute docker build: {}", e))?;
 
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or("Failed to capture docker build stdout")?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or("Failed to capture docker build stderr")?;
+
+    let (out, err, status) = tokio::join!(
+        forward_lines(stdout, log),
+        forward_lines(stderr, log),
+        child.wait()
+    );
+    let status = status.map_err(|e| format!("Failed to wait for docker build: {}", e))?;
+
+    if status.success() {
+        Ok(out)
     } else {
-        Err(String::from_utf8_lossy(&output.stderr).to_string())
+        Err(err)
     }
 }
 
@@ -269,6 +293,18 @@ pub async fn pull_image(image_name: &str) -> Result<(), String> {
     Ok(())
 }
 
+/// Whether the local Docker has the image `tag`.
+pub async fn image_exists(tag: &str) -> Result<bool, String> {
+    let docker = Docker::connect_with_local_defaults().map_err(|e| e.to_string())?;
+    match docker.inspect_image(tag).await {
+        Ok(_) => Ok(true),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(false),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 pub async fn remove_image(id: &str) -> Result<(), String> {
     let docker = Docker::connect_with_local_defaults().map_err(|e| e.to_string())?;
     use bollard::image::RemoveImageOptions;
@@ -0,0 +1,128 @@
+use crate::db::DbPool;
+use crate::jobs::now_millis;
+use crate::models::{Environment, Promotion, SaveEnvironmentReq};
+
+/// Waiting for someone to approve or reject it.
+pub const PROMOTION_PENDING: &str = "pending";
+/// Deploy job started, see `job_id`.
+pub const PROMOTION_APPROVED: &str = "approved";
+pub const PROMOTION_REJECTED: &str = "rejected";
+
+/// Environments of a repository in promotion order.
+pub async fn list(db: &DbPool, repository_id: i64) -> Result<Vec<Environment>, sqlx::Error> {
+    sqlx::query_as::<_, Environment>(
+        "SELECT * FROM environments WHERE repository_id = ? ORDER BY position ASC, id ASC",
+    )
+    .bind(repository_id)
+    .fetch_all(db)
+    .await
+}
+
+/// Creates the environment, or updates the one with the same name.
+pub async fn save(
+    db: &DbPool,
+    repository_id: i64,
+    req: &SaveEnvironmentReq,
+) -> Result<Environment, sqlx::Error> {
+    sqlx::query_as::<_, Environment>(
+        "INSERT INTO environments (repository_id, name, server_id, compose_path, service, position, requires_approval)
+         VALUES (?, ?, ?, ?, NULLIF(?, ''), COALESCE(?, (SELECT COALESCE(MAX(position), -1) + 1 FROM environments WHERE repository_id = ?)), ?)
+         ON CONFLICT(repository_id, name) DO UPDATE SET
+            server_id = excluded.server_id,
+            compose_path = excluded.compose_path,
+            service = excluded.service,
+            position = COALESCE(?, environments.position),
+            requires_approval = excluded.requires_approval
+         RETURNING *",
+    )
+    .bind(repository_id)
+    .bind(&req.name)
+    .bind(req.server_id)
+    .bind(&req.compose_path)
+    .bind(&req.service)
+    .bind(req.position)
+    .bind(repository_id)
+    .bind(req.requires_approval)
+    .bind(req.position)
+    .fetch_one(db)
+    .await
+}
+
+pub async fn delete(db: &DbPool, id: i64) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM environments WHERE id = ?")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub struct NewPromotion<'a> {
+    pub repository_path: &'a str,
+    pub from_env: &'a str,
+    pub to_env: &'a str,
+    pub image: &'a str,
+    pub status: &'a str,
+    pub triggered_by: &'a str,
+}
+
+pub async fn create_promotion(db: &DbPool, p: NewPromotion<'_>) -> Result<Promotion, sqlx::Error> {
+    sqlx::query_as::<_, Promotion>(
+        "INSERT INTO promotions (repository_path, from_env, to_env, image, status, triggered_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING *",
+    )
+    .bind(p.repository_path)
+    .bind(p.from_env)
+    .bind(p.to_env)
+    .bind(p.image)
+    .bind(p.status)
+    .bind(p.triggered_by)
+    .bind(now_millis())
+    .fetch_one(db)
+    .await
+}
+
+pub async fn get_promotion(db: &DbPool, id: i64) -> Result<Option<Promotion>, sqlx::Error> {
+    sqlx::query_as::<_, Promotion>("SELECT * FROM promotions WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn list_promotions(
+    db: &DbPool,
+    status: Option<&str>,
+) -> Result<Vec<Promotion>, sqlx::Error> {
+    sqlx::query_as::<_, Promotion>(
+        "SELECT * FROM promotions WHERE (? IS NULL OR status = ?) ORDER BY created_at DESC, id DESC LIMIT 200",
+    )
+    .bind(status)
+    .bind(status)
+    .fetch_all(db)
+    .await
+}
+
+/// Records the decision on a pending promotion. Returns `None` if it was not
+/// pending anymore, so that two approvals cannot both start a deploy.
+pub async fn decide_promotion(
+    db: &DbPool,
+    id: i64,
+    status: &str,
+) -> Result<Option<Promotion>, sqlx::Error> {
+    sqlx::query_as::<_, Promotion>(
+        "UPDATE promotions SET status = ?, decided_at = ? WHERE id = ? AND status = ? RETURNING *",
+    )
+    .bind(status)
+    .bind(now_millis())
+    .bind(id)
+    .bind(PROMOTION_PENDING)
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn set_promotion_job(db: &DbPool, id: i64, job_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE promotions SET job_id = ? WHERE id = ?")
+        .bind(job_id)
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use utoipa::ToSchema;
 use walkdir::WalkDir;
 
@@ -35,56 +37,205 @@ pub struct WriteFileRequest {
     pub content: String,
 }
 
-pub fn read_file(path_str: &str) -> Result<String, String> {
-    let path = PathBuf::from(path_str);
-    if !path.exists() {
-        return Err("File does not exist".to_string());
+/// Largest file the fs API returns.
+pub const MAX_READ_BYTES: u64 = 1024 * 1024;
+/// Leading bytes checked for NUL bytes to tell binary files apart.
+const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub enum FsError {
+    NotFound(String),
+    /// The path is outside every directory the sandbox allows
+    Denied(String),
+    TooLarge(u64),
+    Binary,
+    Io(String),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound(path) => write!(f, "{} does not exist", path),
+            FsError::Denied(path) => write!(
+                f,
+                "Access denied: {} is outside the registered repositories and browse roots",
+                path
+            ),
+            FsError::TooLarge(size) => write!(
+                f,
+                "File is too large to show ({} bytes, at most {})",
+                size, MAX_READ_BYTES
+            ),
+            FsError::Binary => f.write_str("Binary files cannot be shown"),
+            FsError::Io(e) => f.write_str(e),
+        }
     }
-    fs::read_to_string(path).map_err(|e| e.to_string())
 }
 
-pub fn list_directory(
-    path_str: Option<String>,
-    root_str: Option<String>,
-) -> Result<Vec<FileEntry>, String> {
-    let path = match path_str {
-        Some(p) => PathBuf::from(p),
-        None => std::env::current_dir().map_err(|e| e.to_string())?,
+/// Directories set with `ONEPANEL_BROWSE_ROOTS` (comma separated), where new
+/// repositories can be picked from; the home directory when unset. Directories
+/// that do not exist are skipped.
+pub fn browse_roots() -> Vec<PathBuf> {
+    let configured: Vec<PathBuf> = match std::env::var("ONEPANEL_BROWSE_ROOTS") {
+        Ok(roots) => roots
+            .split(',')
+            .map(str::trim)
+            .filter(|root| !root.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+        Err(_) => dirs::home_dir().into_iter().collect(),
     };
+    configured
+        .into_iter()
+        .filter_map(|root| match dunce::canonicalize(&root) {
+            Ok(root) => Some(root),
+            Err(e) => {
+                tracing::warn!("Ignoring browse root {}: {}", root.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The directories the fs API may reach. Paths are canonicalised before they are
+/// checked, so neither `..` segments nor symlinks lead outside.
+pub struct Sandbox {
+    repositories: Vec<PathBuf>,
+    browse_roots: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new<I, P>(repositories: I, browse_roots: &[PathBuf]) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self {
+            repositories: repositories
+                .into_iter()
+                .filter_map(|repo| dunce::canonicalize(repo).ok())
+                .collect(),
+            browse_roots: browse_roots.to_vec(),
+        }
+    }
+
+    fn resolve(path: &str) -> Result<PathBuf, FsError> {
+        dunce::canonicalize(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => FsError::NotFound(path.to_string()),
+            _ => FsError::Io(e.to_string()),
+        })
+    }
+
+    fn in_repository(&self, path: &Path) -> bool {
+        self.repositories.iter().any(|repo| path.starts_with(repo))
+    }
 
-    if !path.exists() {
-        return Err("Path does not exist".to_string());
+    fn browsable(&self, path: &Path) -> bool {
+        self.in_repository(path) || self.browse_roots.iter().any(|root| path.starts_with(root))
     }
 
-    // Security check: if root is provided, path must be inside root
-    if let Some(ref root) = root_str {
-        let root_path = PathBuf::from(root);
-        if !path.starts_with(&root_path) {
-            return Err("Access denied: Path is outside the root directory".to_string());
+    /// Canonical form of a directory that may be listed or scanned.
+    pub fn browse(&self, path: &str) -> Result<PathBuf, FsError> {
+        let resolved = Self::resolve(path)?;
+        if self.browsable(&resolved) {
+            Ok(resolved)
+        } else {
+            Err(FsError::Denied(path.to_string()))
         }
     }
 
-    let mut entries = Vec::new();
+    /// Canonical form of a file that may be read. Only files in repositories are,
+    /// as browse roots such as the home directory hold keys and other credentials.
+    pub fn read(&self, path: &str) -> Result<PathBuf, FsError> {
+        let resolved = Self::resolve(path)?;
+        if self.in_repository(&resolved) {
+            Ok(resolved)
+        } else {
+            Err(FsError::Denied(path.to_string()))
+        }
+    }
 
-    // Add parent directory ".." only if we are not at the root
-    let is_at_root = if let Some(ref root) = root_str {
-        PathBuf::from(root) == path
-    } else {
-        false
+    /// Top-level entries: the browse roots, then repositories outside them.
+    fn roots(&self) -> Vec<FileEntry> {
+        self.browse_roots
+            .iter()
+            .chain(
+                self.repositories
+                    .iter()
+                    .filter(|repo| !self.browse_roots.iter().any(|root| repo.starts_with(root))),
+            )
+            .map(|root| FileEntry {
+                name: root.to_string_lossy().to_string(),
+                path: root.to_string_lossy().to_string(),
+                is_dir: true,
+            })
+            .collect()
+    }
+}
+
+/// Reads a text file from a repository, refusing large and binary files.
+pub fn read_file(sandbox: &Sandbox, path_str: &str) -> Result<String, FsError> {
+    let path = sandbox.read(path_str)?;
+    let metadata = fs::metadata(&path).map_err(|e| FsError::Io(e.to_string()))?;
+    if !metadata.is_file() {
+        return Err(FsError::Io(format!("{} is not a file", path_str)));
+    }
+    if metadata.len() > MAX_READ_BYTES {
+        return Err(FsError::TooLarge(metadata.len()));
+    }
+
+    let mut bytes = Vec::new();
+    fs::File::open(&path)
+        .and_then(|file| file.take(MAX_READ_BYTES).read_to_end(&mut bytes))
+        .map_err(|e| FsError::Io(e.to_string()))?;
+    if bytes[..bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0) {
+        return Err(FsError::Binary);
+    }
+    String::from_utf8(bytes).map_err(|_| FsError::Binary)
+}
+
+/// Lists a directory the sandbox allows, or its roots when no path is given. With
+/// `root`, the listing stays inside it and has no parent entry at its top.
+pub fn list_directory(
+    sandbox: &Sandbox,
+    path_str: Option<String>,
+    root_str: Option<String>,
+) -> Result<Vec<FileEntry>, FsError> {
+    let Some(path_str) = path_str.filter(|p| !p.is_empty()) else {
+        return Ok(sandbox.roots());
     };
+    let path = sandbox.browse(&path_str)?;
+    let root = match root_str {
+        Some(ref root) => {
+            let root = sandbox.browse(root)?;
+            if !path.starts_with(&root) {
+                return Err(FsError::Denied(path_str));
+            }
+            Some(root)
+        }
+        None => None,
+    };
+
+    let mut entries = Vec::new();
 
-    if !is_at_root {
+    if root.as_ref() != Some(&path) {
         if let Some(parent) = path.parent() {
+            // At the top of a browse root, go back to the list of roots
+            let parent = if sandbox.browsable(parent) {
+                parent.to_string_lossy().to_string()
+            } else {
+                String::new()
+            };
             entries.push(FileEntry {
                 name: "..".to_string(),
-                path: parent.to_string_lossy().to_string(),
+                path: parent,
                 is_dir: true,
             });
         }
     }
 
     // Attempt to open git repo for ignore checking
-    let repo = if let Some(ref root) = root_str {
+    let repo = if let Some(ref root) = root {
         Repository::open(root).ok()
     } else {
         Repository::discover(&path).ok()
@@ -143,7 +294,7 @@ pub fn list_directory(
     Ok(entries)
 }
 
-pub fn scan_for_git_repos(root: &str) -> Vec<String> {
+pub fn scan_for_git_repos(root: &Path) -> Vec<String> {
     let mut repos = Vec::new();
 
     // Use WalkDir to recursively find .git directories
@@ -164,3 +315,183 @@ pub fn scan_for_git_repos(root: &str) -> Vec<String> {
 
     repos
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directory tree for a test: `repo` is registered, `root` is a browse root and
+    /// `outside` is neither.
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str) -> Self {
+            let base = std::env::temp_dir().join(format!("fs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&base);
+            for dir in ["repo/src", "root/notes", "outside"] {
+                fs::create_dir_all(base.join(dir)).unwrap();
+            }
+            fs::write(base.join("repo/src/main.rs"), "fn main() {}").unwrap();
+            fs::write(base.join("root/notes/todo.txt"), "todo").unwrap();
+            fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+            Tree(dunce::canonicalize(base).unwrap())
+        }
+
+        fn path(&self, path: &str) -> String {
+            self.0.join(path).to_string_lossy().to_string()
+        }
+
+        fn sandbox(&self) -> Sandbox {
+            Sandbox::new([self.0.join("repo")], &[self.0.join("root")])
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn denied<T: fmt::Debug>(result: Result<T, FsError>) -> bool {
+        matches!(result, Err(FsError::Denied(_)))
+    }
+
+    #[test]
+    fn reads_only_repository_files() {
+        let tree = Tree::new("read");
+        let sandbox = tree.sandbox();
+
+        assert_eq!(
+            read_file(&sandbox, &tree.path("repo/src/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+        assert!(denied(read_file(
+            &sandbox,
+            &tree.path("outside/secret.txt")
+        )));
+        // Browse roots can be listed but not read
+        assert!(denied(read_file(
+            &sandbox,
+            &tree.path("root/notes/todo.txt")
+        )));
+        assert!(sandbox.browse(&tree.path("root/notes")).is_ok());
+        assert!(matches!(
+            read_file(&sandbox, &tree.path("repo/missing.rs")),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            read_file(&sandbox, &tree.path("repo/src")),
+            Err(FsError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn resolves_parent_segments_before_checking() {
+        let tree = Tree::new("parent");
+        let sandbox = tree.sandbox();
+
+        assert!(denied(read_file(
+            &sandbox,
+            &tree.path("repo/../outside/secret.txt")
+        )));
+        assert!(denied(sandbox.browse(&tree.path("repo/../outside"))));
+        assert!(denied(sandbox.browse(&tree.path("root/.."))));
+        assert_eq!(
+            read_file(&sandbox, &tree.path("repo/src/../src/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_before_checking() {
+        let tree = Tree::new("symlink");
+        let sandbox = tree.sandbox();
+        std::os::unix::fs::symlink(
+            tree.0.join("outside/secret.txt"),
+            tree.0.join("repo/secret.txt"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(tree.0.join("outside"), tree.0.join("root/outside")).unwrap();
+        std::os::unix::fs::symlink(tree.0.join("repo/src/main.rs"), tree.0.join("repo/main.rs"))
+            .unwrap();
+
+        assert!(denied(read_file(&sandbox, &tree.path("repo/secret.txt"))));
+        assert!(denied(sandbox.browse(&tree.path("root/outside"))));
+        assert_eq!(
+            read_file(&sandbox, &tree.path("repo/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+    }
+
+    #[test]
+    fn refuses_large_and_binary_files() {
+        let tree = Tree::new("content");
+        let sandbox = tree.sandbox();
+        fs::write(
+            tree.0.join("repo/large.txt"),
+            vec![b'a'; MAX_READ_BYTES as usize + 1],
+        )
+        .unwrap();
+        fs::write(
+            tree.0.join("repo/limit.txt"),
+            vec![b'a'; MAX_READ_BYTES as usize],
+        )
+        .unwrap();
+        fs::write(
+            tree.0.join("repo/image.png"),
+            b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR",
+        )
+        .unwrap();
+        fs::write(tree.0.join("repo/latin1.txt"), b"caf\xe9").unwrap();
+
+        assert!(matches!(
+            read_file(&sandbox, &tree.path("repo/large.txt")),
+            Err(FsError::TooLarge(size)) if size == MAX_READ_BYTES + 1
+        ));
+        assert_eq!(
+            read_file(&sandbox, &tree.path("repo/limit.txt"))
+                .unwrap()
+                .len() as u64,
+            MAX_READ_BYTES
+        );
+        assert!(matches!(
+            read_file(&sandbox, &tree.path("repo/image.png")),
+            Err(FsError::Binary)
+        ));
+        assert!(matches!(
+            read_file(&sandbox, &tree.path("repo/latin1.txt")),
+            Err(FsError::Binary)
+        ));
+    }
+
+    #[test]
+    fn lists_roots_and_stays_inside_them() {
+        let tree = Tree::new("list");
+        let sandbox = tree.sandbox();
+
+        let roots: Vec<String> = list_directory(&sandbox, None, None)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(roots, [tree.path("root"), tree.path("repo")]);
+
+        // The parent of a browse root leads back to the roots
+        let entries = list_directory(&sandbox, Some(tree.path("root")), None).unwrap();
+        assert_eq!(
+            (entries[0].name.as_str(), entries[0].path.as_str()),
+            ("..", "")
+        );
+        assert!(denied(list_directory(
+            &sandbox,
+            Some(tree.path("root/notes")),
+            Some(tree.path("repo"))
+        )));
+
+        // A sandbox without browse roots, as for a scoped token
+        let scoped = Sandbox::new([tree.0.join("repo")], &[]);
+        assert!(denied(scoped.browse(&tree.path("root"))));
+        assert_eq!(list_directory(&scoped, None, None).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::audit::{self, AuditFilter};
+use crate::models::{AuditEntry, AuditPage};
+use crate::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+
+/// Most entries a single export returns.
+const MAX_EXPORT: i64 = 100_000;
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct AuditQuery {
+    /// Username
+    pub user: Option<String>,
+    /// How the change was made (session, token or mcp)
+    pub actor: Option<String>,
+    /// Part of the action, e.g. `composes/content/update` or `mcp rollback`
+    pub action: Option<String>,
+    /// Server ID
+    pub server: Option<i64>,
+    /// Only successful (true) or failed (false) actions
+    pub success: Option<bool>,
+    /// Only entries at or after this time (ms since epoch)
+    pub from: Option<i64>,
+    /// Only entries before this time (ms since epoch)
+    pub to: Option<i64>,
+    /// Page number, starting at 1 (default 1)
+    pub page: Option<i64>,
+    /// Page size (default 50, max 500)
+    pub page_size: Option<i64>,
+    /// Export format: json (default) or csv
+    pub format: Option<String>,
+}
+
+impl AuditQuery {
+    fn filter(&self) -> AuditFilter<'_> {
+        AuditFilter {
+            username: self.user.as_deref(),
+            actor: self.actor.as_deref(),
+            action: self.action.as_deref(),
+            server_id: self.server,
+            success: self.success,
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/audit",
+    tag = "Audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Audit log, newest first", body = AuditPage)
+    )
+)]
+pub async fn list_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    let page = query.page.unwrap_or(1).max(1);
+    let page_size = query.page_size.unwrap_or(50).clamp(1, 500);
+
+    match audit::list(
+        &state.db,
+        &query.filter(),
+        page_size,
+        (page - 1) * page_size,
+    )
+    .await
+    {
+        Ok((items, total)) => (
+            StatusCode::OK,
+            Json(AuditPage {
+                items,
+                total,
+                page,
+                page_size,
+            }),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/audit/export",
+    tag = "Audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Every matching entry, newest first, as a JSON array or CSV file", body = Vec<AuditEntry>),
+        (status = 400, description = "Unknown format")
+    )
+)]
+pub async fn export_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown format {}; use json or csv", other),
+            )
+                .into_response()
+        }
+    };
+
+    let entries = match audit::list(&state.db, &query.filter(), MAX_EXPORT, 0).await {
+        Ok((items, _)) => items,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    if csv {
+        (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"audit-log.csv\"",
+                ),
+            ],
+            audit::to_csv(&entries),
+        )
+            .into_response()
+    } else {
+        (
+            StatusCode::OK,
+            [(
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit-log.json\"",
+            )],
+            Json(entries),
+        )
+            .into_response()
+    }
+}
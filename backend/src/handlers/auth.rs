@@ -0,0 +1,548 @@
+use crate::auth::{self, AuthMethod, Principal, Role, TokenGrant, MIN_PASSWORD_LEN};
+use crate::jobs::now_millis;
+use crate::models::{
+    ApiToken, AuthStatus, ChangePasswordReq, CreateTokenReq, CreateUserReq, CreatedToken, LoginReq,
+    UpdateUserReq, User,
+};
+use crate::pipeline;
+use crate::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use std::time::Duration;
+
+/// Failed logins are answered after this delay, to slow down password guessing.
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+fn check_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LEN
+        ));
+    }
+    Ok(())
+}
+
+fn parse_role(role: &str) -> Result<Role, String> {
+    Role::parse(role).ok_or_else(|| {
+        format!(
+            "Unknown role {}; use viewer, builder, deployer or admin",
+            role
+        )
+    })
+}
+
+/// Starts a session for the user and answers with its cookie.
+async fn logged_in(state: &AppState, user_id: i64, username: String, role: String) -> Response {
+    match auth::create_session(&state.db, user_id).await {
+        Ok(secret) => (
+            StatusCode::OK,
+            [(header::SET_COOKIE, auth::session_cookie(&secret))],
+            Json(AuthStatus {
+                setup_required: false,
+                authenticated: true,
+                username: Some(username),
+                role: Some(role),
+            }),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/auth/status",
+    tag = "Auth",
+    responses(
+        (status = 200, description = "Whether the caller is logged in", body = AuthStatus)
+    )
+)]
+pub async fn auth_status(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let users = match auth::user_count(&state.db).await {
+        Ok(n) => n,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let principal = match auth::authenticate(&state.db, &headers).await {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    (
+        StatusCode::OK,
+        Json(AuthStatus {
+            setup_required: users == 0,
+            authenticated: principal.is_some(),
+            role: principal.as_ref().map(|p| p.role.as_str().to_string()),
+            username: principal.map(|p| p.username),
+        }),
+    )
+        .into_response()
+}
+
+/// Creates the admin account on first start and logs it in.
+#[utoipa::path(
+    post,
+    path = "/api/auth/setup",
+    tag = "Auth",
+    request_body = LoginReq,
+    responses(
+        (status = 200, description = "Admin created and logged in", body = AuthStatus),
+        (status = 400, description = "Username missing or password too short"),
+        (status = 409, description = "An admin exists already")
+    )
+)]
+pub async fn setup(
+    State(state): State<AppState>,
+    Json(payload): Json<LoginReq>,
+) -> impl IntoResponse {
+    let username = payload.username.trim().to_string();
+    if username.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Username is required").into_response();
+    }
+    if let Err(e) = check_password(&payload.password) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let hash = match auth::hash_password(payload.password).await {
+        Ok(hash) => hash,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    match auth::create_first_user(&state.db, &username, &hash).await {
+        Ok(Some(user_id)) => {
+            logged_in(&state, user_id, username, Role::Admin.as_str().to_string()).await
+        }
+        Ok(None) => (StatusCode::CONFLICT, "Setup has been completed already").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/login",
+    tag = "Auth",
+    request_body = LoginReq,
+    responses(
+        (status = 200, description = "Logged in; the session cookie is set", body = AuthStatus),
+        (status = 401, description = "Wrong username or password")
+    )
+)]
+pub async fn login(
+    State(state): State<AppState>,
+    Json(payload): Json<LoginReq>,
+) -> impl IntoResponse {
+    let user = match auth::find_user(&state.db, payload.username.trim()).await {
+        Ok(user) => user,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    if let Some(user) = user {
+        if auth::verify_password(payload.password, user.password_hash).await {
+            return logged_in(&state, user.id, user.username, user.role).await;
+        }
+    }
+    tokio::time::sleep(LOGIN_FAILURE_DELAY).await;
+    (StatusCode::UNAUTHORIZED, "Invalid username or password").into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/logout",
+    tag = "Auth",
+    responses(
+        (status = 200, description = "Session ended")
+    )
+)]
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(secret) = auth::session_secret(&headers) {
+        if let Err(e) = auth::delete_session(&state.db, secret).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    }
+    (
+        StatusCode::OK,
+        [(header::SET_COOKIE, auth::clear_session_cookie())],
+    )
+        .into_response()
+}
+
+/// Changes the caller's password and ends their other sessions.
+#[utoipa::path(
+    post,
+    path = "/api/auth/password",
+    tag = "Auth",
+    request_body = ChangePasswordReq,
+    responses(
+        (status = 200, description = "Password changed"),
+        (status = 400, description = "New password too short"),
+        (status = 401, description = "Current password is wrong")
+    )
+)]
+pub async fn change_password(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    headers: HeaderMap,
+    Json(payload): Json<ChangePasswordReq>,
+) -> impl IntoResponse {
+    if let Err(e) = check_password(&payload.new_password) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let user = match auth::get_user(&state.db, principal.user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, "User no longer exists").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    if !auth::verify_password(payload.current_password, user.password_hash).await {
+        tokio::time::sleep(LOGIN_FAILURE_DELAY).await;
+        return (StatusCode::UNAUTHORIZED, "Current password is wrong").into_response();
+    }
+
+    let hash = match auth::hash_password(payload.new_password).await {
+        Ok(hash) => hash,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    if let Err(e) = auth::set_password(&state.db, user.id, &hash).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    let keep = match principal.method {
+        AuthMethod::Session => auth::session_secret(&headers),
+        AuthMethod::Token(_) => None,
+    };
+    match auth::delete_other_sessions(&state.db, user.id, keep).await {
+        Ok(()) => (StatusCode::OK, "Password changed").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/auth/tokens",
+    tag = "Auth",
+    responses(
+        (status = 200, description = "The caller's API tokens", body = Vec<ApiToken>)
+    )
+)]
+pub async fn list_tokens(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> impl IntoResponse {
+    match auth::list_tokens(&state.db, principal.user_id).await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// What the requested token may do. A token cannot do more than its creator,
+/// and one created with a scoped token keeps that scope.
+async fn token_grant(
+    state: &AppState,
+    principal: &Principal,
+    payload: CreateTokenReq,
+) -> Result<TokenGrant, Response> {
+    let role = match payload.role.as_deref() {
+        Some(role) => {
+            Some(parse_role(role).map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?)
+        }
+        // Following the user's role would outrank the token used to create this one
+        None if principal.method != AuthMethod::Session => Some(principal.role),
+        None => None,
+    };
+    if let Some(role) = role {
+        principal
+            .require(role)
+            .map_err(IntoResponse::into_response)?;
+    }
+
+    let server_ids = match payload.server_ids {
+        Some(ids) => {
+            for &id in &ids {
+                principal
+                    .require_server(id)
+                    .map_err(IntoResponse::into_response)?;
+                match crate::onepanel::find_server(&state.db, id).await {
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        return Err(
+                            (StatusCode::BAD_REQUEST, format!("Server {} not found", id))
+                                .into_response(),
+                        )
+                    }
+                    Err(e) => {
+                        return Err(
+                            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+                        )
+                    }
+                }
+            }
+            Some(ids)
+        }
+        None => principal.servers.clone(),
+    };
+
+    let repositories = match payload.repositories {
+        Some(names) => {
+            let mut paths = Vec::new();
+            for name in names {
+                let repo = pipeline::resolve_repository(&state.db, &name)
+                    .await
+                    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+                principal
+                    .require_repository(&repo.path)
+                    .map_err(IntoResponse::into_response)?;
+                paths.push(repo.path);
+            }
+            Some(paths)
+        }
+        None => principal.repositories.clone(),
+    };
+
+    Ok(TokenGrant {
+        role,
+        server_ids,
+        repositories,
+    })
+}
+
+/// Creates an API token. Its secret is in the response and cannot be shown again.
+#[utoipa::path(
+    post,
+    path = "/api/auth/tokens",
+    tag = "Auth",
+    request_body = CreateTokenReq,
+    responses(
+        (status = 201, description = "Token created", body = CreatedToken),
+        (status = 400, description = "Name missing, invalid expiry or unknown role, server or repository"),
+        (status = 403, description = "Role or scope exceeds the caller's")
+    )
+)]
+pub async fn create_token(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<CreateTokenReq>,
+) -> impl IntoResponse {
+    let name = payload.name.trim().to_string();
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Name is required").into_response();
+    }
+    let expires_at = match payload.expires_in_days {
+        Some(days) if days <= 0 => {
+            return (StatusCode::BAD_REQUEST, "expires_in_days must be positive").into_response()
+        }
+        Some(days) => Some(now_millis() + days * 24 * 60 * 60 * 1000),
+        None => None,
+    };
+    let grant = match token_grant(&state, &principal, payload).await {
+        Ok(grant) => grant,
+        Err(res) => return res,
+    };
+
+    match auth::create_token(&state.db, principal.user_id, &name, expires_at, grant).await {
+        Ok((token, secret)) => {
+            (StatusCode::CREATED, Json(CreatedToken { token, secret })).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/auth/tokens/{id}",
+    tag = "Auth",
+    params(
+        ("id" = i64, Path, description = "Token ID")
+    ),
+    responses(
+        (status = 200, description = "Token revoked"),
+        (status = 404, description = "Token not found")
+    )
+)]
+pub async fn delete_token(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match auth::delete_token(&state.db, principal.user_id, id).await {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Token not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users",
+    tag = "Auth",
+    responses(
+        (status = 200, description = "Users who can log in", body = Vec<User>)
+    )
+)]
+pub async fn list_users(State(state): State<AppState>) -> impl IntoResponse {
+    match auth::list_users(&state.db).await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/users",
+    tag = "Auth",
+    request_body = CreateUserReq,
+    responses(
+        (status = 201, description = "User created", body = User),
+        (status = 400, description = "Username missing, password too short or unknown role"),
+        (status = 409, description = "Username taken")
+    )
+)]
+pub async fn create_user(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateUserReq>,
+) -> impl IntoResponse {
+    let username = payload.username.trim();
+    if username.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Username is required").into_response();
+    }
+    if let Err(e) = check_password(&payload.password) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let role = match parse_role(&payload.role) {
+        Ok(role) => role,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let hash = match auth::hash_password(payload.password).await {
+        Ok(hash) => hash,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    match auth::create_user(&state.db, username, &hash, role).await {
+        Ok(user) => (StatusCode::CREATED, Json(user)).into_response(),
+        Err(e)
+            if e.as_database_error()
+                .is_some_and(|db| db.is_unique_violation()) =>
+        {
+            (StatusCode::CONFLICT, "A user with this name already exists").into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Changes a user's role or resets their password, which ends their sessions.
+#[utoipa::path(
+    put,
+    path = "/api/users/{id}",
+    tag = "Auth",
+    request_body = UpdateUserReq,
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "User updated", body = User),
+        (status = 400, description = "Password too short or unknown role"),
+        (status = 404, description = "User not found"),
+        (status = 409, description = "The last admin cannot be demoted")
+    )
+)]
+pub async fn update_user(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateUserReq>,
+) -> impl IntoResponse {
+    let role = match payload.role.as_deref().map(parse_role).transpose() {
+        Ok(role) => role,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if let Some(password) = &payload.password {
+        if let Err(e) = check_password(password) {
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    }
+    match auth::get_user(&state.db, id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    if let Some(role) = role {
+        if role != Role::Admin {
+            match auth::other_admins(&state.db, id).await {
+                Ok(0) => {
+                    return (StatusCode::CONFLICT, "The last admin cannot be demoted")
+                        .into_response()
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+                }
+            }
+        }
+        if let Err(e) = auth::set_role(&state.db, id, role).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    }
+    if let Some(password) = payload.password {
+        let hash = match auth::hash_password(password).await {
+            Ok(hash) => hash,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
+        if let Err(e) = auth::set_password(&state.db, id, &hash).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+        if let Err(e) = auth::delete_other_sessions(&state.db, id, None).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    }
+
+    match auth::get_user(&state.db, id).await {
+        Ok(Some(user)) => (StatusCode::OK, Json(user)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Deletes a user together with their sessions and API tokens.
+#[utoipa::path(
+    delete,
+    path = "/api/users/{id}",
+    tag = "Auth",
+    params(
+        ("id" = i64, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "User deleted"),
+        (status = 404, description = "User not found"),
+        (status = 409, description = "Users cannot delete themselves or the last admin")
+    )
+)]
+pub async fn delete_user(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    if id == principal.user_id {
+        return (StatusCode::CONFLICT, "You cannot delete yourself").into_response();
+    }
+    let user = match auth::get_user(&state.db, id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    if Role::parse(&user.role) == Some(Role::Admin) {
+        match auth::other_admins(&state.db, id).await {
+            Ok(0) => {
+                return (StatusCode::CONFLICT, "The last admin cannot be deleted").into_response()
+            }
+            Ok(_) => {}
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+
+    match auth::delete_user(&state.db, id).await {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -1,5 +1,5 @@
-use crate::models::Server;
-use crate::onepanel::OnePanelClient;
+use crate::handlers::server::client_for;
+use crate::models::Compose;
 use crate::state::AppState;
 use axum::{
     extract::{Path, State},
@@ -16,26 +16,22 @@ use axum::{
         ("id" = i64, Path, description = "Server ID")
     ),
     responses(
-        (status = 200, description = "List composes", body = Vec<serde_json::Value>)
+        (status = 200, description = "List composes", body = Vec<Compose>)
     )
 )]
 pub async fn list_composes(
     State(state): State<AppState>,
     Path(id): Path<i64>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::list_composes(&s.host, s.port, &s.api_key).await {
-            Ok(list) => return (StatusCode::OK, Json(list)).into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    match client.list_composes().await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
 
 #[derive(serde::Deserialize, utoipa::ToSchema)]
@@ -60,19 +56,15 @@ pub async fn get_content(
     Path(id): Path<i64>,
     Json(payload): Json<GetContentReq>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::read_file(&s.host, s.port, &s.api_key, &payload.path).await {
-            Ok(content) => return (StatusCode::OK, content).into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    match client.read_file(&payload.path).await {
+        Ok(content) => (StatusCode::OK, content).into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
 
 #[derive(serde::Deserialize, utoipa::ToSchema)]
@@ -99,28 +91,18 @@ pub async fn update_content(
     Path(id): Path<i64>,
     Json(payload): Json<UpdateContentReq>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::update_compose(
-            &s.host,
-            s.port,
-            &s.api_key,
-            &payload.name,
-            &payload.path,
-            &payload.content,
-        )
+    match client
+        .update_compose(&payload.name, &payload.path, &payload.content)
         .await
-        {
-            Ok(_) => return (StatusCode::OK, "Content updated").into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    {
+        Ok(_) => (StatusCode::OK, "Content updated").into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
 
 #[derive(serde::Deserialize, utoipa::ToSchema)]
@@ -147,26 +129,16 @@ pub async fn operate_compose(
     Path(id): Path<i64>,
     Json(payload): Json<OperateComposeReq>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::operate_compose(
-            &s.host,
-            s.port,
-            &s.api_key,
-            &payload.name,
-            &payload.path,
-            &payload.operation,
-        )
+    match client
+        .operate_compose(&payload.name, &payload.path, &payload.operation)
         .await
-        {
-            Ok(_) => return (StatusCode::OK, "Operation successful").into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    {
+        Ok(_) => (StatusCode::OK, "Operation successful").into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
@@ -1,5 +1,5 @@
-use crate::models::{ContainerOperationReq, Server};
-use crate::onepanel::OnePanelClient;
+use crate::handlers::server::client_for;
+use crate::models::{Container, ContainerOperationReq};
 use crate::state::AppState;
 use axum::{
     extract::{Path, Query, State},
@@ -17,26 +17,22 @@ use serde::Deserialize;
         ("id" = i64, Path, description = "Server ID")
     ),
     responses(
-        (status = 200, description = "List containers", body = Vec<serde_json::Value>)
+        (status = 200, description = "List containers", body = Vec<Container>)
     )
 )]
 pub async fn list_containers(
     State(state): State<AppState>,
     Path(id): Path<i64>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::list_containers(&s.host, s.port, &s.api_key).await {
-            Ok(list) => return (StatusCode::OK, Json(list)).into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    match client.list_containers().await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
 
 #[utoipa::path(
@@ -56,27 +52,18 @@ pub async fn operate_container(
     Path(id): Path<i64>,
     Json(payload): Json<ContainerOperationReq>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::operate_container(
-            &s.host,
-            s.port,
-            &s.api_key,
-            payload.names,
-            payload.operation,
-        )
+    match client
+        .operate_container(payload.names, payload.operation)
         .await
-        {
-            Ok(_) => return StatusCode::OK.into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
 
 #[derive(Deserialize)]
@@ -101,18 +88,13 @@ pub async fn get_logs(
     Path(id): Path<i64>,
     Query(query): Query<LogsQuery>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::get_container_logs(&s.host, s.port, &s.api_key, query.container).await
-        {
-            Ok(logs) => return (StatusCode::OK, logs).into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    match client.get_container_logs(&query.container).await {
+        Ok(logs) => (StatusCode::OK, logs).into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
@@ -1,7 +1,19 @@
-use crate::models::{PushImageReq, Server};
-use crate::onepanel::OnePanelClient;
+use crate::auth::{self, Principal};
+use crate::compose::ImageRef;
+use crate::compress::Compression;
+use crate::delta;
+use crate::deployments;
+use crate::jobs::{JobLog, TRIGGER_API};
+use crate::models::{DeployPreview, DeployReq, JobStarted, PushImageReq, RollbackReq};
+use crate::onepanel::{self, OnePanelClient, OnePanelError};
+use crate::pipeline::{self, DeployParams, StaleToken};
+use crate::registries::PULL_POLL_INTERVAL;
 use crate::AppState;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
 
 #[utoipa::path(
     post,
@@ -16,27 +28,31 @@ pub async fn push_image_to_server_inner(
     db: &crate::db::DbPool,
     server_id: i64,
     image_tag: &str,
+    log: &JobLog,
 ) -> Result<(), anyhow::Error> {
     // 1. Get Server
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(server_id)
-        .fetch_optional(db)
-        .await?;
-
-    let server = server.ok_or_else(|| anyhow::anyhow!("Server not found"))?;
+    let server = onepanel::find_server(db, server_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Server not found"))?;
+    let client = OnePanelClient::new(&server)?;
+    let compression = Compression::of(&server).map_err(anyhow::Error::msg)?;
 
     let timestamp = chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0);
     let temp_filename = format!("image_{}.tar", timestamp);
     let temp_path = std::env::temp_dir().join(&temp_filename);
+    // Removes the archive however this function exits, including job cancellation
+    let _archive = TempFile(temp_path.clone());
 
     // 2. Docker Save
     // Ensure docker is in path
-    println!("Executing docker save -o {:?} {}", temp_path, image_tag);
+    info!("Executing docker save -o {:?} {}", temp_path, image_tag);
+    log.line(format!("Saving image {}", image_tag));
     let status = tokio::process::Command::new("docker")
         .arg("save")
         .arg("-o")
         .arg(&temp_path)
         .arg(image_tag)
+        .kill_on_drop(true)
         .status()
         .await?;
 
@@ -44,31 +60,310 @@ pub async fn push_image_to_server_inner(
         return Err(anyhow::anyhow!("docker save failed"));
     }
 
-    // 3. Upload File
-    println!("Uploading file to server...");
-    let remote_dir = "/opt/1panel/tmp"; // Default temp dir
-    let upload_res = OnePanelClient::upload_file(
-        &server.host,
-        server.port,
-        &server.api_key,
+    let size = tokio::fs::metadata(&temp_path).await?.len();
+    log.line(format!("Saved image archive ({})", format_bytes(size)));
+
+    // 3. Send only the layers the server is missing when it has some of them already
+    let delta_path = std::env::temp_dir().join(format!("image_{}_delta.tar", timestamp));
+    let delta = delta_archive(&client, image_tag, &temp_path, &delta_path, log).await;
+    let destination = Destination {
+        db,
+        client: &client,
+        server_name: &server.name,
+        compression,
+    };
+    send_image(
+        &destination,
+        image_tag,
         &temp_path,
-        remote_dir,
+        delta.as_ref().map(|delta| delta.0.as_path()),
+        log,
+    )
+    .await
+}
+
+/// Server image archives are sent to, and how.
+struct Destination<'a> {
+    /// Keeps the progress of uploads, so a retry continues an interrupted one
+    db: &'a crate::db::DbPool,
+    client: &'a OnePanelClient,
+    server_name: &'a str,
+    compression: Compression,
+}
+
+/// Loads `image_tag` on the server from the trimmed archive `delta` when there is
+/// one, falling back to the full `archive` should the server not take it. A loaded
+/// trimmed archive lacks no more than a few layers, so it only gets the request
+/// timeout to appear before the full archive is sent.
+async fn send_image(
+    destination: &Destination<'_>,
+    image_tag: &str,
+    archive: &Path,
+    delta: Option<&Path>,
+    log: &JobLog,
+) -> Result<(), anyhow::Error> {
+    let (client, server_name) = (destination.client, destination.server_name);
+    if let Some(delta) = delta {
+        let loaded = transfer(destination, delta, image_tag, client.request_timeout(), log).await;
+        match loaded {
+            Ok(()) => {
+                log.line(format!("Image {} loaded on {}", image_tag, server_name));
+                return Ok(());
+            }
+            // e.g. the containerd image store, which wants every layer in the archive.
+            // 1Panel v2 loads in the background, so there it only shows as a missing image.
+            Err(e)
+                if matches!(e.downcast_ref(), Some(OnePanelError::Api { .. }))
+                    || e.is::<ImageMissing>() =>
+            {
+                log.line(format!(
+                    "Loading the trimmed archive failed ({}); sending the full image",
+                    e
+                ));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    transfer(
+        destination,
+        archive,
+        image_tag,
+        client.upload_timeout(),
+        log,
     )
-    .await;
+    .await?;
+    log.line(format!("Image {} loaded on {}", image_tag, server_name));
+    Ok(())
+}
 
-    // Cleanup local file
-    let _ = std::fs::remove_file(&temp_path);
+/// The server did not list an image after loading its archive.
+#[derive(Debug)]
+struct ImageMissing(String);
 
-    let remote_path = upload_res?;
-    println!("Upload successful, path: {}", remote_path);
+impl std::fmt::Display for ImageMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Image {} did not appear on the server after loading",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ImageMissing {}
+
+/// Waits up to `timeout` until the server lists `image_tag`. A load can pass without
+/// the image appearing, and 1Panel v2 only starts one in the background.
+async fn wait_for_image(
+    client: &OnePanelClient,
+    image_tag: &str,
+    timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let wanted = ImageRef::parse(image_tag);
+    let tag = |image: &ImageRef| image.tag.clone().unwrap_or_else(|| "latest".to_string());
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let images = client.list_images().await?;
+        if images.iter().flat_map(|i| &i.tags).any(|t| {
+            let listed = ImageRef::parse(t);
+            listed.name == wanted.name && tag(&listed) == tag(&wanted)
+        }) {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(ImageMissing(image_tag.to_string()).into());
+        }
+        tokio::time::sleep(PULL_POLL_INTERVAL).await;
+    }
+}
 
-    // 4. Load Image
-    println!("Loading image from {}", remote_path);
-    OnePanelClient::load_image(&server.host, server.port, &server.api_key, &remote_path).await?;
+/// Copies the image `image_tag` from a server into the local Docker: 1Panel saves it
+/// to an archive, which is downloaded, loaded and removed on both sides.
+pub async fn pull_image_from_server(
+    db: &crate::db::DbPool,
+    server_id: i64,
+    image_tag: &str,
+    log: &JobLog,
+) -> Result<(), anyhow::Error> {
+    let server = onepanel::find_server(db, server_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Server not found"))?;
+    let client = OnePanelClient::new(&server)?;
+
+    let timestamp = chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0);
+    let name = format!("image_{}", timestamp);
+    let local_path = std::env::temp_dir().join(format!("{}.tar", name));
+    let _archive = TempFile(local_path.clone());
 
+    log.line(format!("Saving image {} on {}", image_tag, server.name));
+    let remote_path = client
+        .save_image(image_tag, client.upload_dir(), &name)
+        .await?;
+    let downloaded = client.download_file(&remote_path, &local_path).await;
+    if let Err(e) = client.delete_file(&remote_path).await {
+        log.line(format!("Could not remove {}: {}", remote_path, e));
+    }
+    log.line(format!(
+        "Downloaded image archive ({}) from {}",
+        format_bytes(downloaded?),
+        server.name
+    ));
+
+    let output = tokio::process::Command::new("docker")
+        .arg("load")
+        .arg("-i")
+        .arg(&local_path)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "docker load failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    log.line(format!("Loaded image {} into the local Docker", image_tag));
     Ok(())
 }
 
+/// Trims the saved archive to the layers the server does not have yet. `None` when
+/// the full archive should be sent: the server has none of the layers, or they
+/// could not be determined.
+async fn delta_archive(
+    client: &OnePanelClient,
+    image_tag: &str,
+    archive: &Path,
+    dst: &Path,
+    log: &JobLog,
+) -> Option<TempFile> {
+    let present = match delta::remote_chain_ids(client, image_tag).await {
+        Ok(present) if !present.is_empty() => present,
+        Ok(_) => return None,
+        Err(e) => {
+            log.line(format!("Could not list the layers on the server: {}", e));
+            return None;
+        }
+    };
+
+    let delta = TempFile(dst.to_path_buf());
+    match delta::trim(archive, dst, present).await {
+        Ok(trimmed) if trimmed.skipped_layers > 0 => {
+            log.line(format!(
+                "Server already has {} of {} layers, skipping {}",
+                trimmed.skipped_layers,
+                trimmed.layers,
+                format_bytes(trimmed.skipped_bytes)
+            ));
+            Some(delta)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            log.line(format!("Could not trim the image archive: {}", e));
+            None
+        }
+    }
+}
+
+/// Compresses, uploads and loads an image archive, then removes it from the server
+/// once the image is there, which it has to be within `load_timeout`.
+async fn transfer(
+    destination: &Destination<'_>,
+    archive: &Path,
+    image_tag: &str,
+    load_timeout: Duration,
+    log: &JobLog,
+) -> Result<(), anyhow::Error> {
+    let Destination {
+        db,
+        client,
+        server_name,
+        compression,
+    } = *destination;
+    let size = tokio::fs::metadata(archive).await?.len();
+
+    // Compress the archive; docker load on the server unpacks it
+    let (upload_path, compressed_archive) = if compression == Compression::None {
+        (archive.to_path_buf(), None)
+    } else {
+        let compressed_path = archive.with_extension(compression.extension());
+        let compressed = TempFile(compressed_path.clone());
+        log.line(format!("Compressing archive with {}", compression));
+        compression.compress(archive, &compressed_path).await?;
+        let compressed_size = tokio::fs::metadata(&compressed_path).await?.len();
+        log.line(format!(
+            "Compressed archive to {} ({}% of original)",
+            format_bytes(compressed_size),
+            (compressed_size * 100).checked_div(size).unwrap_or(100)
+        ));
+        (compressed_path, Some(compressed))
+    };
+
+    // Upload
+    info!("Uploading file to server {}", server_name);
+    log.line(format!("Uploading to {}", server_name));
+
+    let progress_log = log.clone();
+    let last_reported = AtomicU64::new(0);
+    let on_progress = move |sent: u64, total: u64| {
+        // Report roughly every 10%
+        let percent = (sent * 100).checked_div(total).unwrap_or(100);
+        let bucket = percent / 10;
+        if bucket > last_reported.load(Ordering::Relaxed) {
+            last_reported.store(bucket, Ordering::Relaxed);
+            progress_log.line(format!(
+                "Uploaded {} / {} ({}%)",
+                format_bytes(sent),
+                format_bytes(total),
+                percent
+            ));
+        }
+    };
+
+    let remote_path = client
+        .upload_file(db, &upload_path, client.upload_dir(), on_progress)
+        .await?;
+    drop(compressed_archive);
+
+    info!("Upload successful, path: {}", remote_path);
+    log.line(format!("Upload complete: {}", remote_path));
+
+    // Load
+    log.line(format!("Loading image from {}", remote_path));
+    client.load_image(&remote_path).await?;
+
+    // 1Panel v2 loads in the background, reading the archive as it goes. It is only
+    // kept when the load failed, for inspection and the uploads cleanup.
+    wait_for_image(client, image_tag, load_timeout).await?;
+    if let Err(e) = client.delete_file(&remote_path).await {
+        log.line(format!("Could not remove {}: {}", remote_path, e));
+    }
+
+    Ok(())
+}
+
+/// Whether `name` is an image archive uploaded by a deploy: `image_<nanos>.tar`, or
+/// its trimmed `_delta` variant, possibly compressed.
+pub fn is_upload_archive(name: &str) -> bool {
+    name.starts_with("image_")
+        && [".tar", ".tar.gz", ".tar.zst"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+}
+
+struct TempFile(std::path::PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const MB: f64 = 1024.0 * 1024.0;
+    format!("{:.1} MB", bytes as f64 / MB)
+}
+
 #[utoipa::path(
     post,
     path = "/api/deploy/image",
@@ -80,10 +375,257 @@ pub async fn push_image_to_server_inner(
 )]
 pub async fn push_image_to_server(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<PushImageReq>,
 ) -> impl IntoResponse {
-    match push_image_to_server_inner(&state.db, payload.server_id, &payload.image_tag).await {
+    if let Err(e) = principal.require_server(payload.server_id) {
+        return e.into_response();
+    }
+    match push_image_to_server_inner(
+        &state.db,
+        payload.server_id,
+        &payload.image_tag,
+        &JobLog::none(),
+    )
+    .await
+    {
         Ok(_) => (StatusCode::OK, "Image pushed successfully").into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
+
+/// Restores the compose file that was in place before the latest deployment to
+/// the given stack and restarts it. Calling it again steps back one more release.
+#[utoipa::path(
+    post,
+    path = "/api/deploy/rollback",
+    request_body = RollbackReq,
+    responses(
+        (status = 202, description = "Rollback job started", body = JobStarted),
+        (status = 404, description = "No deployment to roll back"),
+        (status = 500, description = "Internal Server Error")
+    )
+)]
+pub async fn rollback_deployment(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<RollbackReq>,
+) -> impl IntoResponse {
+    if let Err(e) = principal.require_server(payload.server_id) {
+        return e.into_response();
+    }
+    match deployments::latest_active(&state.db, payload.server_id, &payload.compose_path).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "No deployment to roll back").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    let params = serde_json::json!({
+        "server_id": payload.server_id,
+        "compose_path": payload.compose_path,
+    });
+    match pipeline::start(
+        &state.jobs,
+        pipeline::KIND_ROLLBACK,
+        params,
+        TRIGGER_API,
+        None,
+    )
+    .await
+    {
+        Ok(job_id) => (StatusCode::ACCEPTED, Json(JobStarted { job_id })).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Builds the repository's next image and deploys it to its default server.
+/// With `dry_run` nothing is built or written: the response is the compose diff
+/// and a token; sending the same request with that token applies exactly that diff.
+#[utoipa::path(
+    post,
+    path = "/api/deploy",
+    request_body = DeployReq,
+    responses(
+        (status = 200, description = "Dry run preview", body = DeployPreview),
+        (status = 202, description = "Deploy job started", body = JobStarted),
+        (status = 400, description = "Repository is not set up for deploys"),
+        (status = 409, description = "Deploy no longer matches the previewed change")
+    )
+)]
+pub async fn deploy(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<DeployReq>,
+) -> impl IntoResponse {
+    if let Err(e) = auth::authorize_deploy(&state.db, &principal, &payload.path).await {
+        return e.into_response();
+    }
+    let params = DeployParams {
+        path: payload.path,
+        service: payload.service,
+        version: payload.version,
+        token: payload.token,
+    };
+
+    if payload.dry_run {
+        return match pipeline::preview_deploy(&state.db, &params).await {
+            Ok(preview) => (StatusCode::OK, Json(preview)).into_response(),
+            Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        };
+    }
+
+    match pipeline::start_deploy(&state.jobs, &state.db, params, TRIGGER_API).await {
+        Ok(job_id) => (StatusCode::ACCEPTED, Json(JobStarted { job_id })).into_response(),
+        Err(e) if e.is::<StaleToken>() => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_onepanel::{image, MockPanel};
+    use axum::http::StatusCode;
+
+    fn archive(mock: &MockPanel, name: &str, content: &str) -> TempFile {
+        let path = std::env::temp_dir().join(format!("image_{}_{}.tar", mock.server.port, name));
+        std::fs::write(&path, content).unwrap();
+        TempFile(path)
+    }
+
+    #[tokio::test]
+    async fn sends_the_full_image_when_the_trimmed_one_does_not_appear() {
+        let mut mock = MockPanel::start().await;
+        // The trimmed archive gets no longer than a request to show up
+        mock.server.request_timeout_secs = Some(1);
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let db = crate::db::memory().await;
+        let full = archive(&mock, "full", "image web:2");
+        let delta = archive(&mock, "delta", "missing layers");
+
+        send_image(
+            &Destination {
+                db: &db,
+                client: &client,
+                server_name: "mock",
+                compression: Compression::None,
+            },
+            "web:2",
+            &full.0,
+            Some(&delta.0),
+            &JobLog::none(),
+        )
+        .await
+        .unwrap();
+        let state = mock.state();
+        assert_eq!(state.calls("containers/image/load"), 2);
+        assert!(state.images.iter().all(|listing| listing.len() == 1));
+        // The trimmed archive is left for the uploads cleanup
+        let delta_name = delta.0.file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            state.files.keys().collect::<Vec<_>>(),
+            [&format!("/opt/1panel/tmp/{}", delta_name)]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_the_full_image_when_the_trimmed_one_is_rejected() {
+        let mock = MockPanel::start().await;
+        mock.state().script(
+            "containers/image/load",
+            StatusCode::OK,
+            r#"{"code": 500, "message": "layer does not exist", "data": null}"#,
+        );
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let db = crate::db::memory().await;
+        let full = archive(&mock, "full", "image web:2");
+        let delta = archive(&mock, "delta", "image web:2");
+
+        send_image(
+            &Destination {
+                db: &db,
+                client: &client,
+                server_name: "mock",
+                compression: Compression::None,
+            },
+            "web:2",
+            &full.0,
+            Some(&delta.0),
+            &JobLog::none(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(mock.state().calls("containers/image/load"), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_full_image_does_not_appear() {
+        let mut mock = MockPanel::start().await;
+        mock.server.upload_timeout_secs = Some(1);
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let db = crate::db::memory().await;
+        let full = archive(&mock, "full", "image web:1");
+
+        let result = send_image(
+            &Destination {
+                db: &db,
+                client: &client,
+                server_name: "mock",
+                compression: Compression::None,
+            },
+            "web:2",
+            &full.0,
+            None,
+            &JobLog::none(),
+        )
+        .await;
+        assert!(
+            result.as_ref().is_err_and(|e| e.is::<ImageMissing>()),
+            "{:?}",
+            result
+        );
+        assert_eq!(mock.state().calls("files/del"), 0);
+        assert_eq!(mock.state().files.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn removes_the_archive_once_the_image_appears() {
+        let mock = MockPanel::start().await;
+        // Loaded in the background, as on 1Panel v2
+        mock.state().images = [vec![], vec![], vec![image("web:2")]].into();
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let db = crate::db::memory().await;
+        let full = archive(&mock, "full", "loading");
+
+        send_image(
+            &Destination {
+                db: &db,
+                client: &client,
+                server_name: "mock",
+                compression: Compression::None,
+            },
+            "web:2",
+            &full.0,
+            None,
+            &JobLog::none(),
+        )
+        .await
+        .unwrap();
+        let state = mock.state();
+        let routes: Vec<&str> = state
+            .requests
+            .iter()
+            .map(|(route, _)| route.as_str())
+            .collect();
+        assert_eq!(
+            routes[routes.len() - 4..],
+            [
+                "containers/image/all",
+                "containers/image/all",
+                "containers/image/all",
+                "files/del"
+            ]
+        );
+        assert!(state.files.is_empty());
+    }
+}
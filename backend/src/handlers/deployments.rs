@@ -0,0 +1,74 @@
+use crate::auth::Principal;
+use crate::deployments::{self, DeploymentFilter};
+use crate::models::DeploymentPage;
+use crate::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ListDeploymentsQuery {
+    /// Repository path
+    pub repo: Option<String>,
+    /// Server ID
+    pub server: Option<i64>,
+    /// Environment name (set on promotions)
+    pub environment: Option<String>,
+    /// Filter by result (pending, deployed, failed, rolled_back)
+    pub status: Option<String>,
+    /// Only deployments started at or after this time (ms since epoch)
+    pub from: Option<i64>,
+    /// Only deployments started before this time (ms since epoch)
+    pub to: Option<i64>,
+    /// Page number, starting at 1 (default 1)
+    pub page: Option<i64>,
+    /// Page size (default 20, max 200)
+    pub page_size: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/deployments",
+    tag = "Deploy",
+    params(ListDeploymentsQuery),
+    responses(
+        (status = 200, description = "Deployment history, newest first", body = DeploymentPage)
+    )
+)]
+pub async fn list_deployments(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<ListDeploymentsQuery>,
+) -> impl IntoResponse {
+    let page = query.page.unwrap_or(1).max(1);
+    let page_size = query.page_size.unwrap_or(20).clamp(1, 200);
+
+    let filter = DeploymentFilter {
+        repository_path: query.repo.as_deref(),
+        server_id: query.server,
+        environment: query.environment.as_deref(),
+        status: query.status.as_deref(),
+        from: query.from,
+        to: query.to,
+        servers: principal.servers.as_deref(),
+        repositories: principal.repositories.as_deref(),
+    };
+
+    match deployments::list(&state.db, &filter, page, page_size).await {
+        Ok((items, total)) => (
+            StatusCode::OK,
+            Json(DeploymentPage {
+                items,
+                total,
+                page,
+                page_size,
+            }),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
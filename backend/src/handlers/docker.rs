@@ -1,5 +1,9 @@
+use crate::auth::Principal;
 use crate::docker::{self, ContainerSummary, DockerImage, DockerInfo, PullImageRequest};
-use axum::{response::IntoResponse, Json};
+use crate::jobs::{STATUS_SUCCEEDED, TRIGGER_API};
+use crate::pipeline;
+use crate::state::AppState;
+use axum::{extract::State, response::IntoResponse, Extension, Json};
 use reqwest::StatusCode;
 
 #[utoipa::path(
@@ -47,15 +51,56 @@ pub async fn list_tags(
     path = "/api/docker/build",
     request_body = docker::DockerBuildRequest,
     responses(
-        (status = 200, description = "Build Output", body = String),
+        (status = 200, description = "Build Output (the job ID is in the X-Job-Id header)", body = String),
         (status = 500, description = "Build Failed", body = String)
     )
 )]
-pub async fn build_image(Json(req): Json<docker::DockerBuildRequest>) -> impl IntoResponse {
-    match docker::build_image(req).await {
-        Ok(output) => (StatusCode::OK, output).into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+pub async fn build_image(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(req): Json<docker::DockerBuildRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = principal.require_path(&req.path) {
+        return e.into_response();
     }
+    // Runs as a job so the build shows up in /api/jobs and can be cancelled,
+    // but still answers synchronously with the build log.
+    let params = match serde_json::to_value(&req) {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let job_id = match pipeline::start(
+        &state.jobs,
+        pipeline::KIND_DOCKER_BUILD,
+        params,
+        TRIGGER_API,
+        None,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let status = match state.jobs.wait(&job_id).await {
+        Ok(status) => status.unwrap_or_default(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let output = match state.jobs.logs(&job_id).await {
+        Ok(lines) => lines
+            .into_iter()
+            .map(|l| l.line)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let code = if status == STATUS_SUCCEEDED {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    (code, [("x-job-id", job_id)], output).into_response()
 }
 
 #[utoipa::path(
@@ -0,0 +1,269 @@
+use crate::auth::{self, Principal};
+use crate::environments;
+use crate::jobs::TRIGGER_API;
+use crate::models::{Environment, PromoteReq, Promotion, SaveEnvironmentReq};
+use crate::pipeline;
+use crate::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ListEnvironmentsQuery {
+    /// Repository path or name
+    pub path: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/environments",
+    tag = "Deploy",
+    params(ListEnvironmentsQuery),
+    responses(
+        (status = 200, description = "Environments in promotion order", body = Vec<Environment>),
+        (status = 404, description = "Repository not configured")
+    )
+)]
+pub async fn list_environments(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<ListEnvironmentsQuery>,
+) -> impl IntoResponse {
+    let repo = match pipeline::resolve_repository(&state.db, &query.path).await {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    };
+    if let Err(e) = principal.require_repository(&repo.path) {
+        return e.into_response();
+    }
+
+    match environments::list(&state.db, repo.id).await {
+        Ok(mut envs) => {
+            envs.retain(|env| principal.can_access_server(env.server_id));
+            (StatusCode::OK, Json(envs)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/environments",
+    tag = "Deploy",
+    request_body = SaveEnvironmentReq,
+    responses(
+        (status = 200, description = "Environment created or updated", body = Environment),
+        (status = 400, description = "Unknown server"),
+        (status = 404, description = "Repository not configured")
+    )
+)]
+pub async fn save_environment(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<SaveEnvironmentReq>,
+) -> impl IntoResponse {
+    let repo = match pipeline::resolve_repository(&state.db, &payload.path).await {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    };
+    let allowed = principal
+        .require_repository(&repo.path)
+        .and_then(|()| principal.require_server(payload.server_id));
+    if let Err(e) = allowed {
+        return e.into_response();
+    }
+
+    let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM servers WHERE id = ?")
+        .bind(payload.server_id)
+        .fetch_one(&*state.db)
+        .await
+        .unwrap_or(0);
+    if exists == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Server {} not found", payload.server_id),
+        )
+            .into_response();
+    }
+
+    match environments::save(&state.db, repo.id, &payload).await {
+        Ok(env) => (StatusCode::OK, Json(env)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/environments/{id}",
+    tag = "Deploy",
+    params(
+        ("id" = i64, Path, description = "Environment ID")
+    ),
+    responses(
+        (status = 200, description = "Environment removed"),
+        (status = 404, description = "Environment not found")
+    )
+)]
+pub async fn delete_environment(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match environments::delete(&state.db, id).await {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Environment not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/environments/promote",
+    tag = "Deploy",
+    request_body = PromoteReq,
+    responses(
+        (status = 202, description = "Promotion started, or pending approval when the target environment requires it", body = Promotion),
+        (status = 400, description = "Unknown environment or no image to promote")
+    )
+)]
+pub async fn promote(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<PromoteReq>,
+) -> impl IntoResponse {
+    if let Err(e) = auth::authorize_promote(
+        &state.db,
+        &principal,
+        &payload.path,
+        &payload.from,
+        payload.to.as_deref(),
+    )
+    .await
+    {
+        return e.into_response();
+    }
+    match pipeline::promote(
+        &state.jobs,
+        &state.db,
+        &payload.path,
+        &payload.from,
+        payload.to.as_deref(),
+        TRIGGER_API,
+    )
+    .await
+    {
+        Ok(promotion) => (StatusCode::ACCEPTED, Json(promotion)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ListPromotionsQuery {
+    /// Filter by status (pending, approved, rejected)
+    pub status: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/promotions",
+    tag = "Deploy",
+    params(ListPromotionsQuery),
+    responses(
+        (status = 200, description = "Promotions, newest first", body = Vec<Promotion>)
+    )
+)]
+pub async fn list_promotions(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<ListPromotionsQuery>,
+) -> impl IntoResponse {
+    match environments::list_promotions(&state.db, query.status.as_deref()).await {
+        Ok(mut list) => {
+            list.retain(|promotion| principal.can_access_repository(&promotion.repository_path));
+            (StatusCode::OK, Json(list)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/promotions/{id}/approve",
+    tag = "Deploy",
+    params(
+        ("id" = i64, Path, description = "Promotion ID")
+    ),
+    responses(
+        (status = 202, description = "Promotion approved and its deploy job started", body = Promotion),
+        (status = 404, description = "Promotion not found"),
+        (status = 409, description = "Promotion is not pending")
+    )
+)]
+pub async fn approve_promotion(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match environments::get_promotion(&state.db, id).await {
+        Ok(Some(p)) if p.status == environments::PROMOTION_PENDING => {
+            if let Err(e) = principal.require_repository(&p.repository_path) {
+                return e.into_response();
+            }
+        }
+        Ok(Some(p)) => {
+            return (
+                StatusCode::CONFLICT,
+                format!("Promotion {} is {}", id, p.status),
+            )
+                .into_response()
+        }
+        Ok(None) => return (StatusCode::NOT_FOUND, "Promotion not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    match pipeline::approve_promotion(&state.jobs, &state.db, id, TRIGGER_API).await {
+        Ok(promotion) => (StatusCode::ACCEPTED, Json(promotion)).into_response(),
+        Err(e) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/promotions/{id}/reject",
+    tag = "Deploy",
+    params(
+        ("id" = i64, Path, description = "Promotion ID")
+    ),
+    responses(
+        (status = 200, description = "Promotion rejected", body = Promotion),
+        (status = 409, description = "Promotion not found or not pending")
+    )
+)]
+pub async fn reject_promotion(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match environments::get_promotion(&state.db, id).await {
+        Ok(Some(p)) => {
+            if let Err(e) = principal.require_repository(&p.repository_path) {
+                return e.into_response();
+            }
+        }
+        Ok(None) => return (StatusCode::NOT_FOUND, "Promotion not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    match environments::decide_promotion(&state.db, id, environments::PROMOTION_REJECTED).await {
+        Ok(Some(promotion)) => (StatusCode::OK, Json(promotion)).into_response(),
+        Ok(None) => (
+            StatusCode::CONFLICT,
+            format!("Promotion {} is not pending", id),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -1,18 +1,67 @@
-use crate::fs::{self, FileEntry, ListRequest, ScanRequest};
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use crate::auth::Principal;
+use crate::fs::{self, FileEntry, FsError, ListRequest, Sandbox, ScanRequest};
+use crate::models::Repository;
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+
+/// Sandbox of the registered repositories and the configured browse roots, narrowed
+/// to the repositories of a scoped token. The sandbox checks canonical paths, so the
+/// scope holds against `..` segments and symlinks too.
+async fn sandbox(state: &AppState, principal: &Principal) -> Result<Sandbox, Response> {
+    let repos = sqlx::query_as::<_, Repository>("SELECT * FROM repositories")
+        .fetch_all(&*state.db)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    let browse_roots = match principal.repositories {
+        Some(_) => &[][..],
+        None => &state.browse_roots[..],
+    };
+    Ok(Sandbox::new(
+        repos
+            .iter()
+            .filter(|repo| principal.can_access_repository(&repo.path))
+            .map(|repo| &repo.path),
+        browse_roots,
+    ))
+}
+
+fn fs_error(e: FsError) -> Response {
+    let status = match e {
+        FsError::NotFound(_) => StatusCode::NOT_FOUND,
+        FsError::Denied(_) => StatusCode::FORBIDDEN,
+        FsError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        FsError::Binary => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        FsError::Io(_) => StatusCode::BAD_REQUEST,
+    };
+    (status, e.to_string()).into_response()
+}
 
 #[utoipa::path(
     post,
     path = "/api/fs/list",
     request_body = ListRequest,
     responses(
-        (status = 200, description = "List directory contents", body = Vec<FileEntry>)
+        (status = 200, description = "List directory contents, or the browse roots and repositories without a path", body = Vec<FileEntry>),
+        (status = 403, description = "Outside the registered repositories and browse roots, or the token's repositories")
     )
 )]
-pub async fn list_directory(Json(payload): Json<ListRequest>) -> impl IntoResponse {
-    match fs::list_directory(payload.path, payload.root) {
+pub async fn list_directory(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<ListRequest>,
+) -> impl IntoResponse {
+    let sandbox = match sandbox(&state, &principal).await {
+        Ok(sandbox) => sandbox,
+        Err(res) => return res,
+    };
+    match fs::list_directory(&sandbox, payload.path, payload.root) {
         Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
-        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => fs_error(e),
     }
 }
 
@@ -21,25 +70,49 @@ pub async fn list_directory(Json(payload): Json<ListRequest>) -> impl IntoRespon
     path = "/api/fs/scan",
     request_body = ScanRequest,
     responses(
-        (status = 200, description = "Scan for git repositories", body = Vec<String>)
+        (status = 200, description = "Scan for git repositories", body = Vec<String>),
+        (status = 403, description = "Outside the registered repositories and browse roots, or the token's repositories")
     )
 )]
-pub async fn scan_directory(Json(payload): Json<ScanRequest>) -> impl IntoResponse {
-    let repos = fs::scan_for_git_repos(&payload.path);
-    (StatusCode::OK, Json(repos)).into_response()
+pub async fn scan_directory(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<ScanRequest>,
+) -> impl IntoResponse {
+    let sandbox = match sandbox(&state, &principal).await {
+        Ok(sandbox) => sandbox,
+        Err(res) => return res,
+    };
+    match sandbox.browse(&payload.path) {
+        Ok(root) => (StatusCode::OK, Json(fs::scan_for_git_repos(&root))).into_response(),
+        Err(e) => fs_error(e),
+    }
 }
+
 #[utoipa::path(
     post,
     path = "/api/fs/read",
     request_body = crate::fs::ReadFileRequest,
     responses(
         (status = 200, description = "Read file content", body = String),
-        (status = 400, description = "File not found or error")
+        (status = 400, description = "Not a readable file"),
+        (status = 403, description = "Outside the registered repositories, or the token's"),
+        (status = 404, description = "File not found"),
+        (status = 413, description = "File larger than 1 MiB"),
+        (status = 415, description = "Binary file")
     )
 )]
-pub async fn read_file(Json(payload): Json<crate::fs::ReadFileRequest>) -> impl IntoResponse {
-    match fs::read_file(&payload.path) {
+pub async fn read_file(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<crate::fs::ReadFileRequest>,
+) -> impl IntoResponse {
+    let sandbox = match sandbox(&state, &principal).await {
+        Ok(sandbox) => sandbox,
+        Err(res) => return res,
+    };
+    match fs::read_file(&sandbox, &payload.path) {
         Ok(content) => (StatusCode::OK, content).into_response(),
-        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => fs_error(e),
     }
 }
@@ -1,5 +1,6 @@
+use crate::auth::Principal;
 use crate::git::{self, CommitInfo, FileStatus};
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
 use serde::Deserialize;
 use utoipa::ToSchema;
 
@@ -22,7 +23,13 @@ pub struct GitStatusRequest {
         (status = 200, description = "Get git log", body = Vec<CommitInfo>)
     )
 )]
-pub async fn get_git_log(Json(payload): Json<GitLogRequest>) -> impl IntoResponse {
+pub async fn get_git_log(
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<GitLogRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = principal.require_path(&payload.path) {
+        return e.into_response();
+    }
     let limit = payload.limit.unwrap_or(10);
     match git::get_commit_log(&payload.path, limit) {
         Ok(commits) => (StatusCode::OK, Json(commits)).into_response(),
@@ -38,7 +45,13 @@ pub async fn get_git_log(Json(payload): Json<GitLogRequest>) -> impl IntoRespons
         (status = 200, description = "Get git status", body = Vec<FileStatus>)
     )
 )]
-pub async fn get_git_status(Json(payload): Json<GitStatusRequest>) -> impl IntoResponse {
+pub async fn get_git_status(
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<GitStatusRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = principal.require_path(&payload.path) {
+        return e.into_response();
+    }
     match git::get_detailed_status(&payload.path) {
         Ok(statuses) => (StatusCode::OK, Json(statuses)).into_response(),
         Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
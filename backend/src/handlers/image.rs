@@ -1,5 +1,5 @@
-use crate::models::Server;
-use crate::onepanel::OnePanelClient;
+use crate::handlers::server::client_for;
+use crate::models::Image;
 use crate::state::AppState;
 use axum::{
     extract::{Path, State},
@@ -16,23 +16,19 @@ use axum::{
         ("id" = i64, Path, description = "Server ID")
     ),
     responses(
-        (status = 200, description = "List images", body = Vec<serde_json::Value>)
+        (status = 200, description = "List images", body = Vec<Image>)
     )
 )]
 pub async fn list_images(State(state): State<AppState>, Path(id): Path<i64>) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::list_images(&s.host, s.port, &s.api_key).await {
-            Ok(list) => return (StatusCode::OK, Json(list)).into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    match client.list_images().await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
 
 #[derive(serde::Deserialize, utoipa::ToSchema)]
@@ -58,19 +54,13 @@ pub async fn remove_image(
     Path(id): Path<i64>,
     Json(payload): Json<RemoveImageReq>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(s) = server {
-        match OnePanelClient::remove_image(&s.host, s.port, &s.api_key, &payload.id, payload.force)
-            .await
-        {
-            Ok(_) => return (StatusCode::OK, "Image removed").into_response(),
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-        }
+    match client.remove_image(&payload.id, payload.force).await {
+        Ok(_) => (StatusCode::OK, "Image removed").into_response(),
+        Err(e) => e.into_response(),
     }
-    (StatusCode::NOT_FOUND, "Server not found").into_response()
 }
@@ -1,3 +1,5 @@
+use crate::auth::Principal;
+use crate::compose::ComposeFile;
 use crate::models::Server;
 use crate::onepanel::OnePanelClient;
 use crate::state::AppState;
@@ -5,9 +7,8 @@ use axum::{
     extract::{Query, State},
     http::StatusCode,
     response::IntoResponse,
-    Json,
+    Extension, Json,
 };
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, utoipa::IntoParams)]
@@ -21,6 +22,9 @@ pub struct ImageDeployment {
     pub server_id: i64,
     pub server_name: String,
     pub compose_name: String,
+    pub compose_path: String,
+    /// Compose service that uses the image
+    pub service: String,
     pub image_tag: String,
 }
 
@@ -35,6 +39,7 @@ pub struct ImageDeployment {
 )]
 pub async fn get_image_deployments(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Query(query): Query<ImageDeploymentsQuery>,
 ) -> impl IntoResponse {
     let image_base = query.image_base;
@@ -52,72 +57,45 @@ pub async fn get_image_deployments(
 
     let mut deployments: Vec<ImageDeployment> = Vec::new();
 
-    // Build regex to match image references
-    // Matches patterns like: image: my-app:1.0.0 or image: "my-app:latest"
-    let pattern = format!(
-        r#"image:\s*["']?{}(:[^\s"']+)?["']?"#,
-        regex::escape(&image_base)
-    );
-    let re = match Regex::new(&pattern) {
-        Ok(r) => r,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Invalid regex: {}", e),
-            )
-                .into_response();
-        }
-    };
-
-    for server in servers {
+    for server in servers
+        .into_iter()
+        .filter(|server| principal.can_access_server(server.id))
+    {
         // Get composes for this server
-        let composes =
-            match OnePanelClient::list_composes(&server.host, server.port, &server.api_key).await {
-                Ok(c) => c,
-                Err(_) => continue, // Skip server if we can't get composes
-            };
-
-        for compose in composes {
-            let compose_name = compose
-                .get("name")
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string();
-            let compose_path = compose.get("path").and_then(|v| v.as_str()).unwrap_or("");
+        let client = match OnePanelClient::new(&server) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let composes = match client.list_composes().await {
+            Ok(c) => c,
+            Err(_) => continue, // Skip server if we can't get composes
+        };
 
-            if compose_path.is_empty() {
+        for stack in composes {
+            if stack.path.is_empty() {
                 continue;
             }
 
             // Read compose file content
-            let content = match OnePanelClient::read_file(
-                &server.host,
-                server.port,
-                &server.api_key,
-                compose_path,
-            )
-            .await
-            {
+            let content = match client.read_file(&stack.path).await {
                 Ok(c) => c,
                 Err(_) => continue, // Skip if we can't read the file
             };
 
-            // Find all image references matching our base name
-            for cap in re.captures_iter(&content) {
-                let full_match = cap.get(0).map(|m| m.as_str()).unwrap_or("");
-                // Extract the image tag from the match
-                let image_tag = full_match
-                    .trim_start_matches("image:")
-                    .trim()
-                    .trim_matches('"')
-                    .trim_matches('\'')
-                    .to_string();
+            let compose = match ComposeFile::parse(&content) {
+                Ok(c) => c,
+                Err(_) => continue, // Skip files that are not valid YAML
+            };
 
+            // One entry per service running our image
+            for service in compose.services_using(&image_base) {
                 deployments.push(ImageDeployment {
                     server_id: server.id,
                     server_name: server.name.clone(),
-                    compose_name: compose_name.clone(),
-                    image_tag,
+                    compose_name: stack.name.clone(),
+                    compose_path: stack.path.clone(),
+                    service: service.service.clone(),
+                    image_tag: service.image.to_string(),
                 });
             }
         }
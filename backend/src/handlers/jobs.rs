@@ -0,0 +1,255 @@
+use crate::auth::Principal;
+use crate::jobs::{JobEvent, TRIGGER_API};
+use crate::models::{Job, JobDetail, JobLogLine, JobStarted};
+use crate::pipeline;
+use crate::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+
+/// The job `id` if the caller may see it, and with `act` also cancel or retry it;
+/// otherwise the response to answer with.
+async fn permitted_job(
+    state: &AppState,
+    principal: &Principal,
+    id: &str,
+    act: bool,
+) -> Result<JobDetail, Response> {
+    let detail = match state.jobs.get(id).await {
+        Ok(Some(detail)) => detail,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Job not found").into_response()),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()),
+    };
+    principal
+        .require_job(&detail.job, act)
+        .map_err(IntoResponse::into_response)?;
+    Ok(detail)
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ListJobsQuery {
+    /// Filter by status (running, succeeded, failed, cancelled)
+    pub status: Option<String>,
+    /// Maximum number of jobs to return (default 50)
+    pub limit: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs",
+    tag = "Jobs",
+    params(ListJobsQuery),
+    responses(
+        (status = 200, description = "List build/deploy jobs, newest first", body = Vec<Job>)
+    )
+)]
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<ListJobsQuery>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(50);
+    match state.jobs.list(query.status.as_deref(), limit).await {
+        Ok(mut jobs) => {
+            jobs.retain(|job| principal.require_job(job, false).is_ok());
+            (StatusCode::OK, Json(jobs)).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}",
+    tag = "Jobs",
+    params(
+        ("id" = String, Path, description = "Job ID")
+    ),
+    responses(
+        (status = 200, description = "Job with its steps", body = JobDetail),
+        (status = 404, description = "Job not found")
+    )
+)]
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match permitted_job(&state, &principal, &id, false).await {
+        Ok(job) => (StatusCode::OK, Json(job)).into_response(),
+        Err(res) => res,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}/logs",
+    tag = "Jobs",
+    params(
+        ("id" = String, Path, description = "Job ID")
+    ),
+    responses(
+        (status = 200, description = "Persisted log lines of the job", body = Vec<JobLogLine>)
+    )
+)]
+pub async fn get_job_logs(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(res) = permitted_job(&state, &principal, &id, false).await {
+        return res;
+    }
+    match state.jobs.logs(&id).await {
+        Ok(lines) => (StatusCode::OK, Json(lines)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Kills the running job: the docker build or save process is terminated and
+/// an in-flight upload is dropped. Steps that were running are marked cancelled.
+#[utoipa::path(
+    post,
+    path = "/api/jobs/{id}/cancel",
+    tag = "Jobs",
+    params(
+        ("id" = String, Path, description = "Job ID")
+    ),
+    responses(
+        (status = 200, description = "Job cancelled"),
+        (status = 409, description = "Job is not running")
+    )
+)]
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(res) = permitted_job(&state, &principal, &id, true).await {
+        return res;
+    }
+    if !state.jobs.cancel(&id).await {
+        return (StatusCode::CONFLICT, "Job is not running").into_response();
+    }
+
+    match state.jobs.wait(&id).await {
+        Ok(status) => (StatusCode::OK, status.unwrap_or_default()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Starts a new job from the parameters of a failed or cancelled one. Steps that
+/// already succeeded are reused instead of run again.
+#[utoipa::path(
+    post,
+    path = "/api/jobs/{id}/retry",
+    tag = "Jobs",
+    params(
+        ("id" = String, Path, description = "Job ID")
+    ),
+    responses(
+        (status = 202, description = "Retry started", body = JobStarted),
+        (status = 404, description = "Job not found"),
+        (status = 409, description = "Job cannot be retried")
+    )
+)]
+pub async fn retry_job(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let job = match permitted_job(&state, &principal, &id, true).await {
+        Ok(detail) => detail.job,
+        Err(res) => return res,
+    };
+
+    match pipeline::retry(&state.jobs, &job, TRIGGER_API).await {
+        Ok(job_id) => (StatusCode::ACCEPTED, Json(JobStarted { job_id })).into_response(),
+        Err(e) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+    }
+}
+
+/// Replays the persisted log of a job and, while it is still running, follows new lines.
+/// Emits `log` events (data = line, id = log line id) and a final `end` event whose data is the job status.
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}/logs/stream",
+    tag = "Jobs",
+    params(
+        ("id" = String, Path, description = "Job ID")
+    ),
+    responses(
+        (status = 200, description = "Server-sent event stream of job log lines"),
+        (status = 404, description = "Job not found")
+    )
+)]
+pub async fn stream_job_logs(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    // Subscribe before reading history so no line falls between the two
+    let live = state.jobs.subscribe(&id).await;
+
+    let job = match permitted_job(&state, &principal, &id, false).await {
+        Ok(detail) => detail.job,
+        Err(res) => return res,
+    };
+
+    let history = match state.jobs.logs(&id).await {
+        Ok(lines) => lines,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let last_seen = history.last().map(|l| l.id).unwrap_or(0);
+
+    let replay = stream::iter(
+        history
+            .into_iter()
+            .map(|l| Ok::<Event, Infallible>(log_event(&l))),
+    );
+
+    let follow = match live {
+        Some(rx) => stream::unfold(Some(rx), move |rx| async move {
+            let mut rx = rx?;
+            loop {
+                match rx.recv().await {
+                    Ok(JobEvent::Log(l)) if l.id > last_seen => {
+                        return Some((Ok(log_event(&l)), Some(rx)))
+                    }
+                    Ok(JobEvent::Log(_)) | Err(RecvError::Lagged(_)) => continue,
+                    Ok(JobEvent::Finished { status }) => {
+                        return Some((Ok(end_event(&status)), None))
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed(),
+        None => stream::iter(vec![Ok(end_event(&job.status))]).boxed(),
+    };
+
+    Sse::new(replay.chain(follow))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn log_event(line: &JobLogLine) -> Event {
+    Event::default()
+        .event("log")
+        .id(line.id.to_string())
+        .data(&line.line)
+}
+
+fn end_event(status: &str) -> Event {
+    Event::default().event("end").data(status)
+}
@@ -1,3 +1,4 @@
+use crate::auth::Principal;
 use crate::mcp_server::AppHandler;
 use crate::state::AppState;
 use axum::{
@@ -6,7 +7,7 @@ use axum::{
         sse::{Event, Sse},
         IntoResponse,
     },
-    Json,
+    Extension, Json,
 };
 use futures_util::StreamExt;
 use rust_mcp_sdk::schema::{
@@ -76,6 +77,7 @@ pub struct McpQueryParams {
 
 pub async fn post_handler(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Query(params): Query<McpQueryParams>,
     Json(request): Json<JsonRpcRequest>,
 ) -> impl IntoResponse {
@@ -86,6 +88,8 @@ pub async fn post_handler(
 
     let handler = AppHandler {
         state: state.clone(),
+        principal,
+        session_id: params.session_id.clone(),
     };
 
     match request.method.as_str() {
@@ -1,13 +1,20 @@
+pub mod audit;
+pub mod auth;
 pub mod compose;
 pub mod container;
 pub mod deploy;
+pub mod deployments;
 pub mod docker;
+pub mod environments;
 pub mod fs;
 pub mod git;
 pub mod image;
 pub mod image_deployments;
+pub mod jobs;
 pub mod mcp;
 pub mod notifications;
+pub mod registries;
 pub mod repository;
 pub mod server;
+pub mod uploads;
 pub mod version;
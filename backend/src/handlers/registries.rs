@@ -0,0 +1,137 @@
+use crate::models::{Registry, SaveRegistryReq};
+use crate::registries;
+use crate::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+fn validate(payload: &SaveRegistryReq) -> Result<(), &'static str> {
+    if payload.name.trim().is_empty() || registries::normalize_url(&payload.url).is_empty() {
+        return Err("Name and URL are required");
+    }
+    Ok(())
+}
+
+fn save_error(e: anyhow::Error) -> Response {
+    match e
+        .downcast_ref::<sqlx::Error>()
+        .and_then(|e| e.as_database_error())
+    {
+        Some(db) if db.is_unique_violation() => (
+            StatusCode::CONFLICT,
+            "A registry with this name already exists",
+        )
+            .into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/registries",
+    tag = "Deploy",
+    responses(
+        (status = 200, description = "Configured registries, without passwords", body = Vec<Registry>)
+    )
+)]
+pub async fn list_registries(State(state): State<AppState>) -> impl IntoResponse {
+    match registries::list(&state.db).await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/registries",
+    tag = "Deploy",
+    request_body = SaveRegistryReq,
+    responses(
+        (status = 201, description = "Registry added", body = Registry),
+        (status = 400, description = "Name or URL missing"),
+        (status = 409, description = "Name already in use")
+    )
+)]
+pub async fn create_registry(
+    State(state): State<AppState>,
+    Json(payload): Json<SaveRegistryReq>,
+) -> impl IntoResponse {
+    if let Err(msg) = validate(&payload) {
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    match registries::create(&state.db, &payload).await {
+        Ok(registry) => (StatusCode::CREATED, Json(registry)).into_response(),
+        Err(e) => save_error(e),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/registries/{id}",
+    tag = "Deploy",
+    request_body = SaveRegistryReq,
+    params(
+        ("id" = i64, Path, description = "Registry ID")
+    ),
+    responses(
+        (status = 200, description = "Registry updated", body = Registry),
+        (status = 400, description = "Name or URL missing"),
+        (status = 404, description = "Registry not found"),
+        (status = 409, description = "Name already in use")
+    )
+)]
+pub async fn update_registry(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(payload): Json<SaveRegistryReq>,
+) -> impl IntoResponse {
+    if let Err(msg) = validate(&payload) {
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    match registries::update(&state.db, id, &payload).await {
+        Ok(Some(registry)) => (StatusCode::OK, Json(registry)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Registry not found").into_response(),
+        Err(e) => save_error(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/registries/{id}",
+    tag = "Deploy",
+    params(
+        ("id" = i64, Path, description = "Registry ID")
+    ),
+    responses(
+        (status = 200, description = "Registry removed"),
+        (status = 404, description = "Registry not found"),
+        (status = 409, description = "Registry still used by a repository")
+    )
+)]
+pub async fn delete_registry(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match registries::usage(&state.db, id).await {
+        Ok(0) => {}
+        Ok(n) => {
+            return (
+                StatusCode::CONFLICT,
+                format!("Registry is used by {} repositories", n),
+            )
+                .into_response()
+        }
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    match registries::delete(&state.db, id).await {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Registry not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -1,7 +1,8 @@
+use crate::auth::Principal;
 use crate::git::get_repo_status;
 use crate::models::{CreateDirectoryRequest, DirectoryResponse, Repository};
 use crate::state::AppState;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
 
 #[utoipa::path(
     get,
@@ -26,6 +27,9 @@ pub async fn list_repositories_inner(
                 docker_image_name: repo.docker_image_name.clone(),
                 default_server_id: repo.default_server_id,
                 default_compose_path: repo.default_compose_path.clone(),
+                health_timeout_secs: repo.health_timeout_secs,
+                health_check_url: repo.health_check_url.clone(),
+                registry_id: repo.registry_id,
                 git_status: Some(status),
                 error: None,
             }),
@@ -34,6 +38,9 @@ pub async fn list_repositories_inner(
                 docker_image_name: repo.docker_image_name.clone(),
                 default_server_id: repo.default_server_id,
                 default_compose_path: repo.default_compose_path.clone(),
+                health_timeout_secs: repo.health_timeout_secs,
+                health_check_url: repo.health_check_url.clone(),
+                registry_id: repo.registry_id,
                 git_status: None,
                 error: Some(e),
             }),
@@ -50,9 +57,15 @@ pub async fn list_repositories_inner(
         (status = 200, description = "List all repositories", body = Vec<DirectoryResponse>)
     )
 )]
-pub async fn list_repositories(State(state): State<AppState>) -> impl IntoResponse {
+pub async fn list_repositories(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> impl IntoResponse {
     match list_repositories_inner(&state.db).await {
-        Ok(data) => Json(data).into_response(),
+        Ok(mut data) => {
+            data.retain(|repo| principal.can_access_repository(&repo.path));
+            Json(data).into_response()
+        }
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
@@ -68,8 +81,12 @@ pub async fn list_repositories(State(state): State<AppState>) -> impl IntoRespon
 )]
 pub async fn add_repository(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<CreateDirectoryRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = principal.require_repository(&payload.path) {
+        return e.into_response();
+    }
     // Check if it's already there? UNIQUE constraint handles it.
     let res = sqlx::query("INSERT INTO repositories (path) VALUES (?)")
         .bind(payload.path)
@@ -93,8 +110,12 @@ pub async fn add_repository(
 )]
 pub async fn remove_repository(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<CreateDirectoryRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = principal.require_repository(&payload.path) {
+        return e.into_response();
+    }
     let res = sqlx::query("DELETE FROM repositories WHERE path = ?")
         .bind(payload.path)
         .execute(&*state.db)
@@ -114,25 +135,59 @@ use crate::models::{GetDockerConfigReq, UpdateDockerConfigReq};
     request_body = UpdateDockerConfigReq,
     responses(
         (status = 200, description = "Config updated"),
+        (status = 400, description = "Health timeout too short"),
         (status = 500, description = "Database error")
     )
 )]
 pub async fn update_docker_config(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<UpdateDockerConfigReq>,
 ) -> impl IntoResponse {
+    let allowed = principal.require_repository(&payload.path).and_then(|()| {
+        match payload.default_server_id {
+            Some(id) => principal.require_server(id),
+            None => Ok(()),
+        }
+    });
+    if let Err(e) = allowed {
+        return e.into_response();
+    }
+    if let Some(secs) = payload.health_timeout_secs {
+        // A shorter timeout would fail every deploy before its stack could be passed
+        let min = crate::health::min_timeout_secs();
+        if secs < 0 || (secs > 0 && secs < min) {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Health timeout must be 0 to skip verification, or at least {} seconds",
+                    min
+                ),
+            )
+                .into_response();
+        }
+    }
     // Upsert logic
     let res = sqlx::query(
-        "INSERT INTO repositories (path, docker_image_name, default_server_id, default_compose_path) VALUES (?, ?, ?, ?) 
+        "INSERT INTO repositories (path, docker_image_name, default_server_id, default_compose_path, health_timeout_secs, health_check_url, registry_id) VALUES (?, ?, ?, ?, ?, NULLIF(?, ''), NULLIF(?, 0)) 
          ON CONFLICT(path) DO UPDATE SET 
             docker_image_name = excluded.docker_image_name,
             default_server_id = excluded.default_server_id,
-            default_compose_path = excluded.default_compose_path",
+            default_compose_path = excluded.default_compose_path,
+            health_timeout_secs = COALESCE(?, repositories.health_timeout_secs),
+            health_check_url = CASE WHEN ? IS NULL THEN repositories.health_check_url ELSE excluded.health_check_url END,
+            registry_id = CASE WHEN ? IS NULL THEN repositories.registry_id ELSE excluded.registry_id END",
     )
     .bind(&payload.path)
     .bind(&payload.docker_image_name)
     .bind(payload.default_server_id)
     .bind(&payload.default_compose_path)
+    .bind(payload.health_timeout_secs)
+    .bind(&payload.health_check_url)
+    .bind(payload.registry_id)
+    .bind(payload.health_timeout_secs)
+    .bind(&payload.health_check_url)
+    .bind(payload.registry_id)
     .execute(&*state.db)
     .await;
 
@@ -152,8 +207,12 @@ pub async fn update_docker_config(
 )]
 pub async fn get_docker_config(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<GetDockerConfigReq>,
 ) -> impl IntoResponse {
+    if let Err(e) = principal.require_repository(&payload.path) {
+        return e.into_response();
+    }
     let repo = sqlx::query_as::<_, Repository>("SELECT * FROM repositories WHERE path = ?")
         .bind(&payload.path)
         .fetch_optional(&*state.db)
@@ -166,6 +225,9 @@ pub async fn get_docker_config(
             docker_image_name: r.docker_image_name,
             default_server_id: r.default_server_id,
             default_compose_path: r.default_compose_path,
+            health_timeout_secs: r.health_timeout_secs,
+            health_check_url: r.health_check_url,
+            registry_id: r.registry_id,
             git_status: None,
             error: None,
         })
@@ -175,9 +237,135 @@ pub async fn get_docker_config(
             docker_image_name: None,
             default_server_id: None,
             default_compose_path: None,
+            health_timeout_secs: None,
+            health_check_url: None,
+            registry_id: None,
             git_status: None,
             error: None,
         })
         .into_response(),
     }
 }
+
+use crate::models::{DeployTargetsResponse, UpdateDeployTargetsReq};
+use crate::targets;
+
+#[utoipa::path(
+    post,
+    path = "/api/directories/targets/get",
+    request_body = GetDockerConfigReq,
+    responses(
+        (status = 200, description = "Deploy targets and rollout strategy", body = DeployTargetsResponse),
+        (status = 404, description = "Repository not configured")
+    )
+)]
+pub async fn get_deploy_targets(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<GetDockerConfigReq>,
+) -> impl IntoResponse {
+    if let Err(e) = principal.require_repository(&payload.path) {
+        return e.into_response();
+    }
+    let repo = match sqlx::query_as::<_, Repository>("SELECT * FROM repositories WHERE path = ?")
+        .bind(&payload.path)
+        .fetch_optional(&*state.db)
+        .await
+    {
+        Ok(Some(r)) => r,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Repository not configured").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    match targets::list(&state.db, &repo).await {
+        Ok(list) => {
+            let rollout = targets::Rollout::of(&repo);
+            Json(DeployTargetsResponse {
+                path: repo.path,
+                strategy: rollout.strategy,
+                concurrency: rollout.concurrency as i64,
+                pause_secs: rollout.pause_secs as i64,
+                targets: list,
+            })
+            .into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/directories/targets/update",
+    request_body = UpdateDeployTargetsReq,
+    responses(
+        (status = 200, description = "Deploy targets replaced"),
+        (status = 400, description = "Unknown server or invalid strategy"),
+        (status = 500, description = "Database error")
+    )
+)]
+pub async fn update_deploy_targets(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<UpdateDeployTargetsReq>,
+) -> impl IntoResponse {
+    let allowed = payload
+        .targets
+        .iter()
+        .try_for_each(|target| principal.require_server(target.server_id))
+        .and_then(|()| principal.require_repository(&payload.path));
+    if let Err(e) = allowed {
+        return e.into_response();
+    }
+    if let Some(strategy) = &payload.strategy {
+        if !targets::is_valid_strategy(strategy) {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown strategy {}; use sequential or parallel", strategy),
+            )
+                .into_response();
+        }
+    }
+
+    for target in &payload.targets {
+        let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM servers WHERE id = ?")
+            .bind(target.server_id)
+            .fetch_one(&*state.db)
+            .await
+            .unwrap_or(0);
+        if exists == 0 {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Server {} not found", target.server_id),
+            )
+                .into_response();
+        }
+        if target.compose_path.trim().is_empty() {
+            return (StatusCode::BAD_REQUEST, "Compose path is required").into_response();
+        }
+    }
+
+    let res = sqlx::query(
+        "INSERT INTO repositories (path, deploy_strategy, deploy_concurrency, deploy_pause_secs) VALUES (?, ?, ?, ?)
+         ON CONFLICT(path) DO UPDATE SET
+            deploy_strategy = COALESCE(excluded.deploy_strategy, repositories.deploy_strategy),
+            deploy_concurrency = COALESCE(excluded.deploy_concurrency, repositories.deploy_concurrency),
+            deploy_pause_secs = COALESCE(excluded.deploy_pause_secs, repositories.deploy_pause_secs)
+         RETURNING id",
+    )
+    .bind(&payload.path)
+    .bind(&payload.strategy)
+    .bind(payload.concurrency)
+    .bind(payload.pause_secs)
+    .fetch_one(&*state.db)
+    .await;
+
+    let repository_id: i64 = match res {
+        Ok(row) => sqlx::Row::get(&row, "id"),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    match targets::replace(&state.db, repository_id, &payload.targets).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -1,14 +1,124 @@
+use crate::auth::Principal;
+use crate::compress::Compression;
 use crate::models::{CreateServerRequest, DashboardResponse, Server, ServerResponse};
-use crate::onepanel::OnePanelClient;
+use crate::onepanel::{self, ApiVersion, OnePanelClient};
+use crate::secrets;
 use crate::state::AppState;
+use crate::tls;
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    response::IntoResponse,
-    Json,
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
 use regex::Regex;
 
+/// Connection-level headers, which apply to one hop only.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Request headers not passed on to 1Panel: our own credentials and the browser's
+/// origin, the client's 1Panel token headers, and what describes this hop.
+const UNFORWARDED_REQUEST_HEADERS: &[&str] = &[
+    "host",
+    "cookie",
+    "authorization",
+    "origin",
+    "referer",
+    "content-length",
+    "1panel-token",
+    "1panel-timestamp",
+];
+
+/// Response headers not passed back: the body is re-sent decoded in one piece, and
+/// 1Panel must not set cookies on our origin.
+const UNFORWARDED_RESPONSE_HEADERS: &[&str] = &["content-length", "content-encoding", "set-cookie"];
+
+fn forwarded(name: &axum::http::HeaderName, dropped: &[&str]) -> bool {
+    let name = name.as_str();
+    !HOP_BY_HOP_HEADERS.contains(&name) && !dropped.contains(&name)
+}
+
+/// Client for the server `id` of a request path, or the response to answer with
+/// when it is not configured.
+pub(crate) async fn client_for(state: &AppState, id: i64) -> Result<OnePanelClient, Response> {
+    match onepanel::find_server(&state.db, id).await {
+        Ok(Some(server)) => OnePanelClient::new(&server).map_err(IntoResponse::into_response),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Server not found").into_response()),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()),
+    }
+}
+
+fn validate(payload: &CreateServerRequest) -> Result<(), String> {
+    if let Some(version) = payload
+        .api_version
+        .as_deref()
+        .filter(|v| !v.trim().is_empty())
+    {
+        if ApiVersion::parse(version).is_none() {
+            return Err(format!(
+                "Invalid API version {}; expected v1 or v2",
+                version
+            ));
+        }
+    }
+    Compression::parse(payload.compression.as_deref(), payload.compression_level)?;
+    if let Some(dir) = payload.upload_dir.as_deref() {
+        onepanel::normalize_upload_dir(dir)?;
+    }
+    tls::validate(
+        payload.scheme.as_deref(),
+        payload.ca_cert.as_deref(),
+        payload.tls_fingerprint.as_deref(),
+    )
+}
+
+/// API version to store for the server: the requested one, or the one the server
+/// answers on. Left unset (v1) when the server cannot be reached yet.
+async fn api_version(payload: &CreateServerRequest) -> Option<String> {
+    if let Some(version) = payload.api_version.as_deref().and_then(ApiVersion::parse) {
+        return Some(version.as_str().to_string());
+    }
+
+    let server = Server {
+        id: 0,
+        name: payload.name.clone(),
+        host: payload.host.clone(),
+        port: payload.port,
+        api_key: payload.api_key.clone(),
+        request_timeout_secs: payload.request_timeout_secs,
+        upload_timeout_secs: payload.upload_timeout_secs,
+        scheme: payload.scheme.clone(),
+        ca_cert: payload.ca_cert.clone(),
+        tls_fingerprint: payload.tls_fingerprint.clone(),
+        accept_invalid_certs: payload.accept_invalid_certs,
+        api_version: None,
+        base_path: payload.base_path.clone(),
+        compression: None,
+        compression_level: None,
+        upload_dir: None,
+    };
+    match OnePanelClient::detect_version(&server).await {
+        Ok(version) => Some(version.as_str().to_string()),
+        Err(e) => {
+            tracing::warn!(
+                "Could not detect 1Panel API version of {}: {}",
+                server.host,
+                e
+            );
+            None
+        }
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/api/servers",
@@ -16,7 +126,10 @@ use regex::Regex;
         (status = 200, description = "List all servers", body = Vec<ServerResponse>)
     )
 )]
-pub async fn list_servers(State(state): State<AppState>) -> impl IntoResponse {
+pub async fn list_servers(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> impl IntoResponse {
     let servers = sqlx::query_as::<_, Server>("SELECT * FROM servers")
         .fetch_all(&*state.db)
         .await
@@ -24,12 +137,8 @@ pub async fn list_servers(State(state): State<AppState>) -> impl IntoResponse {
 
     let responses: Vec<ServerResponse> = servers
         .into_iter()
-        .map(|s| ServerResponse {
-            id: s.id,
-            name: s.name,
-            host: s.host,
-            port: s.port,
-        })
+        .filter(|s| principal.can_access_server(s.id))
+        .map(ServerResponse::from)
         .collect();
 
     Json(responses)
@@ -41,6 +150,7 @@ pub async fn list_servers(State(state): State<AppState>) -> impl IntoResponse {
     request_body = CreateServerRequest,
     responses(
         (status = 201, description = "Server added"),
+        (status = 400, description = "Invalid scheme, CA certificate, fingerprint, API version, compression or upload directory"),
         (status = 500, description = "Failed to add server")
     )
 )]
@@ -48,13 +158,40 @@ pub async fn add_server(
     State(state): State<AppState>,
     Json(payload): Json<CreateServerRequest>,
 ) -> impl IntoResponse {
-    let res = sqlx::query("INSERT INTO servers (name, host, port, api_key) VALUES (?, ?, ?, ?)")
-        .bind(payload.name)
-        .bind(payload.host)
-        .bind(payload.port)
-        .bind(payload.api_key)
-        .execute(&*state.db)
-        .await;
+    if let Err(e) = validate(&payload) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let api_version = api_version(&payload).await;
+    let api_key = match secrets::encrypt(&payload.api_key) {
+        Ok(key) => key,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let res = sqlx::query(
+        "INSERT INTO servers (name, host, port, api_key, request_timeout_secs, upload_timeout_secs, scheme, ca_cert, tls_fingerprint, accept_invalid_certs, api_version, base_path, compression, compression_level, upload_dir) VALUES (?, ?, ?, ?, ?, ?, NULLIF(?, ''), NULLIF(?, ''), NULLIF(?, ''), ?, ?, ?, NULLIF(?, ''), ?, ?)",
+    )
+    .bind(payload.name)
+    .bind(payload.host)
+    .bind(payload.port)
+    .bind(api_key)
+    .bind(payload.request_timeout_secs)
+    .bind(payload.upload_timeout_secs)
+    .bind(payload.scheme.map(|s| s.trim().to_lowercase()))
+    .bind(payload.ca_cert)
+    .bind(payload.tls_fingerprint)
+    .bind(payload.accept_invalid_certs)
+    .bind(api_version)
+    .bind(payload.base_path.as_deref().and_then(onepanel::normalize_base_path))
+    .bind(payload.compression.map(|c| c.trim().to_lowercase()))
+    .bind(payload.compression_level)
+    .bind(
+        payload
+            .upload_dir
+            .as_deref()
+            .and_then(|dir| onepanel::normalize_upload_dir(dir).ok().flatten()),
+    )
+    .execute(&*state.db)
+    .await;
 
     match res {
         Ok(_) => StatusCode::CREATED.into_response(),
@@ -71,6 +208,7 @@ pub async fn add_server(
     ),
     responses(
         (status = 200, description = "Server updated"),
+        (status = 400, description = "Invalid scheme, CA certificate, fingerprint, API version, compression or upload directory"),
         (status = 500, description = "Failed to update server")
     )
 )]
@@ -79,15 +217,41 @@ pub async fn update_server(
     Path(id): Path<i64>,
     Json(payload): Json<CreateServerRequest>,
 ) -> impl IntoResponse {
-    let res =
-        sqlx::query("UPDATE servers SET name = ?, host = ?, port = ?, api_key = ? WHERE id = ?")
-            .bind(payload.name)
-            .bind(payload.host)
-            .bind(payload.port)
-            .bind(payload.api_key)
-            .bind(id)
-            .execute(&*state.db)
-            .await;
+    if let Err(e) = validate(&payload) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let api_version = api_version(&payload).await;
+    let api_key = match secrets::encrypt(&payload.api_key) {
+        Ok(key) => key,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let res = sqlx::query(
+        "UPDATE servers SET name = ?, host = ?, port = ?, api_key = ?, request_timeout_secs = ?, upload_timeout_secs = ?, scheme = NULLIF(?, ''), ca_cert = NULLIF(?, ''), tls_fingerprint = NULLIF(?, ''), accept_invalid_certs = ?, api_version = ?, base_path = ?, compression = NULLIF(?, ''), compression_level = ?, upload_dir = ? WHERE id = ?",
+    )
+    .bind(payload.name)
+    .bind(payload.host)
+    .bind(payload.port)
+    .bind(api_key)
+    .bind(payload.request_timeout_secs)
+    .bind(payload.upload_timeout_secs)
+    .bind(payload.scheme.map(|s| s.trim().to_lowercase()))
+    .bind(payload.ca_cert)
+    .bind(payload.tls_fingerprint)
+    .bind(payload.accept_invalid_certs)
+    .bind(api_version)
+    .bind(payload.base_path.as_deref().and_then(onepanel::normalize_base_path))
+    .bind(payload.compression.map(|c| c.trim().to_lowercase()))
+    .bind(payload.compression_level)
+    .bind(
+        payload
+            .upload_dir
+            .as_deref()
+            .and_then(|dir| onepanel::normalize_upload_dir(dir).ok().flatten()),
+    )
+    .bind(id)
+    .execute(&*state.db)
+    .await;
 
     match res {
         Ok(_) => StatusCode::OK.into_response(),
@@ -133,20 +297,10 @@ pub async fn delete_server(
     )
 )]
 pub async fn get_server(State(state): State<AppState>, Path(id): Path<i64>) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let server = onepanel::find_server(&state.db, id).await.unwrap_or(None);
 
     if let Some(s) = server {
-        let response = ServerResponse {
-            id: s.id,
-            name: s.name,
-            host: s.host,
-            port: s.port,
-        };
-        Json(response).into_response()
+        Json(ServerResponse::from(s)).into_response()
     } else {
         StatusCode::NOT_FOUND.into_response()
     }
@@ -167,19 +321,17 @@ pub async fn get_server_status(
     State(state): State<AppState>,
     Path(id): Path<i64>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
 
-    if let Some(server) = server {
-        match OnePanelClient::get_os_info(&server.host, server.port, &server.api_key).await {
-            Ok(info) => (StatusCode::OK, Json(info)).into_response(),
-            Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    match client.get_os_info().await {
+        Ok(mut info) => {
+            info.clock_skew_secs = client.clock_skew();
+            (StatusCode::OK, Json(info)).into_response()
         }
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+        Err(e) => e.into_response(),
     }
 }
 
@@ -188,72 +340,46 @@ pub async fn proxy_request(
     Path((id, path)): Path<(i64, String)>,
     req: axum::extract::Request,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
-
-    if let Some(server) = server {
-        let client = reqwest::Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| reqwest::Client::new());
-
-        let timestamp = chrono::Utc::now().timestamp();
-        let api_key = server.api_key.trim();
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        let target_url = format!("http://{}:{}/{}", server.host, server.port, path);
-        println!("Proxying to: {}", target_url);
-
-        let method = req.method().clone();
-        let headers = req.headers().clone();
-
-        let mut builder = client
-            .request(method, &target_url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string());
-
-        // Forward headers (exclude host to avoid issues)
-        for (key, value) in headers.iter() {
-            if key.as_str().to_lowercase() != "host" {
-                builder = builder.header(key, value);
-            }
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
+
+    tracing::debug!("Proxying server {} request to: {}", id, path);
+
+    let method = req.method().clone();
+    let headers = req.headers().clone();
+
+    let mut builder = client.request(method, &path);
+
+    for (key, value) in headers.iter() {
+        if forwarded(key, UNFORWARDED_REQUEST_HEADERS) {
+            builder = builder.header(key, value);
         }
+    }
 
-        // Handle body if present? For GET/Swagger usually not needed but good for completeness.
-        // For now, simple GET/proxy for swagger docs.
-        // Handling body in a generic proxy is more complex with Axum body types.
-        // Assuming primarily GET for Swagger UI.
-
-        match builder.send().await {
-            Ok(res) => {
-                let status = res.status();
-                let headers = res.headers().clone();
-                let body = res.bytes().await.unwrap_or_default();
-
-                let mut response_builder = axum::response::Response::builder().status(status);
-                for (key, value) in headers.iter() {
-                    let key_str = key.as_str().to_lowercase();
-                    if key_str != "content-length"
-                        && key_str != "transfer-encoding"
-                        && key_str != "content-encoding"
-                        && key_str != "connection"
-                    {
-                        response_builder = response_builder.header(key, value);
-                    }
+    // Handle body if present? For GET/Swagger usually not needed but good for completeness.
+    // For now, simple GET/proxy for swagger docs.
+    // Handling body in a generic proxy is more complex with Axum body types.
+    // Assuming primarily GET for Swagger UI.
+
+    match builder.send().await {
+        Ok(res) => {
+            let status = res.status();
+            let headers = res.headers().clone();
+            let body = res.bytes().await.unwrap_or_default();
+
+            let mut response_builder = axum::response::Response::builder().status(status);
+            for (key, value) in headers.iter() {
+                if forwarded(key, UNFORWARDED_RESPONSE_HEADERS) {
+                    response_builder = response_builder.header(key, value);
                 }
-                response_builder
-                    .body(axum::body::Body::from(body))
-                    .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
             }
-            Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+            response_builder
+                .body(axum::body::Body::from(body))
+                .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
         }
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+        Err(e) => onepanel::OnePanelError::from(e).into_response(),
     }
 }
 
@@ -261,78 +387,90 @@ pub async fn serve_scalar_docs(
     State(state): State<AppState>,
     Path(id): Path<i64>,
 ) -> impl IntoResponse {
-    let server = sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&*state.db)
-        .await
-        .unwrap_or(None);
-
-    if let Some(server) = server {
-        let client = reqwest::Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| reqwest::Client::new());
-
-        let timestamp = chrono::Utc::now().timestamp();
-        let api_key = server.api_key.trim();
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        let target_url = format!(
-            "http://{}:{}/1panel/swagger/doc.json",
-            server.host, server.port
-        );
-
-        let response = client
-            .get(&target_url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .send()
-            .await;
-
-        let spec_content = match response {
-            Ok(res) => res.text().await.unwrap_or_default(),
-            Err(_) => "{}".to_string(),
-        };
-        let html_re = Regex::new(r"<!DOCTYPE html>[\s\S]*?</html>").unwrap();
-        let cleaned_spec = html_re.replace(&spec_content, "").into_owned();
-        let spec_json = serde_json::to_string(&cleaned_spec).unwrap();
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
+
+    let response = client
+        .request(reqwest::Method::GET, "1panel/swagger/doc.json")
+        .send()
+        .await;
+
+    let spec_content = match response {
+        Ok(res) => res.text().await.unwrap_or_default(),
+        Err(_) => "{}".to_string(),
+    };
+    let html_re = Regex::new(r"<!DOCTYPE html>[\s\S]*?</html>").unwrap();
+    let cleaned_spec = html_re.replace(&spec_content, "").into_owned();
+    let spec_json = serde_json::to_string(&cleaned_spec).unwrap();
 
-        let html = format!(
-            r#"
+    let html = format!(
+        r#"
 <!doctype html>
 <html>
   <head>
-    <title>API Reference</title>
-    <meta charset="utf-8" />
-    <meta
-      name="viewport"
-      content="width=device-width, initial-scale=1" />
-    <style>
-      body {{
-        margin: 0;
-      }}
-    </style>
+<title>API Reference</title>
+<meta charset="utf-8" />
+<meta
+  name="viewport"
+  content="width=device-width, initial-scale=1" />
+<style>
+  body {{
+    margin: 0;
+  }}
+</style>
   </head>
   <body>
-    <script id="api-reference"></script>
-    <script>
-      var spec = {};
-      var configuration = {{
-        theme: 'default',
-        spec: {{ content: spec }},
-      }}
-      document.getElementById('api-reference').dataset.configuration = JSON.stringify(configuration)
-    </script>
-    <script src="https://cdn.jsdelivr.net/npm/@scalar/api-reference"></script>
+<script id="api-reference"></script>
+<script>
+  var spec = {};
+  var configuration = {{
+    theme: 'default',
+    spec: {{ content: spec }},
+  }}
+  document.getElementById('api-reference').dataset.configuration = JSON.stringify(configuration)
+</script>
+<script src="https://cdn.jsdelivr.net/npm/@scalar/api-reference"></script>
   </body>
 </html>
 "#,
-            spec_json
-        );
-        axum::response::Html(html).into_response()
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+        spec_json
+    );
+    axum::response::Html(html).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderName;
+
+    #[test]
+    fn keeps_credentials_out_of_proxied_requests() {
+        let request = |name: &'static str| {
+            forwarded(&HeaderName::from_static(name), UNFORWARDED_REQUEST_HEADERS)
+        };
+        for name in [
+            "cookie",
+            "authorization",
+            "origin",
+            "host",
+            "connection",
+            "upgrade",
+            "proxy-authorization",
+            "1panel-token",
+        ] {
+            assert!(!request(name), "{}", name);
+        }
+        for name in ["accept", "accept-language", "content-type", "if-none-match"] {
+            assert!(request(name), "{}", name);
+        }
+
+        let response = |name: &'static str| {
+            forwarded(&HeaderName::from_static(name), UNFORWARDED_RESPONSE_HEADERS)
+        };
+        assert!(!response("set-cookie"));
+        assert!(!response("transfer-encoding"));
+        assert!(response("content-type"));
     }
 }
@@ -0,0 +1,128 @@
+use crate::handlers::deploy::is_upload_archive;
+use crate::handlers::server::client_for;
+use crate::models::FileInfo;
+use crate::onepanel::{OnePanelClient, OnePanelError};
+use crate::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// Archives younger than this may still be loading.
+const DEFAULT_MIN_AGE_MINS: i64 = 60;
+
+#[derive(Deserialize, IntoParams)]
+pub struct StaleUploadsQuery {
+    /// Only archives last modified at least this many minutes ago; defaults to 60
+    pub older_than_mins: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CleanupUploadsResponse {
+    /// Archives deleted from the server
+    pub removed: Vec<FileInfo>,
+    /// Bytes freed by the deleted archives
+    pub freed_bytes: i64,
+    /// Archives that could not be deleted, with the reason
+    pub failed: Vec<String>,
+}
+
+/// Image archives in the server's upload directory left behind by earlier
+/// deploys, e.g. when a load failed or the job was interrupted.
+async fn stale_uploads(
+    client: &OnePanelClient,
+    older_than_mins: Option<i64>,
+) -> Result<Vec<FileInfo>, OnePanelError> {
+    let min_age = chrono::Duration::minutes(older_than_mins.unwrap_or(DEFAULT_MIN_AGE_MINS).max(0));
+    let now = Utc::now();
+
+    Ok(client
+        .list_dir(client.upload_dir())
+        .await?
+        .into_iter()
+        .filter(|file| !file.is_dir && is_upload_archive(&file.name))
+        .filter(|file| {
+            // Archives with an unreadable date are left alone
+            DateTime::parse_from_rfc3339(&file.mod_time)
+                .is_ok_and(|modified| now.signed_duration_since(modified) >= min_age)
+        })
+        .collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/servers/{id}/uploads",
+    tag = "Image",
+    params(
+        ("id" = i64, Path, description = "Server ID"),
+        StaleUploadsQuery
+    ),
+    responses(
+        (status = 200, description = "Stale image archives in the server's upload directory", body = Vec<FileInfo>)
+    )
+)]
+pub async fn list_stale_uploads(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(query): Query<StaleUploadsQuery>,
+) -> impl IntoResponse {
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
+
+    match stale_uploads(&client, query.older_than_mins).await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/servers/{id}/uploads",
+    tag = "Image",
+    params(
+        ("id" = i64, Path, description = "Server ID"),
+        StaleUploadsQuery
+    ),
+    responses(
+        (status = 200, description = "Stale image archives removed", body = CleanupUploadsResponse)
+    )
+)]
+pub async fn cleanup_uploads(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(query): Query<StaleUploadsQuery>,
+) -> impl IntoResponse {
+    let client = match client_for(&state, id).await {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
+
+    let stale = match stale_uploads(&client, query.older_than_mins).await {
+        Ok(list) => list,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut response = CleanupUploadsResponse {
+        removed: Vec::new(),
+        freed_bytes: 0,
+        failed: Vec::new(),
+    };
+    for file in stale {
+        match client.delete_file(&file.path).await {
+            Ok(()) => {
+                response.freed_bytes += file.size;
+                response.removed.push(file);
+            }
+            Err(e) => response.failed.push(format!("{}: {}", file.path, e)),
+        }
+    }
+
+    (StatusCode::OK, Json(response)).into_response()
+}
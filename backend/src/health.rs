@@ -0,0 +1,382 @@
+use crate::jobs::JobLog;
+use crate::models::Container;
+use crate::onepanel::OnePanelClient;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+#[cfg(not(test))]
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+#[cfg(test)]
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Consecutive polls in which every container must be ready without having
+/// restarted, so a container that crashes shortly after starting is not passed.
+const READY_POLLS: u32 = 3;
+/// How long every container has to stay ready, from the first of the `READY_POLLS`
+/// polls to the last
+const STABILITY_WINDOW: Duration = POLL_INTERVAL.saturating_mul(READY_POLLS - 1);
+/// Timeout used when a repository has none set
+pub const DEFAULT_TIMEOUT_SECS: i64 = 60;
+
+/// Shortest timeout a stack can pass verification within.
+pub fn min_timeout_secs() -> i64 {
+    STABILITY_WINDOW.as_millis().div_ceil(1000) as i64
+}
+
+#[derive(Debug, PartialEq)]
+enum ContainerHealth {
+    Ready,
+    Starting,
+    /// Its healthcheck failed; Docker may still see it recover
+    Unhealthy,
+    /// Exited with an error or dead: it will not become ready by waiting
+    Failed,
+    /// Stopped otherwise, e.g. exited cleanly or paused
+    Stopped,
+}
+
+/// Reads the container state and, when the image defines a healthcheck, the health
+/// status Docker appends to the status text ("Up 5 seconds (health: starting)").
+fn container_health(container: &Container) -> ContainerHealth {
+    let status = container.run_time.to_lowercase();
+
+    match container.state.to_lowercase().as_str() {
+        "running" if status.contains("unhealthy") => ContainerHealth::Unhealthy,
+        "running" if status.contains("starting") => ContainerHealth::Starting,
+        "running" => ContainerHealth::Ready,
+        "created" | "restarting" => ContainerHealth::Starting,
+        "dead" => ContainerHealth::Failed,
+        "exited" if exit_code(&status).is_some_and(|code| code != 0) => ContainerHealth::Failed,
+        _ => ContainerHealth::Stopped,
+    }
+}
+
+/// Exit code from Docker's status text of a stopped container ("Exited (1) 3 seconds ago").
+fn exit_code(status: &str) -> Option<i64> {
+    let rest = status.to_lowercase();
+    let rest = rest.strip_prefix("exited (")?;
+    rest[..rest.find(')')?].parse().ok()
+}
+
+/// Seconds the container has been up, from Docker's status text ("Up 5 minutes",
+/// "Up About an hour (healthy)"). `None` when it is not up.
+fn uptime_secs(status: &str) -> Option<u64> {
+    let status = status.to_lowercase();
+    let up = status.strip_prefix("up ")?;
+    if up.starts_with("less than") {
+        return Some(0);
+    }
+    let mut words = up.split_whitespace().skip_while(|w| *w == "about");
+    let count = match words.next()? {
+        "a" | "an" => 1,
+        count => count.parse().ok()?,
+    };
+    let unit = match words.next()?.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(count * unit)
+}
+
+/// Whether the container restarted since an earlier poll: it was recreated or its
+/// uptime went down. Remembers the container's current uptime in `seen`.
+fn restarted(seen: &mut HashMap<String, (String, u64)>, container: &Container) -> bool {
+    let Some(uptime) = uptime_secs(&container.run_time) else {
+        return false;
+    };
+    let previous = seen.insert(
+        container_name(&container.name).to_string(),
+        (container.container_id.clone(), uptime),
+    );
+    matches!(previous, Some((id, up)) if id != container.container_id || uptime < up)
+}
+
+fn container_name(name: &str) -> &str {
+    name.trim_start_matches('/')
+}
+
+fn describe(container: &Container) -> String {
+    let name = container_name(&container.name);
+    let state = if container.state.is_empty() {
+        "?"
+    } else {
+        &container.state
+    };
+    if container.run_time.is_empty() {
+        format!("{}: {}", name, state)
+    } else {
+        format!("{}: {} ({})", name, state, container.run_time)
+    }
+}
+
+/// Names of the containers 1Panel lists under the stack. Empty when the compose
+/// listing does not include them, in which case the compose project prefix is used.
+async fn stack_container_names(
+    client: &OnePanelClient,
+    stack_name: &str,
+) -> Result<HashSet<String>> {
+    let composes = client.list_composes().await?;
+
+    Ok(composes
+        .iter()
+        .find(|c| c.name == stack_name)
+        .map(|c| {
+            c.containers
+                .iter()
+                .map(|c| container_name(&c.name).to_string())
+                .filter(|n| !n.is_empty())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Polls the stack's containers until all of them are running and healthy, and
+/// stay so without restarting for `READY_POLLS` polls in a row. Gives up early once
+/// a container exited with an error, or stayed unhealthy for `READY_POLLS` polls.
+async fn wait_for_containers(
+    client: &OnePanelClient,
+    stack_name: &str,
+    deadline: Instant,
+    log: &JobLog,
+) -> Result<()> {
+    let project = stack_name.to_lowercase();
+    let mut last_summary = String::new();
+    let mut ready_polls = 0;
+    let mut uptimes = HashMap::new();
+    let mut unhealthy_polls: HashMap<String, u32> = HashMap::new();
+
+    loop {
+        let summary = match stack_container_names(client, stack_name).await {
+            Ok(names) => match client.list_containers().await {
+                Ok(containers) => {
+                    let stack: Vec<&Container> = containers
+                        .iter()
+                        .filter(|c| {
+                            let name = container_name(&c.name);
+                            if names.is_empty() {
+                                name.starts_with(&format!("{}-", project))
+                                    || name.starts_with(&format!("{}_", project))
+                            } else {
+                                names.contains(name)
+                            }
+                        })
+                        .collect();
+
+                    // Every container is checked so each one's uptime is remembered
+                    let restarts: Vec<&str> = stack
+                        .iter()
+                        .filter(|c| restarted(&mut uptimes, c))
+                        .map(|c| container_name(&c.name))
+                        .collect();
+
+                    if let Some(failed) = stack
+                        .iter()
+                        .find(|c| container_health(c) == ContainerHealth::Failed)
+                    {
+                        return Err(anyhow!(
+                            "Stack {} is not healthy: {}",
+                            stack_name,
+                            describe(failed)
+                        ));
+                    }
+                    for c in &stack {
+                        let name = container_name(&c.name).to_string();
+                        if container_health(c) != ContainerHealth::Unhealthy {
+                            unhealthy_polls.remove(&name);
+                            continue;
+                        }
+                        let polls = unhealthy_polls.entry(name).or_default();
+                        *polls += 1;
+                        if *polls >= READY_POLLS {
+                            return Err(anyhow!(
+                                "Stack {} is not healthy: {}",
+                                stack_name,
+                                describe(c)
+                            ));
+                        }
+                    }
+
+                    let ready = !stack.is_empty()
+                        && stack
+                            .iter()
+                            .all(|c| container_health(c) == ContainerHealth::Ready);
+
+                    if ready && restarts.is_empty() {
+                        ready_polls += 1;
+                        if ready_polls >= READY_POLLS {
+                            log.line(format!(
+                                "All {} container(s) of {} are running",
+                                stack.len(),
+                                stack_name
+                            ));
+                            return Ok(());
+                        }
+                    } else {
+                        ready_polls = 0;
+                    }
+
+                    if stack.is_empty() {
+                        "no containers found".to_string()
+                    } else if !restarts.is_empty() {
+                        format!("{} restarted", restarts.join(", "))
+                    } else if ready {
+                        format!(
+                            "all {} container(s) running, checking they stay up",
+                            stack.len()
+                        )
+                    } else {
+                        stack
+                            .iter()
+                            .map(|c| describe(c))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    }
+                }
+                Err(e) => format!("listing containers failed: {}", e),
+            },
+            Err(e) => format!("listing composes failed: {}", e),
+        };
+
+        if summary != last_summary {
+            log.line(format!("Waiting for {}: {}", stack_name, summary));
+            last_summary = summary;
+        }
+
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Stack {} is not healthy: {}",
+                stack_name,
+                last_summary
+            ));
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Requests `url` until it answers with a success status.
+async fn probe_url(url: &str, deadline: Instant, log: &JobLog) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let mut last_error = String::new();
+
+    loop {
+        let error = match client.get(url).send().await {
+            Ok(res) if res.status().is_success() => {
+                log.line(format!("{} answered {}", url, res.status()));
+                return Ok(());
+            }
+            Ok(res) => format!("status {}", res.status()),
+            Err(e) => e.to_string(),
+        };
+
+        if error != last_error {
+            log.line(format!("Probing {}: {}", url, error));
+            last_error = error;
+        }
+
+        if Instant::now() >= deadline {
+            return Err(anyhow!("Health check {} failed: {}", url, last_error));
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Verifies a freshly deployed stack within `timeout_secs`: its containers must reach
+/// running (and healthy, if they have a healthcheck), then `url` must answer with 2xx.
+pub async fn verify(
+    client: &OnePanelClient,
+    stack_name: &str,
+    timeout_secs: i64,
+    url: Option<&str>,
+    log: &JobLog,
+) -> Result<()> {
+    log.line(format!(
+        "Verifying {} (timeout {}s)",
+        stack_name, timeout_secs
+    ));
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs.max(0) as u64);
+
+    wait_for_containers(client, stack_name, deadline, log).await?;
+
+    if let Some(url) = url {
+        probe_url(url, deadline, log).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_onepanel::container;
+
+    #[test]
+    fn reads_uptime_from_status() {
+        assert_eq!(uptime_secs("Up Less than a second"), Some(0));
+        assert_eq!(uptime_secs("Up 5 seconds"), Some(5));
+        assert_eq!(uptime_secs("Up 1 second (health: starting)"), Some(1));
+        assert_eq!(uptime_secs("Up About a minute"), Some(60));
+        assert_eq!(uptime_secs("Up 3 minutes (healthy)"), Some(180));
+        assert_eq!(uptime_secs("Up About an hour"), Some(3600));
+        assert_eq!(uptime_secs("Up 2 days"), Some(2 * 24 * 3600));
+        assert_eq!(uptime_secs("Exited (1) 3 seconds ago"), None);
+        assert_eq!(uptime_secs("Restarting (1) 2 seconds ago"), None);
+        assert_eq!(uptime_secs(""), None);
+    }
+
+    #[test]
+    fn detects_restarts() {
+        let mut seen = HashMap::new();
+        let mut poll = |status: &str, id: &str| {
+            let mut c = container("web-app-1", "running", status);
+            c.container_id = id.to_string();
+            restarted(&mut seen, &c)
+        };
+
+        assert!(!poll("Up 2 seconds", "a"));
+        assert!(!poll("Up 5 seconds", "a"));
+        // Uptime shown in coarser units stays put between polls
+        assert!(!poll("Up 5 seconds", "a"));
+        assert!(poll("Up 1 second", "a"));
+        // Stopped in between: compared with the last time it was up
+        assert!(!poll("Exited (1) 1 second ago", "a"));
+        assert!(poll("Up Less than a second", "a"));
+        // Recreated
+        assert!(poll("Up 3 seconds", "b"));
+    }
+
+    #[test]
+    fn classifies_container_state() {
+        let health = |state, status| container_health(&container("c", state, status));
+        assert_eq!(health("running", "Up 3 seconds"), ContainerHealth::Ready);
+        assert_eq!(
+            health("running", "Up 3 seconds (healthy)"),
+            ContainerHealth::Ready
+        );
+        assert_eq!(
+            health("running", "Up 3 seconds (health: starting)"),
+            ContainerHealth::Starting
+        );
+        assert_eq!(
+            health("running", "Up 3 minutes (unhealthy)"),
+            ContainerHealth::Unhealthy
+        );
+        assert_eq!(health("restarting", ""), ContainerHealth::Starting);
+        assert_eq!(
+            health("exited", "Exited (1) 2 seconds ago"),
+            ContainerHealth::Failed
+        );
+        assert_eq!(
+            health("exited", "Exited (0) 2 seconds ago"),
+            ContainerHealth::Stopped
+        );
+        assert_eq!(health("dead", ""), ContainerHealth::Failed);
+    }
+}
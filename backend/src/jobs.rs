@@ -0,0 +1,500 @@
+use crate::db::DbPool;
+use crate::models::{Job, JobDetail, JobLogLine, JobStep};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::task::AbortHandle;
+use tracing::{error, info};
+use uuid::Uuid;
+
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_SUCCEEDED: &str = "succeeded";
+pub const STATUS_FAILED: &str = "failed";
+pub const STATUS_CANCELLED: &str = "cancelled";
+/// Step reused from the job being retried instead of running again.
+pub const STATUS_SKIPPED: &str = "skipped";
+/// Step whose effect was undone later in the job; a retry runs it again.
+pub const STATUS_REVERTED: &str = "reverted";
+
+/// Started through the HTTP API.
+pub const TRIGGER_API: &str = "api";
+/// Started by an agent through an MCP tool call.
+pub const TRIGGER_MCP: &str = "mcp";
+
+pub fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Events published to live log subscribers of a running job.
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    Log(JobLogLine),
+    Finished { status: String },
+}
+
+enum LogMsg {
+    Line(String),
+    Close,
+}
+
+/// Cheap, cloneable sink for job log lines. Lines are persisted to `job_logs`
+/// and fanned out to SSE subscribers. A default `JobLog` discards everything.
+#[derive(Clone, Default)]
+pub struct JobLog {
+    tx: Option<mpsc::UnboundedSender<LogMsg>>,
+}
+
+impl JobLog {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn line(&self, line: impl Into<String>) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(LogMsg::Line(line.into()));
+        }
+    }
+}
+
+/// Runs build/deploy pipelines in the background and persists their progress
+/// in the `jobs` and `job_steps` tables so callers can poll the outcome.
+#[derive(Clone)]
+pub struct JobRunner {
+    db: Arc<DbPool>,
+    live: Arc<RwLock<HashMap<String, LiveJob>>>,
+}
+
+struct LiveJob {
+    events: broadcast::Sender<JobEvent>,
+    abort: AbortHandle,
+}
+
+/// What gets recorded for a new job; `params` is enough for `pipeline::start` to run it again.
+pub struct NewJob<'a> {
+    pub kind: &'a str,
+    pub repository_path: Option<&'a str>,
+    pub params: &'a serde_json::Value,
+    pub retry_of: Option<&'a str>,
+    pub triggered_by: &'a str,
+}
+
+impl JobRunner {
+    pub fn new(db: Arc<DbPool>) -> Self {
+        Self {
+            db,
+            live: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Records a new job and runs `task` on the tokio runtime.
+    /// Returns the job id immediately; the final status is written when the task ends.
+    pub async fn spawn<F, Fut>(&self, new_job: NewJob<'_>, task: F) -> Result<String, sqlx::Error>
+    where
+        F: FnOnce(JobContext) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let id = Uuid::new_v4().to_string();
+        let now = now_millis();
+
+        // Outputs of steps that already succeeded in the job being retried
+        let resume = match new_job.retry_of {
+            Some(prev) => self.step_outputs(prev).await?,
+            None => HashMap::new(),
+        };
+
+        sqlx::query(
+            "INSERT INTO jobs (id, kind, status, repository_path, params, retry_of, triggered_by, created_at, started_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&id)
+        .bind(new_job.kind)
+        .bind(STATUS_RUNNING)
+        .bind(new_job.repository_path)
+        .bind(new_job.params.to_string())
+        .bind(new_job.retry_of)
+        .bind(new_job.triggered_by)
+        .bind(now)
+        .bind(now)
+        .execute(&*self.db)
+        .await?;
+
+        let (events, _) = broadcast::channel(1024);
+
+        let (log_tx, log_rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_logs(
+            self.db.clone(),
+            id.clone(),
+            log_rx,
+            events.clone(),
+        ));
+
+        let ctx = JobContext {
+            job_id: id.clone(),
+            triggered_by: new_job.triggered_by.to_string(),
+            db: self.db.clone(),
+            log: JobLog {
+                tx: Some(log_tx.clone()),
+            },
+            resume: Arc::new(resume),
+        };
+
+        // Run the body in its own task so it can be aborted; dropping it kills
+        // child processes spawned with `kill_on_drop` and in-flight uploads.
+        let mut live = self.live.write().await;
+        let handle = tokio::spawn(task(ctx));
+        live.insert(
+            id.clone(),
+            LiveJob {
+                events: events.clone(),
+                abort: handle.abort_handle(),
+            },
+        );
+        drop(live);
+
+        let db = self.db.clone();
+        let live = self.live.clone();
+        let job_id = id.clone();
+        let kind = new_job.kind.to_string();
+
+        tokio::spawn(async move {
+            info!("Job {} ({}) started", job_id, kind);
+
+            let (status, err) = match handle.await {
+                Ok(Ok(_)) => (STATUS_SUCCEEDED, None),
+                Ok(Err(e)) => (STATUS_FAILED, Some(e.to_string())),
+                Err(e) if e.is_cancelled() => (STATUS_CANCELLED, Some("Cancelled".to_string())),
+                Err(e) => (STATUS_FAILED, Some(format!("Job panicked: {}", e))),
+            };
+
+            match &err {
+                Some(e) => error!("Job {} ({}) {}: {}", job_id, kind, status, e),
+                None => info!("Job {} ({}) succeeded", job_id, kind),
+            }
+
+            if status == STATUS_CANCELLED {
+                let _ = log_tx.send(LogMsg::Line("Job cancelled".to_string()));
+            }
+
+            // Flush remaining log lines before announcing completion
+            let _ = log_tx.send(LogMsg::Close);
+            let _ = writer.await;
+
+            // A cancelled or panicked body leaves its current step unfinished
+            let _ = sqlx::query(
+                "UPDATE job_steps SET status = ?, finished_at = ? WHERE job_id = ? AND status = ?",
+            )
+            .bind(status)
+            .bind(now_millis())
+            .bind(&job_id)
+            .bind(STATUS_RUNNING)
+            .execute(&*db)
+            .await;
+
+            if let Err(e) =
+                sqlx::query("UPDATE jobs SET status = ?, error = ?, finished_at = ? WHERE id = ?")
+                    .bind(status)
+                    .bind(err)
+                    .bind(now_millis())
+                    .bind(&job_id)
+                    .execute(&*db)
+                    .await
+            {
+                error!("Failed to record final status of job {}: {}", job_id, e);
+            }
+
+            live.write().await.remove(&job_id);
+            let _ = events.send(JobEvent::Finished {
+                status: status.to_string(),
+            });
+        });
+
+        Ok(id)
+    }
+
+    /// Aborts a running job. Returns `false` if the job is not running.
+    pub async fn cancel(&self, id: &str) -> bool {
+        match self.live.read().await.get(id) {
+            Some(job) => {
+                job.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits until a job has finished and returns its final status.
+    pub async fn wait(&self, id: &str) -> Result<Option<String>, sqlx::Error> {
+        if let Some(mut rx) = self.subscribe(id).await {
+            loop {
+                match rx.recv().await {
+                    Ok(JobEvent::Finished { status }) => return Ok(Some(status)),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+        Ok(self.get(id).await?.map(|d| d.job.status))
+    }
+
+    /// Collects outputs of steps that succeeded (or were themselves reused) in `job_id`.
+    async fn step_outputs(
+        &self,
+        job_id: &str,
+    ) -> Result<HashMap<String, serde_json::Value>, sqlx::Error> {
+        let steps = sqlx::query_as::<_, JobStep>(
+            "SELECT * FROM job_steps WHERE job_id = ? AND status IN (?, ?) AND output IS NOT NULL",
+        )
+        .bind(job_id)
+        .bind(STATUS_SUCCEEDED)
+        .bind(STATUS_SKIPPED)
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(steps
+            .into_iter()
+            .filter_map(|s| {
+                let output = serde_json::from_str(s.output.as_deref()?).ok()?;
+                Some((s.name, output))
+            })
+            .collect())
+    }
+
+    /// Marks jobs left `running` by a previous process as failed.
+    pub async fn fail_interrupted(&self) -> Result<(), sqlx::Error> {
+        let now = now_millis();
+        sqlx::query("UPDATE job_steps SET status = ?, error = ?, finished_at = ? WHERE status = ?")
+            .bind(STATUS_FAILED)
+            .bind("Interrupted by restart")
+            .bind(now)
+            .bind(STATUS_RUNNING)
+            .execute(&*self.db)
+            .await?;
+
+        sqlx::query("UPDATE jobs SET status = ?, error = ?, finished_at = ? WHERE status = ?")
+            .bind(STATUS_FAILED)
+            .bind("Interrupted by restart")
+            .bind(now)
+            .bind(STATUS_RUNNING)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list(&self, status: Option<&str>, limit: i64) -> Result<Vec<Job>, sqlx::Error> {
+        sqlx::query_as::<_, Job>(
+            "SELECT * FROM jobs WHERE (? IS NULL OR status = ?) ORDER BY created_at DESC LIMIT ?",
+        )
+        .bind(status)
+        .bind(status)
+        .bind(limit)
+        .fetch_all(&*self.db)
+        .await
+    }
+
+    /// Latest running or succeeded job of `kind` started with the given preview token.
+    pub async fn find_by_token(
+        &self,
+        kind: &str,
+        token: &str,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let row: Option<(String,)> = sqlx::query_as(
+            "SELECT id FROM jobs WHERE kind = ? AND json_extract(params, '$.token') = ? AND status IN (?, ?) ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(kind)
+        .bind(token)
+        .bind(STATUS_RUNNING)
+        .bind(STATUS_SUCCEEDED)
+        .fetch_optional(&*self.db)
+        .await?;
+        Ok(row.map(|(id,)| id))
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<JobDetail>, sqlx::Error> {
+        let job = sqlx::query_as::<_, Job>("SELECT * FROM jobs WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&*self.db)
+            .await?;
+
+        let job = match job {
+            Some(j) => j,
+            None => return Ok(None),
+        };
+
+        let steps = sqlx::query_as::<_, JobStep>(
+            "SELECT * FROM job_steps WHERE job_id = ? ORDER BY id ASC",
+        )
+        .bind(id)
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(Some(JobDetail { job, steps }))
+    }
+
+    pub async fn logs(&self, id: &str) -> Result<Vec<JobLogLine>, sqlx::Error> {
+        sqlx::query_as::<_, JobLogLine>("SELECT * FROM job_logs WHERE job_id = ? ORDER BY id ASC")
+            .bind(id)
+            .fetch_all(&*self.db)
+            .await
+    }
+
+    /// Subscribes to live events of a running job. Returns `None` once the job has finished.
+    pub async fn subscribe(&self, id: &str) -> Option<broadcast::Receiver<JobEvent>> {
+        self.live.read().await.get(id).map(|j| j.events.subscribe())
+    }
+}
+
+async fn write_logs(
+    db: Arc<DbPool>,
+    job_id: String,
+    mut rx: mpsc::UnboundedReceiver<LogMsg>,
+    events: broadcast::Sender<JobEvent>,
+) {
+    while let Some(LogMsg::Line(line)) = rx.recv().await {
+        let timestamp = now_millis();
+        let res = sqlx::query("INSERT INTO job_logs (job_id, line, timestamp) VALUES (?, ?, ?)")
+            .bind(&job_id)
+            .bind(&line)
+            .bind(timestamp)
+            .execute(&*db)
+            .await;
+
+        match res {
+            Ok(r) => {
+                let _ = events.send(JobEvent::Log(JobLogLine {
+                    id: r.last_insert_rowid(),
+                    job_id: job_id.clone(),
+                    line,
+                    timestamp,
+                }));
+            }
+            Err(e) => error!("Failed to persist log line for job {}: {}", job_id, e),
+        }
+    }
+}
+
+/// Handle passed to a running job for recording its steps and outputs.
+#[derive(Clone)]
+pub struct JobContext {
+    pub job_id: String,
+    pub triggered_by: String,
+    pub db: Arc<DbPool>,
+    pub log: JobLog,
+    resume: Arc<HashMap<String, serde_json::Value>>,
+}
+
+impl JobContext {
+    /// Context of a job that has no row in `jobs`, resuming from the `resume` outputs.
+    #[cfg(test)]
+    pub fn for_test(db: DbPool, resume: HashMap<String, serde_json::Value>) -> Self {
+        JobContext {
+            job_id: "test".to_string(),
+            triggered_by: "test".to_string(),
+            db: Arc::new(db),
+            log: JobLog::none(),
+            resume: Arc::new(resume),
+        }
+    }
+
+    /// Runs `fut` as a named step, recording start/finish timestamps, its output and any error.
+    /// When retrying, a step that already succeeded in the previous attempt is not run again;
+    /// its recorded output is returned instead.
+    pub async fn step<T, Fut>(&self, name: &str, fut: Fut) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if let Some(output) = self.resume.get(name) {
+            if let Ok(value) = serde_json::from_value::<T>(output.clone()) {
+                let now = now_millis();
+                sqlx::query(
+                    "INSERT INTO job_steps (job_id, name, status, output, started_at, finished_at) VALUES (?, ?, ?, ?, ?, ?)",
+                )
+                .bind(&self.job_id)
+                .bind(name)
+                .bind(STATUS_SKIPPED)
+                .bind(output.to_string())
+                .bind(now)
+                .bind(now)
+                .execute(&*self.db)
+                .await?;
+
+                self.log
+                    .line(format!("==> {} (reused from previous attempt)", name));
+                return Ok(value);
+            }
+        }
+
+        let step_id = sqlx::query(
+            "INSERT INTO job_steps (job_id, name, status, started_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&self.job_id)
+        .bind(name)
+        .bind(STATUS_RUNNING)
+        .bind(now_millis())
+        .execute(&*self.db)
+        .await?
+        .last_insert_rowid();
+
+        info!("Job {}: step {} started", self.job_id, name);
+        self.log.line(format!("==> {}", name));
+        let result = fut.await;
+
+        let (status, output, err) = match &result {
+            Ok(v) => (STATUS_SUCCEEDED, serde_json::to_string(v).ok(), None),
+            Err(e) => (STATUS_FAILED, None, Some(e.to_string())),
+        };
+
+        if let Some(e) = &err {
+            self.log.line(format!("{} failed: {}", name, e));
+        }
+
+        sqlx::query(
+            "UPDATE job_steps SET status = ?, output = ?, error = ?, finished_at = ? WHERE id = ?",
+        )
+        .bind(status)
+        .bind(output)
+        .bind(err)
+        .bind(now_millis())
+        .bind(step_id)
+        .execute(&*self.db)
+        .await?;
+
+        result
+    }
+
+    pub async fn set_image_tag(&self, image_tag: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE jobs SET image_tag = ? WHERE id = ?")
+            .bind(image_tag)
+            .bind(&self.job_id)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_server_id(&self, server_id: i64) -> anyhow::Result<()> {
+        sqlx::query("UPDATE jobs SET server_id = ? WHERE id = ?")
+            .bind(server_id)
+            .bind(&self.job_id)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Marks completed steps as reverted so that retrying this job runs them again.
+    pub async fn revert_steps(&self, names: &[&str]) -> anyhow::Result<()> {
+        for name in names {
+            sqlx::query(
+                "UPDATE job_steps SET status = ? WHERE job_id = ? AND name = ? AND status IN (?, ?)",
+            )
+            .bind(STATUS_REVERTED)
+            .bind(&self.job_id)
+            .bind(name)
+            .bind(STATUS_SUCCEEDED)
+            .bind(STATUS_SKIPPED)
+            .execute(&*self.db)
+            .await?;
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use axum::{
-    http::{header, StatusCode, Uri},
+    http::{header, Method, StatusCode, Uri},
     response::IntoResponse,
     routing::get,
     Router,
@@ -10,7 +10,7 @@ use rust_embed::RustEmbed;
 use std::net::SocketAddr;
 use std::thread;
 use tao::event_loop::{ControlFlow, EventLoop};
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use tray_icon::{
     menu::{Menu, MenuItem},
@@ -19,16 +19,32 @@ use tray_icon::{
 use utoipa::OpenApi;
 use utoipa_scalar::{Scalar, Servable};
 
+mod audit;
+mod auth;
+mod compose;
+mod compress;
 mod db;
+mod delta;
+mod deployments;
 mod docker;
+mod environments;
 mod fs;
 mod git;
 mod handlers;
+mod health;
 mod icon;
+mod jobs;
 mod mcp_server;
+#[cfg(test)]
+mod mock_onepanel;
 mod models;
 mod onepanel;
+mod pipeline;
+mod registries;
+mod secrets;
 mod state;
+mod targets;
+mod tls;
 
 use docker::DockerInfo;
 use fs::{FileEntry, ListRequest, ReadFileRequest, ScanRequest};
@@ -43,11 +59,27 @@ use state::AppState;
 #[derive(OpenApi)]
 #[openapi(
     paths(
+        handlers::auth::auth_status,
+        handlers::auth::setup,
+        handlers::auth::login,
+        handlers::auth::logout,
+        handlers::auth::change_password,
+        handlers::auth::list_tokens,
+        handlers::auth::create_token,
+        handlers::auth::delete_token,
+        handlers::auth::list_users,
+        handlers::auth::create_user,
+        handlers::auth::update_user,
+        handlers::auth::delete_user,
+        handlers::audit::list_audit,
+        handlers::audit::export_audit,
         handlers::repository::list_repositories,
         handlers::repository::add_repository,
         handlers::repository::remove_repository,
         handlers::repository::update_docker_config,
         handlers::repository::get_docker_config,
+        handlers::repository::get_deploy_targets,
+        handlers::repository::update_deploy_targets,
         handlers::fs::list_directory,
         handlers::fs::scan_directory,
         handlers::fs::read_file,
@@ -74,24 +106,49 @@ use state::AppState;
         handlers::container::operate_container,
         handlers::container::get_logs,
         handlers::deploy::push_image_to_server,
+        handlers::deploy::rollback_deployment,
+        handlers::deploy::deploy,
+        handlers::deployments::list_deployments,
+        handlers::environments::list_environments,
+        handlers::environments::save_environment,
+        handlers::environments::delete_environment,
+        handlers::environments::promote,
+        handlers::environments::list_promotions,
+        handlers::environments::approve_promotion,
+        handlers::environments::reject_promotion,
+        handlers::registries::list_registries,
+        handlers::registries::create_registry,
+        handlers::registries::update_registry,
+        handlers::registries::delete_registry,
         handlers::compose::list_composes,
         handlers::compose::get_content,
         handlers::compose::update_content,
         handlers::image::list_images,
         handlers::image::remove_image,
+        handlers::uploads::list_stale_uploads,
+        handlers::uploads::cleanup_uploads,
         handlers::compose::operate_compose,
         handlers::image_deployments::get_image_deployments,
+        handlers::jobs::list_jobs,
+        handlers::jobs::get_job,
+        handlers::jobs::get_job_logs,
+        handlers::jobs::stream_job_logs,
+        handlers::jobs::cancel_job,
+        handlers::jobs::retry_job,
     ),
     components(
-        schemas(CreateDirectoryRequest, DirectoryResponse, GitStatus, FileEntry, ListRequest, ScanRequest, ReadFileRequest, CommitInfo, FileStatus, GitLogRequest, GitStatusRequest, CreateServerRequest, ServerResponse, DashboardResponse, OsInfo, Server, Repository, DockerInfo, docker::DockerImage, docker::ContainerSummary, docker::PullImageRequest, models::ContainerOperationReq, models::PushImageReq, handlers::compose::GetContentReq, handlers::compose::OperateComposeReq, handlers::image_deployments::ImageDeployment)
+        schemas(CreateDirectoryRequest, DirectoryResponse, GitStatus, FileEntry, ListRequest, ScanRequest, ReadFileRequest, CommitInfo, FileStatus, GitLogRequest, GitStatusRequest, CreateServerRequest, ServerResponse, DashboardResponse, OsInfo, Server, Repository, DockerInfo, docker::DockerImage, docker::ContainerSummary, docker::PullImageRequest, models::ContainerOperationReq, models::PushImageReq, handlers::compose::GetContentReq, handlers::compose::OperateComposeReq, handlers::image_deployments::ImageDeployment, models::Job, models::JobStep, models::JobDetail, models::JobLogLine, models::JobStarted, models::Deployment, models::DeploymentPage, models::RollbackReq, models::DeployReq, models::DeployPreview, models::DeployTargetPreview, models::ServiceImageChange, models::DeployTarget, models::DeployTargetInput, models::UpdateDeployTargetsReq, models::DeployTargetsResponse, models::Environment, models::SaveEnvironmentReq, models::PromoteReq, models::Promotion, models::Container, models::ComposeContainer, models::Compose, models::Image, models::FileInfo, models::Registry, models::SaveRegistryReq, handlers::uploads::CleanupUploadsResponse, models::AuthStatus, models::LoginReq, models::ChangePasswordReq, models::ApiToken, models::CreateTokenReq, models::CreatedToken, models::User, models::CreateUserReq, models::UpdateUserReq, models::AuditEntry, models::AuditPage)
     ),
     tags(
+        (name = "Auth", description = "Login, sessions, users and API tokens"),
+        (name = "Audit", description = "Log of every change made through the API and MCP"),
         (name = "directories", description = "Directory management endpoints"),
         (name = "fs", description = "File system endpoints"),
         (name = "git", description = "Git operations endpoints"),
         (name = "servers", description = "Server management endpoints"),
         (name = "docker", description = "Docker endpoints"),
-        (name = "Container", description = "1Panel Container management")
+        (name = "Container", description = "1Panel Container management"),
+        (name = "Jobs", description = "Background build and deploy jobs")
     )
 )]
 struct ApiDoc;
@@ -136,10 +193,52 @@ async fn run_server() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     let db = db::init_db().await.unwrap();
-    let state = AppState::new(db);
+    if let Err(e) = secrets::init(&db).await {
+        tracing::error!("Could not load the master key: {:#}", e);
+        std::process::exit(1);
+    }
+    let allowed_origins = auth::allowed_origins(addr.port());
+    let state = AppState::new(db, allowed_origins.clone(), fs::browse_roots());
+    state.jobs.fail_interrupted().await.unwrap();
 
     let app = Router::new()
+        .route("/api/auth/status", get(handlers::auth::auth_status))
+        .route(
+            "/api/auth/setup",
+            axum::routing::post(handlers::auth::setup),
+        )
+        .route(
+            "/api/auth/login",
+            axum::routing::post(handlers::auth::login),
+        )
+        .route(
+            "/api/auth/logout",
+            axum::routing::post(handlers::auth::logout),
+        )
+        .route(
+            "/api/auth/password",
+            axum::routing::post(handlers::auth::change_password),
+        )
+        .route(
+            "/api/auth/tokens",
+            get(handlers::auth::list_tokens).post(handlers::auth::create_token),
+        )
+        .route(
+            "/api/auth/tokens/{id}",
+            axum::routing::delete(handlers::auth::delete_token),
+        )
+        .route(
+            "/api/users",
+            get(handlers::auth::list_users).post(handlers::auth::create_user),
+        )
+        .route(
+            "/api/users/{id}",
+            axum::routing::put(handlers::auth::update_user).delete(handlers::auth::delete_user),
+        )
+        .route("/api/audit", get(handlers::audit::list_audit))
+        .route("/api/audit/export", get(handlers::audit::export_audit))
         .route(
             "/api/directories",
             get(handlers::repository::list_repositories)
@@ -154,6 +253,14 @@ async fn run_server() {
             "/api/directories/config/get",
             axum::routing::post(handlers::repository::get_docker_config),
         )
+        .route(
+            "/api/directories/targets/get",
+            axum::routing::post(handlers::repository::get_deploy_targets),
+        )
+        .route(
+            "/api/directories/targets/update",
+            axum::routing::post(handlers::repository::update_deploy_targets),
+        )
         .route(
             "/api/fs/list",
             axum::routing::post(handlers::fs::list_directory),
@@ -268,14 +375,76 @@ async fn run_server() {
             "/api/servers/{id}/images/remove",
             axum::routing::post(handlers::image::remove_image),
         )
+        .route(
+            "/api/servers/{id}/uploads",
+            get(handlers::uploads::list_stale_uploads).delete(handlers::uploads::cleanup_uploads),
+        )
         .route(
             "/api/deploy/image",
             axum::routing::post(handlers::deploy::push_image_to_server),
         )
+        .route(
+            "/api/deployments",
+            get(handlers::deployments::list_deployments),
+        )
+        .route("/api/deploy", axum::routing::post(handlers::deploy::deploy))
+        .route(
+            "/api/deploy/rollback",
+            axum::routing::post(handlers::deploy::rollback_deployment),
+        )
         .route(
             "/api/image-deployments",
             get(handlers::image_deployments::get_image_deployments),
         )
+        .route(
+            "/api/environments",
+            get(handlers::environments::list_environments)
+                .post(handlers::environments::save_environment),
+        )
+        .route(
+            "/api/environments/{id}",
+            axum::routing::delete(handlers::environments::delete_environment),
+        )
+        .route(
+            "/api/environments/promote",
+            axum::routing::post(handlers::environments::promote),
+        )
+        .route(
+            "/api/promotions",
+            get(handlers::environments::list_promotions),
+        )
+        .route(
+            "/api/promotions/{id}/approve",
+            axum::routing::post(handlers::environments::approve_promotion),
+        )
+        .route(
+            "/api/promotions/{id}/reject",
+            axum::routing::post(handlers::environments::reject_promotion),
+        )
+        .route(
+            "/api/registries",
+            get(handlers::registries::list_registries).post(handlers::registries::create_registry),
+        )
+        .route(
+            "/api/registries/{id}",
+            axum::routing::put(handlers::registries::update_registry)
+                .delete(handlers::registries::delete_registry),
+        )
+        .route("/api/jobs", get(handlers::jobs::list_jobs))
+        .route("/api/jobs/{id}", get(handlers::jobs::get_job))
+        .route("/api/jobs/{id}/logs", get(handlers::jobs::get_job_logs))
+        .route(
+            "/api/jobs/{id}/cancel",
+            axum::routing::post(handlers::jobs::cancel_job),
+        )
+        .route(
+            "/api/jobs/{id}/retry",
+            axum::routing::post(handlers::jobs::retry_job),
+        )
+        .route(
+            "/api/jobs/{id}/logs/stream",
+            get(handlers::jobs::stream_job_logs),
+        )
         .route("/api/version", get(handlers::version::get_version))
         .route(
             "/api/notifications",
@@ -289,23 +458,73 @@ async fn run_server() {
         )
         .route("/mcp", axum::routing::post(handlers::mcp::post_handler))
         .merge(Scalar::with_url("/scalar", ApiDoc::openapi()))
+        // Inside require_auth, which identifies the caller
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            audit::record_requests,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ))
         .layer(
             CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
+                .allow_origin(AllowOrigin::list(allowed_origins))
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+                .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+                .allow_credentials(true),
         )
         .with_state(state)
         .fallback(static_handler);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     println!("Starting OnePanel CI version: {}", env!("APP_VERSION"));
     println!("listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
+/// `OnePanel rotate-key`: re-encrypts the stored credentials with a new master
+/// key. Run it while the app is stopped. What it does is also appended to
+/// `rotate-key.log` next to the database, as release builds on Windows have no
+/// console to print to.
+fn rotate_key() {
+    let log_path = std::path::Path::new(db::DB_FILE).with_file_name("rotate-key.log");
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .ok();
+    let report = |line: &str| {
+        println!("{}", line);
+        if let Some(mut file) = log.as_ref() {
+            let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+            let _ = std::io::Write::write_all(&mut file, format!("{} {}\n", now, line).as_bytes());
+        }
+    };
+
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let db = db::init_db().await?;
+            secrets::rotate(&db, &report).await
+        });
+    match result {
+        Ok(()) => report("Key rotation finished"),
+        Err(e) => {
+            report(&format!("Key rotation failed: {:#}", e));
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("rotate-key") {
+        rotate_key();
+        return;
+    }
+
     let event_loop = EventLoop::new();
 
     // Spawn server in a separate thread
@@ -12,13 +12,12 @@ use rust_mcp_sdk::{
 use std::sync::Arc;
 use tracing::info;
 
-use crate::docker;
-use crate::models::Repository;
-
-use crate::handlers::deploy;
-use crate::onepanel::OnePanelClient;
+use crate::audit;
+use crate::auth::{self, AccessError, Principal, Role};
+use crate::jobs::TRIGGER_MCP;
+use crate::pipeline::{self, DeployParams};
 use crate::state::AppState;
-use regex::Regex;
+use crate::targets;
 
 // --- Tool Definitions ---
 
@@ -47,46 +46,203 @@ pub struct BuildImageTool {
 
 #[macros::mcp_tool(
     name = "build_and_deploy",
-    description = "Build a Docker image from a project path and deploy it to the configured server."
+    description = "Build a Docker image from a project path and deploy it to the project's deploy targets (its default server, or every configured target using the project's rollout strategy). Use dry_run first to get the compose diff and a token, then call again with the token to apply exactly that change."
 )]
 #[derive(Debug, ::serde::Deserialize, ::serde::Serialize, macros::JsonSchema)]
 pub struct BuildAndDeployTool {
     pub path: String,
+    /// Compose service to update; defaults to every service using the project's image
+    pub service: Option<String>,
+    /// Image tag to deploy; defaults to the next version
+    pub version: Option<String>,
+    /// Only show the compose diff that would be applied
+    pub dry_run: Option<bool>,
+    /// Token returned by a dry run
+    pub token: Option<String>,
+}
+
+#[macros::mcp_tool(
+    name = "rollback",
+    description = "Roll a project back to the image tag that was deployed before the latest deploy, on each of its deploy targets, in the order and with the parallelism of its rollout settings."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, macros::JsonSchema)]
+pub struct RollbackTool {
+    pub path: String,
+}
+
+#[macros::mcp_tool(
+    name = "promote",
+    description = "Promote the exact image tag running in one environment of a project (e.g. staging) to another (by default the next one, e.g. prod) without rebuilding. Promotions into environments that require approval stay pending until approved by a person through the API."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, macros::JsonSchema)]
+pub struct PromoteTool {
+    pub path: String,
+    /// Environment whose deployed image is promoted
+    pub from: String,
+    /// Target environment; defaults to the one after `from`
+    pub to: Option<String>,
+}
+
+#[macros::mcp_tool(
+    name = "get_job_status",
+    description = "Get the status, steps and errors of a build or deploy job by its ID."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, macros::JsonSchema)]
+pub struct GetJobStatusTool {
+    pub job_id: String,
+}
+
+#[macros::mcp_tool(
+    name = "cancel_job",
+    description = "Cancel a running build or deploy job, killing its docker build or upload."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, macros::JsonSchema)]
+pub struct CancelJobTool {
+    pub job_id: String,
+}
+
+#[macros::mcp_tool(
+    name = "retry_job",
+    description = "Retry a failed or cancelled job from the step that failed, reusing the already built image."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, macros::JsonSchema)]
+pub struct RetryJobTool {
+    pub job_id: String,
+}
+
+/// Least role allowed to call a tool. Jobs need more to cancel or retry when they deploy.
+fn tool_role(name: &str) -> Role {
+    match name {
+        "build_image" | "cancel_job" | "retry_job" => Role::Builder,
+        "build_and_deploy" | "rollback" | "promote" => Role::Deployer,
+        _ => Role::Viewer,
+    }
+}
+
+fn denied(e: AccessError) -> CallToolError {
+    CallToolError::from_message(e.to_string())
 }
 
 pub struct AppHandler {
     pub state: AppState,
+    /// Who calls the tools; they get the same permissions as through the API
+    pub principal: Principal,
+    /// MCP session the calls belong to, recorded in the audit log
+    pub session_id: Option<String>,
 }
 
 impl AppHandler {
+    /// Tools the caller's role allows.
     pub async fn list_tools(&self) -> std::result::Result<ListToolsResult, String> {
+        let mut tools = vec![
+            ListProjectsTool::tool(),
+            ListServersTool::tool(),
+            BuildImageTool::tool(),
+            BuildAndDeployTool::tool(),
+            RollbackTool::tool(),
+            PromoteTool::tool(),
+            GetJobStatusTool::tool(),
+            CancelJobTool::tool(),
+            RetryJobTool::tool(),
+        ];
+        tools.retain(|tool| self.principal.role >= tool_role(&tool.name));
         Ok(ListToolsResult {
-            tools: vec![
-                ListProjectsTool::tool(),
-                ListServersTool::tool(),
-                BuildImageTool::tool(),
-                BuildAndDeployTool::tool(),
-            ],
+            tools,
             meta: None,
             next_cursor: None,
         })
     }
 
+    /// Runs a tool, recording calls of those that change something in the audit log.
     pub async fn call_tool(
         &self,
         name: String,
         arguments: Option<serde_json::Map<String, serde_json::Value>>,
     ) -> std::result::Result<CallToolResult, CallToolError> {
         info!("Calling tool: {}", name);
+        if tool_role(&name) == Role::Viewer {
+            return self.run_tool(&name, arguments).await.map(|(res, _)| res);
+        }
+        let params = serde_json::Value::Object(arguments.clone().unwrap_or_default());
+
+        // The error is not Send, so only its message is kept across the audit write
+        let result = self
+            .run_tool(&name, arguments)
+            .await
+            .map_err(|e| e.to_string());
+        let job_id = match &result {
+            Ok((_, job_id)) => job_id.clone(),
+            Err(_) => None,
+        };
+        let error = match &result {
+            Ok((res, _)) if res.is_error == Some(true) => Some("Tool reported an error"),
+            Ok(_) => None,
+            Err(e) => Some(e.as_str()),
+        };
+        let action = format!("mcp {}", name);
+        let entry = audit::NewEntry {
+            principal: &self.principal,
+            mcp_session: Some(self.session_id.as_deref().unwrap_or_default()),
+            action: &action,
+            path: None,
+            server_id: params.get("server_id").and_then(|v| v.as_i64()),
+            params: &params,
+            status: None,
+            success: error.is_none(),
+            error,
+            job_id: job_id.as_deref(),
+        };
+        if let Err(e) = audit::record(&self.state.db, entry).await {
+            tracing::error!("Failed to write audit log entry for {}: {}", action, e);
+        }
 
-        match name.as_str() {
+        // Starting a job says nothing about its outcome, which gets an entry of its own
+        if let Some(job_id) = job_id {
+            let state = self.state.clone();
+            let principal = self.principal.clone();
+            let session_id = self.session_id.clone();
+            tokio::spawn(async move {
+                let entry = audit::NewEntry {
+                    principal: &principal,
+                    mcp_session: Some(session_id.as_deref().unwrap_or_default()),
+                    action: &action,
+                    path: None,
+                    server_id: params.get("server_id").and_then(|v| v.as_i64()),
+                    params: &params,
+                    status: None,
+                    success: true,
+                    error: None,
+                    job_id: Some(&job_id),
+                };
+                if let Err(e) = audit::record_job_end(&state.db, &state.jobs, &job_id, entry).await
+                {
+                    tracing::error!("Failed to write audit log entry for job {}: {}", job_id, e);
+                }
+            });
+        }
+        result
+            .map(|(res, _)| res)
+            .map_err(CallToolError::from_message)
+    }
+
+    /// Runs a tool, returning with its result the id of the job it started, if any.
+    async fn run_tool(
+        &self,
+        name: &str,
+        arguments: Option<serde_json::Map<String, serde_json::Value>>,
+    ) -> std::result::Result<(CallToolResult, Option<String>), CallToolError> {
+        self.principal.require(tool_role(name)).map_err(denied)?;
+
+        match name {
             "list_projects" => {
-                let repos = crate::handlers::repository::list_repositories_inner(&self.state.db)
-                    .await
-                    .map_err(|e| CallToolError::from_message(e.to_string()))?;
+                let mut repos =
+                    crate::handlers::repository::list_repositories_inner(&self.state.db)
+                        .await
+                        .map_err(|e| CallToolError::from_message(e.to_string()))?;
+                repos.retain(|repo| self.principal.can_access_repository(&repo.path));
 
                 let text = serde_json::to_string_pretty(&repos).unwrap_or_default();
-                Ok(CallToolResult::text_content(vec![text.into()]))
+                Ok((CallToolResult::text_content(vec![text.into()]), None))
             }
             "list_servers" => {
                 let servers = sqlx::query_as::<_, crate::models::Server>("SELECT * FROM servers")
@@ -96,6 +252,7 @@ impl AppHandler {
 
                 let safe_servers: Vec<serde_json::Value> = servers
                     .into_iter()
+                    .filter(|s| self.principal.can_access_server(s.id))
                     .map(|s| {
                         serde_json::json!({
                             "id": s.id,
@@ -107,465 +264,237 @@ impl AppHandler {
                     .collect();
 
                 let text = serde_json::to_string_pretty(&safe_servers).unwrap_or_default();
-                Ok(CallToolResult::text_content(vec![text.into()]))
+                Ok((CallToolResult::text_content(vec![text.into()]), None))
             }
             "build_image" => {
-                info!("build_image: Parsing args for");
                 let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
                 let args: BuildImageTool = serde_json::from_value(args_val).map_err(|e| {
                     CallToolError::from_message(format!("Invalid parameters: {}", e))
                 })?;
-                info!("build_image: Parsed args: {}", args.path);
-
-                // Fire and forget
-                let state_clone = self.state.clone();
-                let args_clone = args;
-
-                info!("build_image: Spawning background task");
-                tokio::spawn(async move {
-                    info!(
-                        "task: Starting background build for path: {}",
-                        args_clone.path
-                    );
-
-                    // 1. Verify path exists in DB
-                    info!("task: Verifying path in DB");
-                    let mut repo = sqlx::query_as::<_, Repository>(
-                        "SELECT * FROM repositories WHERE path = ?",
-                    )
-                    .bind(&args_clone.path)
-                    .fetch_optional(&*state_clone.db)
-                    .await
-                    .unwrap_or(None);
-
-                    if repo.is_none() {
-                        info!("task: Exact match not found, trying smart search");
-                        // Fetch all and search manually
-                        if let Ok(all_repos) =
-                            sqlx::query_as::<_, Repository>("SELECT * FROM repositories")
-                                .fetch_all(&*state_clone.db)
-                                .await
-                        {
-                            let search_term = args_clone.path.to_lowercase().replace("\\", "/");
-                            let search_term_trim = search_term.trim_end_matches('/');
-
-                            for r in all_repos {
-                                let r_path = r.path.to_lowercase().replace("\\", "/");
-                                let r_path_trim = r_path.trim_end_matches('/');
-
-                                // 1. Path match
-                                if r_path_trim == search_term_trim {
-                                    repo = Some(r);
-                                    break;
-                                }
-
-                                // 2. Name match
-                                if let Some(n) = &r.name {
-                                    if n.to_lowercase() == search_term_trim {
-                                        repo = Some(r);
-                                        break;
-                                    }
-                                }
-
-                                // 3. Folder name match (ends with /search_term)
-                                if r_path_trim.ends_with(&format!("/{}", search_term_trim)) {
-                                    repo = Some(r);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-
-                    let repo = match repo {
-                        Some(r) => {
-                            info!("task: Found repo in DB: {} (id: {})", r.path, r.id);
-                            r
-                        }
-                        None => {
-                            info!("Build failed: Path not configured: {}", args_clone.path);
-                            return;
-                        }
-                    };
-
-                    // 2. Determine image name
-                    let image_name = repo.docker_image_name.clone().unwrap_or_else(|| {
-                        std::path::Path::new(&repo.path)
-                            .file_name()
-                            .map(|s| s.to_string_lossy().to_string())
-                            .unwrap_or_else(|| "unknown".to_string())
-                    });
-
-                    // 3. Determine version (auto-increment)
-                    let tags = match docker::list_tags(&image_name).await {
-                        Ok(t) => t,
-                        Err(e) => {
-                            info!("Build failed: List tags error: {}", e);
-                            return;
-                        }
-                    };
-
-                    let version_regex = Regex::new(r"^v?(\d+)(\.(\d+))?(\.(\d+))?$").unwrap();
-                    let mut max_ver = (0, 0, 0); // major, minor, patch
-                    let mut max_int_ver = 0;
-                    let mut found_semver = false;
-
-                    for img in tags {
-                        for tag in img.tags {
-                            if let Some(pos) = tag.rfind(':') {
-                                let ver_str = &tag[pos + 1..];
-                                if let Some(caps) = version_regex.captures(ver_str) {
-                                    let major =
-                                        caps.get(1).map_or(0, |m| m.as_str().parse().unwrap_or(0));
-                                    let minor =
-                                        caps.get(3).map_or(0, |m| m.as_str().parse().unwrap_or(0));
-                                    let patch =
-                                        caps.get(5).map_or(0, |m| m.as_str().parse().unwrap_or(0));
-
-                                    // Poor man's version compare
-                                    if major > max_ver.0
-                                        || (major == max_ver.0 && minor > max_ver.1)
-                                        || (major == max_ver.0
-                                            && minor == max_ver.1
-                                            && patch > max_ver.2)
-                                    {
-                                        max_ver = (major, minor, patch);
-                                        found_semver = true;
-                                    }
-                                } else if let Ok(v) = ver_str.parse::<i32>() {
-                                    if v > max_int_ver {
-                                        max_int_ver = v;
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    let new_version = if found_semver {
-                        format!("v{}.{}.{}", max_ver.0, max_ver.1, max_ver.2 + 1)
-                    } else if max_int_ver > 0 {
-                        format!("{}", max_int_ver + 1)
-                    } else {
-                        "v1.0.0".to_string()
-                    };
-
-                    let req = docker::DockerBuildRequest {
-                        path: repo.path.clone(),
-                        image_name: image_name.clone(),
-                        version: new_version.clone(),
-                    };
-
-                    match docker::build_image(req).await {
-                        Ok(_) => info!(
-                            "Background Build Successful for {}:{}",
-                            image_name, new_version
-                        ),
-                        Err(e) => info!(
-                            "Background Build Failed for {}:{}: {}",
-                            image_name, new_version, e
-                        ),
-                    }
-                    info!("task: Background build finished");
-                });
-
-                info!("build_image: Returning immediate success");
-                Ok(CallToolResult::text_content(vec![
-                    "Build started in background.".into(),
-                ]))
+                self.principal.require_path(&args.path).map_err(denied)?;
+
+                let job_id = pipeline::start(
+                    &self.state.jobs,
+                    pipeline::KIND_BUILD,
+                    serde_json::json!({ "path": args.path }),
+                    TRIGGER_MCP,
+                    None,
+                )
+                .await
+                .map_err(|e| CallToolError::from_message(e.to_string()))?;
+
+                let text = format!(
+                    "Build started in background. Job ID: {} (use get_job_status to follow it)",
+                    job_id
+                );
+                Ok((
+                    CallToolResult::text_content(vec![text.into()]),
+                    Some(job_id),
+                ))
             }
             "build_and_deploy" => {
                 let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
                 let args: BuildAndDeployTool = serde_json::from_value(args_val).map_err(|e| {
                     CallToolError::from_message(format!("Invalid parameters: {}", e))
                 })?;
+                auth::authorize_deploy(&self.state.db, &self.principal, &args.path)
+                    .await
+                    .map_err(denied)?;
+
+                let dry_run = args.dry_run.unwrap_or(false);
+                let params = DeployParams {
+                    path: args.path,
+                    service: args.service,
+                    version: args.version,
+                    token: args.token,
+                };
+
+                if dry_run {
+                    let preview = pipeline::preview_deploy(&self.state.db, &params)
+                        .await
+                        .map_err(|e| CallToolError::from_message(e.to_string()))?;
+                    let text = serde_json::to_string_pretty(&preview).unwrap_or_default();
+                    return Ok((CallToolResult::text_content(vec![text.into()]), None));
+                }
+
+                let job_id =
+                    pipeline::start_deploy(&self.state.jobs, &self.state.db, params, TRIGGER_MCP)
+                        .await
+                        .map_err(|e| CallToolError::from_message(e.to_string()))?;
+
+                let text = format!(
+                    "Build and Deploy started in background. Job ID: {} (use get_job_status to follow it)",
+                    job_id
+                );
+                Ok((
+                    CallToolResult::text_content(vec![text.into()]),
+                    Some(job_id),
+                ))
+            }
+            "rollback" => {
+                let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
+                let args: RollbackTool = serde_json::from_value(args_val).map_err(|e| {
+                    CallToolError::from_message(format!("Invalid parameters: {}", e))
+                })?;
 
-                // Fire and forget
-                let state_clone = self.state.clone();
-                let args_clone = args;
-
-                tokio::spawn(async move {
-                    info!(
-                        "Starting background build_and_deploy for path: {}",
-                        args_clone.path
-                    );
-
-                    // 1. Verify path exists in DB
-                    let mut repo = sqlx::query_as::<_, Repository>(
-                        "SELECT * FROM repositories WHERE path = ?",
-                    )
-                    .bind(&args_clone.path)
-                    .fetch_optional(&*state_clone.db)
+                let repo = pipeline::resolve_repository(&self.state.db, &args.path)
                     .await
-                    .unwrap_or(None);
-
-                    if repo.is_none() {
-                        info!("task: Exact match not found, trying smart search");
-                        // Fetch all and search manually
-                        if let Ok(all_repos) =
-                            sqlx::query_as::<_, Repository>("SELECT * FROM repositories")
-                                .fetch_all(&*state_clone.db)
-                                .await
-                        {
-                            let search_term = args_clone.path.to_lowercase().replace("\\", "/");
-                            let search_term_trim = search_term.trim_end_matches('/');
-
-                            for r in all_repos {
-                                let r_path = r.path.to_lowercase().replace("\\", "/");
-                                let r_path_trim = r_path.trim_end_matches('/');
-
-                                // 1. Path match
-                                if r_path_trim == search_term_trim {
-                                    repo = Some(r);
-                                    break;
-                                }
-
-                                // 2. Name match
-                                if let Some(n) = &r.name {
-                                    if n.to_lowercase() == search_term_trim {
-                                        repo = Some(r);
-                                        break;
-                                    }
-                                }
-
-                                // 3. Folder name match (ends with /search_term)
-                                if r_path_trim.ends_with(&format!("/{}", search_term_trim)) {
-                                    repo = Some(r);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-
-                    let repo = match repo {
-                        Some(r) => {
-                            info!("task: Found repo in DB: {} (id: {})", r.path, r.id);
-                            r
-                        }
-                        None => {
-                            info!("Deploy failed: Path not configured: {}", args_clone.path);
-                            return;
-                        }
-                    };
-
-                    let server_id = match repo.default_server_id {
-                        Some(id) => id,
-                        None => {
-                            info!("Deploy failed: Default server not configured");
-                            return;
-                        }
-                    };
-
-                    let compose_path = match repo.default_compose_path {
-                        Some(p) => p,
-                        None => {
-                            info!("Deploy failed: Default compose path not configured");
-                            return;
-                        }
-                    };
-
-                    // 2. SemVer Logic
-                    let image_name = repo.docker_image_name.clone().unwrap_or_else(|| {
-                        std::path::Path::new(&repo.path)
-                            .file_name()
-                            .map(|s| s.to_string_lossy().to_string())
-                            .unwrap_or_else(|| "unknown".to_string())
-                    });
-
-                    let tags = match docker::list_tags(&image_name).await {
-                        Ok(t) => t,
-                        Err(e) => {
-                            info!("Deploy failed: List tags error: {}", e);
-                            return;
-                        }
-                    };
-
-                    let version_regex = Regex::new(r"^v?(\d+)(\.(\d+))?(\.(\d+))?$").unwrap();
-                    let mut max_ver = (0, 0, 0); // major, minor, patch
-                    let mut max_int_ver = 0;
-                    let mut found_semver = false;
-
-                    for img in tags {
-                        for tag in img.tags {
-                            if let Some(pos) = tag.rfind(':') {
-                                let ver_str = &tag[pos + 1..];
-                                if let Some(caps) = version_regex.captures(ver_str) {
-                                    let major =
-                                        caps.get(1).map_or(0, |m| m.as_str().parse().unwrap_or(0));
-                                    let minor =
-                                        caps.get(3).map_or(0, |m| m.as_str().parse().unwrap_or(0));
-                                    let patch =
-                                        caps.get(5).map_or(0, |m| m.as_str().parse().unwrap_or(0));
-
-                                    // Poor man's version compare
-                                    if major > max_ver.0
-                                        || (major == max_ver.0 && minor > max_ver.1)
-                                        || (major == max_ver.0
-                                            && minor == max_ver.1
-                                            && patch > max_ver.2)
-                                    {
-                                        max_ver = (major, minor, patch);
-                                        found_semver = true;
-                                    }
-                                } else if let Ok(v) = ver_str.parse::<i32>() {
-                                    if v > max_int_ver {
-                                        max_int_ver = v;
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    let new_version = if found_semver {
-                        format!("v{}.{}.{}", max_ver.0, max_ver.1, max_ver.2 + 1)
-                    } else if max_int_ver > 0 {
-                        format!("{}", max_int_ver + 1)
-                    } else {
-                        "v1.0.0".to_string()
-                    };
-
-                    // 3. Build
-                    let req = docker::DockerBuildRequest {
-                        path: repo.path.clone(),
-                        image_name: image_name.clone(),
-                        version: new_version.clone(),
-                    };
-                    if let Err(e) = docker::build_image(req).await {
-                        info!("Deploy failed: Build error: {}", e);
-                        return;
-                    }
-
-                    // 4. Push
-                    let full_tag = format!("{}:{}", image_name, new_version);
-                    if let Err(e) =
-                        deploy::push_image_to_server_inner(&state_clone.db, server_id, &full_tag)
-                            .await
-                    {
-                        info!("Deploy failed: Push error: {}", e);
-                        return;
-                    }
-
-                    // 5. Deploy (Update Compose)
-                    let server = match sqlx::query_as::<_, crate::models::Server>(
-                        "SELECT * FROM servers WHERE id = ?",
-                    )
-                    .bind(server_id)
-                    .fetch_one(&*state_clone.db)
+                    .map_err(|e| CallToolError::from_message(e.to_string()))?;
+                let targets = targets::list(&self.state.db, &repo)
                     .await
-                    {
-                        Ok(s) => s,
-                        Err(e) => {
-                            info!("Deploy failed: Fetch server error: {}", e);
-                            return;
-                        }
-                    };
-
-                    // Find stack name by matching compose path
-                    let composes = match OnePanelClient::list_composes(
-                        &server.host,
-                        server.port,
-                        &server.api_key,
-                    )
+                    .map_err(|e| CallToolError::from_message(e.to_string()))?;
+                if targets.is_empty() {
+                    return Err(CallToolError::from_message(
+                        "No deploy target configured for this project",
+                    ));
+                }
+                self.principal
+                    .require_repository(&repo.path)
+                    .and_then(|()| {
+                        targets
+                            .iter()
+                            .try_for_each(|t| self.principal.require_server(t.server_id))
+                    })
+                    .map_err(denied)?;
+
+                let job_id = pipeline::start(
+                    &self.state.jobs,
+                    pipeline::KIND_ROLLBACK_REPOSITORY,
+                    serde_json::json!({ "path": repo.path }),
+                    TRIGGER_MCP,
+                    None,
+                )
+                .await
+                .map_err(|e| CallToolError::from_message(e.to_string()))?;
+
+                let text = format!(
+                    "Rollback of {} target(s) started in background. Job ID: {} (use get_job_status to follow it)",
+                    targets.len(),
+                    job_id
+                );
+                Ok((
+                    CallToolResult::text_content(vec![text.into()]),
+                    Some(job_id),
+                ))
+            }
+            "promote" => {
+                let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
+                let args: PromoteTool = serde_json::from_value(args_val).map_err(|e| {
+                    CallToolError::from_message(format!("Invalid parameters: {}", e))
+                })?;
+                auth::authorize_promote(
+                    &self.state.db,
+                    &self.principal,
+                    &args.path,
+                    &args.from,
+                    args.to.as_deref(),
+                )
+                .await
+                .map_err(denied)?;
+
+                let promotion = pipeline::promote(
+                    &self.state.jobs,
+                    &self.state.db,
+                    &args.path,
+                    &args.from,
+                    args.to.as_deref(),
+                    TRIGGER_MCP,
+                )
+                .await
+                .map_err(|e| CallToolError::from_message(e.to_string()))?;
+
+                let text = match &promotion.job_id {
+                    Some(job_id) => format!(
+                        "Promoting {} from {} to {}. Job ID: {} (use get_job_status to follow it)",
+                        promotion.image, promotion.from_env, promotion.to_env, job_id
+                    ),
+                    None => format!(
+                        "Promotion {} of {} to {} is waiting for approval",
+                        promotion.id, promotion.image, promotion.to_env
+                    ),
+                };
+                Ok((
+                    CallToolResult::text_content(vec![text.into()]),
+                    promotion.job_id,
+                ))
+            }
+            "get_job_status" => {
+                let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
+                let args: GetJobStatusTool = serde_json::from_value(args_val).map_err(|e| {
+                    CallToolError::from_message(format!("Invalid parameters: {}", e))
+                })?;
+
+                let job = self
+                    .state
+                    .jobs
+                    .get(&args.job_id)
                     .await
-                    {
-                        Ok(c) => c,
-                        Err(e) => {
-                            info!("Deploy failed: List composes error: {}", e);
-                            return;
-                        }
-                    };
-
-                    let mut stack_name = None;
-                    for item in composes {
-                        if let Some(path) = item.get("path").and_then(|s| s.as_str()) {
-                            if path == compose_path {
-                                if let Some(name) = item.get("name").and_then(|s| s.as_str()) {
-                                    stack_name = Some(name.to_string());
-                                    break;
-                                }
-                            }
-                        }
-                    }
-
-                    let stack_name = match stack_name {
-                        Some(n) => n,
-                        None => {
-                            info!(
-                                "Deploy failed: Could not find 1Panel stack for path: {}",
-                                compose_path
-                            );
-                            return;
-                        }
-                    };
-
-                    // Read File
-                    let content = match OnePanelClient::read_file(
-                        &server.host,
-                        server.port,
-                        &server.api_key,
-                        &compose_path,
-                    )
+                    .map_err(|e| CallToolError::from_message(e.to_string()))?
+                    .ok_or_else(|| {
+                        CallToolError::from_message(format!("Job not found: {}", args.job_id))
+                    })?;
+                self.principal
+                    .require_job(&job.job, false)
+                    .map_err(denied)?;
+
+                let text = serde_json::to_string_pretty(&job).unwrap_or_default();
+                Ok((CallToolResult::text_content(vec![text.into()]), None))
+            }
+            "cancel_job" => {
+                let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
+                let args: CancelJobTool = serde_json::from_value(args_val).map_err(|e| {
+                    CallToolError::from_message(format!("Invalid parameters: {}", e))
+                })?;
+                let job = self
+                    .state
+                    .jobs
+                    .get(&args.job_id)
                     .await
-                    {
-                        Ok(c) => c,
-                        Err(e) => {
-                            info!("Deploy failed: Read file error: {}", e);
-                            return;
-                        }
-                    };
-
-                    // Replace Image Tag
-                    let pattern = format!(r"(image:\s*{}:)[\w.-]+", regex::escape(&image_name));
-                    let re = match Regex::new(&pattern) {
-                        Ok(r) => r,
-                        Err(e) => {
-                            info!("Deploy failed: Regex error: {}", e);
-                            return;
-                        }
-                    };
-                    let new_line = format!("${{1}}{}", new_version);
-                    let new_content = re.replace_all(&content, new_line.as_str());
-
-                    // Update Compose
-                    if let Err(e) = OnePanelClient::update_compose(
-                        &server.host,
-                        server.port,
-                        &server.api_key,
-                        &stack_name,
-                        &compose_path,
-                        &new_content,
-                    )
+                    .map_err(|e| CallToolError::from_message(e.to_string()))?
+                    .ok_or_else(|| {
+                        CallToolError::from_message(format!("Job not found: {}", args.job_id))
+                    })?;
+                self.principal.require_job(&job.job, true).map_err(denied)?;
+
+                if !self.state.jobs.cancel(&args.job_id).await {
+                    return Err(CallToolError::from_message(format!(
+                        "Job {} is not running",
+                        args.job_id
+                    )));
+                }
+
+                let text = format!("Cancellation requested for job {}", args.job_id);
+                Ok((CallToolResult::text_content(vec![text.into()]), None))
+            }
+            "retry_job" => {
+                let args_val = serde_json::Value::Object(arguments.unwrap_or_default());
+                let args: RetryJobTool = serde_json::from_value(args_val).map_err(|e| {
+                    CallToolError::from_message(format!("Invalid parameters: {}", e))
+                })?;
+
+                let job = self
+                    .state
+                    .jobs
+                    .get(&args.job_id)
                     .await
-                    {
-                        info!("Deploy failed: Update compose error: {}", e);
-                        return;
-                    }
-
-                    // Restart
-                    if let Err(e) = OnePanelClient::operate_compose(
-                        &server.host,
-                        server.port,
-                        &server.api_key,
-                        &stack_name,
-                        &compose_path,
-                        "up",
-                    )
+                    .map_err(|e| CallToolError::from_message(e.to_string()))?
+                    .ok_or_else(|| {
+                        CallToolError::from_message(format!("Job not found: {}", args.job_id))
+                    })?;
+                self.principal.require_job(&job.job, true).map_err(denied)?;
+
+                let job_id = pipeline::retry(&self.state.jobs, &job.job, TRIGGER_MCP)
                     .await
-                    {
-                        info!("Deploy failed: Operate compose error: {}", e);
-                        return;
-                    }
-
-                    info!(
-                        "Background Deploy Successful for {}:version {}",
-                        server.name, new_version
-                    );
-                });
-
-                Ok(CallToolResult::text_content(vec![
-                    "Build and Deploy started in background.".into(),
-                ]))
+                    .map_err(|e| CallToolError::from_message(e.to_string()))?;
+
+                let text = format!("Retry started in background. Job ID: {}", job_id);
+                Ok((
+                    CallToolResult::text_content(vec![text.into()]),
+                    Some(job_id),
+                ))
             }
 
-            _ => Err(CallToolError::unknown_tool(name)),
+            _ => Err(CallToolError::unknown_tool(name.to_string())),
         }
     }
 }
@@ -594,3 +523,52 @@ impl ServerHandler for AppHandler {
         self.call_tool(params.name, params.arguments).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthMethod;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn records_how_started_jobs_end() {
+        let state = AppState::new(crate::db::memory().await, Vec::new(), Vec::new());
+        let handler = AppHandler {
+            state: state.clone(),
+            principal: Principal {
+                user_id: 1,
+                username: "admin".to_string(),
+                method: AuthMethod::Session,
+                role: Role::Admin,
+                servers: None,
+                repositories: None,
+            },
+            session_id: Some("session-1".to_string()),
+        };
+        let arguments = json!({ "path": "/src/missing" }).as_object().cloned();
+
+        handler
+            .call_tool("build_image".to_string(), arguments)
+            .await
+            .unwrap();
+
+        let mut entries: Vec<(bool, Option<String>, Option<String>)> = Vec::new();
+        for _ in 0..100 {
+            entries = sqlx::query_as("SELECT success, error, job_id FROM audit_log ORDER BY id")
+                .fetch_all(&*state.db)
+                .await
+                .unwrap();
+            if entries.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(entries.len(), 2, "{:?}", entries);
+        let (started, ended) = (&entries[0], &entries[1]);
+        assert!(started.0);
+        assert!(started.2.is_some());
+        assert!(!ended.0);
+        assert!(ended.1.is_some());
+        assert_eq!(ended.2, started.2);
+    }
+}
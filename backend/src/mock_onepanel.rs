@@ -0,0 +1,363 @@
+//! Stand-in for a 1Panel server in tests: answers the API routes the client uses
+//! from in-memory state, and records the requests it gets.
+
+use crate::models::{Compose, Container, FileInfo, Image, Server};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+pub struct MockState {
+    /// Requests received, as the route after `api/<version>/` and the body
+    pub requests: Vec<(String, String)>,
+    /// Container listings returned by successive searches; the last one repeats
+    pub containers: VecDeque<Vec<Container>>,
+    pub composes: Vec<Compose>,
+    /// Image listings returned by successive calls; the last one repeats
+    pub images: VecDeque<Vec<Image>>,
+    /// File contents by path
+    pub files: HashMap<String, Vec<u8>>,
+    /// Raw responses (status and body) returned ahead of the normal handling, by route;
+    /// `None` lets a request through to it
+    pub scripted: HashMap<String, VecDeque<Option<(StatusCode, String)>>>,
+    /// Seconds the mock's clock is ahead of the real one. Tokens must be
+    /// timestamped with the mock's clock, which is sent in the `Date` header.
+    pub clock_skew: Option<i64>,
+    chunks: HashMap<String, BTreeMap<u64, Vec<u8>>>,
+}
+
+impl MockState {
+    /// Requests made to `route`.
+    pub fn calls(&self, route: &str) -> usize {
+        self.requests.iter().filter(|(r, _)| r == route).count()
+    }
+
+    /// Answers the next request to `route` with `status` and `body`.
+    pub fn script(&mut self, route: &str, status: StatusCode, body: &str) {
+        self.scripted
+            .entry(route.to_string())
+            .or_default()
+            .push_back(Some((status, body.to_string())));
+    }
+
+    /// Handles the next request to `route` as usual, ahead of what is scripted after.
+    pub fn pass(&mut self, route: &str) {
+        self.scripted
+            .entry(route.to_string())
+            .or_default()
+            .push_back(None);
+    }
+}
+
+pub struct MockPanel {
+    pub server: Server,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockPanel {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new().fallback(handle).with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        MockPanel {
+            server: Server {
+                id: 1,
+                name: "mock".to_string(),
+                host: "127.0.0.1".to_string(),
+                port,
+                api_key: "mock-key".to_string(),
+                request_timeout_secs: Some(5),
+                upload_timeout_secs: Some(5),
+                scheme: Some("http".to_string()),
+                ca_cert: None,
+                tls_fingerprint: None,
+                accept_invalid_certs: false,
+                api_version: Some("v1".to_string()),
+                base_path: None,
+                compression: None,
+                compression_level: None,
+                upload_dir: None,
+            },
+            state,
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Image as listed by `containers/image/all`.
+pub fn image(tag: &str) -> Image {
+    Image {
+        id: format!("sha256:{}", tag),
+        tags: vec![tag.to_string()],
+        ..Default::default()
+    }
+}
+
+/// Running container as listed by `containers/search`.
+pub fn container(name: &str, state: &str, status: &str) -> Container {
+    Container {
+        name: name.to_string(),
+        state: state.to_string(),
+        run_time: status.to_string(),
+        ..Default::default()
+    }
+}
+
+fn ok(data: Value) -> Response {
+    axum::Json(json!({ "code": 200, "message": "", "data": data })).into_response()
+}
+
+/// Returns the next of a series of listings, keeping the last one.
+fn next<T: Clone>(queue: &mut VecDeque<T>) -> Option<T> {
+    if queue.len() > 1 {
+        queue.pop_front()
+    } else {
+        queue.front().cloned()
+    }
+}
+
+async fn handle(
+    State(state): State<Arc<Mutex<MockState>>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let route = uri
+        .path()
+        .trim_start_matches("/api/")
+        .split_once('/')
+        .map(|(_, route)| route.to_string())
+        .unwrap_or_default();
+    let mut state = state.lock().unwrap();
+    state
+        .requests
+        .push((route.clone(), String::from_utf8_lossy(&body).to_string()));
+
+    let mut res = respond(&mut state, &route, &uri, &headers, &body);
+    if let Some(skew) = state.clock_skew {
+        let date = chrono::Utc::now() + chrono::Duration::seconds(skew);
+        res.headers_mut().insert(
+            header::DATE,
+            date.to_rfc2822().replace("+0000", "GMT").parse().unwrap(),
+        );
+    }
+    res
+}
+
+fn respond(
+    state: &mut MockState,
+    route: &str,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Response {
+    if let Some(skew) = state.clock_skew {
+        let timestamp = headers
+            .get("1Panel-Timestamp")
+            .and_then(|t| t.to_str().ok()?.parse::<i64>().ok())
+            .unwrap_or_default();
+        if (timestamp - (chrono::Utc::now().timestamp() + skew)).abs() > 1 {
+            return (StatusCode::UNAUTHORIZED, "token expired").into_response();
+        }
+    }
+    if let Some(Some((status, body))) = state.scripted.get_mut(route).and_then(VecDeque::pop_front)
+    {
+        return (status, body).into_response();
+    }
+
+    let json: Value = serde_json::from_slice(body).unwrap_or_default();
+    let path = json["path"].as_str().unwrap_or_default().to_string();
+    match route {
+        "dashboard/base/os" => ok(json!({})),
+        "containers/search" => {
+            let containers = next(&mut state.containers).unwrap_or_default();
+            let page = json["page"].as_u64().unwrap_or(1).max(1) as usize;
+            let size = json["pageSize"].as_u64().unwrap_or(100).max(1) as usize;
+            let items: Vec<&Container> = containers
+                .iter()
+                .skip((page - 1) * size)
+                .take(size)
+                .collect();
+            ok(json!({ "total": containers.len(), "items": items }))
+        }
+        "containers/compose/search" => {
+            ok(json!({ "total": state.composes.len(), "items": state.composes }))
+        }
+        "containers/compose/update" => {
+            let content = json["content"]
+                .as_str()
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec();
+            state.files.insert(path, content);
+            ok(Value::Null)
+        }
+        "files/save" => {
+            let content = json["content"]
+                .as_str()
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec();
+            state.files.insert(path, content);
+            ok(Value::Null)
+        }
+        "files/content" => match state.files.get(&path) {
+            Some(content) => ok(json!(FileInfo {
+                path,
+                content: String::from_utf8_lossy(content).to_string(),
+                ..Default::default()
+            })),
+            None => ok_error("file not found"),
+        },
+        "files/search" => match state.files.get(&path) {
+            Some(content) => ok(json!(FileInfo {
+                size: content.len() as i64,
+                path,
+                ..Default::default()
+            })),
+            None => ok_error("file not found"),
+        },
+        "files/download" => {
+            let path = uri
+                .query()
+                .and_then(|q| q.strip_prefix("path="))
+                .map(|p| p.replace("%2F", "/"))
+                .unwrap_or_default();
+            match state.files.get(&path) {
+                Some(content) => content.clone().into_response(),
+                None => ok_error("file not found"),
+            }
+        }
+        "containers/image/save" => {
+            let archive = format!("{}/{}.tar", path, json["name"].as_str().unwrap_or_default());
+            let content = format!(
+                "archive of {}",
+                json["tagName"].as_str().unwrap_or_default()
+            );
+            state.files.insert(archive, content.into_bytes());
+            ok(Value::Null)
+        }
+        "files/del" => {
+            state.files.remove(&path);
+            ok(Value::Null)
+        }
+        "files/upload" => {
+            let form = multipart(headers, body);
+            let (file_name, content) = form.get("file").cloned().unwrap_or_default();
+            let dir = text(&form, "path");
+            state.files.insert(
+                format!("{}/{}", dir.trim_end_matches('/'), file_name),
+                content,
+            );
+            ok(Value::Null)
+        }
+        "files/chunkupload" => {
+            let form = multipart(headers, body);
+            let (file_name, chunk) = form.get("chunk").cloned().unwrap_or_default();
+            let dir = text(&form, "path");
+            let target = format!("{}/{}", dir.trim_end_matches('/'), file_name);
+            let index: u64 = text(&form, "chunkIndex").parse().unwrap_or_default();
+            let count: u64 = text(&form, "chunkCount").parse().unwrap_or(1);
+            let chunks = state.chunks.entry(target.clone()).or_default();
+            chunks.insert(index, chunk);
+            if chunks.len() as u64 == count {
+                let joined = state.chunks.remove(&target).unwrap_or_default();
+                state
+                    .files
+                    .insert(target, joined.into_values().flatten().collect());
+                return ok(Value::Null);
+            }
+            // 1Panel answers only the last chunk
+            StatusCode::OK.into_response()
+        }
+        "containers/image/all" => ok(json!(next(&mut state.images).unwrap_or_default())),
+        "containers/image/load" => {
+            // Archives in tests are `image <tag>`; anything else loads nothing
+            let tag = state.files.get(&path).and_then(|content| {
+                Some(
+                    std::str::from_utf8(content)
+                        .ok()?
+                        .strip_prefix("image ")?
+                        .to_string(),
+                )
+            });
+            if let Some(tag) = tag {
+                if state.images.is_empty() {
+                    state.images.push_back(Vec::new());
+                }
+                for listing in state.images.iter_mut() {
+                    listing.push(image(&tag));
+                }
+            }
+            ok(Value::Null)
+        }
+        "containers/image/tag" | "containers/operate" | "containers/compose/operate" => {
+            ok(Value::Null)
+        }
+        _ => (StatusCode::NOT_FOUND, "404 page not found").into_response(),
+    }
+}
+
+fn ok_error(message: &str) -> Response {
+    axum::Json(json!({ "code": 500, "message": message, "data": null })).into_response()
+}
+
+fn text(form: &HashMap<String, (String, Vec<u8>)>, name: &str) -> String {
+    form.get(name)
+        .map(|(_, value)| String::from_utf8_lossy(value).to_string())
+        .unwrap_or_default()
+}
+
+/// Fields of a `multipart/form-data` body by name, as the file name (empty for
+/// plain fields) and the content.
+fn multipart(headers: &HeaderMap, body: &[u8]) -> HashMap<String, (String, Vec<u8>)> {
+    let boundary = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split("boundary=").nth(1))
+        .unwrap_or_default();
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut fields = HashMap::new();
+
+    let mut rest = body;
+    while let Some(start) = find(rest, &delimiter) {
+        rest = &rest[start + delimiter.len()..];
+        let Some(end) = find(rest, &delimiter) else {
+            break;
+        };
+        let part = &rest[..end];
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        if let Some(split) = find(part, b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&part[..split]).to_string();
+            let attr = |key: &str| {
+                head.split(&format!("{}=\"", key))
+                    .nth(1)
+                    .and_then(|v| v.split('"').next())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            fields.insert(attr("name"), (attr("filename"), part[split + 4..].to_vec()));
+        }
+    }
+    fields
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -12,17 +12,156 @@ pub struct Server {
     // Don't expose API key in list response by default? Or maybe user needs to see it? For security, skip.
     pub api_key: String,
     // created_at is strictly DB managed for now, or fetch if needed
+    /// Timeout of 1Panel API calls; the client default when unset
+    pub request_timeout_secs: Option<i64>,
+    /// Timeout of image uploads and loads; the client default when unset
+    pub upload_timeout_secs: Option<i64>,
+    /// `http` or `https`; inferred from the host when unset
+    pub scheme: Option<String>,
+    /// PEM CA bundle to trust for this server
+    pub ca_cert: Option<String>,
+    /// Pinned SHA-256 certificate fingerprint
+    pub tls_fingerprint: Option<String>,
+    pub accept_invalid_certs: bool,
+    /// 1Panel API version (`v1` or `v2`), detected when the server is saved
+    pub api_version: Option<String>,
+    /// Path prefix in front of `/api`, e.g. a reverse proxy location
+    pub base_path: Option<String>,
+    /// How image archives are compressed for upload: `none`, `gzip` or `zstd`
+    pub compression: Option<String>,
+    /// Compression level; the algorithm's default when unset
+    pub compression_level: Option<i64>,
+    /// Directory on the server image archives are uploaded to; `/opt/1panel/tmp` when unset
+    pub upload_dir: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+// 1Panel API responses. 1Panel is written in Go, which encodes empty lists as
+// `null`, so list fields go through `null_as_default`.
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Accepts sizes that 1Panel v1 sends as text and v2 as a number of bytes.
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(s)) => s,
+            Some(serde_json::Value::Null) | None => String::new(),
+            Some(other) => other.to_string(),
+        },
+    )
+}
+
+/// One page of a 1Panel search endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct PageResult<T> {
+    #[serde(default)]
+    pub total: i64,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub items: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Container {
-    pub id: String,
+    #[serde(rename = "containerID")]
+    pub container_id: String,
     pub name: String,
-    pub image: String,
+    pub image_id: String,
+    pub image_name: String,
+    pub create_time: String,
+    /// Docker state: `running`, `exited`, `created`, `restarting`, ...
     pub state: String,
-    pub status: String,
-    // Add more fields as we identify them from the 1Panel response
-    // For now, this is a best-guess structure
+    /// Docker status text, e.g. "Up 5 minutes (healthy)"
+    #[serde(alias = "status")]
+    pub run_time: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub network: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub ports: Vec<String>,
+    pub is_from_app: bool,
+    pub is_from_compose: bool,
+    pub app_name: String,
+}
+
+/// Container as listed under its compose stack.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ComposeContainer {
+    #[serde(rename = "containerID")]
+    pub container_id: String,
+    pub name: String,
+    pub create_time: String,
+    pub state: String,
+}
+
+/// Compose stack managed by 1Panel.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Compose {
+    pub name: String,
+    pub created_at: String,
+    /// `1Panel` for stacks created in the panel, `Local` for imported ones
+    pub created_by: String,
+    pub container_number: i64,
+    pub config_file: String,
+    pub workdir: String,
+    /// Path of the compose file
+    pub path: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub containers: Vec<ComposeContainer>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Image {
+    pub id: String,
+    pub created_at: String,
+    /// Whether a container uses the image
+    pub is_used: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    pub tags: Vec<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub size: String,
+}
+
+/// The parts of `docker image inspect` output used to compare image layers.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ImageInspect {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "RootFS")]
+    pub root_fs: RootFs,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RootFs {
+    /// Diff IDs of the layers, base layer first
+    #[serde(rename = "Layers", deserialize_with = "null_as_default")]
+    pub layers: Vec<String>,
+}
+
+/// File on the 1Panel host, with its content when read through `files/content`.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FileInfo {
+    pub path: String,
+    pub name: String,
+    pub content: String,
+    pub size: i64,
+    pub is_dir: bool,
+    pub mod_time: String,
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
@@ -46,6 +185,18 @@ pub struct Repository {
     pub docker_image_name: Option<String>,
     pub default_server_id: Option<i64>,
     pub default_compose_path: Option<String>,
+    /// Seconds to wait for the stack to become healthy after a deploy; 60 when unset, 0 skips the check
+    pub health_timeout_secs: Option<i64>,
+    /// URL that must answer with a 2xx status before a deploy counts as successful
+    pub health_check_url: Option<String>,
+    /// How a deploy to several targets is rolled out: `sequential` or `parallel`
+    pub deploy_strategy: Option<String>,
+    /// Targets deployed at the same time with the parallel strategy
+    pub deploy_concurrency: Option<i64>,
+    /// Seconds to wait between targets with the sequential strategy
+    pub deploy_pause_secs: Option<i64>,
+    /// Registry images are deployed through instead of being uploaded to each server
+    pub registry_id: Option<i64>,
 }
 
 #[derive(Debug, Clone, Deserialize, ToSchema)]
@@ -59,6 +210,9 @@ pub struct DirectoryResponse {
     pub docker_image_name: Option<String>,
     pub default_server_id: Option<i64>,
     pub default_compose_path: Option<String>,
+    pub health_timeout_secs: Option<i64>,
+    pub health_check_url: Option<String>,
+    pub registry_id: Option<i64>,
     pub git_status: Option<crate::git::GitStatus>,
     pub error: Option<String>,
 }
@@ -69,6 +223,37 @@ pub struct CreateServerRequest {
     pub host: String,
     pub port: u16,
     pub api_key: String,
+    #[serde(default)]
+    pub request_timeout_secs: Option<i64>,
+    #[serde(default)]
+    pub upload_timeout_secs: Option<i64>,
+    /// `http` (default) or `https`
+    #[serde(default)]
+    pub scheme: Option<String>,
+    /// PEM CA bundle to trust in addition to the system roots
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// SHA-256 fingerprint of the server certificate, e.g. from `openssl x509 -fingerprint -sha256`
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
+    /// Skip certificate verification entirely
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// `v1` or `v2`; detected from the server when omitted
+    #[serde(default)]
+    pub api_version: Option<String>,
+    /// Path prefix in front of `/api`, for panels behind a reverse proxy location
+    #[serde(default)]
+    pub base_path: Option<String>,
+    /// `none`, `gzip` (default) or `zstd`
+    #[serde(default)]
+    pub compression: Option<String>,
+    /// 1-9 for gzip, 1-22 for zstd
+    #[serde(default)]
+    pub compression_level: Option<i64>,
+    /// Absolute directory for uploaded image archives; `/opt/1panel/tmp` when omitted
+    #[serde(default)]
+    pub upload_dir: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, ToSchema)]
@@ -77,6 +262,39 @@ pub struct ServerResponse {
     pub name: String,
     pub host: String,
     pub port: u16,
+    pub request_timeout_secs: Option<i64>,
+    pub upload_timeout_secs: Option<i64>,
+    pub scheme: Option<String>,
+    pub ca_cert: Option<String>,
+    pub tls_fingerprint: Option<String>,
+    pub accept_invalid_certs: bool,
+    pub api_version: Option<String>,
+    pub base_path: Option<String>,
+    pub compression: Option<String>,
+    pub compression_level: Option<i64>,
+    pub upload_dir: Option<String>,
+}
+
+impl From<Server> for ServerResponse {
+    fn from(s: Server) -> Self {
+        ServerResponse {
+            id: s.id,
+            name: s.name,
+            host: s.host,
+            port: s.port,
+            request_timeout_secs: s.request_timeout_secs,
+            upload_timeout_secs: s.upload_timeout_secs,
+            scheme: s.scheme,
+            ca_cert: s.ca_cert,
+            tls_fingerprint: s.tls_fingerprint,
+            accept_invalid_certs: s.accept_invalid_certs,
+            api_version: s.api_version,
+            base_path: s.base_path,
+            compression: s.compression,
+            compression_level: s.compression_level,
+            upload_dir: s.upload_dir,
+        }
+    }
 }
 
 // OS Status Response (from 1Panel API)
@@ -100,6 +318,10 @@ pub struct DashboardResponse {
     pub code: i32,
     pub message: String,
     pub data: Option<OsInfo>,
+    /// Seconds the server's clock is ahead of ours (negative when behind), measured
+    /// from its `Date` header. Filled in by OnePanel, not 1Panel.
+    #[serde(default)]
+    pub clock_skew_secs: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -116,6 +338,16 @@ pub struct UpdateDockerConfigReq {
     pub docker_image_name: String,
     pub default_server_id: Option<i64>,
     pub default_compose_path: Option<String>,
+    /// Left unchanged when omitted; 0 disables post-deploy health verification, which
+    /// otherwise waits 60 seconds by default
+    #[serde(default)]
+    pub health_timeout_secs: Option<i64>,
+    /// Left unchanged when omitted; an empty string clears it
+    #[serde(default)]
+    pub health_check_url: Option<String>,
+    /// Left unchanged when omitted; 0 goes back to uploading the image to each server
+    #[serde(default)]
+    pub registry_id: Option<i64>,
 }
 
 #[derive(Debug, Clone, Deserialize, ToSchema)]
@@ -123,6 +355,53 @@ pub struct GetDockerConfigReq {
     pub path: String,
 }
 
+/// A server and compose file a repository is deployed to.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct DeployTarget {
+    /// 0 for a target that is not stored in `deploy_targets`, such as the repository's
+    /// default server and compose path
+    pub id: i64,
+    pub repository_id: i64,
+    pub server_id: i64,
+    pub compose_path: String,
+    /// Compose service to update; defaults to every service using the image
+    pub service: Option<String>,
+    pub position: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DeployTargetInput {
+    pub server_id: i64,
+    pub compose_path: String,
+    #[serde(default)]
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct UpdateDeployTargetsReq {
+    pub path: String,
+    /// Deployed in this order with the sequential strategy
+    pub targets: Vec<DeployTargetInput>,
+    /// `sequential` (default) or `parallel`
+    #[serde(default)]
+    pub strategy: Option<String>,
+    /// Maximum targets deployed at once with the parallel strategy
+    #[serde(default)]
+    pub concurrency: Option<i64>,
+    /// Pause between targets with the sequential strategy
+    #[serde(default)]
+    pub pause_secs: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DeployTargetsResponse {
+    pub path: String,
+    pub strategy: String,
+    pub concurrency: i64,
+    pub pause_secs: i64,
+    pub targets: Vec<DeployTarget>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
 pub struct Notification {
     pub id: String,
@@ -147,3 +426,374 @@ pub struct CreateNotificationReq {
     pub duration: Option<i64>,
     pub server_name: Option<String>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    pub status: String,
+    pub repository_path: Option<String>,
+    pub image_tag: Option<String>,
+    pub server_id: Option<i64>,
+    pub error: Option<String>,
+    /// JSON parameters the job was started with
+    pub params: Option<String>,
+    /// ID of the job this one retries
+    pub retry_of: Option<String>,
+    /// What started the job (api, mcp)
+    pub triggered_by: Option<String>,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct JobStep {
+    pub id: i64,
+    pub job_id: String,
+    pub name: String,
+    pub status: String,
+    pub error: Option<String>,
+    /// JSON output, reused when the job is retried
+    pub output: Option<String>,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobDetail {
+    #[serde(flatten)]
+    pub job: Job,
+    pub steps: Vec<JobStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct JobLogLine {
+    pub id: i64,
+    pub job_id: String,
+    pub line: String,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct JobStarted {
+    pub job_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Deployment {
+    pub id: i64,
+    pub job_id: Option<String>,
+    pub server_id: i64,
+    pub stack_name: String,
+    pub compose_path: String,
+    pub image_name: String,
+    pub previous_tag: Option<String>,
+    pub new_tag: String,
+    #[serde(skip_serializing)]
+    pub previous_content: String,
+    #[serde(skip_serializing)]
+    pub new_content: String,
+    pub status: String,
+    pub created_at: i64,
+    /// deploy, promote for an image taken from another environment, or rollback
+    /// for a deployment that restored an earlier compose file
+    pub action: String,
+    pub repository_path: Option<String>,
+    /// HEAD of the repository when the image was built
+    pub commit_hash: Option<String>,
+    pub triggered_by: Option<String>,
+    pub error: Option<String>,
+    pub finished_at: Option<i64>,
+    pub duration_ms: Option<i64>,
+    /// Environment deployed to, for promotions
+    pub environment: Option<String>,
+    /// Environment the promoted image was taken from
+    pub promoted_from: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeploymentPage {
+    pub items: Vec<Deployment>,
+    pub total: i64,
+    pub page: i64,
+    pub page_size: i64,
+}
+
+/// A change made through the API or MCP.
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub created_at: i64,
+    /// session, token or mcp
+    pub actor: String,
+    pub user_id: Option<i64>,
+    pub username: Option<String>,
+    /// API token used, including for MCP sessions opened with one
+    pub token_id: Option<i64>,
+    pub token_name: Option<String>,
+    pub mcp_session: Option<String>,
+    /// Method and route, e.g. `POST /api/servers/{id}/containers/operate`, or
+    /// `mcp <tool>` for MCP tool calls
+    pub action: String,
+    pub path: Option<String>,
+    pub server_id: Option<i64>,
+    /// Request parameters as a JSON object, with credentials redacted
+    pub params: Option<String>,
+    /// HTTP status of the response; empty on the entry recording how a job ended
+    pub status: Option<i64>,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Job started by the action. A second entry with the same job records its outcome.
+    pub job_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditPage {
+    pub items: Vec<AuditEntry>,
+    pub total: i64,
+    pub page: i64,
+    pub page_size: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RollbackReq {
+    #[serde(rename = "serverId")]
+    pub server_id: i64,
+    #[serde(rename = "composePath")]
+    pub compose_path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DeployReq {
+    /// Repository path or name
+    pub path: String,
+    /// Image tag to deploy; defaults to the next version
+    pub version: Option<String>,
+    /// Compose service to update; defaults to every service using the image
+    pub service: Option<String>,
+    /// Only compute the compose changes; nothing is built or written
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Token from a dry run. The deploy is refused if the compose file changed since,
+    /// and repeating a request with the same token returns the job already started.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ServiceImageChange {
+    pub service: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeployTargetPreview {
+    pub server_id: i64,
+    pub server_name: String,
+    pub stack_name: String,
+    pub compose_path: String,
+    pub changes: Vec<ServiceImageChange>,
+    /// Unified diff of the remote compose file
+    pub diff: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeployPreview {
+    pub repository_path: String,
+    /// Image that would be deployed, name:tag
+    pub image: String,
+    pub targets: Vec<DeployTargetPreview>,
+    /// Pass back with the deploy request to apply exactly this preview
+    pub token: String,
+}
+
+/// A named stage of a repository (dev, staging, prod) deployed to one server and compose file.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Environment {
+    pub id: i64,
+    pub repository_id: i64,
+    pub name: String,
+    pub server_id: i64,
+    pub compose_path: String,
+    /// Compose service to update; defaults to every service using the image
+    pub service: Option<String>,
+    /// Promotion order: an environment is promoted to the one with the next position
+    pub position: i64,
+    /// Promotions into this environment wait for manual approval
+    pub requires_approval: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SaveEnvironmentReq {
+    /// Repository path or name
+    pub path: String,
+    pub name: String,
+    pub server_id: i64,
+    pub compose_path: String,
+    #[serde(default)]
+    pub service: Option<String>,
+    /// Defaults to after the last environment
+    #[serde(default)]
+    pub position: Option<i64>,
+    #[serde(default)]
+    pub requires_approval: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PromoteReq {
+    /// Repository path or name
+    pub path: String,
+    /// Environment whose deployed image is promoted
+    pub from: String,
+    /// Target environment; defaults to the one after `from`
+    #[serde(default)]
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Promotion {
+    pub id: i64,
+    pub repository_path: String,
+    pub from_env: String,
+    pub to_env: String,
+    /// Image promoted, name:tag, as deployed in `from_env` when the promotion was requested
+    pub image: String,
+    /// pending, approved or rejected
+    pub status: String,
+    pub triggered_by: Option<String>,
+    /// Deploy job, once the promotion is approved
+    pub job_id: Option<String>,
+    pub created_at: i64,
+    pub decided_at: Option<i64>,
+}
+
+/// A container registry that deploys can go through instead of uploading the image.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Registry {
+    pub id: i64,
+    pub name: String,
+    /// Host and optional port, e.g. `registry.example.com` or `10.0.0.5:5000`
+    pub url: String,
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// Served over plain HTTP, like a local `registry:2`
+    pub insecure: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SaveRegistryReq {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Left unchanged on update when omitted
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// Image registry as configured in 1Panel.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ImageRepo {
+    pub id: i64,
+    pub name: String,
+    pub download_url: String,
+}
+
+/// A user who can log in to the web UI.
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    #[serde(skip_serializing)]
+    pub password_hash: String,
+    /// `viewer`, `builder`, `deployer` or `admin`
+    pub role: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AuthStatus {
+    /// No admin exists yet; `POST /api/auth/setup` creates it
+    pub setup_required: bool,
+    pub authenticated: bool,
+    pub username: Option<String>,
+    /// The caller's role, lowered to the token's for API tokens
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct LoginReq {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct ChangePasswordReq {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Bearer token for scripts and MCP clients. The secret itself is only shown once,
+/// when the token is created.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    /// Role the token is limited to; its user's role when unset
+    pub role: Option<String>,
+    /// Servers the token may act on; all when unset
+    pub server_ids: Option<Vec<i64>>,
+    /// Repository paths the token may act on; all when unset
+    pub repositories: Option<Vec<String>>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct CreateTokenReq {
+    pub name: String,
+    /// Never expires when omitted
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+    /// `viewer`, `builder`, `deployer` or `admin`, at most the caller's role;
+    /// follows the user's role when omitted
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Limit the token to these servers
+    #[serde(default)]
+    pub server_ids: Option<Vec<i64>>,
+    /// Limit the token to these repositories, by path or name
+    #[serde(default)]
+    pub repositories: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct CreateUserReq {
+    pub username: String,
+    pub password: String,
+    /// `viewer`, `builder`, `deployer` or `admin`
+    pub role: String,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct UpdateUserReq {
+    /// New role; unchanged when omitted
+    #[serde(default)]
+    pub role: Option<String>,
+    /// New password; ends the user's sessions. Unchanged when omitted
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CreatedToken {
+    pub token: ApiToken,
+    /// Send as `Authorization: Bearer <secret>`
+    pub secret: String,
+}
@@ -1,486 +1,1041 @@
-use crate::models::DashboardResponse;
-use anyhow::{anyhow, Result};
+use crate::db::DbPool;
+use crate::models::{
+    Compose, Container, DashboardResponse, FileInfo, Image, ImageInspect, ImageRepo, PageResult,
+    Server,
+};
+use crate::secrets;
+use crate::tls::{self, TlsSettings};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use chrono::Utc;
-#[allow(unused_imports)]
-use reqwest::multipart;
-use reqwest::Client;
+use futures_util::StreamExt;
+use reqwest::{multipart, Client, Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::SeekFrom;
 use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, warn};
+
+/// Failure talking to a 1Panel server.
+#[derive(Debug)]
+pub enum OnePanelError {
+    /// The server rejected the API key (wrong key, IP not whitelisted, or clock skew)
+    Auth(String),
+    /// 1Panel answered with a `code` other than 200
+    Api { code: i64, message: String },
+    /// Non-2xx HTTP status without a 1Panel error body
+    Http { status: StatusCode, body: String },
+    /// Connection failure or timeout
+    Transport(reqwest::Error),
+    /// The response was not in the expected shape
+    Decode(String),
+    /// The server's connection settings are unusable (e.g. an invalid CA bundle)
+    Config(String),
+    /// An uploaded file did not arrive intact
+    Upload(String),
+}
 
-pub struct OnePanelClient;
+impl fmt::Display for OnePanelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnePanelError::Auth(msg) => write!(f, "1Panel authentication failed: {}", msg),
+            OnePanelError::Api { code, message } => {
+                write!(f, "1Panel API returned error code {}: {}", code, message)
+            }
+            OnePanelError::Http { status, body } => {
+                write!(f, "1Panel request failed: {} - {}", status, body)
+            }
+            OnePanelError::Transport(e) if e.is_timeout() => {
+                write!(f, "1Panel request timed out: {}", e)
+            }
+            OnePanelError::Transport(e) => {
+                write!(f, "Could not reach 1Panel: {}", e)?;
+                // reqwest keeps the cause (e.g. a rejected certificate) in the source chain
+                let mut source = std::error::Error::source(e);
+                while let Some(cause) = source {
+                    write!(f, ": {}", cause)?;
+                    source = cause.source();
+                }
+                Ok(())
+            }
+            OnePanelError::Decode(msg) => write!(f, "Unexpected 1Panel response: {}", msg),
+            OnePanelError::Config(msg) => write!(f, "Invalid 1Panel server settings: {}", msg),
+            OnePanelError::Upload(msg) => write!(f, "Upload to 1Panel failed: {}", msg),
+        }
+    }
+}
 
-impl OnePanelClient {
-    #[allow(dead_code)]
-    pub async fn test_connection(host: &str, port: u16, api_key: &str) -> Result<String> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
+impl std::error::Error for OnePanelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OnePanelError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-        let timestamp = Utc::now().timestamp();
+impl From<reqwest::Error> for OnePanelError {
+    fn from(e: reqwest::Error) -> Self {
+        OnePanelError::Transport(e)
+    }
+}
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+impl OnePanelError {
+    /// Status to answer an API caller with when a 1Panel call fails.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            OnePanelError::Transport(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
 
-        let url = format!("http://{}:{}/api/v1/system/info", host, port);
+    /// Connection failures, timeouts and gateway errors, which may pass on a retry.
+    fn is_transient(&self) -> bool {
+        match self {
+            OnePanelError::Transport(e) => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+            }
+            OnePanelError::Http { status, .. } => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            _ => false,
+        }
+    }
+}
 
-        let res = client
-            .get(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .send()
-            .await?;
+impl IntoResponse for OnePanelError {
+    fn into_response(self) -> Response {
+        (self.status_code(), self.to_string()).into_response()
+    }
+}
 
-        if res.status().is_success() {
-            Ok("Connection successful".to_string())
-        } else if res.status() == reqwest::StatusCode::UNAUTHORIZED {
-            Err(anyhow!("Authentication failed"))
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Request failed: {} - {}", status, body))
+pub type Result<T> = std::result::Result<T, OnePanelError>;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Attempts for an idempotent call failing with a transient error
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for each further one
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Clock differences below this are within the precision of the `Date` header
+const MIN_CLOCK_SKEW_SECS: i64 = 2;
+/// Containers fetched per `containers/search` request
+const CONTAINER_PAGE_SIZE: usize = 100;
+/// Bounds the container listing should a server keep reporting more
+const MAX_CONTAINER_PAGES: usize = 50;
+/// Part size for chunked uploads. A failed chunk is retried on its own, and the chunks
+/// a server got are recorded in `upload_progress`, so uploading the same file again
+/// continues after them.
+#[cfg(not(test))]
+const UPLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+#[cfg(test)]
+const UPLOAD_CHUNK_SIZE: u64 = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Applies to every call except uploads and image loads
+    pub request: Duration,
+    pub upload: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            request: Duration::from_secs(60),
+            upload: Duration::from_secs(30 * 60),
         }
     }
+}
 
-    pub async fn get_os_info(host: &str, port: u16, api_key: &str) -> Result<DashboardResponse> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
+impl Timeouts {
+    /// Defaults overridden by the timeouts configured on the server.
+    pub fn of(server: &Server) -> Self {
+        let secs = |value: Option<i64>, default: Duration| match value {
+            Some(secs) if secs > 0 => Duration::from_secs(secs as u64),
+            _ => default,
+        };
+        let default = Timeouts::default();
+        Timeouts {
+            request: secs(server.request_timeout_secs, default.request),
+            upload: secs(server.upload_timeout_secs, default.upload),
+        }
+    }
+}
 
-        let timestamp = Utc::now().timestamp();
+/// Clock of a server relative to ours, measured from the `Date` header of its responses.
+#[derive(Debug, Clone, Copy, Default)]
+struct ClockSkew {
+    /// Seconds the server's clock is ahead of ours; negative when it is behind
+    secs: i64,
+    /// Set once the server rejected a token; tokens are then timestamped with its clock
+    corrected: bool,
+}
 
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
+/// Skew per server base URL, kept across clients since they are built per request.
+fn clock_skews() -> MutexGuard<'static, HashMap<String, ClockSkew>> {
+    static SKEWS: OnceLock<Mutex<HashMap<String, ClockSkew>>> = OnceLock::new();
+    SKEWS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+/// Loads a configured server by id.
+pub async fn find_server(db: &DbPool, id: i64) -> std::result::Result<Option<Server>, sqlx::Error> {
+    sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
 
-        // Assume HTTP for now as user example was HTTP. TODO: Support HTTPS toggle.
-        let url = format!("http://{}:{}/api/v1/dashboard/base/os", host_clean, port);
+/// Path and number of chunks sent of an earlier upload of the file with hash `sha256`
+/// that did not finish.
+async fn upload_progress(
+    db: &DbPool,
+    server_id: i64,
+    sha256: &str,
+) -> std::result::Result<Option<(String, u64)>, sqlx::Error> {
+    let row: Option<(String, i64)> = sqlx::query_as(
+        "SELECT remote_path, chunks_done FROM upload_progress WHERE server_id = ? AND sha256 = ?",
+    )
+    .bind(server_id)
+    .bind(sha256)
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|(path, chunks_done)| (path, chunks_done.max(0) as u64)))
+}
 
-        println!("Requesting URL: {}", url);
+/// Records that the server holds the first `chunks_done` chunks of the file with hash
+/// `sha256`. A lost record only means sending them again, so failures are just logged.
+async fn save_upload_progress(
+    db: &DbPool,
+    server_id: i64,
+    sha256: &str,
+    remote_path: &str,
+    chunks_done: u64,
+) {
+    let res = sqlx::query(
+        "INSERT INTO upload_progress (server_id, sha256, remote_path, chunks_done, updated_at) VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(server_id, sha256) DO UPDATE SET remote_path = excluded.remote_path, chunks_done = excluded.chunks_done, updated_at = excluded.updated_at",
+    )
+    .bind(server_id)
+    .bind(sha256)
+    .bind(remote_path)
+    .bind(chunks_done as i64)
+    .bind(Utc::now().timestamp())
+    .execute(db)
+    .await;
+    if let Err(e) = res {
+        warn!(
+            "Could not record the progress of uploading {}: {}",
+            remote_path, e
+        );
+    }
+}
 
-        let res = client
-            .get(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .send()
-            .await?;
+async fn clear_upload_progress(db: &DbPool, server_id: i64, sha256: &str) {
+    let res = sqlx::query("DELETE FROM upload_progress WHERE server_id = ? AND sha256 = ?")
+        .bind(server_id)
+        .bind(sha256)
+        .execute(db)
+        .await;
+    if let Err(e) = res {
+        warn!("Could not clear the progress of an upload: {}", e);
+    }
+}
 
-        if res.status().is_success() {
-            let body_text = res.text().await?;
-            println!("Response Body: {}", body_text);
+/// A local file being uploaded to `remote_dir` on the server as `file_name`.
+struct Upload<'a> {
+    file_path: &'a Path,
+    file_name: String,
+    remote_dir: &'a str,
+    total: u64,
+    sha256: String,
+}
 
-            let dashboard_res: Result<DashboardResponse, _> = serde_json::from_str(&body_text);
-            match dashboard_res {
-                Ok(data) => Ok(data),
-                Err(e) => {
-                    println!("Deserialization Error: {}", e);
-                    Err(anyhow!("Deserialization failed: {}", e))
-                }
-            }
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            println!("Request failed. Status: {}, Body: {}", status, body);
-            Err(anyhow!("Request failed: {} - {}", status, body))
+/// Hex SHA-256 of a local file.
+async fn file_sha256(path: &Path) -> Result<String> {
+    let read_error =
+        |e: std::io::Error| OnePanelError::Decode(format!("Cannot read {:?}: {}", path, e));
+    let mut file = tokio::fs::File::open(path).await.map_err(read_error)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.map_err(read_error)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Path prefix the API is mounted under (security entrance or reverse proxy
+/// location) as `/prefix`, or `None` for the server root.
+pub fn normalize_base_path(path: &str) -> Option<String> {
+    let path = path.trim().trim_matches('/');
+    (!path.is_empty()).then(|| format!("/{}", path))
+}
+
+/// Directory image archives are uploaded to when the server does not set one.
+pub const DEFAULT_UPLOAD_DIR: &str = "/opt/1panel/tmp";
+
+/// Upload directory as stored: absolute, without trailing slash, or `None` for the
+/// default. Relative paths and `..` components are rejected.
+pub fn normalize_upload_dir(dir: &str) -> std::result::Result<Option<String>, String> {
+    let dir = dir.trim();
+    if dir.is_empty() {
+        return Ok(None);
+    }
+    if !dir.starts_with('/') || dir.split('/').any(|part| part == "..") {
+        return Err(format!(
+            "Invalid upload directory {}; expected an absolute path",
+            dir
+        ));
+    }
+    let dir = dir.trim_end_matches('/');
+    Ok(Some(if dir.is_empty() { "/" } else { dir }.to_string()))
+}
+
+/// Major version of the 1Panel API a server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version.trim().to_lowercase().as_str() {
+            "v1" | "1" => Some(ApiVersion::V1),
+            "v2" | "2" => Some(ApiVersion::V2),
+            _ => None,
         }
     }
 
-    pub async fn list_containers(
-        host: &str,
-        port: u16,
-        api_key: &str,
-    ) -> Result<Vec<serde_json::Value>> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
+    /// Version configured on the server; servers saved before v2 support speak v1.
+    pub fn of(server: &Server) -> Self {
+        server
+            .api_version
+            .as_deref()
+            .and_then(ApiVersion::parse)
+            .unwrap_or(ApiVersion::V1)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "v1",
+            ApiVersion::V2 => "v2",
+        }
+    }
+}
 
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
+/// 1Panel operations the client uses, mapped to the route of each API version.
+#[derive(Debug, Clone, Copy)]
+enum Endpoint {
+    OsInfo,
+    ContainerSearch,
+    ContainerOperate,
+    ContainerLogs,
+    FileUpload,
+    FileChunkUpload,
+    FileSearch,
+    FileContent,
+    FileSave,
+    FileDelete,
+    FileDownload,
+    ImageLoad,
+    ImageSave,
+    ImageAll,
+    ImageRemove,
+    ImagePull,
+    ImageTag,
+    RepoList,
+    RepoCreate,
+    Inspect,
+    ComposeSearch,
+    ComposeUpdate,
+    ComposeOperate,
+}
+
+impl Endpoint {
+    fn route(self, version: ApiVersion) -> (Method, &'static str) {
+        use ApiVersion::*;
+        use Endpoint::*;
+
+        match (self, version) {
+            (OsInfo, _) => (Method::GET, "dashboard/base/os"),
+            (ContainerSearch, _) => (Method::POST, "containers/search"),
+            (ContainerOperate, _) => (Method::POST, "containers/operate"),
+            // v2 streams logs as server-sent events
+            (ContainerLogs, V1) => (Method::POST, "containers/search/log"),
+            (ContainerLogs, V2) => (Method::GET, "containers/search/log"),
+            (FileUpload, _) => (Method::POST, "files/upload"),
+            (FileChunkUpload, _) => (Method::POST, "files/chunkupload"),
+            (FileSearch, _) => (Method::POST, "files/search"),
+            (FileContent, _) => (Method::POST, "files/content"),
+            (FileSave, _) => (Method::POST, "files/save"),
+            (FileDelete, _) => (Method::POST, "files/del"),
+            (FileDownload, _) => (Method::GET, "files/download"),
+            (ImageLoad, _) => (Method::POST, "containers/image/load"),
+            (ImageSave, _) => (Method::POST, "containers/image/save"),
+            (ImageAll, _) => (Method::GET, "containers/image/all"),
+            (ImageRemove, _) => (Method::POST, "containers/image/remove"),
+            (ImagePull, _) => (Method::POST, "containers/image/pull"),
+            (ImageTag, _) => (Method::POST, "containers/image/tag"),
+            (RepoList, _) => (Method::GET, "containers/repo"),
+            (RepoCreate, _) => (Method::POST, "containers/repo"),
+            (Inspect, _) => (Method::POST, "containers/inspect"),
+            (ComposeSearch, _) => (Method::POST, "containers/compose/search"),
+            (ComposeUpdate, _) => (Method::POST, "containers/compose/update"),
+            (ComposeOperate, _) => (Method::POST, "containers/compose/operate"),
+        }
+    }
+
+    /// Whether repeating the call has no further effect, so it can be retried after
+    /// a transient failure. Whole-file uploads are left out as resending them is
+    /// expensive; a resent chunk just replaces the stored one.
+    fn idempotent(self) -> bool {
+        use Endpoint::*;
+
+        matches!(
+            self,
+            OsInfo
+                | ContainerSearch
+                | ContainerLogs
+                | FileChunkUpload
+                | FileSearch
+                | FileContent
+                | FileSave
+                | ImageAll
+                | ImageTag
+                | RepoList
+                | Inspect
+                | ComposeSearch
+                | ComposeUpdate
+        )
+    }
+}
+
+/// Client for the API of one 1Panel server.
+#[derive(Clone)]
+pub struct OnePanelClient {
+    http: Client,
+    server_id: i64,
+    base_url: String,
+    api_key: String,
+    timeouts: Timeouts,
+    version: ApiVersion,
+    upload_dir: String,
+}
+
+impl OnePanelClient {
+    pub fn new(server: &Server) -> Result<Self> {
+        let host = server
+            .host
             .trim()
             .trim_start_matches("http://")
             .trim_start_matches("https://")
             .trim_end_matches('/');
+        let settings = TlsSettings::of(server).map_err(OnePanelError::Config)?;
+        let base_path = server
+            .base_path
+            .as_deref()
+            .and_then(normalize_base_path)
+            .unwrap_or_default();
+        let api_key =
+            secrets::decrypt(&server.api_key).map_err(|e| OnePanelError::Config(e.to_string()))?;
+
+        Ok(OnePanelClient {
+            http: tls::client(&settings, CONNECT_TIMEOUT).map_err(OnePanelError::Config)?,
+            server_id: server.id,
+            base_url: format!(
+                "{}://{}:{}{}",
+                settings.scheme, host, server.port, base_path
+            ),
+            api_key: api_key.trim().to_string(),
+            timeouts: Timeouts::of(server),
+            version: ApiVersion::of(server),
+            upload_dir: server
+                .upload_dir
+                .as_deref()
+                .and_then(|dir| normalize_upload_dir(dir).ok().flatten())
+                .unwrap_or_else(|| DEFAULT_UPLOAD_DIR.to_string()),
+        })
+    }
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        let url = format!("http://{}:{}/api/v1/containers/search", host_clean, port);
+    /// Finds the API version of a server by asking each version for system info,
+    /// newest first.
+    pub async fn detect_version(server: &Server) -> Result<ApiVersion> {
+        let mut client = OnePanelClient::new(server)?;
+        let mut last_error = None;
+        for version in [ApiVersion::V2, ApiVersion::V1] {
+            client.version = version;
+            match client.call(Endpoint::OsInfo, |req| req).await {
+                Ok(_) => return Ok(version),
+                // Unreachable servers and rejected keys fail the same way on every version
+                Err(e @ (OnePanelError::Auth(_) | OnePanelError::Transport(_))) => return Err(e),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| OnePanelError::Decode("no API version".to_string())))
+    }
 
-        let payload = serde_json::json!({
-            "page": 1,
-            "pageSize": 100,
-            "name": "",
-            "state": "all",
-            "orderBy": "created_at",
-            "order": "descending"
-        });
+    /// Signed request to `path` (relative to the server root and base path, e.g.
+    /// `api/v1/dashboard/base/os`).
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let timestamp = Utc::now().timestamp() + self.clock_offset();
+        let token = format!(
+            "{:x}",
+            md5::compute(format!("1panel{}{}", self.api_key, timestamp))
+        );
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
+        self.http
+            .request(
+                method,
+                format!("{}/{}", self.base_url, path.trim_start_matches('/')),
+            )
+            .header("1Panel-Token", token)
             .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
-            .await?;
+            .timeout(self.timeouts.request)
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            println!("List Containers Raw Response: {}", text);
-            // Parse as Value to handle wrapper
-            let json: serde_json::Value = serde_json::from_str(&text)
-                .map_err(|e| anyhow!("Failed to parse response JSON: {} | Body: {}", e, text))?;
-
-            // Check code
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
+    /// Seconds the server's clock was ahead of ours (negative when behind) in its
+    /// last response, if it sent a `Date` header.
+    pub fn clock_skew(&self) -> Option<i64> {
+        clock_skews().get(&self.base_url).map(|skew| skew.secs)
+    }
 
-            // Extract data
-            let data = json
-                .get("data")
-                .ok_or_else(|| anyhow!("Missing data field in response"))?;
+    /// Seconds to add to our clock when timestamping tokens.
+    fn clock_offset(&self) -> i64 {
+        clock_skews()
+            .get(&self.base_url)
+            .filter(|skew| skew.corrected)
+            .map_or(0, |skew| skew.secs)
+    }
 
-            println!("Data extracted. Is array? {}", data.is_array());
+    fn observe_clock(&self, res: &reqwest::Response) {
+        let Some(date) = res
+            .headers()
+            .get(reqwest::header::DATE)
+            .and_then(|d| d.to_str().ok())
+            .and_then(|d| chrono::DateTime::parse_from_rfc2822(d).ok())
+        else {
+            return;
+        };
+        let secs = date.timestamp() - Utc::now().timestamp();
+        clock_skews().entry(self.base_url.clone()).or_default().secs = secs;
+    }
 
-            // Extract items logic: search endpoint return structure might differ slightly or be standard page wrapper
-            let items = if let Some(items) = data.get("items").and_then(|i| i.as_array()) {
-                println!("Found items in pagination");
-                items.clone()
-            } else if let Some(arr) = data.as_array() {
-                println!("Found direct array");
-                arr.clone()
-            } else {
-                return Err(anyhow!("Data field is not an array or page result"));
-            };
+    /// Switches to timestamping tokens with the server's clock after it rejected one.
+    /// Returns false when the clocks agree, i.e. the rejection was not caused by skew.
+    fn correct_clock(&self) -> bool {
+        let mut skews = clock_skews();
+        match skews.get_mut(&self.base_url) {
+            Some(skew) if !skew.corrected && skew.secs.abs() >= MIN_CLOCK_SKEW_SECS => {
+                skew.corrected = true;
+                warn!(
+                    "1Panel at {} rejected our token; its clock is {}s off, using it for timestamps",
+                    self.base_url, skew.secs
+                );
+                true
+            }
+            _ => false,
+        }
+    }
 
-            println!("Items count: {}", items.len());
+    fn api(&self, endpoint: Endpoint) -> RequestBuilder {
+        let (method, route) = endpoint.route(self.version);
+        self.request(method, &format!("api/{}/{}", self.version.as_str(), route))
+    }
 
-            // Return items directly as values
-            Ok(items)
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Search containers failed: {} - {}", status, body))
+    /// Calls `endpoint` and returns the raw body of a 2xx response. `build` adds the
+    /// payload and runs for every attempt, as each needs a freshly signed request.
+    ///
+    /// A rejected token is retried once with the server's clock when the clocks
+    /// differ; idempotent calls are also retried with backoff on transient errors.
+    async fn call_raw(
+        &self,
+        endpoint: Endpoint,
+        build: impl Fn(RequestBuilder) -> Result<RequestBuilder>,
+    ) -> Result<String> {
+        let mut attempt = 1;
+        loop {
+            match self.send_raw(build(self.api(endpoint))?).await {
+                Err(OnePanelError::Auth(_)) if self.correct_clock() => {}
+                Err(e) if e.is_transient() && endpoint.idempotent() && attempt < MAX_ATTEMPTS => {
+                    let delay = RETRY_DELAY * 2u32.pow(attempt - 1);
+                    warn!(
+                        "1Panel {:?} call to {} failed ({}), retrying in {:?}",
+                        endpoint, self.base_url, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
         }
     }
 
-    pub async fn operate_container(
-        host: &str,
-        port: u16,
-        api_key: &str,
-        names: Vec<String>,
-        operation: String,
-    ) -> Result<()> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
+    /// Calls `endpoint` and returns the `data` of the 1Panel response envelope.
+    async fn call(
+        &self,
+        endpoint: Endpoint,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Value> {
+        let body = self.call_raw(endpoint, |req| Ok(build(req))).await?;
+        envelope_data(&body)
+    }
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+    /// Sends a request and returns the raw body of a 2xx response.
+    async fn send_raw(&self, req: RequestBuilder) -> Result<String> {
+        let res = req.send().await?;
+        self.observe_clock(&res);
+        let status = res.status();
+        let body = res.text().await?;
 
-        let url = format!("http://{}:{}/api/v1/containers/operate", host_clean, port);
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(OnePanelError::Auth(format!("{} - {}", status, body)));
+        }
+        if !status.is_success() {
+            // 1Panel reports most failures in its JSON envelope even on error statuses.
+            // Anything else, like a proxy's HTML error page, is left to the status.
+            if serde_json::from_str::<Value>(&body).is_ok() {
+                check_envelope(&body)?;
+            }
+            return Err(OnePanelError::Http { status, body });
+        }
+        Ok(body)
+    }
+
+    pub async fn get_os_info(&self) -> Result<DashboardResponse> {
+        let body = self.call_raw(Endpoint::OsInfo, Ok).await?;
+        check_envelope(&body)?;
+        serde_json::from_str(&body).map_err(|e| OnePanelError::Decode(e.to_string()))
+    }
+
+    /// Every container on the server, fetched a page at a time.
+    pub async fn list_containers(&self) -> Result<Vec<Container>> {
+        let mut containers = Vec::new();
+        for page in 1..=MAX_CONTAINER_PAGES {
+            let payload = serde_json::json!({
+                "page": page,
+                "pageSize": CONTAINER_PAGE_SIZE,
+                "name": "",
+                "state": "all",
+                "orderBy": "created_at",
+                "order": "descending"
+            });
+
+            let data = self
+                .call(Endpoint::ContainerSearch, |req| req.json(&payload))
+                .await?;
+            if !data.is_object() {
+                // Not paginated: everything came at once
+                containers.extend(page_items::<Container>(data)?);
+                break;
+            }
+            let page = decode::<PageResult<Container>>(data)?;
+            let last = page.items.is_empty();
+            containers.extend(page.items);
+            if last || containers.len() as i64 >= page.total {
+                break;
+            }
+        }
+        Ok(containers)
+    }
 
+    pub async fn operate_container(&self, names: Vec<String>, operation: String) -> Result<()> {
         let payload = serde_json::json!({
             "names": names,
             "operation": operation
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
+        self.call(Endpoint::ContainerOperate, |req| req.json(&payload))
             .await?;
-
-        if res.status().is_success() {
-            let text = res.text().await?;
-            // Parse wrapper. Even for operate, we should check code.
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
-            Ok(())
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Operate container failed: {} - {}", status, body))
-        }
+        Ok(())
     }
 
-    pub async fn get_container_logs(
-        host: &str,
-        port: u16,
-        api_key: &str,
-        container: String,
-    ) -> Result<String> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
-
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        // search/log is POST
-        let url = format!(
-            "http://{}:{}/api/v1/containers/search/log?container={}&tail=100",
-            host_clean, port, container
-        );
-
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .send()
+    pub async fn get_container_logs(&self, container: &str) -> Result<String> {
+        let body = self
+            .call_raw(Endpoint::ContainerLogs, |req| {
+                Ok(req.query(&[
+                    ("container", container),
+                    ("tail", "100"),
+                    ("follow", "false"),
+                ]))
+            })
             .await?;
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            // If it's pure text logs it won't be valid JSON (or it will be just a string).
-            // But if it's wrapped JSON...
-            // Let's check if it has "code" and "data".
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code == 200 {
-                    // return data (string)
-                    if let Some(data) = json.get("data").and_then(|d| d.as_str()) {
-                        return Ok(data.to_string());
-                    }
-                    // If data is not string, maybe it's not wrapped or logic differs?
-                    // Falling back to returning whole text or debug.
-                } else {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
+        if self.version == ApiVersion::V2 {
+            // One `data:` line per log line
+            return Ok(body
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|line| line.strip_prefix(' ').unwrap_or(line))
+                .collect::<Vec<_>>()
+                .join("\n"));
+        }
 
-            // If fallback (not JSON or no code), usually implies raw logs or we failed to parse JSON
-            // But if we failed to parse JSON (unwrap_or Null), we return text.
-            if json.is_null() {
-                Ok(text)
-            } else {
-                // It was valid JSON but didn't have code/data? Treat as logs?
-                // Or maybe data was just missing.
-                Ok(text)
+        // Logs come either wrapped in the JSON envelope or as plain text
+        match serde_json::from_str::<Value>(&body) {
+            Ok(json) if json.get("code").is_some() => {
+                let json = check_envelope(&body)?;
+                Ok(match json.get("data").and_then(|d| d.as_str()) {
+                    Some(data) => data.to_string(),
+                    None => body,
+                })
             }
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Get logs failed: {} - {}", status, body))
+            _ => Ok(body),
         }
     }
 
+    /// Uploads a file into `remote_dir` and returns its remote path once it is there
+    /// intact. Uses 1Panel's chunked upload, falling back to a single streamed request
+    /// on servers without it. An earlier upload of the same file to this server that
+    /// stopped part-way is continued after the chunks it sent. `on_progress` gets the
+    /// bytes sent.
     pub async fn upload_file(
-        host: &str,
-        port: u16,
-        api_key: &str,
+        &self,
+        db: &DbPool,
         file_path: &Path,
         remote_dir: &str,
+        on_progress: impl Fn(u64, u64) + Send + Sync + 'static,
     ) -> Result<String> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
-
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        let url = format!("http://{}:{}/api/v1/files/upload", host_clean, port);
-
-        // Prepare multipart
         let file_name = file_path
             .file_name()
             .and_then(|n| n.to_str())
-            .ok_or_else(|| anyhow!("Invalid filename"))?
+            .ok_or_else(|| OnePanelError::Decode("Invalid filename".to_string()))?
             .to_string();
+        let total = tokio::fs::metadata(file_path)
+            .await
+            .map_err(|e| OnePanelError::Decode(format!("Cannot read {:?}: {}", file_path, e)))?
+            .len();
+        let on_progress = Arc::new(on_progress);
+
+        // Identifies the file across attempts: a retried job saves the image again,
+        // under another name
+        let sha256 = file_sha256(file_path).await?;
+        let remote_dir = remote_dir.trim_end_matches('/');
+        let resumed = match upload_progress(db, self.server_id, &sha256).await {
+            Ok(progress) => progress.and_then(|(path, chunks_done)| {
+                let (dir, name) = path.rsplit_once('/')?;
+                (dir == remote_dir).then(|| (name.to_string(), chunks_done))
+            }),
+            Err(e) => {
+                warn!(
+                    "Could not look up earlier uploads of {:?}: {}",
+                    file_path, e
+                );
+                None
+            }
+        };
+        let (file_name, first_chunk) = resumed.unwrap_or((file_name, 0));
+        let upload = Upload {
+            file_path,
+            file_name,
+            remote_dir,
+            total,
+            sha256,
+        };
+
+        let remote_path = match self
+            .upload_chunks(db, &upload, first_chunk, on_progress.as_ref())
+            .await
+        {
+            Err(OnePanelError::Http {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }) => {
+                debug!(
+                    "1Panel at {} has no chunked upload, sending the file whole",
+                    self.base_url
+                );
+                self.upload_whole(file_path, &upload.file_name, remote_dir, on_progress)
+                    .await?
+            }
+            result => result?,
+        };
+
+        // A file that arrived damaged is sent from the start next time
+        let verified = self
+            .verify_upload(&remote_path, total, &upload.sha256)
+            .await;
+        clear_upload_progress(db, self.server_id, &upload.sha256).await;
+        verified?;
+        Ok(remote_path)
+    }
 
-        let file_content = tokio::fs::read(file_path).await?;
-        let part_file = multipart::Part::bytes(file_content).file_name(file_name.clone());
+    /// Checks an uploaded file against the local one by size and SHA-256. 1Panel
+    /// reports no checksums, so the file is read back to hash it.
+    async fn verify_upload(&self, remote_path: &str, total: u64, sha256: &str) -> Result<()> {
+        let size = self.remote_size(remote_path).await?;
+        if size != total {
+            return Err(OnePanelError::Upload(format!(
+                "{} is {} bytes on the server, expected {}",
+                remote_path, size, total
+            )));
+        }
 
-        let form = multipart::Form::new()
-            .part("file", part_file)
-            .part("path", multipart::Part::text(remote_dir.to_string()))
-            .part("overwrite", multipart::Part::text("true"));
+        let remote = self.remote_sha256(remote_path).await.map_err(|e| {
+            OnePanelError::Upload(format!(
+                "Could not read back {} to verify its checksum: {}",
+                remote_path, e
+            ))
+        })?;
+        if remote != sha256 {
+            return Err(OnePanelError::Upload(format!(
+                "{} does not match the local file (SHA-256 {} on the server, {} here)",
+                remote_path, remote, sha256
+            )));
+        }
+        Ok(())
+    }
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .multipart(form)
-            .send()
-            .await?;
+    /// Sends the file in `UPLOAD_CHUNK_SIZE` parts from `first_chunk` on, which 1Panel
+    /// joins after the last. 1Panel keeps the parts it got until then, so each one sent
+    /// is recorded for a later attempt to skip.
+    async fn upload_chunks(
+        &self,
+        db: &DbPool,
+        upload: &Upload<'_>,
+        first_chunk: u64,
+        on_progress: &(impl Fn(u64, u64) + Send + Sync),
+    ) -> Result<String> {
+        let Upload {
+            file_path,
+            file_name,
+            remote_dir,
+            total,
+            sha256,
+        } = upload;
+        let (file_name, total) = (file_name.as_str(), *total);
+        let remote_path = format!("{}/{}", remote_dir, file_name);
+        let chunk_count = total.div_ceil(UPLOAD_CHUNK_SIZE).max(1);
+        let first_chunk = first_chunk.min(chunk_count - 1);
+        let read_error = |e: std::io::Error| {
+            OnePanelError::Decode(format!("Cannot read {:?}: {}", file_path, e))
+        };
+        let mut file = tokio::fs::File::open(file_path).await.map_err(read_error)?;
+
+        let mut sent = first_chunk * UPLOAD_CHUNK_SIZE;
+        if first_chunk > 0 {
+            debug!(
+                "Continuing the upload of {} at chunk {} of {}",
+                remote_path,
+                first_chunk + 1,
+                chunk_count
+            );
+            file.seek(SeekFrom::Start(sent)).await.map_err(read_error)?;
+            on_progress(sent, total);
+        }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            // Check for error code in successful http response
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("Upload failed (API {}): {}", code, msg));
+        for index in first_chunk..chunk_count {
+            let last = index + 1 == chunk_count;
+            let mut chunk = Vec::with_capacity(UPLOAD_CHUNK_SIZE.min(total - sent) as usize);
+            (&mut file)
+                .take(UPLOAD_CHUNK_SIZE)
+                .read_to_end(&mut chunk)
+                .await
+                .map_err(read_error)?;
+
+            let result = self
+                .call_raw(Endpoint::FileChunkUpload, |req| {
+                    let part =
+                        multipart::Part::bytes(chunk.clone()).file_name(file_name.to_string());
+                    let form = multipart::Form::new()
+                        .part("chunk", part)
+                        .text("filename", file_name.to_string())
+                        .text("path", remote_dir.to_string())
+                        .text("chunkIndex", index.to_string())
+                        .text("chunkCount", chunk_count.to_string())
+                        .text("overwrite", "true");
+                    Ok(req.multipart(form))
+                })
+                .await;
+            let joined = match result {
+                // 1Panel only answers the last chunk, once the parts are joined
+                Ok(body) if body.trim().is_empty() => Ok(()),
+                Ok(body) => check_envelope(&body).map(drop),
+                // A lost answer to the last chunk leaves the joined file in place
+                Err(e) if last => {
+                    if self.remote_size(&remote_path).await.ok() == Some(total) {
+                        Ok(())
+                    } else {
+                        Err(e)
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = joined {
+                if last && first_chunk > 0 {
+                    // The server may have dropped the parts of the earlier attempt
+                    clear_upload_progress(db, self.server_id, sha256).await;
                 }
+                return Err(e);
             }
 
-            if let Some(data) = json.get("data").and_then(|d| d.as_str()) {
-                Ok(data.to_string())
-            } else {
-                // If data is null, assume successes and construct path
-                // But usually 1Panel might return something?
-                // If null, we construct it: remote_dir + / + filename
-                let full_path = format!("{}/{}", Self::remote_path_clean(remote_dir), file_name);
-                Ok(full_path)
+            sent += chunk.len() as u64;
+            if !last {
+                save_upload_progress(db, self.server_id, sha256, &remote_path, index + 1).await;
             }
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Upload file failed: {} - {}", status, body))
+            on_progress(sent, total);
         }
+        Ok(remote_path)
     }
 
-    // Helper helper
-    fn remote_path_clean(p: &str) -> String {
-        if p.ends_with('/') {
-            p[..p.len() - 1].to_string()
-        } else {
-            p.to_string()
-        }
-    }
+    /// Sends the file as one streamed multipart request.
+    async fn upload_whole(
+        &self,
+        file_path: &Path,
+        file_name: &str,
+        remote_dir: &str,
+        on_progress: Arc<impl Fn(u64, u64) + Send + Sync + 'static>,
+    ) -> Result<String> {
+        let body = self
+            .call_raw(Endpoint::FileUpload, |req| {
+                let form = upload_form(file_path, file_name, remote_dir, on_progress.clone())?;
+                Ok(req.multipart(form).timeout(self.timeouts.upload))
+            })
+            .await?;
+        let data = envelope_data(&body)?;
 
-    pub async fn load_image(host: &str, port: u16, api_key: &str, remote_path: &str) -> Result<()> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
+        Ok(match data.as_str() {
+            Some(path) => path.to_string(),
+            // 1Panel usually returns no data; the file lands under its own name
+            None => format!("{}/{}", remote_dir.trim_end_matches('/'), file_name),
+        })
+    }
 
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
+    /// Size in bytes of a file on the server.
+    async fn remote_size(&self, path: &str) -> Result<u64> {
+        let payload = serde_json::json!({
+            "path": path,
+            "expand": false,
+            "page": 1,
+            "pageSize": 1
+        });
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+        let data = self
+            .call(Endpoint::FileSearch, |req| req.json(&payload))
+            .await?;
+        Ok(decode::<FileInfo>(data)?.size.max(0) as u64)
+    }
 
-        let url = format!(
-            "http://{}:{}/api/v1/containers/image/load",
-            host_clean, port
-        );
+    pub async fn load_image(&self, remote_path: &str) -> Result<()> {
+        let payload = match self.version {
+            ApiVersion::V1 => serde_json::json!({
+                "path": remote_path
+            }),
+            // v2 runs the load as a background task named by the caller
+            ApiVersion::V2 => serde_json::json!({
+                "path": remote_path,
+                "taskID": uuid::Uuid::new_v4().to_string()
+            }),
+        };
+
+        self.call(Endpoint::ImageLoad, |req| {
+            req.json(&payload).timeout(self.timeouts.upload)
+        })
+        .await?;
+        Ok(())
+    }
 
+    /// Saves the image `tag` on the server as `dir/name.tar` and returns that path.
+    pub async fn save_image(&self, tag: &str, dir: &str, name: &str) -> Result<String> {
+        let dir = dir.trim_end_matches('/');
         let payload = serde_json::json!({
-            "path": remote_path
+            "tagName": tag,
+            "path": dir,
+            "name": name
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
+        self.call(Endpoint::ImageSave, |req| {
+            req.json(&payload).timeout(self.timeouts.upload)
+        })
+        .await?;
+        Ok(format!("{}/{}.tar", dir, name))
+    }
+
+    /// Response carrying the content of the file at `path` on the server.
+    async fn download(&self, path: &str) -> Result<reqwest::Response> {
+        let res = self
+            .api(Endpoint::FileDownload)
+            .query(&[("path", path)])
+            .timeout(self.timeouts.upload)
             .send()
             .await?;
-
-        if res.status().is_success() {
-            Ok(())
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Load image failed: {} - {}", status, body))
+        self.observe_clock(&res);
+        let status = res.status();
+        let is_json = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("json"));
+
+        if !status.is_success() || is_json {
+            let body = res.text().await?;
+            if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+                return Err(OnePanelError::Auth(format!("{} - {}", status, body)));
+            }
+            // Failures come as the JSON envelope instead of the file
+            if serde_json::from_str::<Value>(&body).is_ok() {
+                check_envelope(&body)?;
+            }
+            if !status.is_success() {
+                return Err(OnePanelError::Http { status, body });
+            }
+            return Err(OnePanelError::Decode(format!(
+                "Expected the content of {}, got {}",
+                path, body
+            )));
         }
+        Ok(res)
     }
 
-    pub async fn list_composes(
-        host: &str,
-        port: u16,
-        api_key: &str,
-    ) -> Result<Vec<serde_json::Value>> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
-
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+    /// Downloads the file at `path` on the server to `dst` and returns its size.
+    pub async fn download_file(&self, path: &str, dst: &Path) -> Result<u64> {
+        let res = self.download(path).await?;
+
+        let mut file = tokio::fs::File::create(dst)
+            .await
+            .map_err(|e| OnePanelError::Decode(format!("Cannot write {:?}: {}", dst, e)))?;
+        let mut stream = res.bytes_stream();
+        let mut size = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| OnePanelError::Decode(format!("Cannot write {:?}: {}", dst, e)))?;
+        }
+        file.flush()
+            .await
+            .map_err(|e| OnePanelError::Decode(format!("Cannot write {:?}: {}", dst, e)))?;
+        Ok(size)
+    }
 
-        let url = format!(
-            "http://{}:{}/api/v1/containers/compose/search",
-            host_clean, port
-        );
+    /// Hex SHA-256 of the file at `path` on the server, which is read to compute it.
+    async fn remote_sha256(&self, path: &str) -> Result<String> {
+        let mut stream = self.download(path).await?.bytes_stream();
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
 
-        // Payload for listing composes
+    pub async fn list_composes(&self) -> Result<Vec<Compose>> {
         let payload = serde_json::json!({
             "page": 1,
             "pageSize": 100,
@@ -489,391 +1044,584 @@ impl OnePanelClient {
             "order": "descending"
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
+        let data = self
+            .call(Endpoint::ComposeSearch, |req| req.json(&payload))
             .await?;
+        page_items(data)
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
-
-            let data = json
-                .get("data")
-                .ok_or_else(|| anyhow!("Missing data field in response"))?;
-
-            // Extract items logic
-            let items = if let Some(items) = data.get("items").and_then(|i| i.as_array()) {
-                items.clone()
-            } else if let Some(arr) = data.as_array() {
-                arr.clone()
-            } else {
-                return Err(anyhow!("Data field is not an array or page result"));
-            };
+    pub async fn read_file(&self, path: &str) -> Result<String> {
+        let payload = serde_json::json!({
+            "path": path,
+        });
 
-            Ok(items)
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("List composes failed: {} - {}", status, body))
-        }
+        let data = self
+            .call(Endpoint::FileContent, |req| req.json(&payload))
+            .await?;
+        Ok(decode::<FileInfo>(data)?.content)
     }
 
-    pub async fn read_file(host: &str, port: u16, api_key: &str, path: &str) -> Result<String> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
+    #[allow(dead_code)]
+    pub async fn save_file(&self, path: &str, content: &str) -> Result<()> {
+        let payload = serde_json::json!({
+            "path": path,
+            "content": content
+        });
 
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
+        self.call(Endpoint::FileSave, |req| req.json(&payload))
+            .await?;
+        Ok(())
+    }
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+    /// Files and directories directly inside `path`.
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<FileInfo>> {
+        let payload = serde_json::json!({
+            "path": path,
+            "expand": true,
+            "page": 1,
+            "pageSize": 1000,
+            "showHidden": false
+        });
 
-        let url = format!("http://{}:{}/api/v1/files/content", host_clean, port);
+        let data = self
+            .call(Endpoint::FileSearch, |req| req.json(&payload))
+            .await?;
+        page_items(data)
+    }
 
+    pub async fn delete_file(&self, path: &str) -> Result<()> {
         let payload = serde_json::json!({
             "path": path,
+            "isDir": false,
+            "forceDelete": true
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
+        self.call(Endpoint::FileDelete, |req| req.json(&payload))
             .await?;
+        Ok(())
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
+    pub async fn update_compose(&self, name: &str, path: &str, content: &str) -> Result<()> {
+        let payload = serde_json::json!({
+            "name": name,
+            "path": path,
+            "content": content,
+            // v2 takes the .env file content instead of a list of lines
+            "env": match self.version {
+                ApiVersion::V1 => serde_json::json!([]),
+                ApiVersion::V2 => serde_json::json!(""),
             }
+        });
 
-            let content = json
-                .get("data")
-                .and_then(|d| d.get("content"))
-                .and_then(|c| c.as_str())
-                .unwrap_or("");
-
-            Ok(content.to_string())
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Read file failed: {} - {}", status, body))
-        }
+        self.call(Endpoint::ComposeUpdate, |req| req.json(&payload))
+            .await?;
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn save_file(
-        host: &str,
-        port: u16,
-        api_key: &str,
-        path: &str,
-        content: &str,
-    ) -> Result<()> {
-        let timestamp = Utc::now().timestamp();
-        let host_clean = host.trim_end_matches('/');
-        let client = reqwest::Client::new();
-
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        let url = format!("http://{}:{}/api/v1/files/save", host_clean, port);
-
+    pub async fn operate_compose(&self, name: &str, path: &str, operation: &str) -> Result<()> {
         let payload = serde_json::json!({
+            "name": name,
+            "operation": operation,
             "path": path,
-            "content": content
+            "withFile": true
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
+        self.call(Endpoint::ComposeOperate, |req| req.json(&payload))
             .await?;
+        Ok(())
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
-            Ok(())
-        } else {
-            let status = res.status();
-            let text = res.text().await?;
-            Err(anyhow!("1Panel API error: {} - {}", status, text))
-        }
+    pub async fn list_images(&self) -> Result<Vec<Image>> {
+        let data = self.call(Endpoint::ImageAll, |req| req).await?;
+        Ok(decode::<Option<Vec<Image>>>(data)?.unwrap_or_default())
+    }
+
+    /// Image registries configured in 1Panel.
+    pub async fn list_repos(&self) -> Result<Vec<ImageRepo>> {
+        let data = self.call(Endpoint::RepoList, |req| req).await?;
+        Ok(decode::<Option<Vec<ImageRepo>>>(data)?.unwrap_or_default())
     }
 
-    pub async fn update_compose(
-        host: &str,
-        port: u16,
-        api_key: &str,
+    /// Adds a registry to 1Panel. For a plain HTTP registry 1Panel adds it to the
+    /// insecure registries of the Docker daemon, which restarts Docker.
+    pub async fn create_repo(
+        &self,
         name: &str,
-        path: &str,
-        content: &str,
+        download_url: &str,
+        insecure: bool,
+        username: Option<&str>,
+        password: Option<&str>,
     ) -> Result<()> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
-
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
-
-        let url = format!(
-            "http://{}:{}/api/v1/containers/compose/update",
-            host_clean, port
-        );
-
         let payload = serde_json::json!({
             "name": name,
-            "path": path,
-            "content": content,
-            "env": []
+            "downloadUrl": download_url,
+            "protocol": if insecure { "http" } else { "https" },
+            "auth": username.is_some(),
+            "username": username.unwrap_or_default(),
+            "password": password.unwrap_or_default()
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
+        self.call(Endpoint::RepoCreate, |req| req.json(&payload))
             .await?;
+        Ok(())
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
-            Ok(())
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Update compose failed: {} - {}", status, body))
-        }
+    /// Starts pulling `image` from the 1Panel registry `repo_id` (0 for Docker Hub).
+    /// 1Panel pulls in the background; the image shows up in `list_images` once done.
+    pub async fn pull_image(&self, repo_id: i64, image: &str) -> Result<()> {
+        let payload = match self.version {
+            ApiVersion::V1 => serde_json::json!({
+                "repoID": repo_id,
+                "imageName": image
+            }),
+            ApiVersion::V2 => serde_json::json!({
+                "repoID": repo_id,
+                "imageName": [image],
+                "taskID": uuid::Uuid::new_v4().to_string()
+            }),
+        };
+
+        self.call(Endpoint::ImagePull, |req| req.json(&payload))
+            .await?;
+        Ok(())
     }
 
-    pub async fn operate_compose(
-        host: &str,
-        port: u16,
-        api_key: &str,
-        name: &str,
-        path: &str,
-        operation: &str,
-    ) -> Result<()> {
-        let client = Client::builder()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-
-        let timestamp = Utc::now().timestamp();
-        let api_key = api_key.trim();
-        let host_clean = host
-            .trim()
-            .trim_start_matches("http://")
-            .trim_start_matches("https://")
-            .trim_end_matches('/');
+    /// Tags the image `source` (an ID or reference) as `target`.
+    pub async fn tag_image(&self, source: &str, target: &str) -> Result<()> {
+        let payload = match self.version {
+            ApiVersion::V1 => serde_json::json!({
+                "sourceID": source,
+                "targetName": target
+            }),
+            ApiVersion::V2 => serde_json::json!({
+                "sourceID": source,
+                "tags": [target]
+            }),
+        };
+
+        self.call(Endpoint::ImageTag, |req| req.json(&payload))
+            .await?;
+        Ok(())
+    }
+
+    /// How long any other call may take.
+    pub fn request_timeout(&self) -> Duration {
+        self.timeouts.request
+    }
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+    /// How long image uploads, loads and pulls may take.
+    pub fn upload_timeout(&self) -> Duration {
+        self.timeouts.upload
+    }
 
-        let url = format!(
-            "http://{}:{}/api/v1/containers/compose/operate",
-            host_clean, port
-        );
+    /// Directory image archives are uploaded to on this server.
+    pub fn upload_dir(&self) -> &str {
+        &self.upload_dir
+    }
 
+    pub async fn inspect_image(&self, id: &str) -> Result<ImageInspect> {
         let payload = serde_json::json!({
-            "name": name,
-            "operation": operation,
-            "path": path,
-            "withFile": true
+            "id": id,
+            "type": "image"
         });
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
+        let data = self
+            .call(Endpoint::Inspect, |req| req.json(&payload))
             .await?;
+        // 1Panel passes the `docker inspect` output on as a JSON string
+        match data {
+            Value::String(json) => {
+                serde_json::from_str(&json).map_err(|e| OnePanelError::Decode(e.to_string()))
+            }
+            data => decode(data),
+        }
+    }
+
+    pub async fn remove_image(&self, id: &str, force: bool) -> Result<()> {
+        let payload = serde_json::json!({
+            "names": [id],
+            "force": force
+        });
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
+        self.call(Endpoint::ImageRemove, |req| req.json(&payload))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Multipart form uploading `file_path` to `remote_dir`. The archive is streamed from
+/// disk instead of buffered, reporting the bytes sent to `on_progress`.
+fn upload_form(
+    file_path: &Path,
+    file_name: &str,
+    remote_dir: &str,
+    on_progress: Arc<impl Fn(u64, u64) + Send + Sync + 'static>,
+) -> Result<multipart::Form> {
+    let file = std::fs::File::open(file_path)
+        .map_err(|e| OnePanelError::Decode(format!("Cannot read {:?}: {}", file_path, e)))?;
+    let total = file
+        .metadata()
+        .map_err(|e| OnePanelError::Decode(e.to_string()))?
+        .len();
+    let file = tokio::fs::File::from_std(file);
+    let body = futures_util::stream::unfold((file, 0u64), move |(mut file, sent)| {
+        let on_progress = on_progress.clone();
+        async move {
+            let mut buf = vec![0u8; 256 * 1024];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    let sent = sent + n as u64;
+                    on_progress(sent, total);
+                    Some((Ok::<Vec<u8>, std::io::Error>(buf), (file, sent)))
                 }
+                Err(e) => Some((Err(e), (file, sent))),
             }
-            Ok(())
-        } else {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-            Err(anyhow!("Operate compose failed: {} - {}", status, body))
+        }
+    });
+    let part_file = multipart::Part::stream_with_length(reqwest::Body::wrap_stream(body), total)
+        .file_name(file_name.to_string());
+
+    Ok(multipart::Form::new()
+        .part("file", part_file)
+        .part("path", multipart::Part::text(remote_dir.to_string()))
+        .part("overwrite", multipart::Part::text("true")))
+}
+
+/// `data` of the 1Panel response envelope.
+fn envelope_data(body: &str) -> Result<Value> {
+    let json = check_envelope(body)?;
+    Ok(json.get("data").cloned().unwrap_or(Value::Null))
+}
+
+/// Parses the `{code, message, data}` envelope and fails on a code other than 200.
+fn check_envelope(body: &str) -> Result<Value> {
+    let json: Value = serde_json::from_str(body).map_err(|e| {
+        debug!("Non-JSON 1Panel response: {}", body);
+        OnePanelError::Decode(format!("{} | Body: {}", e, body))
+    })?;
+
+    if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
+        if code != 200 {
+            let message = json
+                .get("message")
+                .and_then(|m| m.as_str())
+                .unwrap_or("Unknown error")
+                .to_string();
+            return Err(if code == 401 {
+                OnePanelError::Auth(message)
+            } else {
+                OnePanelError::Api { code, message }
+            });
         }
     }
+    Ok(json)
+}
 
-    pub async fn list_images(host: &str, port: u16, api_key: &str) -> Result<serde_json::Value> {
-        let client = reqwest::Client::new();
-        let host_clean = host
-            .trim_start_matches("http://")
-            .trim_start_matches("https://");
-        let timestamp = Utc::now().timestamp();
+fn decode<T: DeserializeOwned>(data: Value) -> Result<T> {
+    serde_json::from_value(data).map_err(|e| OnePanelError::Decode(e.to_string()))
+}
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+/// Items of a paginated search result, or the data itself when it is a plain array.
+fn page_items<T: DeserializeOwned>(data: Value) -> Result<Vec<T>> {
+    match data {
+        Value::Array(_) => decode(data),
+        Value::Null => Ok(Vec::new()),
+        _ => Ok(decode::<PageResult<T>>(data)?.items),
+    }
+}
 
-        let url = format!("http://{}:{}/api/v1/containers/image/all", host_clean, port);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_onepanel::{container, MockPanel};
+    use std::time::Instant;
+
+    const BAD_GATEWAY_PAGE: &str = "<html><body><h1>502 Bad Gateway</h1></body></html>";
+
+    #[tokio::test]
+    async fn lists_containers_across_pages() {
+        let mock = MockPanel::start().await;
+        let containers: Vec<_> = (0..250)
+            .map(|i| container(&format!("c{}", i), "running", "Up 1 second"))
+            .collect();
+        mock.state().containers.push_back(containers);
+
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        assert_eq!(client.list_containers().await.unwrap().len(), 250);
+        assert_eq!(mock.state().calls("containers/search"), 3);
+    }
 
-        let res = client
-            .get(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .send()
-            .await?;
+    #[tokio::test]
+    async fn saves_and_downloads_images() {
+        let mock = MockPanel::start().await;
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let dst = std::env::temp_dir().join(format!("onepanel-download-{}.tar", mock.server.port));
+
+        let path = client
+            .save_image("web:2", "/opt/1panel/tmp/", "image_1")
+            .await
+            .unwrap();
+        assert_eq!(path, "/opt/1panel/tmp/image_1.tar");
+        assert_eq!(client.download_file(&path, &dst).await.unwrap(), 16);
+        assert_eq!(std::fs::read(&dst).unwrap(), b"archive of web:2");
+        std::fs::remove_file(&dst).unwrap();
+
+        let missing = client.download_file("/opt/1panel/tmp/gone.tar", &dst).await;
+        assert!(
+            matches!(missing, Err(OnePanelError::Api { code: 500, .. })),
+            "{:?}",
+            missing
+        );
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
+    #[tokio::test]
+    async fn retries_gateway_errors_with_backoff() {
+        let mock = MockPanel::start().await;
+        for _ in 0..2 {
+            mock.state().script(
+                "containers/image/all",
+                StatusCode::BAD_GATEWAY,
+                BAD_GATEWAY_PAGE,
+            );
+        }
+        let client = OnePanelClient::new(&mock.server).unwrap();
+
+        let started = Instant::now();
+        client.list_images().await.unwrap();
+        assert!(
+            started.elapsed() >= RETRY_DELAY * 3,
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(mock.state().calls("containers/image/all"), 3);
+    }
 
-            Ok(json.get("data").cloned().unwrap_or(serde_json::Value::Null))
-        } else {
-            Err(anyhow!("API request failed with status: {}", res.status()))
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let mock = MockPanel::start().await;
+        for _ in 0..MAX_ATTEMPTS {
+            mock.state().script(
+                "containers/image/all",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service Unavailable",
+            );
         }
+        let client = OnePanelClient::new(&mock.server).unwrap();
+
+        let result = client.list_images().await;
+        assert!(
+            matches!(
+                result,
+                Err(OnePanelError::Http {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            mock.state().calls("containers/image/all"),
+            MAX_ATTEMPTS as usize
+        );
     }
 
-    pub async fn remove_image(
-        host: &str,
-        port: u16,
-        api_key: &str,
-        id: &str,
-        force: bool,
-    ) -> Result<()> {
-        let client = reqwest::Client::new();
-        let host_clean = host
-            .trim_start_matches("http://")
-            .trim_start_matches("https://");
-        let timestamp = Utc::now().timestamp();
+    #[tokio::test]
+    async fn does_not_retry_changes() {
+        let mock = MockPanel::start().await;
+        mock.state().script(
+            "containers/image/load",
+            StatusCode::BAD_GATEWAY,
+            BAD_GATEWAY_PAGE,
+        );
+        let client = OnePanelClient::new(&mock.server).unwrap();
+
+        let result = client.load_image("/opt/1panel/tmp/web.tar").await;
+        assert!(
+            matches!(
+                result,
+                Err(OnePanelError::Http {
+                    status: StatusCode::BAD_GATEWAY,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(mock.state().calls("containers/image/load"), 1);
+    }
 
-        let token_raw = format!("1panel{}{}", api_key, timestamp);
-        let token_digest = md5::compute(token_raw.as_bytes());
-        let token_str = format!("{:x}", token_digest);
+    #[tokio::test]
+    async fn timestamps_tokens_with_the_server_clock() {
+        let mock = MockPanel::start().await;
+        mock.state().clock_skew = Some(120);
+        let client = OnePanelClient::new(&mock.server).unwrap();
+
+        // Rejected once, then signed with the clock from the `Date` header
+        client.list_images().await.unwrap();
+        assert_eq!(mock.state().calls("containers/image/all"), 2);
+        client.list_images().await.unwrap();
+        assert_eq!(mock.state().calls("containers/image/all"), 3);
+    }
 
-        let url = format!(
-            "http://{}:{}/api/v1/containers/image/remove",
-            host_clean, port
+    #[tokio::test]
+    async fn reports_rejected_tokens_when_clocks_agree() {
+        let mock = MockPanel::start().await;
+        mock.state().script(
+            "dashboard/base/os",
+            StatusCode::UNAUTHORIZED,
+            "token invalid",
         );
+        let client = OnePanelClient::new(&mock.server).unwrap();
 
-        let payload = serde_json::json!({
-            "names": [id],
-            "force": force
-        });
+        let result = client.get_os_info().await;
+        assert!(
+            matches!(result, Err(OnePanelError::Auth(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(mock.state().calls("dashboard/base/os"), 1);
+    }
 
-        let res = client
-            .post(&url)
-            .header("1Panel-Token", token_str)
-            .header("1Panel-Timestamp", timestamp.to_string())
-            .json(&payload)
-            .send()
-            .await?;
+    fn upload_source(name: &str, content: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
 
-        if res.status().is_success() {
-            let text = res.text().await?;
-            let json: serde_json::Value =
-                serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
-
-            if let Some(code) = json.get("code").and_then(|c| c.as_i64()) {
-                if code != 200 {
-                    let msg = json
-                        .get("message")
-                        .and_then(|m| m.as_str())
-                        .unwrap_or("Unknown error");
-                    return Err(anyhow!("API returned error code {}: {}", code, msg));
-                }
-            }
-            Ok(())
-        } else {
-            Err(anyhow!("API request failed with status: {}", res.status()))
+    #[tokio::test]
+    async fn retries_failed_chunks() {
+        let mock = MockPanel::start().await;
+        mock.state().script(
+            "files/chunkupload",
+            StatusCode::BAD_GATEWAY,
+            BAD_GATEWAY_PAGE,
+        );
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let file = upload_source(
+            &format!("onepanel-chunk-{}.tar", mock.server.port),
+            b"layers",
+        );
+
+        let path = client
+            .upload_file(
+                &crate::db::memory().await,
+                &file,
+                "/opt/1panel/tmp",
+                |_, _| {},
+            )
+            .await;
+        std::fs::remove_file(&file).unwrap();
+        let path = path.unwrap();
+
+        let state = mock.state();
+        assert_eq!(state.files[&path], b"layers");
+        // Two chunks, the first sent twice
+        assert_eq!(state.calls("files/chunkupload"), 3);
+        assert_eq!(state.calls("files/upload"), 0);
+    }
+
+    #[tokio::test]
+    async fn uploads_whole_files_without_chunked_upload() {
+        let mock = MockPanel::start().await;
+        mock.state().script(
+            "files/chunkupload",
+            StatusCode::NOT_FOUND,
+            "404 page not found",
+        );
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let file = upload_source(
+            &format!("onepanel-whole-{}.tar", mock.server.port),
+            b"layers",
+        );
+
+        let path = client
+            .upload_file(
+                &crate::db::memory().await,
+                &file,
+                "/opt/1panel/tmp/",
+                |_, _| {},
+            )
+            .await;
+        std::fs::remove_file(&file).unwrap();
+        let path = path.unwrap();
+
+        let state = mock.state();
+        assert_eq!(
+            path,
+            format!(
+                "/opt/1panel/tmp/{}",
+                file.file_name().unwrap().to_str().unwrap()
+            )
+        );
+        assert_eq!(state.files[&path], b"layers");
+        assert_eq!(state.calls("files/chunkupload"), 1);
+        assert_eq!(state.calls("files/upload"), 1);
+    }
+
+    #[tokio::test]
+    async fn resumes_uploads_after_the_chunks_the_server_got() {
+        let mock = MockPanel::start().await;
+        {
+            let mut state = mock.state();
+            state.pass("files/chunkupload");
+            state.script(
+                "files/chunkupload",
+                StatusCode::OK,
+                r#"{"code": 500, "message": "no space left on device", "data": null}"#,
+            );
         }
+        let db = crate::db::memory().await;
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let file = upload_source(
+            &format!("onepanel-resume-{}.tar", mock.server.port),
+            b"0123456789",
+        );
+
+        let first = client
+            .upload_file(&db, &file, "/opt/1panel/tmp", |_, _| {})
+            .await;
+        let second = client
+            .upload_file(&db, &file, "/opt/1panel/tmp", |_, _| {})
+            .await;
+        std::fs::remove_file(&file).unwrap();
+        assert!(first.is_err());
+        let path = second.unwrap();
+
+        let (left,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM upload_progress")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+        let state = mock.state();
+        assert_eq!(state.files[&path], b"0123456789");
+        // Three chunks; only the one that failed is sent again
+        assert_eq!(state.calls("files/chunkupload"), 4);
+    }
+
+    #[tokio::test]
+    async fn rejects_uploads_that_do_not_match_the_local_file() {
+        let mock = MockPanel::start().await;
+        mock.state()
+            .script("files/download", StatusCode::OK, "layerz");
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let file = upload_source(
+            &format!("onepanel-checksum-{}.tar", mock.server.port),
+            b"layers",
+        );
+
+        let result = client
+            .upload_file(
+                &crate::db::memory().await,
+                &file,
+                "/opt/1panel/tmp",
+                |_, _| {},
+            )
+            .await;
+        std::fs::remove_file(&file).unwrap();
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("does not match the local file"), "{}", error);
     }
 }
@@ -0,0 +1,1733 @@
+use crate::compose::{self, ComposeEdit, ComposeFile, ImageRef};
+use crate::db::DbPool;
+use crate::deployments::{self, NewDeployment};
+use crate::docker;
+use crate::environments::{self, NewPromotion};
+use crate::git;
+use crate::handlers::deploy;
+use crate::health;
+use crate::jobs::{JobContext, JobLog, JobRunner, NewJob, STATUS_CANCELLED, STATUS_FAILED};
+use crate::models::{
+    DeployPreview, DeployTarget, DeployTargetPreview, Deployment, Environment, Job, Promotion,
+    Registry, Repository, Server, ServiceImageChange,
+};
+use crate::onepanel::{self, OnePanelClient};
+use crate::registries;
+use crate::targets;
+use anyhow::{anyhow, Result};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{self, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+/// Finds a configured repository by exact path, then by path/name/folder name
+/// (case-insensitive, separator-agnostic) so agents can refer to projects loosely.
+pub async fn resolve_repository(db: &DbPool, path: &str) -> Result<Repository> {
+    let repo = sqlx::query_as::<_, Repository>("SELECT * FROM repositories WHERE path = ?")
+        .bind(path)
+        .fetch_optional(db)
+        .await?;
+
+    if let Some(r) = repo {
+        return Ok(r);
+    }
+
+    let all_repos = sqlx::query_as::<_, Repository>("SELECT * FROM repositories")
+        .fetch_all(db)
+        .await?;
+
+    let search_term = path.to_lowercase().replace("\\", "/");
+    let search_term_trim = search_term.trim_end_matches('/');
+
+    for r in all_repos {
+        let r_path = r.path.to_lowercase().replace("\\", "/");
+        let r_path_trim = r_path.trim_end_matches('/');
+
+        // 1. Path match
+        if r_path_trim == search_term_trim {
+            return Ok(r);
+        }
+
+        // 2. Name match
+        if let Some(n) = &r.name {
+            if n.to_lowercase() == search_term_trim {
+                return Ok(r);
+            }
+        }
+
+        // 3. Folder name match (ends with /search_term)
+        if r_path_trim.ends_with(&format!("/{}", search_term_trim)) {
+            return Ok(r);
+        }
+    }
+
+    Err(anyhow!("Path not configured: {}", path))
+}
+
+pub fn image_name_for(repo: &Repository) -> String {
+    repo.docker_image_name.clone().unwrap_or_else(|| {
+        std::path::Path::new(&repo.path)
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    })
+}
+
+/// Computes the next version for `image_name` by bumping the highest local
+/// semver tag's patch, or the highest integer tag, defaulting to `v1.0.0`.
+pub async fn next_version(image_name: &str) -> Result<String> {
+    let tags = docker::list_tags(image_name)
+        .await
+        .map_err(|e| anyhow!("List tags error: {}", e))?;
+
+    let version_regex = Regex::new(r"^v?(\d+)(\.(\d+))?(\.(\d+))?$").unwrap();
+    let mut max_ver = (0, 0, 0); // major, minor, patch
+    let mut max_int_ver = 0;
+    let mut found_semver = false;
+
+    for img in tags {
+        for tag in img.tags {
+            if let Some(pos) = tag.rfind(':') {
+                let ver_str = &tag[pos + 1..];
+                if let Some(caps) = version_regex.captures(ver_str) {
+                    let major = caps.get(1).map_or(0, |m| m.as_str().parse().unwrap_or(0));
+                    let minor = caps.get(3).map_or(0, |m| m.as_str().parse().unwrap_or(0));
+                    let patch = caps.get(5).map_or(0, |m| m.as_str().parse().unwrap_or(0));
+
+                    // Poor man's version compare
+                    if major > max_ver.0
+                        || (major == max_ver.0 && minor > max_ver.1)
+                        || (major == max_ver.0 && minor == max_ver.1 && patch > max_ver.2)
+                    {
+                        max_ver = (major, minor, patch);
+                        found_semver = true;
+                    }
+                } else if let Ok(v) = ver_str.parse::<i32>() {
+                    if v > max_int_ver {
+                        max_int_ver = v;
+                    }
+                }
+            }
+        }
+    }
+
+    let new_version = if found_semver {
+        format!("v{}.{}.{}", max_ver.0, max_ver.1, max_ver.2 + 1)
+    } else if max_int_ver > 0 {
+        format!("{}", max_int_ver + 1)
+    } else {
+        "v1.0.0".to_string()
+    };
+
+    Ok(new_version)
+}
+
+async fn build(repo: &Repository, image_name: &str, version: &str, log: &JobLog) -> Result<()> {
+    let req = docker::DockerBuildRequest {
+        path: repo.path.clone(),
+        image_name: image_name.to_string(),
+        version: version.to_string(),
+    };
+    docker::build_image(req, log)
+        .await
+        .map(|_| ())
+        .map_err(|e| anyhow!("Build error: {}", e))
+}
+
+pub const KIND_BUILD: &str = "build";
+pub const KIND_BUILD_AND_DEPLOY: &str = "build_and_deploy";
+pub const KIND_DOCKER_BUILD: &str = "docker_build";
+pub const KIND_ROLLBACK: &str = "rollback";
+/// Rollback of every deploy target of a repository, following its rollout settings
+pub const KIND_ROLLBACK_REPOSITORY: &str = "rollback_repository";
+pub const KIND_PROMOTE: &str = "promote";
+
+type JobTask = Box<dyn FnOnce(JobContext) -> BoxFuture<'static, Result<()>> + Send>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathParams {
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployParams {
+    pub path: String,
+    /// Compose service to point at the new image; defaults to every service using it
+    #[serde(default)]
+    pub service: Option<String>,
+    /// Tag to build and deploy; defaults to the next version
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Token of an approved preview
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// The compose change a deploy would make differs from the preview its token was issued for.
+#[derive(Debug)]
+pub struct StaleToken;
+
+impl std::fmt::Display for StaleToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The deploy no longer matches its preview (the compose file or the requested version changed); run a new dry run"
+        )
+    }
+}
+
+impl std::error::Error for StaleToken {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackParams {
+    pub server_id: i64,
+    pub compose_path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteParams {
+    pub path: String,
+    pub from: String,
+    pub to: String,
+    /// Tag that was running in `from` when the promotion was requested
+    pub version: String,
+    pub promotion_id: i64,
+}
+
+/// Starts a job of `kind` from its JSON `params`. Every job kind goes through here
+/// so that a failed job can be re-run from its recorded parameters.
+pub async fn start(
+    jobs: &JobRunner,
+    kind: &str,
+    params: serde_json::Value,
+    triggered_by: &str,
+    retry_of: Option<&str>,
+) -> Result<String> {
+    let task: JobTask = match kind {
+        KIND_BUILD => {
+            let p: PathParams = serde_json::from_value(params.clone())?;
+            Box::new(move |ctx| run_build(ctx, p.path).boxed())
+        }
+        KIND_BUILD_AND_DEPLOY => {
+            let p: DeployParams = serde_json::from_value(params.clone())?;
+            Box::new(move |ctx| run_build_and_deploy(ctx, p).boxed())
+        }
+        KIND_DOCKER_BUILD => {
+            let req: docker::DockerBuildRequest = serde_json::from_value(params.clone())?;
+            Box::new(move |ctx| run_docker_build(ctx, req).boxed())
+        }
+        KIND_ROLLBACK => {
+            let p: RollbackParams = serde_json::from_value(params.clone())?;
+            Box::new(move |ctx| run_rollback(ctx, p).boxed())
+        }
+        KIND_ROLLBACK_REPOSITORY => {
+            let p: PathParams = serde_json::from_value(params.clone())?;
+            Box::new(move |ctx| run_repository_rollback(ctx, p).boxed())
+        }
+        KIND_PROMOTE => {
+            let p: PromoteParams = serde_json::from_value(params.clone())?;
+            Box::new(move |ctx| run_promote(ctx, p).boxed())
+        }
+        _ => return Err(anyhow!("Unknown job kind: {}", kind)),
+    };
+
+    let repository_path = params.get("path").and_then(|p| p.as_str());
+    let id = jobs
+        .spawn(
+            NewJob {
+                kind,
+                repository_path,
+                params: &params,
+                retry_of,
+                triggered_by,
+            },
+            task,
+        )
+        .await?;
+    Ok(id)
+}
+
+/// Starts a build-and-deploy job. With a preview token, a job already started for
+/// that token is returned instead of deploying twice, and a token whose preview no
+/// longer matches the remote compose file is rejected with [`StaleToken`].
+pub async fn start_deploy(
+    jobs: &JobRunner,
+    db: &DbPool,
+    params: DeployParams,
+    triggered_by: &str,
+) -> Result<String> {
+    if let Some(token) = &params.token {
+        if let Some(id) = jobs.find_by_token(KIND_BUILD_AND_DEPLOY, token).await? {
+            return Ok(id);
+        }
+        if &preview_deploy(db, &params).await?.token != token {
+            return Err(StaleToken.into());
+        }
+    }
+
+    start(
+        jobs,
+        KIND_BUILD_AND_DEPLOY,
+        serde_json::to_value(&params)?,
+        triggered_by,
+        None,
+    )
+    .await
+}
+
+/// Computes what a deploy would change on every target without building or
+/// writing anything.
+pub async fn preview_deploy(db: &DbPool, params: &DeployParams) -> Result<DeployPreview> {
+    let repo = resolve_repository(db, &params.path).await?;
+    let targets = load_targets(db, &repo).await?;
+
+    let image_name = image_name_for(&repo);
+    let version = match &params.version {
+        Some(v) => v.clone(),
+        None => next_version(&image_name).await?,
+    };
+
+    let plans = plan_targets(&targets, &image_name, &version, params.service.as_deref()).await?;
+
+    Ok(DeployPreview {
+        repository_path: repo.path.clone(),
+        image: format!("{}:{}", image_name, version),
+        token: preview_token(&plans),
+        targets: plans
+            .iter()
+            .map(|(t, plan)| DeployTargetPreview {
+                server_id: t.server.id,
+                server_name: t.server.name.clone(),
+                stack_name: plan.stack_name.clone(),
+                compose_path: t.target.compose_path.clone(),
+                changes: plan
+                    .edit
+                    .changes
+                    .iter()
+                    .map(|c| ServiceImageChange {
+                        service: c.service.clone(),
+                        from: c.from.to_string(),
+                        to: c.to.to_string(),
+                    })
+                    .collect(),
+                diff: compose::unified_diff(
+                    &t.target.compose_path,
+                    &plan.current,
+                    &plan.edit.content,
+                ),
+            })
+            .collect(),
+    })
+}
+
+/// A deploy target together with its server.
+struct TargetServer {
+    target: DeployTarget,
+    server: Server,
+    client: OnePanelClient,
+    /// Environment of the repository that deploys to the same stack
+    environment: Option<String>,
+}
+
+impl TargetServer {
+    fn label(&self) -> String {
+        format!("{}:{}", self.server.name, self.target.compose_path)
+    }
+}
+
+async fn load_targets(db: &DbPool, repo: &Repository) -> Result<Vec<TargetServer>> {
+    let list = targets::list(db, repo).await?;
+    if list.is_empty() {
+        return Err(anyhow!(
+            "No deploy target configured; set a default server and compose path or add deploy targets"
+        ));
+    }
+
+    let envs = environments::list(db, repo.id).await?;
+    let mut loaded = Vec::with_capacity(list.len());
+    for target in list {
+        let server = onepanel::find_server(db, target.server_id)
+            .await?
+            .ok_or_else(|| anyhow!("Server {} not found", target.server_id))?;
+        let client = OnePanelClient::new(&server)?;
+        let environment = envs
+            .iter()
+            .find(|e| e.server_id == target.server_id && e.compose_path == target.compose_path)
+            .map(|e| e.name.clone());
+        loaded.push(TargetServer {
+            target,
+            server,
+            client,
+            environment,
+        });
+    }
+    Ok(loaded)
+}
+
+/// Plans the compose edit of every target. `service` overrides the service
+/// configured on the targets.
+async fn plan_targets<'a>(
+    targets: &'a [TargetServer],
+    image_name: &str,
+    version: &str,
+    service: Option<&str>,
+) -> Result<Vec<(&'a TargetServer, ComposePlan)>> {
+    let mut plans = Vec::with_capacity(targets.len());
+    for t in targets {
+        let plan = plan_compose_edit(
+            &t.client,
+            &t.target.compose_path,
+            image_name,
+            version,
+            service.or(t.target.service.as_deref()),
+        )
+        .await
+        .map_err(|e| match targets.len() {
+            1 => e,
+            _ => anyhow!("{}: {}", t.label(), e),
+        })?;
+        plans.push((t, plan));
+    }
+    Ok(plans)
+}
+
+/// Token of a whole preview. With a single target it is that target's edit token.
+fn preview_token(plans: &[(&TargetServer, ComposePlan)]) -> String {
+    match plans {
+        [(_, plan)] => plan.token(),
+        _ => {
+            let tokens: Vec<String> = plans.iter().map(|(_, plan)| plan.token()).collect();
+            format!("{:x}", md5::compute(tokens.join("\n")))
+        }
+    }
+}
+
+/// Finds the `from` environment of a repository and the one to promote it to:
+/// `to`, or else the environment following `from`.
+pub(crate) async fn resolve_environments(
+    db: &DbPool,
+    repo: &Repository,
+    from: &str,
+    to: Option<&str>,
+) -> Result<(Environment, Environment)> {
+    let envs = environments::list(db, repo.id).await?;
+    let i = envs
+        .iter()
+        .position(|e| e.name == from)
+        .ok_or_else(|| anyhow!("Unknown environment: {}", from))?;
+
+    let target = match to {
+        Some(name) => envs
+            .iter()
+            .find(|e| e.name == name)
+            .ok_or_else(|| anyhow!("Unknown environment: {}", name))?,
+        None => envs
+            .get(i + 1)
+            .ok_or_else(|| anyhow!("{} is the last environment; nothing to promote to", from))?,
+    };
+    if target.name == from {
+        return Err(anyhow!("Cannot promote {} to itself", from));
+    }
+
+    Ok((envs[i].clone(), target.clone()))
+}
+
+/// Tag of `image_name` that `env` currently runs, read from its compose file the
+/// same way `get_image_deployments` discovers deployments.
+async fn deployed_version(db: &DbPool, env: &Environment, image_name: &str) -> Result<String> {
+    let server = onepanel::find_server(db, env.server_id)
+        .await?
+        .ok_or_else(|| anyhow!("Server {} not found", env.server_id))?;
+
+    let content = OnePanelClient::new(&server)?
+        .read_file(&env.compose_path)
+        .await?;
+    let compose = ComposeFile::parse(&content)?;
+    let service = compose
+        .services_using(image_name)
+        .find(|s| env.service.as_deref().is_none_or(|name| s.service == name))
+        .ok_or_else(|| anyhow!("{} does not run {}", env.name, image_name))?;
+
+    service.image.tag.clone().ok_or_else(|| {
+        anyhow!(
+            "{} runs {} without a tag; only tagged images can be promoted",
+            env.name,
+            service.image
+        )
+    })
+}
+
+/// Promotes the image running in environment `from` to `to` (by default the next
+/// environment). Into an environment that requires approval the promotion waits as
+/// pending; otherwise its deploy job starts right away.
+pub async fn promote(
+    jobs: &JobRunner,
+    db: &DbPool,
+    path: &str,
+    from: &str,
+    to: Option<&str>,
+    triggered_by: &str,
+) -> Result<Promotion> {
+    let repo = resolve_repository(db, path).await?;
+    let (source, target) = resolve_environments(db, &repo, from, to).await?;
+    let image_name = image_name_for(&repo);
+    let version = deployed_version(db, &source, &image_name).await?;
+
+    let promotion = environments::create_promotion(
+        db,
+        NewPromotion {
+            repository_path: &repo.path,
+            from_env: &source.name,
+            to_env: &target.name,
+            image: &format!("{}:{}", image_name, version),
+            status: if target.requires_approval {
+                environments::PROMOTION_PENDING
+            } else {
+                environments::PROMOTION_APPROVED
+            },
+            triggered_by,
+        },
+    )
+    .await?;
+
+    if target.requires_approval {
+        return Ok(promotion);
+    }
+    start_promotion(jobs, db, promotion, triggered_by).await
+}
+
+/// Approves a pending promotion and starts its deploy job.
+pub async fn approve_promotion(
+    jobs: &JobRunner,
+    db: &DbPool,
+    id: i64,
+    triggered_by: &str,
+) -> Result<Promotion> {
+    let promotion = environments::decide_promotion(db, id, environments::PROMOTION_APPROVED)
+        .await?
+        .ok_or_else(|| anyhow!("Promotion {} is not pending", id))?;
+    start_promotion(jobs, db, promotion, triggered_by).await
+}
+
+async fn start_promotion(
+    jobs: &JobRunner,
+    db: &DbPool,
+    mut promotion: Promotion,
+    triggered_by: &str,
+) -> Result<Promotion> {
+    let version = ImageRef::parse(&promotion.image)
+        .tag
+        .ok_or_else(|| anyhow!("Promotion {} has no image tag", promotion.id))?;
+    let params = PromoteParams {
+        path: promotion.repository_path.clone(),
+        from: promotion.from_env.clone(),
+        to: promotion.to_env.clone(),
+        version,
+        promotion_id: promotion.id,
+    };
+
+    let job_id = start(
+        jobs,
+        KIND_PROMOTE,
+        serde_json::to_value(&params)?,
+        triggered_by,
+        None,
+    )
+    .await?;
+    environments::set_promotion_job(db, promotion.id, &job_id).await?;
+    promotion.job_id = Some(job_id);
+    Ok(promotion)
+}
+
+/// Re-runs a failed or cancelled job. Steps that succeeded last time are skipped,
+/// so an image that was already built is reused rather than rebuilt.
+pub async fn retry(jobs: &JobRunner, job: &Job, triggered_by: &str) -> Result<String> {
+    if job.status != STATUS_FAILED && job.status != STATUS_CANCELLED {
+        return Err(anyhow!(
+            "Only failed or cancelled jobs can be retried (job is {})",
+            job.status
+        ));
+    }
+
+    let params = match &job.params {
+        Some(p) => serde_json::from_str(p)?,
+        None => return Err(anyhow!("Job has no recorded parameters")),
+    };
+
+    start(jobs, &job.kind, params, triggered_by, Some(&job.id)).await
+}
+
+/// Job body for `/api/docker/build`, where the caller picks the version.
+async fn run_docker_build(ctx: JobContext, req: docker::DockerBuildRequest) -> Result<()> {
+    ctx.set_image_tag(&format!("{}:{}", req.image_name, req.version))
+        .await?;
+
+    ctx.step("build_image", async {
+        docker::build_image(req, &ctx.log)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("Build error: {}", e))
+    })
+    .await
+}
+
+/// Job body for the `build_image` MCP tool.
+async fn run_build(ctx: JobContext, path: String) -> Result<()> {
+    let repo = ctx
+        .step("resolve_repository", resolve_repository(&ctx.db, &path))
+        .await?;
+    let image_name = image_name_for(&repo);
+
+    let version = ctx
+        .step("compute_version", next_version(&image_name))
+        .await?;
+    ctx.set_image_tag(&format!("{}:{}", image_name, version))
+        .await?;
+
+    ctx.step("build_image", build(&repo, &image_name, &version, &ctx.log))
+        .await
+}
+
+/// Job body for the `build_and_deploy` MCP tool. The image is built once and then
+/// rolled out to every deploy target of the repository.
+async fn run_build_and_deploy(ctx: JobContext, p: DeployParams) -> Result<()> {
+    let repo = ctx
+        .step("resolve_repository", async {
+            let repo = resolve_repository(&ctx.db, &p.path).await?;
+            load_targets(&ctx.db, &repo).await?;
+            Ok(repo)
+        })
+        .await?;
+
+    // Read the settings again so that a retry picks up changes made since the first attempt
+    let settings = sqlx::query_as::<_, Repository>("SELECT * FROM repositories WHERE id = ?")
+        .bind(repo.id)
+        .fetch_optional(&*ctx.db)
+        .await?
+        .unwrap_or(repo);
+    let targets = load_targets(&ctx.db, &settings).await?;
+    ctx.set_server_id(targets[0].server.id).await?;
+
+    let image_name = image_name_for(&settings);
+    let version = ctx
+        .step("compute_version", async {
+            match &p.version {
+                Some(v) => Ok(v.clone()),
+                None => next_version(&image_name).await,
+            }
+        })
+        .await?;
+    let full_tag = format!("{}:{}", image_name, version);
+    ctx.set_image_tag(&full_tag).await?;
+
+    ctx.step(
+        "build_image",
+        build(&settings, &image_name, &version, &ctx.log),
+    )
+    .await?;
+
+    if let Some(token) = &p.token {
+        ctx.step("verify_preview", async {
+            let plans = plan_targets(&targets, &image_name, &version, p.service.as_deref()).await?;
+            if &preview_token(&plans) != token {
+                return Err(anyhow::Error::from(StaleToken));
+            }
+            Ok(())
+        })
+        .await?;
+    }
+
+    let rollout = targets::Rollout::of(&settings);
+    let run = TargetDeploy {
+        ctx: &ctx,
+        repo: &settings,
+        image_name: &image_name,
+        version: &version,
+        full_tag: &full_tag,
+        service: p.service.as_deref(),
+        timeout_secs: settings
+            .health_timeout_secs
+            .unwrap_or(health::DEFAULT_TIMEOUT_SECS),
+        pushes: targets
+            .iter()
+            .map(|t| (t.server.id, OnceCell::new()))
+            .collect(),
+        registry: registries::of_repository(&ctx.db, &settings).await?,
+        registry_push: OnceCell::new(),
+        labelled: targets.len() > 1,
+        action: deployments::ACTION_DEPLOY,
+        commit_hash: git::get_commit_log(&settings.path, 1)
+            .ok()
+            .and_then(|commits| commits.into_iter().next())
+            .map(|c| c.hash),
+        promoted_from: None,
+    };
+
+    if targets.len() > 1 {
+        ctx.log.line(format!(
+            "Deploying {} to {} targets ({})",
+            full_tag,
+            targets.len(),
+            rollout.strategy
+        ));
+    }
+
+    let labels: Vec<String> = targets.iter().map(TargetServer::label).collect();
+    let (run, targets) = (&run, &targets[..]);
+    roll_out(&ctx, &rollout, "Deploy", &labels, move |i| {
+        run.deploy(&targets[i]).boxed()
+    })
+    .await
+}
+
+/// Runs `run` for the target at each index of `labels` as `rollout` says: one
+/// after the other, stopping at the first failure, or several at once.
+async fn roll_out<'a>(
+    ctx: &JobContext,
+    rollout: &targets::Rollout,
+    action: &str,
+    labels: &[String],
+    run: impl Fn(usize) -> BoxFuture<'a, Result<()>>,
+) -> Result<()> {
+    if rollout.strategy == targets::STRATEGY_PARALLEL && labels.len() > 1 {
+        // Boxed up front: a stream over a borrowing closure would not be `Send` for the job task
+        let runs: Vec<BoxFuture<'a, Result<()>>> = labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                let label = label.clone();
+                run(i)
+                    .map(move |r| r.map_err(|e| anyhow!("{}: {}", label, e)))
+                    .boxed()
+            })
+            .collect();
+        let results: Vec<Result<()>> = stream::iter(runs)
+            .buffer_unordered(rollout.concurrency)
+            .collect()
+            .await;
+        let failures: Vec<String> = results
+            .into_iter()
+            .filter_map(|r| r.err().map(|e| e.to_string()))
+            .collect();
+
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "{} failed on {} of {} targets: {}",
+                action,
+                failures.len(),
+                labels.len(),
+                failures.join("; ")
+            ));
+        }
+        return Ok(());
+    }
+
+    for (i, label) in labels.iter().enumerate() {
+        if i > 0 && rollout.pause_secs > 0 {
+            ctx.log.line(format!(
+                "Waiting {}s before continuing with {}",
+                rollout.pause_secs, label
+            ));
+            tokio::time::sleep(std::time::Duration::from_secs(rollout.pause_secs)).await;
+        }
+
+        if let Err(e) = run(i).await {
+            if labels.len() == 1 {
+                return Err(e);
+            }
+            let remaining = &labels[i + 1..];
+            return Err(if remaining.is_empty() {
+                anyhow!("{}: {}", label, e)
+            } else {
+                anyhow!("{}: {}; not done on {}", label, e, remaining.join(", "))
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Step names are qualified with the target when a job deploys to several, so
+/// that a retry can tell which targets are already done.
+fn target_step(name: &str, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{} [{}]", name, label),
+        None => name.to_string(),
+    }
+}
+
+/// What every target of a build-and-deploy job shares.
+struct TargetDeploy<'a> {
+    ctx: &'a JobContext,
+    repo: &'a Repository,
+    image_name: &'a str,
+    version: &'a str,
+    full_tag: &'a str,
+    service: Option<&'a str>,
+    timeout_secs: i64,
+    /// The image is pushed once per server, however many targets it hosts
+    pushes: HashMap<i64, OnceCell<()>>,
+    /// Servers pull the image from here instead of having it uploaded
+    registry: Option<Registry>,
+    /// The image is pushed to the registry once per job
+    registry_push: OnceCell<()>,
+    labelled: bool,
+    /// Recorded with every deployment of the job
+    action: &'static str,
+    commit_hash: Option<String>,
+    promoted_from: Option<&'a str>,
+}
+
+impl TargetDeploy<'_> {
+    /// Rewrites the image tag in the target's compose file and records the deployment,
+    /// keeping the previous content so it can be rolled back.
+    async fn update_compose(&self, t: &TargetServer) -> Result<ComposeUpdate> {
+        let ctx = self.ctx;
+        let server = &t.server;
+        let compose_path = t.target.compose_path.as_str();
+
+        let plan = plan_compose_edit(
+            &t.client,
+            compose_path,
+            self.image_name,
+            self.version,
+            self.service.or(t.target.service.as_deref()),
+        )
+        .await?;
+        ctx.log.line(format!(
+            "Found stack {} for {}",
+            plan.stack_name, compose_path
+        ));
+
+        let ComposePlan {
+            stack_name,
+            current: content,
+            edit,
+        } = plan;
+        for change in &edit.changes {
+            ctx.log.line(format!(
+                "Setting service {} image {} (was {}) in {}",
+                change.service, change.to, change.from, compose_path
+            ));
+        }
+        let previous_tag = edit.previous_version();
+        let new_content = edit.content.as_str();
+        t.client
+            .update_compose(&stack_name, compose_path, new_content)
+            .await?;
+
+        let deployment_id = deployments::record(
+            &ctx.db,
+            NewDeployment {
+                job_id: &ctx.job_id,
+                action: self.action,
+                server_id: server.id,
+                stack_name: &stack_name,
+                compose_path,
+                image_name: self.image_name,
+                previous_tag,
+                new_tag: self.version,
+                previous_content: &content,
+                new_content,
+                repository_path: Some(&self.repo.path),
+                commit_hash: self.commit_hash.as_deref(),
+                triggered_by: &ctx.triggered_by,
+                environment: t.environment.as_deref(),
+                promoted_from: self.promoted_from,
+            },
+        )
+        .await?;
+
+        Ok(ComposeUpdate {
+            stack_name,
+            deployment_id,
+        })
+    }
+
+    /// Pushes the image, updates the compose file, brings the stack up and verifies
+    /// it, rolling this target back when verification fails.
+    async fn deploy(&self, t: &TargetServer) -> Result<()> {
+        let ctx = self.ctx;
+        let server = &t.server;
+        let compose_path = t.target.compose_path.as_str();
+        let label = self.labelled.then(|| t.label());
+        let label = label.as_deref();
+
+        if let Some(label) = label {
+            ctx.log.line(format!("--- {}", label));
+        }
+
+        if let Some(push) = self.pushes.get(&server.id) {
+            push.get_or_try_init(|| {
+                let name = target_step("push_image", self.labelled.then_some(&*server.name));
+                async move {
+                    match &self.registry {
+                        Some(registry) => {
+                            ctx.step(&name, async {
+                                self.registry_push
+                                    .get_or_try_init(|| {
+                                        registries::push(registry, self.full_tag, &ctx.log)
+                                    })
+                                    .await?;
+                                registries::pull(&t.client, registry, self.full_tag, &ctx.log).await
+                            })
+                            .await
+                        }
+                        None => {
+                            ctx.step(
+                                &name,
+                                deploy::push_image_to_server_inner(
+                                    &ctx.db,
+                                    server.id,
+                                    self.full_tag,
+                                    &ctx.log,
+                                ),
+                            )
+                            .await
+                        }
+                    }
+                }
+            })
+            .await?;
+        }
+
+        let update = ctx
+            .step(
+                &target_step("update_compose", label),
+                self.update_compose(t),
+            )
+            .await?;
+
+        // Reused from an earlier attempt that got this target through; its
+        // outcome stands
+        if let Some(done) = deployments::get(&ctx.db, update.deployment_id)
+            .await?
+            .filter(|d| deployments::is_settled(&d.status))
+        {
+            ctx.log.line(format!(
+                "{} was already {} by the previous attempt",
+                t.label(),
+                done.status
+            ));
+            return Ok(());
+        }
+
+        let result = ctx
+            .step(&target_step("operate_compose", label), async {
+                ctx.log
+                    .line(format!("Bringing up stack {}", update.stack_name));
+                t.client
+                    .operate_compose(&update.stack_name, compose_path, "up")
+                    .await?;
+                Ok(())
+            })
+            .await;
+
+        if let Err(e) = result {
+            finish_deployment(ctx, update.deployment_id, &Err(anyhow!("{}", e))).await?;
+            return Err(e);
+        }
+
+        verify_deployment(
+            ctx,
+            &t.client,
+            &update,
+            label,
+            self.timeout_secs,
+            self.repo.health_check_url.as_deref(),
+        )
+        .await
+    }
+}
+
+/// Verifies a stack that was just brought up, unless `timeout_secs` is 0, and
+/// records the deployment as deployed, or rolls it back when verification fails.
+async fn verify_deployment(
+    ctx: &JobContext,
+    client: &OnePanelClient,
+    update: &ComposeUpdate,
+    label: Option<&str>,
+    timeout_secs: i64,
+    url: Option<&str>,
+) -> Result<()> {
+    if timeout_secs > 0 {
+        let verified = ctx
+            .step(
+                &target_step("verify_health", label),
+                health::verify(client, &update.stack_name, timeout_secs, url, &ctx.log),
+            )
+            .await;
+
+        if let Err(e) = verified {
+            return auto_rollback(ctx, client, update.deployment_id, label, e).await;
+        }
+    }
+
+    finish_deployment(ctx, update.deployment_id, &Ok(())).await
+}
+
+async fn finish_deployment(
+    ctx: &JobContext,
+    deployment_id: i64,
+    result: &Result<()>,
+) -> Result<()> {
+    match result {
+        Ok(()) => {
+            deployments::finish(&ctx.db, deployment_id, deployments::STATUS_DEPLOYED, None).await?
+        }
+        Err(e) => {
+            deployments::finish(
+                &ctx.db,
+                deployment_id,
+                deployments::STATUS_FAILED,
+                Some(&e.to_string()),
+            )
+            .await?
+        }
+    }
+    Ok(())
+}
+
+/// Restores the previous compose file after a deploy failed verification. The job
+/// still fails with the verification error; a retry deploys the new image again.
+async fn auto_rollback(
+    ctx: &JobContext,
+    client: &OnePanelClient,
+    deployment_id: i64,
+    label: Option<&str>,
+    error: anyhow::Error,
+) -> Result<()> {
+    let target = deployments::get(&ctx.db, deployment_id)
+        .await?
+        .ok_or_else(|| anyhow!("Deployment {} not found", deployment_id))?;
+
+    ctx.log.line(format!(
+        "Rolling back {} to {}",
+        target.stack_name,
+        target
+            .previous_tag
+            .as_deref()
+            .unwrap_or("the previous compose file")
+    ));
+    let reason = error.to_string();
+    if let Err(rollback_error) = ctx
+        .step(
+            &target_step("auto_rollback", label),
+            restore_previous(ctx, client, &target, Some(&reason)),
+        )
+        .await
+    {
+        // Once the previous compose file is back, the deployment stays rolled back
+        // even though bringing the stack up failed
+        let restored = deployments::get(&ctx.db, deployment_id)
+            .await?
+            .is_some_and(|d| d.status == deployments::STATUS_ROLLED_BACK);
+        if !restored {
+            deployments::finish(
+                &ctx.db,
+                deployment_id,
+                deployments::STATUS_FAILED,
+                Some(&reason),
+            )
+            .await?;
+        }
+        return Err(anyhow!(
+            "{}; automatic rollback failed: {}",
+            error,
+            rollback_error
+        ));
+    }
+
+    let steps =
+        ["update_compose", "operate_compose", "auto_rollback"].map(|s| target_step(s, label));
+    ctx.revert_steps(&steps.each_ref().map(String::as_str))
+        .await?;
+    Err(anyhow!("{}; rolled back to the previous release", error))
+}
+
+/// Deploys the image running in one environment to another. Nothing is rebuilt:
+/// the image built for the source environment is pushed from the local Docker, or
+/// when it is no longer there, taken from the registry or the source server.
+async fn run_promote(ctx: JobContext, p: PromoteParams) -> Result<()> {
+    let (repo, source, env) = ctx
+        .step("resolve_environment", async {
+            let repo = resolve_repository(&ctx.db, &p.path).await?;
+            let (source, env) = resolve_environments(&ctx.db, &repo, &p.from, Some(&p.to)).await?;
+            Ok((repo, source, env))
+        })
+        .await?;
+
+    let server = onepanel::find_server(&ctx.db, env.server_id)
+        .await?
+        .ok_or_else(|| anyhow!("Server {} not found", env.server_id))?;
+    ctx.set_server_id(server.id).await?;
+
+    let image_name = image_name_for(&repo);
+    let full_tag = format!("{}:{}", image_name, p.version);
+    ctx.set_image_tag(&full_tag).await?;
+    ctx.log.line(format!(
+        "Promoting {} from {} to {}",
+        full_tag, p.from, p.to
+    ));
+
+    let registry = registries::of_repository(&ctx.db, &repo).await?;
+    let in_registry = ctx
+        .step(
+            "locate_image",
+            locate_image(&ctx, &full_tag, &source, registry.as_ref()),
+        )
+        .await?;
+
+    let target = TargetServer {
+        target: DeployTarget {
+            id: 0,
+            repository_id: repo.id,
+            server_id: env.server_id,
+            compose_path: env.compose_path.clone(),
+            service: env.service.clone(),
+            position: env.position,
+        },
+        client: OnePanelClient::new(&server)?,
+        server,
+        environment: Some(env.name.clone()),
+    };
+    let run = TargetDeploy {
+        ctx: &ctx,
+        repo: &repo,
+        image_name: &image_name,
+        version: &p.version,
+        full_tag: &full_tag,
+        service: None,
+        timeout_secs: repo
+            .health_timeout_secs
+            .unwrap_or(health::DEFAULT_TIMEOUT_SECS),
+        pushes: HashMap::from([(target.server.id, OnceCell::new())]),
+        registry,
+        // Already in the registry: the server pulls it without a push from here
+        registry_push: OnceCell::new_with(in_registry.then_some(())),
+        labelled: false,
+        action: deployments::ACTION_PROMOTE,
+        commit_hash: deployments::commit_of(&ctx.db, &image_name, &p.version).await?,
+        promoted_from: Some(&p.from),
+    };
+    run.deploy(&target).await
+}
+
+/// Makes sure the promoted image can be sent to the target. Returns true when it
+/// is missing locally but the repository deploys through a registry, which has it
+/// from the source environment's deploy; otherwise a missing image is copied from
+/// the source environment's server into the local Docker.
+async fn locate_image(
+    ctx: &JobContext,
+    full_tag: &str,
+    source: &Environment,
+    registry: Option<&Registry>,
+) -> Result<bool> {
+    if docker::image_exists(full_tag)
+        .await
+        .map_err(|e| anyhow!("Docker error: {}", e))?
+    {
+        return Ok(false);
+    }
+
+    match registry {
+        Some(registry) => {
+            ctx.log.line(format!(
+                "{} is not in the local Docker; the server pulls it from {}",
+                full_tag, registry.url
+            ));
+            Ok(true)
+        }
+        None => {
+            ctx.log.line(format!(
+                "{} is not in the local Docker; copying it from {}",
+                full_tag, source.name
+            ));
+            deploy::pull_image_from_server(&ctx.db, source.server_id, full_tag, &ctx.log).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Restores the compose content that the latest deployment to the target replaced
+/// and brings the stack up again.
+async fn run_rollback(ctx: JobContext, p: RollbackParams) -> Result<()> {
+    ctx.set_server_id(p.server_id).await?;
+
+    let server = onepanel::find_server(&ctx.db, p.server_id)
+        .await?
+        .ok_or_else(|| anyhow!("Server not found"))?;
+    let client = OnePanelClient::new(&server)?;
+
+    rollback_target(&ctx, &client, p.server_id, &p.compose_path, None).await
+}
+
+/// Rolls back every deploy target of a repository, one after the other or in
+/// parallel as its rollout strategy says.
+async fn run_repository_rollback(ctx: JobContext, p: PathParams) -> Result<()> {
+    let repo = resolve_repository(&ctx.db, &p.path).await?;
+    let targets = load_targets(&ctx.db, &repo).await?;
+    let rollout = targets::Rollout::of(&repo);
+    let labelled = targets.len() > 1;
+
+    if labelled {
+        ctx.log.line(format!(
+            "Rolling back {} targets ({})",
+            targets.len(),
+            rollout.strategy
+        ));
+    } else {
+        ctx.set_server_id(targets[0].server.id).await?;
+    }
+
+    let labels: Vec<String> = targets.iter().map(TargetServer::label).collect();
+    let (ctx, targets) = (&ctx, &targets[..]);
+    roll_out(ctx, &rollout, "Rollback", &labels, move |i| {
+        let t = &targets[i];
+        async move {
+            let label = labelled.then(|| t.label());
+            rollback_target(
+                ctx,
+                &t.client,
+                t.server.id,
+                &t.target.compose_path,
+                label.as_deref(),
+            )
+            .await
+        }
+        .boxed()
+    })
+    .await
+}
+
+/// Rolls the stack at `compose_path` back to before its latest deployment.
+async fn rollback_target(
+    ctx: &JobContext,
+    client: &OnePanelClient,
+    server_id: i64,
+    compose_path: &str,
+    label: Option<&str>,
+) -> Result<()> {
+    if let Some(label) = label {
+        ctx.log.line(format!("--- {}", label));
+    }
+
+    let target = ctx
+        .step(&target_step("find_previous_deployment", label), async {
+            deployments::latest_active(&ctx.db, server_id, compose_path)
+                .await?
+                .ok_or_else(|| anyhow!("No deployment to roll back for {}", compose_path))
+        })
+        .await?;
+
+    match &target.previous_tag {
+        Some(tag) => {
+            ctx.log.line(format!(
+                "Rolling back {} from {} to {}",
+                target.image_name, target.new_tag, tag
+            ));
+            ctx.set_image_tag(&format!("{}:{}", target.image_name, tag))
+                .await?;
+        }
+        None => ctx.log.line(format!(
+            "Rolling back {} to the compose content before {}",
+            target.compose_path, target.new_tag
+        )),
+    }
+
+    ctx.step(
+        &target_step("restore_compose", label),
+        restore_previous(ctx, client, &target, None),
+    )
+    .await
+}
+
+/// Writes back the compose content `target` replaced, records that as a rollback
+/// deployment and brings the stack up. `target` counts as rolled back, with `reason`
+/// as its error, as soon as its compose file is restored; should bringing the stack
+/// up fail, that is recorded on the rollback deployment.
+async fn restore_previous(
+    ctx: &JobContext,
+    client: &OnePanelClient,
+    target: &Deployment,
+    reason: Option<&str>,
+) -> Result<()> {
+    client
+        .update_compose(
+            &target.stack_name,
+            &target.compose_path,
+            &target.previous_content,
+        )
+        .await?;
+    match reason {
+        Some(reason) => {
+            deployments::finish(
+                &ctx.db,
+                target.id,
+                deployments::STATUS_ROLLED_BACK,
+                Some(reason),
+            )
+            .await?
+        }
+        None => {
+            deployments::set_status(&ctx.db, target.id, deployments::STATUS_ROLLED_BACK).await?
+        }
+    }
+
+    let rollback_id = deployments::record(
+        &ctx.db,
+        NewDeployment {
+            job_id: &ctx.job_id,
+            action: deployments::ACTION_ROLLBACK,
+            server_id: target.server_id,
+            stack_name: &target.stack_name,
+            compose_path: &target.compose_path,
+            image_name: &target.image_name,
+            previous_tag: Some(&target.new_tag),
+            new_tag: target.previous_tag.as_deref().unwrap_or_default(),
+            previous_content: &target.new_content,
+            new_content: &target.previous_content,
+            repository_path: target.repository_path.as_deref(),
+            commit_hash: None,
+            triggered_by: &ctx.triggered_by,
+            environment: target.environment.as_deref(),
+            promoted_from: None,
+        },
+    )
+    .await?;
+
+    ctx.log
+        .line(format!("Bringing up stack {}", target.stack_name));
+    let result = client
+        .operate_compose(&target.stack_name, &target.compose_path, "up")
+        .await
+        .map_err(anyhow::Error::from);
+
+    finish_deployment(ctx, rollback_id, &result).await?;
+    result
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ComposeUpdate {
+    stack_name: String,
+    deployment_id: i64,
+}
+
+/// Remote compose content and the edit that points it at a new image.
+struct ComposePlan {
+    stack_name: String,
+    current: String,
+    edit: ComposeEdit,
+}
+
+impl ComposePlan {
+    fn token(&self) -> String {
+        compose::edit_token(&self.current, &self.edit.content)
+    }
+}
+
+async fn plan_compose_edit(
+    client: &OnePanelClient,
+    compose_path: &str,
+    image_name: &str,
+    version: &str,
+    service: Option<&str>,
+) -> Result<ComposePlan> {
+    // Find stack name by matching compose path
+    let composes = client.list_composes().await?;
+
+    let stack_name = composes
+        .iter()
+        .find(|item| item.path == compose_path)
+        .map(|item| item.name.clone())
+        .ok_or_else(|| anyhow!("Could not find 1Panel stack for path: {}", compose_path))?;
+
+    let current = client.read_file(compose_path).await?;
+    let edit = ComposeFile::parse(&current)?.set_image(image_name, version, service)?;
+
+    Ok(ComposePlan {
+        stack_name,
+        current,
+        edit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_onepanel::{container, MockPanel};
+
+    const COMPOSE_PATH: &str = "/opt/1panel/docker/compose/web/docker-compose.yml";
+    const PREVIOUS: &str = "services:\n  app:\n    image: web:1\n";
+    const NEW: &str = "services:\n  app:\n    image: web:2\n";
+
+    /// Mock server with the new compose file in place, and a job that deployed it.
+    async fn deployed(statuses: &[&str]) -> (MockPanel, JobContext, ComposeUpdate) {
+        let mock = MockPanel::start().await;
+        {
+            let mut state = mock.state();
+            state
+                .files
+                .insert(COMPOSE_PATH.to_string(), NEW.as_bytes().to_vec());
+            state.containers = statuses
+                .iter()
+                .map(|status| {
+                    let state = if status.starts_with("Exited") {
+                        "exited"
+                    } else {
+                        "running"
+                    };
+                    vec![container("web-app-1", state, status)]
+                })
+                .collect();
+        }
+
+        let ctx = JobContext::for_test(crate::db::memory().await, HashMap::new());
+        let update = ComposeUpdate {
+            stack_name: "web".to_string(),
+            deployment_id: record_deployment(&ctx, mock.server.id, COMPOSE_PATH).await,
+        };
+        (mock, ctx, update)
+    }
+
+    async fn record_deployment(ctx: &JobContext, server_id: i64, compose_path: &str) -> i64 {
+        deployments::record(
+            &ctx.db,
+            NewDeployment {
+                job_id: &ctx.job_id,
+                action: deployments::ACTION_DEPLOY,
+                server_id,
+                stack_name: "web",
+                compose_path,
+                image_name: "web",
+                previous_tag: Some("web:1"),
+                new_tag: "web:2",
+                previous_content: PREVIOUS,
+                new_content: NEW,
+                repository_path: None,
+                commit_hash: None,
+                triggered_by: "test",
+                environment: None,
+                promoted_from: None,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn status_of(ctx: &JobContext, deployment_id: i64) -> String {
+        deployments::get(&ctx.db, deployment_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .status
+    }
+
+    /// Stores the mock server and a repository deploying to `compose_paths` on it.
+    async fn repository(
+        db: &DbPool,
+        mock: &MockPanel,
+        strategy: &str,
+        compose_paths: &[&str],
+    ) -> (Repository, Server) {
+        let server_id = sqlx::query(
+            "INSERT INTO servers (name, host, port, api_key, scheme, api_version) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&mock.server.name)
+        .bind(&mock.server.host)
+        .bind(mock.server.port)
+        .bind(&mock.server.api_key)
+        .bind(&mock.server.scheme)
+        .bind(&mock.server.api_version)
+        .execute(db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        let repository_id = sqlx::query(
+            "INSERT INTO repositories (path, docker_image_name, deploy_strategy) VALUES ('/src/web', 'web', ?)",
+        )
+        .bind(strategy)
+        .execute(db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        for (position, path) in compose_paths.iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO deploy_targets (repository_id, server_id, compose_path, position) VALUES (?, ?, ?, ?)",
+            )
+            .bind(repository_id)
+            .bind(server_id)
+            .bind(path)
+            .bind(position as i64)
+            .execute(db)
+            .await
+            .unwrap();
+        }
+
+        let repo = resolve_repository(db, "/src/web").await.unwrap();
+        let server = onepanel::find_server(db, server_id).await.unwrap().unwrap();
+        (repo, server)
+    }
+
+    async fn verify(
+        mock: &MockPanel,
+        ctx: &JobContext,
+        update: &ComposeUpdate,
+        timeout_secs: i64,
+    ) -> (Result<()>, String) {
+        let client = OnePanelClient::new(&mock.server).unwrap();
+        let result = verify_deployment(ctx, &client, update, None, timeout_secs, None).await;
+        (result, status_of(ctx, update.deployment_id).await)
+    }
+
+    #[tokio::test]
+    async fn healthy_stack_is_deployed() {
+        let (mock, ctx, update) = deployed(&[
+            "Up 1 second",
+            "Up 2 seconds",
+            "Up 3 seconds",
+            "Up 4 seconds",
+        ])
+        .await;
+
+        let (result, status) = verify(&mock, &ctx, &update, 5).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(status, deployments::STATUS_DEPLOYED);
+        let state = mock.state();
+        assert_eq!(state.calls("containers/search"), 3);
+        assert_eq!(state.files[COMPOSE_PATH], NEW.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn unhealthy_stack_is_rolled_back() {
+        let (mock, ctx, update) = deployed(&["Up 4 seconds (unhealthy)"]).await;
+
+        let (result, status) = verify(&mock, &ctx, &update, 1).await;
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("unhealthy"), "{}", error);
+        assert!(error.contains("rolled back"), "{}", error);
+        assert_eq!(status, deployments::STATUS_ROLLED_BACK);
+        let state = mock.state();
+        assert_eq!(state.files[COMPOSE_PATH], PREVIOUS.as_bytes());
+        assert_eq!(state.calls("containers/compose/operate"), 1);
+    }
+
+    #[tokio::test]
+    async fn restarting_stack_times_out_and_is_rolled_back() {
+        // Running whenever it is polled, but restarted in between
+        let crash_loop = ["Up 1 second", "Up 2 seconds"].repeat(200);
+        let (mock, ctx, update) = deployed(&crash_loop).await;
+
+        let (result, status) = verify(&mock, &ctx, &update, 1).await;
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("is not healthy"), "{}", error);
+        assert_eq!(status, deployments::STATUS_ROLLED_BACK);
+        assert_eq!(mock.state().files[COMPOSE_PATH], PREVIOUS.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn crashed_container_fails_without_waiting_for_timeout() {
+        let (mock, ctx, update) = deployed(&["Up 1 second", "Exited (1) 1 second ago"]).await;
+
+        let (result, status) = verify(&mock, &ctx, &update, 60).await;
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Exited (1)"), "{}", error);
+        assert_eq!(status, deployments::STATUS_ROLLED_BACK);
+        assert_eq!(mock.state().calls("containers/search"), 2);
+    }
+
+    #[tokio::test]
+    async fn unhealthy_container_fails_once_it_stays_unhealthy() {
+        let (mock, ctx, update) = deployed(&[
+            "Up 3 seconds (unhealthy)",
+            "Up 6 seconds (healthy)",
+            "Up 9 seconds (unhealthy)",
+        ])
+        .await;
+
+        let (result, status) = verify(&mock, &ctx, &update, 60).await;
+        assert!(result.unwrap_err().to_string().contains("unhealthy"));
+        assert_eq!(status, deployments::STATUS_ROLLED_BACK);
+        // Recovered once, then unhealthy on three polls in a row
+        assert_eq!(mock.state().calls("containers/search"), 5);
+    }
+
+    #[tokio::test]
+    async fn verification_is_off_without_timeout() {
+        let (mock, ctx, update) = deployed(&["Exited (1) 1 second ago"]).await;
+
+        let (result, status) = verify(&mock, &ctx, &update, 0).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(status, deployments::STATUS_DEPLOYED);
+        assert_eq!(mock.state().calls("containers/search"), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_leaves_settled_targets_alone() {
+        let mock = MockPanel::start().await;
+        let db = crate::db::memory().await;
+        let (repo, server) =
+            repository(&db, &mock, targets::STRATEGY_SEQUENTIAL, &[COMPOSE_PATH]).await;
+        let first = JobContext::for_test(db, HashMap::new());
+        let deployment_id = record_deployment(&first, server.id, COMPOSE_PATH).await;
+        // Deployed by the first attempt, then rolled back by hand
+        deployments::set_status(&first.db, deployment_id, deployments::STATUS_ROLLED_BACK)
+            .await
+            .unwrap();
+
+        let update = ComposeUpdate {
+            stack_name: "web".to_string(),
+            deployment_id,
+        };
+        let retry = JobContext::for_test(
+            (*first.db).clone(),
+            HashMap::from([(
+                "update_compose".to_string(),
+                serde_json::to_value(&update).unwrap(),
+            )]),
+        );
+        let run = TargetDeploy {
+            ctx: &retry,
+            repo: &repo,
+            image_name: "web",
+            version: "2",
+            full_tag: "web:2",
+            service: None,
+            timeout_secs: 0,
+            pushes: HashMap::new(),
+            registry: None,
+            registry_push: OnceCell::new(),
+            labelled: false,
+            action: deployments::ACTION_DEPLOY,
+            commit_hash: None,
+            promoted_from: None,
+        };
+        let target = load_targets(&retry.db, &repo).await.unwrap().remove(0);
+
+        run.deploy(&target).await.unwrap();
+        assert_eq!(
+            status_of(&retry, deployment_id).await,
+            deployments::STATUS_ROLLED_BACK
+        );
+        assert_eq!(mock.state().calls("containers/compose/operate"), 0);
+    }
+
+    /// Rolls back a repository with two targets, of which only the second has a
+    /// deployment; returns the job result and that deployment's status.
+    async fn roll_back_two_targets(strategy: &str) -> (Result<()>, String) {
+        let mock = MockPanel::start().await;
+        let db = crate::db::memory().await;
+        let (repo, server) =
+            repository(&db, &mock, strategy, &["/a/compose.yml", "/b/compose.yml"]).await;
+        let ctx = JobContext::for_test(db, HashMap::new());
+        let deployment_id = record_deployment(&ctx, server.id, "/b/compose.yml").await;
+
+        let result = run_repository_rollback(ctx.clone(), PathParams { path: repo.path }).await;
+        (result, status_of(&ctx, deployment_id).await)
+    }
+
+    #[tokio::test]
+    async fn sequential_rollback_stops_at_first_failure() {
+        let (result, status) = roll_back_two_targets(targets::STRATEGY_SEQUENTIAL).await;
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.contains("No deployment to roll back for /a/compose.yml"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("not done on mock:/b/compose.yml"),
+            "{}",
+            error
+        );
+        assert_eq!(status, deployments::STATUS_PENDING);
+    }
+
+    #[tokio::test]
+    async fn parallel_rollback_continues_past_failures() {
+        let (result, status) = roll_back_two_targets(targets::STRATEGY_PARALLEL).await;
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.starts_with("Rollback failed on 1 of 2 targets"),
+            "{}",
+            error
+        );
+        assert_eq!(status, deployments::STATUS_ROLLED_BACK);
+    }
+
+    #[tokio::test]
+    async fn rollback_stays_restored_when_the_stack_fails_to_come_up() {
+        let mock = MockPanel::start().await;
+        let db = crate::db::memory().await;
+        let (repo, server) =
+            repository(&db, &mock, targets::STRATEGY_SEQUENTIAL, &[COMPOSE_PATH]).await;
+        let ctx = JobContext::for_test(db, HashMap::new());
+        let deployment_id = record_deployment(&ctx, server.id, COMPOSE_PATH).await;
+        mock.state().script(
+            "containers/compose/operate",
+            axum::http::StatusCode::OK,
+            r#"{"code": 500, "message": "port is already allocated"}"#,
+        );
+
+        let result = run_repository_rollback(ctx.clone(), PathParams { path: repo.path }).await;
+        assert!(result.is_err());
+        assert_eq!(
+            status_of(&ctx, deployment_id).await,
+            deployments::STATUS_ROLLED_BACK
+        );
+        let rollback: (String, Option<String>) =
+            sqlx::query_as("SELECT status, error FROM deployments WHERE action = ?")
+                .bind(deployments::ACTION_ROLLBACK)
+                .fetch_one(&*ctx.db)
+                .await
+                .unwrap();
+        assert_eq!(rollback.0, deployments::STATUS_FAILED);
+        assert!(rollback.1.unwrap().contains("port is already allocated"));
+    }
+
+    #[tokio::test]
+    async fn automatic_rollback_keeps_the_verification_error() {
+        let (mock, ctx, update) = deployed(&["Exited (1) 1 second ago"]).await;
+        mock.state().script(
+            "containers/compose/operate",
+            axum::http::StatusCode::OK,
+            r#"{"code": 500, "message": "port is already allocated"}"#,
+        );
+
+        let (result, status) = verify(&mock, &ctx, &update, 60).await;
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("automatic rollback failed"), "{}", error);
+        assert_eq!(status, deployments::STATUS_ROLLED_BACK);
+        let target = deployments::get(&ctx.db, update.deployment_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(target.error.unwrap().contains("Exited (1)"));
+    }
+
+    #[tokio::test]
+    async fn targets_carry_their_environment() {
+        let mock = MockPanel::start().await;
+        let db = crate::db::memory().await;
+        let (repo, server) = repository(
+            &db,
+            &mock,
+            targets::STRATEGY_SEQUENTIAL,
+            &["/staging/compose.yml", "/other/compose.yml"],
+        )
+        .await;
+        sqlx::query(
+            "INSERT INTO environments (repository_id, name, server_id, compose_path, position) VALUES (?, 'staging', ?, '/staging/compose.yml', 0)",
+        )
+        .bind(repo.id)
+        .bind(server.id)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let targets = load_targets(&db, &repo).await.unwrap();
+        assert_eq!(targets[0].environment.as_deref(), Some("staging"));
+        assert_eq!(targets[1].environment, None);
+    }
+}
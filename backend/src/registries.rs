@@ -0,0 +1,226 @@
+use crate::db::DbPool;
+use crate::jobs::JobLog;
+use crate::models::{Registry, Repository, SaveRegistryReq};
+use crate::onepanel::OnePanelClient;
+use crate::secrets;
+use anyhow::{anyhow, Result};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// How often a server is asked whether a pull or image load has finished.
+#[cfg(not(test))]
+pub const PULL_POLL_INTERVAL: Duration = Duration::from_secs(2);
+#[cfg(test)]
+pub const PULL_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub async fn list(db: &DbPool) -> Result<Vec<Registry>, sqlx::Error> {
+    sqlx::query_as::<_, Registry>("SELECT * FROM registries ORDER BY name ASC")
+        .fetch_all(db)
+        .await
+}
+
+pub async fn get(db: &DbPool, id: i64) -> Result<Option<Registry>, sqlx::Error> {
+    sqlx::query_as::<_, Registry>("SELECT * FROM registries WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Registry the repository deploys through, if it has one, with its password decrypted.
+pub async fn of_repository(db: &DbPool, repo: &Repository) -> Result<Option<Registry>> {
+    let Some(id) = repo.registry_id else {
+        return Ok(None);
+    };
+    let mut registry = get(db, id)
+        .await?
+        .ok_or_else(|| anyhow!("Registry {} not found", id))?;
+    registry.password = registry
+        .password
+        .as_deref()
+        .map(secrets::decrypt)
+        .transpose()?;
+    Ok(Some(registry))
+}
+
+/// Registry address as stored: host and port, without scheme or trailing slash.
+pub fn normalize_url(url: &str) -> String {
+    url.trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Password of the request as stored: encrypted, or empty to clear it.
+fn stored_password(req: &SaveRegistryReq) -> Result<Option<String>> {
+    req.password
+        .as_deref()
+        .map(|password| match password {
+            "" => Ok(String::new()),
+            password => secrets::encrypt(password),
+        })
+        .transpose()
+}
+
+pub async fn create(db: &DbPool, req: &SaveRegistryReq) -> Result<Registry> {
+    let password = stored_password(req)?;
+    Ok(sqlx::query_as::<_, Registry>(
+        "INSERT INTO registries (name, url, username, password, insecure) VALUES (?, ?, NULLIF(?, ''), NULLIF(?, ''), ?) RETURNING *",
+    )
+    .bind(req.name.trim())
+    .bind(normalize_url(&req.url))
+    .bind(&req.username)
+    .bind(password)
+    .bind(req.insecure)
+    .fetch_one(db)
+    .await?)
+}
+
+pub async fn update(db: &DbPool, id: i64, req: &SaveRegistryReq) -> Result<Option<Registry>> {
+    let password = stored_password(req)?;
+    Ok(sqlx::query_as::<_, Registry>(
+        "UPDATE registries SET name = ?, url = ?, username = NULLIF(?, ''),
+            password = CASE WHEN ? IS NULL THEN password ELSE NULLIF(?, '') END, insecure = ?
+         WHERE id = ? RETURNING *",
+    )
+    .bind(req.name.trim())
+    .bind(normalize_url(&req.url))
+    .bind(&req.username)
+    .bind(&password)
+    .bind(&password)
+    .bind(req.insecure)
+    .bind(id)
+    .fetch_optional(db)
+    .await?)
+}
+
+/// Number of repositories deploying through the registry.
+pub async fn usage(db: &DbPool, id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM repositories WHERE registry_id = ?")
+        .bind(id)
+        .fetch_one(db)
+        .await
+}
+
+pub async fn delete(db: &DbPool, id: i64) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM registries WHERE id = ?")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+async fn docker(args: &[&str], stdin: Option<&str>) -> Result<()> {
+    let mut child = Command::new("docker")
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input.as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "docker {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Pushes the local image `image` (`name:tag`) to the registry.
+pub async fn push(registry: &Registry, image: &str, log: &JobLog) -> Result<()> {
+    if let Some(username) = &registry.username {
+        docker(
+            &[
+                "login",
+                "--username",
+                username,
+                "--password-stdin",
+                &registry.url,
+            ],
+            Some(registry.password.as_deref().unwrap_or_default()),
+        )
+        .await?;
+    }
+
+    let remote = format!("{}/{}", registry.url, image);
+    docker(&["tag", image, &remote], None).await?;
+    log.line(format!("Pushing {}", remote));
+    docker(&["push", &remote], None).await?;
+    log.line(format!("Pushed {}", remote));
+    Ok(())
+}
+
+/// Has the server pull `image` from the registry through 1Panel, then tags it with its
+/// plain name so compose files keep referring to `image`. The registry is added to
+/// 1Panel, with its credentials, the first time a server pulls from it.
+pub async fn pull(
+    client: &OnePanelClient,
+    registry: &Registry,
+    image: &str,
+    log: &JobLog,
+) -> Result<()> {
+    let repo_id = match client
+        .list_repos()
+        .await?
+        .into_iter()
+        .find(|repo| normalize_url(&repo.download_url) == registry.url)
+    {
+        Some(repo) => repo.id,
+        None => {
+            log.line(format!("Adding registry {} to 1Panel", registry.url));
+            client
+                .create_repo(
+                    &registry.name,
+                    &registry.url,
+                    registry.insecure,
+                    registry.username.as_deref(),
+                    registry.password.as_deref(),
+                )
+                .await?;
+            client
+                .list_repos()
+                .await?
+                .into_iter()
+                .find(|repo| normalize_url(&repo.download_url) == registry.url)
+                .ok_or_else(|| anyhow!("1Panel did not add registry {}", registry.url))?
+                .id
+        }
+    };
+
+    let remote = format!("{}/{}", registry.url, image);
+    log.line(format!("Pulling {}", remote));
+    client.pull_image(repo_id, image).await?;
+
+    // 1Panel pulls in the background
+    let deadline = tokio::time::Instant::now() + client.upload_timeout();
+    loop {
+        let images = client.list_images().await?;
+        if images.iter().any(|i| i.tags.contains(&remote)) {
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Timed out waiting for the server to pull {}",
+                remote
+            ));
+        }
+        tokio::time::sleep(PULL_POLL_INTERVAL).await;
+    }
+
+    client.tag_image(&remote, image).await?;
+    log.line(format!("Pulled {} as {}", remote, image));
+    Ok(())
+}
@@ -0,0 +1,554 @@
+//! Encryption of credentials stored in the database (1Panel API keys, registry
+//! passwords) with a master key kept outside of it.
+//!
+//! The master key is taken from, in order: the `ONEPANEL_MASTER_KEY` environment
+//! variable, the file named by `ONEPANEL_KEY_FILE`, then the OS keyring on Windows
+//! and macOS or `onepanel.key` in the working directory elsewhere. It is created
+//! on first start when none exists. A key file is plain base64, protected only by
+//! its permissions.
+//!
+//! Rotating keeps the old key as a backup and stages the new one beside the current
+//! key (`.old` and `.new` after the file name or keyring entry) before the database
+//! is re-encrypted. Should rotation stop before the new key replaced the current
+//! one, the next start finds it staged and moves it in place.
+
+use crate::db::DbPool;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Marks encrypted values; anything else is plaintext from before encryption.
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const KEY_ENV: &str = "ONEPANEL_MASTER_KEY";
+const KEY_FILE_ENV: &str = "ONEPANEL_KEY_FILE";
+/// Rotating a key taken from the environment needs the replacement from there too.
+const NEW_KEY_ENV: &str = "ONEPANEL_NEW_MASTER_KEY";
+#[cfg(not(any(windows, target_os = "macos")))]
+const DEFAULT_KEY_FILE: &str = "onepanel.key";
+#[cfg(any(windows, target_os = "macos"))]
+const KEYRING_SERVICE: &str = "OnePanel-CI";
+#[cfg(any(windows, target_os = "macos"))]
+const KEYRING_USER: &str = "master-key";
+
+const BACKUP_SUFFIX: &str = ".old";
+const STAGED_SUFFIX: &str = ".new";
+
+/// Columns holding encrypted credentials, as `(table, column)`.
+const SECRET_COLUMNS: &[(&str, &str)] = &[("servers", "api_key"), ("registries", "password")];
+
+type Key = [u8; 32];
+
+static MASTER_KEY: OnceLock<Key> = OnceLock::new();
+
+/// Where the master key is kept.
+#[derive(Debug, Clone)]
+enum KeySource {
+    Env,
+    File(PathBuf),
+    /// Entry of the OS keyring, by user name
+    #[cfg(any(windows, target_os = "macos"))]
+    Keyring(String),
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Env => write!(f, "environment variable {}", KEY_ENV),
+            KeySource::File(path) => write!(f, "key file {}", path.display()),
+            #[cfg(any(windows, target_os = "macos"))]
+            KeySource::Keyring(user) => {
+                write!(f, "OS keyring entry {}/{}", KEYRING_SERVICE, user)
+            }
+        }
+    }
+}
+
+impl KeySource {
+    fn configured() -> Self {
+        if std::env::var_os(KEY_ENV).is_some() {
+            return KeySource::Env;
+        }
+        if let Some(path) = std::env::var_os(KEY_FILE_ENV) {
+            return KeySource::File(path.into());
+        }
+        #[cfg(any(windows, target_os = "macos"))]
+        return KeySource::Keyring(KEYRING_USER.to_string());
+        #[cfg(not(any(windows, target_os = "macos")))]
+        KeySource::File(DEFAULT_KEY_FILE.into())
+    }
+
+    fn load(&self) -> Result<Option<Key>> {
+        let encoded = match self {
+            KeySource::Env => std::env::var(KEY_ENV).ok(),
+            KeySource::File(path) => match std::fs::read_to_string(path) {
+                Ok(content) => Some(content),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).with_context(|| format!("Could not read {}", self)),
+            },
+            #[cfg(any(windows, target_os = "macos"))]
+            KeySource::Keyring(user) => match keyring_entry(user)?.get_password() {
+                Ok(key) => Some(key),
+                Err(keyring::Error::NoEntry) => None,
+                Err(e) => return Err(anyhow!("Could not read the OS keyring: {}", e)),
+            },
+        };
+        encoded
+            .map(|encoded| {
+                decode_key(&encoded).with_context(|| format!("Invalid master key in {}", self))
+            })
+            .transpose()
+    }
+
+    fn store(&self, key: &Key) -> Result<()> {
+        match self {
+            KeySource::Env => Err(anyhow!(
+                "The master key comes from {}; set it there",
+                KEY_ENV
+            )),
+            KeySource::File(path) => write_key_file(path, key),
+            #[cfg(any(windows, target_os = "macos"))]
+            KeySource::Keyring(user) => keyring_entry(user)?
+                .set_password(&STANDARD.encode(key))
+                .map_err(|e| anyhow!("Could not write the OS keyring: {}", e)),
+        }
+    }
+
+    fn remove(&self) -> Result<()> {
+        match self {
+            KeySource::Env => Ok(()),
+            KeySource::File(path) => match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Could not remove {}", self))
+                }
+                _ => Ok(()),
+            },
+            #[cfg(any(windows, target_os = "macos"))]
+            KeySource::Keyring(user) => match keyring_entry(user)?.delete_credential() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(anyhow!("Could not remove {}: {}", self, e)),
+            },
+        }
+    }
+
+    /// Where a key is kept beside this one, named with `suffix`. Keys from the
+    /// environment have nothing beside them.
+    fn sibling(&self, suffix: &str) -> Option<KeySource> {
+        match self {
+            KeySource::Env => None,
+            KeySource::File(path) => {
+                let mut name = path.clone().into_os_string();
+                name.push(suffix);
+                Some(KeySource::File(name.into()))
+            }
+            #[cfg(any(windows, target_os = "macos"))]
+            KeySource::Keyring(user) => Some(KeySource::Keyring(format!("{}{}", user, suffix))),
+        }
+    }
+}
+
+#[cfg(any(windows, target_os = "macos"))]
+fn keyring_entry(user: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, user)
+        .map_err(|e| anyhow!("Could not open the OS keyring: {}", e))
+}
+
+fn decode_key(encoded: &str) -> Result<Key> {
+    let bytes = STANDARD.decode(encoded.trim())?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("expected 32 bytes, base64 encoded"))
+}
+
+fn new_key() -> Key {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Writes the key readable by the current user only.
+fn write_key_file(path: &PathBuf, key: &Key) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    std::io::Write::write_all(&mut file, STANDARD.encode(key).as_bytes())?;
+    Ok(())
+}
+
+fn encrypt_with(key: &Key, plain: &str) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let sealed = Aes256Gcm::new(key.into())
+        .encrypt(Nonce::from_slice(&nonce), plain.as_bytes())
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    let mut data = nonce.to_vec();
+    data.extend(sealed);
+    Ok(format!("{}{}", PREFIX, STANDARD.encode(data)))
+}
+
+fn decrypt_with(key: &Key, stored: &str) -> Result<String> {
+    let Some(encoded) = stored.strip_prefix(PREFIX) else {
+        return Ok(stored.to_string());
+    };
+    let data = STANDARD
+        .decode(encoded)
+        .context("Corrupt encrypted value")?;
+    if data.len() < NONCE_LEN {
+        return Err(anyhow!("Corrupt encrypted value"));
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    let plain = Aes256Gcm::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| {
+            anyhow!("Could not decrypt a stored credential; the master key does not match")
+        })?;
+    Ok(String::from_utf8(plain)?)
+}
+
+fn master_key() -> Result<&'static Key> {
+    MASTER_KEY
+        .get()
+        .ok_or_else(|| anyhow!("The master key has not been loaded"))
+}
+
+/// Encrypts a credential for storage.
+pub fn encrypt(plain: &str) -> Result<String> {
+    encrypt_with(master_key()?, plain)
+}
+
+/// Decrypts a stored credential. Values stored before encryption pass through.
+pub fn decrypt(stored: &str) -> Result<String> {
+    if !is_encrypted(stored) {
+        return Ok(stored.to_string());
+    }
+    decrypt_with(master_key()?, stored)
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+/// Whether `key` decrypts every encrypted value in `stored`.
+fn opens(key: &Key, stored: &[(&str, &str, i64, String)]) -> bool {
+    stored
+        .iter()
+        .filter(|(_, _, _, value)| is_encrypted(value))
+        .all(|(_, _, _, value)| decrypt_with(key, value).is_ok())
+}
+
+/// Non-empty stored values of every secret column, as `(table, column, rowid, value)`.
+async fn stored_secrets(db: &DbPool) -> Result<Vec<(&'static str, &'static str, i64, String)>> {
+    let mut all = Vec::new();
+    for (table, column) in SECRET_COLUMNS {
+        let rows = sqlx::query_as::<_, (i64, String)>(&format!(
+            "SELECT rowid, {column} FROM {table} WHERE {column} IS NOT NULL AND {column} != ''"
+        ))
+        .fetch_all(db)
+        .await?;
+        all.extend(
+            rows.into_iter()
+                .map(|(rowid, value)| (*table, *column, rowid, value)),
+        );
+    }
+    Ok(all)
+}
+
+/// Loads the master key, creating it on first start, checks it against the
+/// credentials already encrypted, and encrypts any still stored in plaintext.
+pub async fn init(db: &DbPool) -> Result<()> {
+    let key = init_from(db, &KeySource::configured()).await?;
+    let _ = MASTER_KEY.set(key);
+    Ok(())
+}
+
+async fn init_from(db: &DbPool, source: &KeySource) -> Result<Key> {
+    let stored = stored_secrets(db).await?;
+
+    let key = match source.load() {
+        Ok(Some(key)) if opens(&key, &stored) => key,
+        loaded => match finish_rotation(source, &stored)? {
+            Some(key) => key,
+            None => match loaded? {
+                Some(key) => key,
+                None => {
+                    // A new key cannot read what an earlier one encrypted
+                    if stored.iter().any(|(_, _, _, value)| is_encrypted(value)) {
+                        return Err(anyhow!(
+                            "The database holds encrypted credentials but no master key was found in {}. Restore the key, or point {} or {} at it.",
+                            source, KEY_ENV, KEY_FILE_ENV
+                        ));
+                    }
+                    let key = new_key();
+                    source.store(&key)?;
+                    tracing::info!("Created a new master key in {}", source);
+                    key
+                }
+            },
+        },
+    };
+
+    let mut encrypted = 0;
+    for (table, column, rowid, value) in stored {
+        if is_encrypted(&value) {
+            decrypt_with(&key, &value).with_context(|| format!("Master key from {}", source))?;
+            continue;
+        }
+        sqlx::query(&format!("UPDATE {table} SET {column} = ? WHERE rowid = ?"))
+            .bind(encrypt_with(&key, &value)?)
+            .bind(rowid)
+            .execute(db)
+            .await?;
+        encrypted += 1;
+    }
+    if encrypted > 0 {
+        tracing::info!("Encrypted {} stored credentials", encrypted);
+    }
+    Ok(key)
+}
+
+/// Completes a rotation that re-encrypted the database but stopped before storing
+/// the new key: the key staged beside `source` replaces it when that key opens the
+/// stored credentials.
+fn finish_rotation(
+    source: &KeySource,
+    stored: &[(&str, &str, i64, String)],
+) -> Result<Option<Key>> {
+    let Some(staged) = source.sibling(STAGED_SUFFIX) else {
+        return Ok(None);
+    };
+    let has_encrypted = stored.iter().any(|(_, _, _, value)| is_encrypted(value));
+    let key = match staged.load()? {
+        Some(key) if has_encrypted && opens(&key, stored) => key,
+        _ => return Ok(None),
+    };
+    source.store(&key)?;
+    staged.remove()?;
+    tracing::warn!(
+        "Moved the master key an interrupted rotation left in {} to {}",
+        staged,
+        source
+    );
+    Ok(Some(key))
+}
+
+/// Re-encrypts every stored credential with a new master key and stores the key
+/// where the old one was. The app must not be running meanwhile. Progress goes to
+/// `report`.
+pub async fn rotate(db: &DbPool, report: &dyn Fn(&str)) -> Result<()> {
+    rotate_at(db, KeySource::configured(), report).await
+}
+
+async fn rotate_at(db: &DbPool, source: KeySource, report: &dyn Fn(&str)) -> Result<()> {
+    let old = source
+        .load()?
+        .ok_or_else(|| anyhow!("No master key found in {}", source))?;
+    let new = match source {
+        KeySource::Env => std::env::var(NEW_KEY_ENV)
+            .map_err(|_| {
+                anyhow!(
+                    "Set {} to the new key to rotate a key from {}",
+                    NEW_KEY_ENV,
+                    KEY_ENV
+                )
+            })
+            .and_then(|encoded| decode_key(&encoded))?,
+        _ => new_key(),
+    };
+
+    // Both keys are kept beside the current one before the database changes, so
+    // whichever it ends up encrypted with is not lost
+    if let Some(backup) = source.sibling(BACKUP_SUFFIX) {
+        backup.store(&old)?;
+        report(&format!("Backed up the old master key to {}", backup));
+    }
+    let staged = source.sibling(STAGED_SUFFIX);
+    if let Some(staged) = &staged {
+        staged.store(&new)?;
+    }
+
+    let stored = stored_secrets(db).await?;
+    let mut tx = db.begin().await?;
+    let mut count = 0;
+    for (table, column, rowid, value) in stored {
+        let plain = decrypt_with(&old, &value)?;
+        sqlx::query(&format!("UPDATE {table} SET {column} = ? WHERE rowid = ?"))
+            .bind(encrypt_with(&new, &plain)?)
+            .bind(rowid)
+            .execute(&mut *tx)
+            .await?;
+        count += 1;
+    }
+    tx.commit().await?;
+    report(&format!("Re-encrypted {} stored credentials", count));
+
+    match staged {
+        None => report(&format!(
+            "Set {} to the value of {} before the next start",
+            KEY_ENV, NEW_KEY_ENV
+        )),
+        Some(staged) => {
+            source.store(&new).with_context(|| {
+                format!(
+                    "The new master key stays in {}; the next start moves it to {}",
+                    staged, source
+                )
+            })?;
+            if let Err(e) = staged.remove() {
+                report(&format!("{:#}", e));
+            }
+            report(&format!("Stored the new master key in {}", source));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("onepanel-{}-{}.key", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn with_credentials() -> DbPool {
+        let db = crate::db::memory().await;
+        sqlx::query("INSERT INTO servers (name, host, port, api_key) VALUES ('a', 'h', 1, 'panel-key'), ('b', 'h', 2, '')")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO registries (name, url, username, password) VALUES ('r', 'u', 'me', 'hunter2')")
+            .execute(&db)
+            .await
+            .unwrap();
+        db
+    }
+
+    async fn credentials(db: &DbPool) -> (String, String) {
+        let (api_key,): (String,) = sqlx::query_as("SELECT api_key FROM servers WHERE name = 'a'")
+            .fetch_one(db)
+            .await
+            .unwrap();
+        let (password,): (String,) = sqlx::query_as("SELECT password FROM registries")
+            .fetch_one(db)
+            .await
+            .unwrap();
+        (api_key, password)
+    }
+
+    #[test]
+    fn round_trips_credentials() {
+        let key = new_key();
+        let first = encrypt_with(&key, "secret").unwrap();
+        let second = encrypt_with(&key, "secret").unwrap();
+
+        assert!(is_encrypted(&first));
+        assert_ne!(first, second, "nonces must differ");
+        assert_eq!(decrypt_with(&key, &first).unwrap(), "secret");
+        assert_eq!(decrypt_with(&key, &second).unwrap(), "secret");
+        assert!(decrypt_with(&new_key(), &first).is_err());
+        assert!(decrypt_with(&key, "enc:v1:AAAA").is_err());
+    }
+
+    #[test]
+    fn passes_plaintext_through() {
+        assert!(!is_encrypted("panel-key"));
+        assert_eq!(decrypt_with(&new_key(), "panel-key").unwrap(), "panel-key");
+        // No master key needed
+        assert_eq!(decrypt("panel-key").unwrap(), "panel-key");
+    }
+
+    #[tokio::test]
+    async fn encrypts_plaintext_rows_on_init() {
+        let path = key_file("init");
+        let source = KeySource::File(path.clone());
+        let db = with_credentials().await;
+
+        let key = init_from(&db, &source).await.unwrap();
+        assert_eq!(source.load().unwrap(), Some(key));
+        let (api_key, password) = credentials(&db).await;
+        assert_eq!(decrypt_with(&key, &api_key).unwrap(), "panel-key");
+        assert_eq!(decrypt_with(&key, &password).unwrap(), "hunter2");
+        let (empty,): (String,) = sqlx::query_as("SELECT api_key FROM servers WHERE name = 'b'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(empty, "");
+
+        // The next start reuses the key and leaves encrypted rows alone
+        assert_eq!(init_from(&db, &source).await.unwrap(), key);
+        assert_eq!(credentials(&db).await, (api_key, password));
+
+        // Without the key the encrypted rows cannot be read
+        std::fs::remove_file(&path).unwrap();
+        assert!(init_from(&db, &source).await.is_err());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn rotates_the_master_key() {
+        let path = key_file("rotate");
+        let backup = path.with_extension("key.old");
+        let db = with_credentials().await;
+        let old = init_from(&db, &KeySource::File(path.clone()))
+            .await
+            .unwrap();
+
+        rotate_at(&db, KeySource::File(path.clone()), &|_| {})
+            .await
+            .unwrap();
+        let new = KeySource::File(path.clone()).load().unwrap().unwrap();
+        assert_ne!(new, old);
+        assert_eq!(KeySource::File(backup.clone()).load().unwrap(), Some(old));
+        assert!(!path.with_extension("key.new").exists());
+
+        let (api_key, password) = credentials(&db).await;
+        assert_eq!(decrypt_with(&new, &api_key).unwrap(), "panel-key");
+        assert_eq!(decrypt_with(&new, &password).unwrap(), "hunter2");
+        assert!(decrypt_with(&old, &api_key).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finishes_an_interrupted_rotation() {
+        let path = key_file("interrupted");
+        let staged = path.with_extension("key.new");
+        let db = with_credentials().await;
+        let old = init_from(&db, &KeySource::File(path.clone()))
+            .await
+            .unwrap();
+
+        // Re-encrypted with the staged key, which never replaced the old one
+        let new = new_key();
+        write_key_file(&staged, &new).unwrap();
+        for (table, column, rowid, value) in stored_secrets(&db).await.unwrap() {
+            let plain = decrypt_with(&old, &value).unwrap();
+            sqlx::query(&format!("UPDATE {table} SET {column} = ? WHERE rowid = ?"))
+                .bind(encrypt_with(&new, &plain).unwrap())
+                .bind(rowid)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        let source = KeySource::File(path.clone());
+        assert_eq!(init_from(&db, &source).await.unwrap(), new);
+        assert_eq!(source.load().unwrap(), Some(new));
+        assert!(!staged.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
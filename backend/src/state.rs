@@ -1,4 +1,7 @@
 use crate::db::DbPool;
+use crate::jobs::JobRunner;
+use axum::http::HeaderValue;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use std::collections::HashMap;
@@ -8,13 +11,22 @@ use tokio::sync::{mpsc::UnboundedSender, RwLock};
 pub struct AppState {
     pub db: Arc<DbPool>,
     pub mcp_sessions: Arc<RwLock<HashMap<String, UnboundedSender<String>>>>,
+    pub jobs: JobRunner,
+    /// Browser origins the API accepts requests from
+    pub allowed_origins: Arc<Vec<HeaderValue>>,
+    /// Canonical directories the fs API may browse besides repositories
+    pub browse_roots: Arc<Vec<PathBuf>>,
 }
 
 impl AppState {
-    pub fn new(db: DbPool) -> Self {
+    pub fn new(db: DbPool, allowed_origins: Vec<HeaderValue>, browse_roots: Vec<PathBuf>) -> Self {
+        let db = Arc::new(db);
         Self {
-            db: Arc::new(db),
+            jobs: JobRunner::new(db.clone()),
+            db,
             mcp_sessions: Arc::new(RwLock::new(HashMap::new())),
+            allowed_origins: Arc::new(allowed_origins),
+            browse_roots: Arc::new(browse_roots),
         }
     }
 }
@@ -0,0 +1,93 @@
+use crate::db::DbPool;
+use crate::models::{DeployTarget, DeployTargetInput, Repository};
+
+/// Deploy one target after the other, stopping at the first one that fails.
+pub const STRATEGY_SEQUENTIAL: &str = "sequential";
+/// Deploy several targets at once, up to the configured concurrency.
+pub const STRATEGY_PARALLEL: &str = "parallel";
+
+pub const DEFAULT_CONCURRENCY: i64 = 2;
+
+/// How a deploy to several targets is rolled out.
+#[derive(Debug, Clone)]
+pub struct Rollout {
+    pub strategy: String,
+    pub concurrency: usize,
+    pub pause_secs: u64,
+}
+
+impl Rollout {
+    pub fn of(repo: &Repository) -> Self {
+        Rollout {
+            strategy: repo
+                .deploy_strategy
+                .clone()
+                .unwrap_or_else(|| STRATEGY_SEQUENTIAL.to_string()),
+            concurrency: repo
+                .deploy_concurrency
+                .unwrap_or(DEFAULT_CONCURRENCY)
+                .max(1) as usize,
+            pause_secs: repo.deploy_pause_secs.unwrap_or(0).max(0) as u64,
+        }
+    }
+}
+
+pub fn is_valid_strategy(strategy: &str) -> bool {
+    strategy == STRATEGY_SEQUENTIAL || strategy == STRATEGY_PARALLEL
+}
+
+/// Targets of `repo` in deploy order. A repository without explicit targets is
+/// deployed to its default server and compose path, when both are set.
+pub async fn list(db: &DbPool, repo: &Repository) -> Result<Vec<DeployTarget>, sqlx::Error> {
+    let targets = sqlx::query_as::<_, DeployTarget>(
+        "SELECT * FROM deploy_targets WHERE repository_id = ? ORDER BY position ASC, id ASC",
+    )
+    .bind(repo.id)
+    .fetch_all(db)
+    .await?;
+
+    if !targets.is_empty() {
+        return Ok(targets);
+    }
+
+    Ok(match (repo.default_server_id, &repo.default_compose_path) {
+        (Some(server_id), Some(compose_path)) => vec![DeployTarget {
+            id: 0,
+            repository_id: repo.id,
+            server_id,
+            compose_path: compose_path.clone(),
+            service: None,
+            position: 0,
+        }],
+        _ => Vec::new(),
+    })
+}
+
+/// Replaces all targets of a repository, keeping the given order.
+pub async fn replace(
+    db: &DbPool,
+    repository_id: i64,
+    targets: &[DeployTargetInput],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query("DELETE FROM deploy_targets WHERE repository_id = ?")
+        .bind(repository_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for (position, target) in targets.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO deploy_targets (repository_id, server_id, compose_path, service, position) VALUES (?, ?, ?, NULLIF(?, ''), ?)",
+        )
+        .bind(repository_id)
+        .bind(target.server_id)
+        .bind(&target.compose_path)
+        .bind(&target.service)
+        .bind(position as i64)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
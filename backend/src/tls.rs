@@ -0,0 +1,357 @@
+use crate::models::Server;
+use reqwest::{Certificate, Client};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+pub const SCHEME_HTTP: &str = "http";
+pub const SCHEME_HTTPS: &str = "https";
+
+/// How to reach a server: the scheme and how its certificate is checked.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TlsSettings {
+    pub scheme: String,
+    /// PEM bundle trusted in addition to the system roots
+    pub ca_cert: Option<String>,
+    /// SHA-256 of the server certificate; replaces chain and hostname checks
+    pub fingerprint: Option<[u8; 32]>,
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsSettings {
+    /// Settings stored on the server. A host entered as `https://...` implies HTTPS
+    /// when no scheme is configured.
+    pub fn of(server: &Server) -> Result<Self, String> {
+        let scheme = match server.scheme.as_deref().map(str::trim) {
+            Some(scheme) if !scheme.is_empty() => scheme.to_lowercase(),
+            _ if server.host.trim().starts_with("https://") => SCHEME_HTTPS.to_string(),
+            _ => SCHEME_HTTP.to_string(),
+        };
+
+        Ok(TlsSettings {
+            scheme,
+            ca_cert: server
+                .ca_cert
+                .as_deref()
+                .map(str::trim)
+                .filter(|pem| !pem.is_empty())
+                .map(str::to_string),
+            fingerprint: match server.tls_fingerprint.as_deref() {
+                Some(fp) if !fp.trim().is_empty() => Some(parse_fingerprint(fp)?),
+                _ => None,
+            },
+            accept_invalid_certs: server.accept_invalid_certs,
+        })
+    }
+
+    fn is_default(&self) -> bool {
+        self.ca_cert.is_none() && self.fingerprint.is_none() && !self.accept_invalid_certs
+    }
+}
+
+/// Checks the TLS fields of a server before it is saved.
+pub fn validate(
+    scheme: Option<&str>,
+    ca_cert: Option<&str>,
+    fingerprint: Option<&str>,
+) -> Result<(), String> {
+    if let Some(scheme) = scheme.map(str::trim).filter(|s| !s.is_empty()) {
+        if !scheme.eq_ignore_ascii_case(SCHEME_HTTP) && !scheme.eq_ignore_ascii_case(SCHEME_HTTPS) {
+            return Err(format!(
+                "Invalid scheme {}; expected {} or {}",
+                scheme, SCHEME_HTTP, SCHEME_HTTPS
+            ));
+        }
+    }
+    if let Some(pem) = ca_cert.map(str::trim).filter(|p| !p.is_empty()) {
+        let certs = Certificate::from_pem_bundle(pem.as_bytes())
+            .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+        if certs.is_empty() {
+            return Err("Invalid CA certificate: no PEM certificate found".to_string());
+        }
+    }
+    if let Some(fp) = fingerprint.filter(|f| !f.trim().is_empty()) {
+        parse_fingerprint(fp)?;
+    }
+    Ok(())
+}
+
+/// Parses a SHA-256 fingerprint written as hex, with or without `:` separators and
+/// an optional `sha256:` prefix (the format `openssl x509 -fingerprint -sha256` prints).
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], String> {
+    let fp = fingerprint.trim();
+    let fp = fp
+        .strip_prefix("sha256:")
+        .or_else(|| fp.strip_prefix("SHA256:"))
+        .or_else(|| fp.strip_prefix("sha256 Fingerprint="))
+        .or_else(|| fp.strip_prefix("SHA256 Fingerprint="))
+        .unwrap_or(fp);
+    let hex: String = fp.chars().filter(|c| *c != ':' && *c != ' ').collect();
+
+    let invalid = || format!("Invalid SHA-256 fingerprint: {}", fingerprint);
+    // Checked up front: `from_str_radix` would also take a sign, e.g. `+a`
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// HTTP client for the given TLS settings. Clients are cached so that servers with
+/// the same settings share a connection pool.
+pub fn client(settings: &TlsSettings, connect_timeout: Duration) -> Result<Client, String> {
+    static CLIENTS: OnceLock<Mutex<HashMap<TlsSettings, Client>>> = OnceLock::new();
+
+    let key = cache_key(settings);
+    let mut clients = CLIENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+
+    let client = build(&key, connect_timeout)?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+/// Settings a client is cached under. Plain HTTP and default certificate checks
+/// share one pool.
+fn cache_key(settings: &TlsSettings) -> TlsSettings {
+    if settings.scheme == SCHEME_HTTP || settings.is_default() {
+        TlsSettings {
+            scheme: SCHEME_HTTPS.to_string(),
+            ca_cert: None,
+            fingerprint: None,
+            accept_invalid_certs: false,
+        }
+    } else {
+        settings.clone()
+    }
+}
+
+fn build(settings: &TlsSettings, connect_timeout: Duration) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .no_proxy()
+        .connect_timeout(connect_timeout);
+
+    if let Some(fingerprint) = settings.fingerprint {
+        // native-tls cannot pin a certificate, so pinned servers go through rustls
+        let provider = Arc::new(crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                fingerprint,
+                provider,
+            }))
+            .with_no_client_auth();
+        builder = builder.use_preconfigured_tls(config);
+    } else {
+        if let Some(pem) = &settings.ca_cert {
+            let certs = Certificate::from_pem_bundle(pem.as_bytes())
+                .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if settings.accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Accepts exactly the certificate with the pinned fingerprint, whoever issued it.
+/// Handshake signatures are still verified, so the peer must hold its private key.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = Sha256::digest(end_entity.as_ref());
+        if actual.as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate fingerprint {} does not match the pinned fingerprint",
+                actual
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+
+    fn expected() -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&HEX[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn colons(hex: &str) -> String {
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    #[test]
+    fn parses_fingerprint_formats() {
+        let upper = HEX.to_uppercase();
+        for input in [
+            HEX.to_string(),
+            upper.clone(),
+            colons(HEX),
+            format!("  {}  ", colons(&upper)),
+            format!("sha256:{}", HEX),
+            format!("SHA256:{}", upper),
+            format!("sha256 Fingerprint={}", colons(&upper)),
+            format!("SHA256 Fingerprint={}", colons(&upper)),
+        ] {
+            assert_eq!(parse_fingerprint(&input), Ok(expected()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_fingerprints() {
+        for input in [
+            "",
+            &HEX[..62],
+            &format!("{}00", HEX),
+            &format!("+a{}", &HEX[2..]),
+            &format!("-a{}", &HEX[2..]),
+            &format!("zz{}", &HEX[2..]),
+            &format!("é{}", &HEX[2..]),
+            "md5:5e884898da28047151d0e56f8dc62927",
+        ] {
+            assert!(parse_fingerprint(input).is_err(), "{}", input);
+        }
+    }
+
+    fn verify(fingerprint: [u8; 32], cert: &[u8]) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = PinnedCertificate {
+            fingerprint,
+            provider: Arc::new(crypto::ring::default_provider()),
+        };
+        verifier.verify_server_cert(
+            &CertificateDer::from(cert.to_vec()),
+            &[],
+            &ServerName::try_from("1panel.example").unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    #[test]
+    fn accepts_only_the_pinned_certificate() {
+        let cert = b"certificate der bytes";
+        let pinned: [u8; 32] = Sha256::digest(cert).into();
+
+        assert!(verify(pinned, cert).is_ok());
+        let error = verify(pinned, b"another certificate").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("does not match the pinned fingerprint"),
+            "{}",
+            error
+        );
+    }
+
+    fn settings(scheme: &str, fingerprint: Option<[u8; 32]>) -> TlsSettings {
+        TlsSettings {
+            scheme: scheme.to_string(),
+            ca_cert: None,
+            fingerprint,
+            accept_invalid_certs: false,
+        }
+    }
+
+    #[test]
+    fn caches_clients_per_certificate_check() {
+        let shared = cache_key(&settings(SCHEME_HTTPS, None));
+        // TLS settings do not apply to plain HTTP
+        assert_eq!(cache_key(&settings(SCHEME_HTTP, Some(expected()))), shared);
+
+        let pinned = settings(SCHEME_HTTPS, Some(expected()));
+        assert_eq!(cache_key(&pinned), pinned);
+        assert_ne!(
+            cache_key(&settings(SCHEME_HTTPS, Some([0; 32]))),
+            cache_key(&pinned)
+        );
+
+        let insecure = TlsSettings {
+            accept_invalid_certs: true,
+            ..settings(SCHEME_HTTPS, None)
+        };
+        assert_eq!(cache_key(&insecure), insecure);
+        assert_ne!(cache_key(&insecure), shared);
+    }
+}
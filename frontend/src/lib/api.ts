@@ -1,4 +1,4 @@
-const API_BASE = "http://localhost:3000/api";
+const API_BASE = "/api";
 
 export async function listServers() {
     const res = await fetch(`${API_BASE}/servers`);
@@ -21,11 +21,16 @@ export async function getServer(id: number) {
 export interface Container {
     containerID: string;
     name: string;
+    imageId: string;
     imageName: string;
     state: string; // "running", "exited", etc.
     createTime: string;
+    runTime: string;
+    network: string[];
     ports: string[];
-    // Add other fields as needed
+    isFromApp: boolean;
+    isFromCompose: boolean;
+    appName: string;
 }
 
 export async function listContainers(serverId: number): Promise<Container[]> {
@@ -120,6 +125,21 @@ export async function removeImage(serverId: number, id: string, force: boolean)
     }
 }
 
+export interface CleanupUploadsResult {
+    removed: { path: string; name: string; size: number; modTime: string }[];
+    freed_bytes: number;
+    failed: string[];
+}
+
+export async function cleanupUploads(serverId: number, olderThanMins?: number): Promise<CleanupUploadsResult> {
+    const query = olderThanMins !== undefined ? `?older_than_mins=${olderThanMins}` : "";
+    const res = await fetch(`${API_BASE}/servers/${serverId}/uploads${query}`, {
+        method: "DELETE",
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
 export async function removeLocalImage(id: string) {
     const res = await fetch(`${API_BASE}/docker/images/${id}`, {
         method: "DELETE",
@@ -164,7 +184,8 @@ export async function updateDockerConfig(
     path: string,
     docker_image_name: string,
     defaultServerId?: number,
-    defaultComposePath?: string
+    defaultComposePath?: string,
+    registryId?: number
 ) {
     const res = await fetch(`${API_BASE}/directories/config/update`, {
         method: "POST",
@@ -173,16 +194,107 @@ export async function updateDockerConfig(
             path,
             docker_image_name,
             default_server_id: defaultServerId,
-            default_compose_path: defaultComposePath
+            default_compose_path: defaultComposePath,
+            registry_id: registryId
         }),
     });
     if (!res.ok) throw new Error(await res.text());
 }
 
+export interface DeployTarget {
+    id: number;
+    repository_id: number;
+    server_id: number;
+    compose_path: string;
+    service?: string | null;
+    position: number;
+}
+
+export interface DeployTargets {
+    path: string;
+    strategy: "sequential" | "parallel";
+    concurrency: number;
+    pause_secs: number;
+    targets: DeployTarget[];
+}
+
+export async function getDeployTargets(path: string): Promise<DeployTargets> {
+    const res = await fetch(`${API_BASE}/directories/targets/get`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ path }),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function updateDeployTargets(
+    path: string,
+    targets: { server_id: number; compose_path: string; service?: string }[],
+    strategy?: "sequential" | "parallel",
+    concurrency?: number,
+    pauseSecs?: number
+) {
+    const res = await fetch(`${API_BASE}/directories/targets/update`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+            path,
+            targets,
+            strategy,
+            concurrency,
+            pause_secs: pauseSecs
+        }),
+    });
+    if (!res.ok) throw new Error(await res.text());
+}
+
+export interface Registry {
+    id: number;
+    name: string;
+    url: string;
+    username?: string | null;
+    insecure: boolean;
+}
+
+export interface SaveRegistry {
+    name: string;
+    url: string;
+    username?: string;
+    /** Omit to keep the stored password when updating */
+    password?: string;
+    insecure: boolean;
+}
+
+export async function listRegistries(): Promise<Registry[]> {
+    const res = await fetch(`${API_BASE}/registries`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function saveRegistry(registry: SaveRegistry, id?: number): Promise<Registry> {
+    const res = await fetch(id ? `${API_BASE}/registries/${id}` : `${API_BASE}/registries`, {
+        method: id ? "PUT" : "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(registry),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function deleteRegistry(id: number) {
+    const res = await fetch(`${API_BASE}/registries/${id}`, {
+        method: "DELETE",
+    });
+    if (!res.ok) throw new Error(await res.text());
+}
+
 export interface ImageDeployment {
     server_id: number;
     server_name: string;
     compose_name: string;
+    compose_path: string;
+    service: string;
     image_tag: string;
 }
 
@@ -237,3 +349,193 @@ export async function clearNotifications() {
     });
     if (!res.ok) throw new Error(await res.text());
 }
+
+export interface Environment {
+    id: number;
+    repository_id: number;
+    name: string;
+    server_id: number;
+    compose_path: string;
+    service?: string | null;
+    position: number;
+    requires_approval: boolean;
+}
+
+export interface Promotion {
+    id: number;
+    repository_path: string;
+    from_env: string;
+    to_env: string;
+    image: string;
+    status: "pending" | "approved" | "rejected";
+    triggered_by?: string | null;
+    job_id?: string | null;
+    created_at: number;
+    decided_at?: number | null;
+}
+
+export async function getEnvironments(path: string): Promise<Environment[]> {
+    const res = await fetch(`${API_BASE}/environments?path=${encodeURIComponent(path)}`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function promoteEnvironment(path: string, from: string, to?: string): Promise<Promotion> {
+    const res = await fetch(`${API_BASE}/environments/promote`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ path, from, to }),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function getPromotions(status?: string): Promise<Promotion[]> {
+    const query = status ? `?status=${encodeURIComponent(status)}` : "";
+    const res = await fetch(`${API_BASE}/promotions${query}`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function decidePromotion(id: number, approve: boolean): Promise<Promotion> {
+    const res = await fetch(`${API_BASE}/promotions/${id}/${approve ? "approve" : "reject"}`, {
+        method: "POST",
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export type Role = "viewer" | "builder" | "deployer" | "admin";
+
+export const ROLES: Role[] = ["viewer", "builder", "deployer", "admin"];
+
+export interface AuthStatus {
+    setup_required: boolean;
+    authenticated: boolean;
+    username?: string | null;
+    role?: Role | null;
+}
+
+export async function getAuthStatus(): Promise<AuthStatus> {
+    const res = await fetch(`${API_BASE}/auth/status`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+/** Logs in, or creates the admin account when `setup` is set. */
+export async function login(username: string, password: string, setup = false): Promise<AuthStatus> {
+    const res = await fetch(`${API_BASE}/auth/${setup ? "setup" : "login"}`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ username, password }),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function logout() {
+    const res = await fetch(`${API_BASE}/auth/logout`, {
+        method: "POST",
+    });
+    if (!res.ok) throw new Error(await res.text());
+}
+
+export async function changePassword(currentPassword: string, newPassword: string) {
+    const res = await fetch(`${API_BASE}/auth/password`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ current_password: currentPassword, new_password: newPassword }),
+    });
+    if (!res.ok) throw new Error(await res.text());
+}
+
+export interface ApiToken {
+    id: number;
+    name: string;
+    /** Unset: follows the user's role */
+    role?: Role | null;
+    /** Unset: every server */
+    server_ids?: number[] | null;
+    /** Unset: every repository */
+    repositories?: string[] | null;
+    created_at: number;
+    last_used_at?: number | null;
+    expires_at?: number | null;
+}
+
+export async function listTokens(): Promise<ApiToken[]> {
+    const res = await fetch(`${API_BASE}/auth/tokens`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export interface CreateToken {
+    name: string;
+    expires_in_days?: number;
+    role?: Role;
+    server_ids?: number[];
+    repositories?: string[];
+}
+
+export async function createToken(token: CreateToken): Promise<{ token: ApiToken; secret: string }> {
+    const res = await fetch(`${API_BASE}/auth/tokens`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(token),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function deleteToken(id: number) {
+    const res = await fetch(`${API_BASE}/auth/tokens/${id}`, {
+        method: "DELETE",
+    });
+    if (!res.ok) throw new Error(await res.text());
+}
+
+export async function listDirectories(): Promise<{ path: string }[]> {
+    const res = await fetch(`${API_BASE}/directories`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export interface User {
+    id: number;
+    username: string;
+    role: Role;
+    created_at: number;
+}
+
+export async function listUsers(): Promise<User[]> {
+    const res = await fetch(`${API_BASE}/users`);
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function createUser(username: string, password: string, role: Role): Promise<User> {
+    const res = await fetch(`${API_BASE}/users`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ username, password, role }),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function updateUser(id: number, update: { role?: Role; password?: string }): Promise<User> {
+    const res = await fetch(`${API_BASE}/users/${id}`, {
+        method: "PUT",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(update),
+    });
+    if (!res.ok) throw new Error(await res.text());
+    return res.json();
+}
+
+export async function deleteUser(id: number) {
+    const res = await fetch(`${API_BASE}/users/${id}`, {
+        method: "DELETE",
+    });
+    if (!res.ok) throw new Error(await res.text());
+}
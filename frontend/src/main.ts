@@ -3,6 +3,17 @@ import './lib/i18n'
 import './app.css'
 import App from './App.svelte'
 
+// Bring back the login screen when the session ends while the app is open
+const nativeFetch = window.fetch.bind(window)
+window.fetch = async (input, init) => {
+  const res = await nativeFetch(input, init)
+  const url = typeof input === 'string' ? input : input instanceof URL ? input.href : input.url
+  if (res.status === 401 && !url.includes('/api/auth/')) {
+    window.dispatchEvent(new Event('unauthorized'))
+  }
+  return res
+}
+
 const app = mount(App, {
   target: document.getElementById('app')!,
 })